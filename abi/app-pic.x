@@ -0,0 +1,27 @@
+/* Linker script for position-independent RAM apps (`.ELF`). Build with
+ * `-C relocation-model=pie` and link with `-pie`; the loader picks the load
+ * address and applies R_ARM_RELATIVE / R_ARM_ABS32 relocations from
+ * `.rel.dyn`. The AppHeader must stay first so it lands at the load address. */
+SECTIONS {
+    . = 0;
+
+    .app_header : { KEEP(*(.app_header)); }
+    .text : { *(.text .text.*); }
+    .rodata : ALIGN(4) { *(.rodata .rodata.*); }
+    .data : ALIGN(4) { *(.data .data.*); *(.got .got.*); }
+    .bss (NOLOAD) : ALIGN(4)
+    {
+        __bss_start = .;
+        *(.bss .bss.*);
+        *(COMMON);
+        . = ALIGN(4);
+        __bss_end = .;
+    }
+
+    .dynsym : { *(.dynsym); }
+    .dynstr : { *(.dynstr); }
+    .rel.dyn : { *(.rel.*); }
+    .dynamic : { *(.dynamic); }
+
+    /DISCARD/ : { *(.ARM.exidx .ARM.exidx.*); }
+}
@@ -0,0 +1,27 @@
+/* Linker script for RAM apps launched by the loader. The loader copies the
+ * image to APP_RAM_START and calls the entry in the AppHeader placed first.
+ * .bss is not in the image, the header points the loader at it. */
+MEMORY {
+    APPRAM : ORIGIN = 0x20020000, LENGTH = 128K
+}
+
+SECTIONS {
+    .app_header ORIGIN(APPRAM) :
+    {
+        KEEP(*(.app_header));
+    } > APPRAM
+
+    .text : { *(.text .text.*); } > APPRAM
+    .rodata : ALIGN(4) { *(.rodata .rodata.*); } > APPRAM
+    .data : ALIGN(4) { *(.data .data.*); } > APPRAM
+    .bss (NOLOAD) : ALIGN(4)
+    {
+        __bss_start = .;
+        *(.bss .bss.*);
+        *(COMMON);
+        . = ALIGN(4);
+        __bss_end = .;
+    } > APPRAM
+
+    /DISCARD/ : { *(.ARM.exidx .ARM.exidx.*); }
+}
@@ -0,0 +1,213 @@
+//! Service table shared between the loader and the applications it launches.
+//!
+//! The loader places a [`ServiceTable`] at [`SERVICE_TABLE_ADDR`] in flash. An
+//! application looks it up with [`table`] and calls the display and SD card
+//! routines through it instead of linking its own drivers.
+#![no_std]
+
+/// "RPLD" in little endian.
+pub const MAGIC: u32 = 0x444C_5052;
+
+pub const ABI_VERSION_MAJOR: u16 = 1;
+pub const ABI_VERSION_MINOR: u16 = 2;
+pub const ABI_VERSION: u32 = (ABI_VERSION_MAJOR as u32) << 16 | ABI_VERSION_MINOR as u32;
+
+/// Splits an `abi_version` word into its major and minor version.
+pub const fn split_version(abi_version: u32) -> (u16, u16) {
+    ((abi_version >> 16) as u16, abi_version as u16)
+}
+
+/// Must match the `SERVICES` region in the loader's `memory.x`. Apps built
+/// against any 1.x version look here, so it must not move within a major
+/// version.
+pub const SERVICE_TABLE_ADDR: usize = 0x101F_FF00;
+
+/// RAM the loader copies apps into. Must match the `APPRAM` region in the
+/// loader's `memory.x` and `app.x`.
+pub const APP_RAM_START: usize = 0x2002_0000;
+pub const APP_RAM_SIZE: usize = 128 * 1024;
+
+/// "RPAP" in little endian.
+pub const APP_MAGIC: u32 = 0x5041_5052;
+
+pub const ERR_NOT_FOUND: i32 = -1;
+pub const ERR_IO: i32 = -2;
+pub const ERR_INVALID: i32 = -3;
+
+/// Colors are passed as `0x00RRGGBB`.
+pub type Rgb = u32;
+
+/// Function pointers may only be appended; existing entries never move. New
+/// entries bump the minor version, anything else bumps the major version.
+#[repr(C)]
+pub struct ServiceTable {
+    pub magic: u32,
+    pub abi_version: u32,
+    /// Size of the table in bytes, so apps can tell which entries exist.
+    pub size: u32,
+    pub draw_rect: unsafe extern "C" fn(x: u8, y: u8, width: u8, height: u8, color: Rgb),
+    pub draw_text: unsafe extern "C" fn(
+        text: *const u8,
+        len: usize,
+        x: u8,
+        y: u8,
+        size_scalar: u32,
+        text_color: Rgb,
+        background_color: Rgb,
+    ),
+    /// Returns the number of bytes read, or one of the `ERR_*` codes.
+    pub read_file: unsafe extern "C" fn(
+        name: *const u8,
+        name_len: usize,
+        offset: u32,
+        buf: *mut u8,
+        buf_len: usize,
+    ) -> i32,
+    /// Ends the running app and returns `code` to the loader. Since 1.1.
+    pub exit: unsafe extern "C" fn(code: i32) -> !,
+}
+
+const PTR: usize = core::mem::size_of::<usize>();
+/// The three `u32` header words, padded up to pointer alignment.
+const HEADER: usize = (3 * 4usize).div_ceil(PTR) * PTR;
+
+const _: () = assert!(core::mem::size_of::<ServiceTable>() == HEADER + 4 * PTR);
+const _: () = assert!(core::mem::align_of::<ServiceTable>() == PTR);
+
+// The offsets are the ABI, a reordered field must not build.
+const _: () = assert!(core::mem::offset_of!(ServiceTable, magic) == 0);
+const _: () = assert!(core::mem::offset_of!(ServiceTable, abi_version) == 4);
+const _: () = assert!(core::mem::offset_of!(ServiceTable, size) == 8);
+const _: () = assert!(core::mem::offset_of!(ServiceTable, draw_rect) == HEADER);
+const _: () = assert!(core::mem::offset_of!(ServiceTable, draw_text) == HEADER + PTR);
+const _: () = assert!(core::mem::offset_of!(ServiceTable, read_file) == HEADER + 2 * PTR);
+const _: () = assert!(core::mem::offset_of!(ServiceTable, exit) == HEADER + 3 * PTR);
+
+extern "C" {
+    // Defined by `app.x` and `app-pic.x`.
+    static mut __bss_start: u32;
+    static mut __bss_end: u32;
+}
+
+/// Placed at the very start of a RAM app image by `app.x`.
+#[repr(C)]
+pub struct AppHeader {
+    pub magic: u32,
+    /// The ABI version the app was built against.
+    pub abi_version: u32,
+    /// Returning from `entry` has the same effect as calling [`exit`].
+    pub entry: unsafe extern "C" fn() -> i32,
+    /// `.bss`, which is not in the image. The loader reserves and zeroes it
+    /// before calling `entry`. Since 1.2.
+    pub bss_start: *mut u32,
+    pub bss_end: *mut u32,
+}
+
+// The header is never written, the pointers only tell the loader where
+// `.bss` is.
+unsafe impl Sync for AppHeader {}
+
+impl AppHeader {
+    pub const fn new(entry: unsafe extern "C" fn() -> i32) -> Self {
+        Self {
+            magic: APP_MAGIC,
+            abi_version: ABI_VERSION,
+            entry,
+            bss_start: &raw mut __bss_start,
+            bss_end: &raw mut __bss_end,
+        }
+    }
+
+    /// Whether a header of `abi_version` has the `.bss` fields. Nothing is
+    /// known about the layout of other major versions.
+    pub fn has_bss(abi_version: u32) -> bool {
+        let (major, minor) = split_version(abi_version);
+        major == ABI_VERSION_MAJOR && minor >= 2
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.magic == APP_MAGIC && split_version(self.abi_version).0 == ABI_VERSION_MAJOR
+    }
+}
+
+impl ServiceTable {
+    pub fn is_compatible(&self) -> bool {
+        self.magic == MAGIC && split_version(self.abi_version).0 == ABI_VERSION_MAJOR
+    }
+
+    /// Whether the entry starting at `offset` is covered by this table.
+    pub fn provides(&self, offset: usize) -> bool {
+        offset + PTR <= self.size as usize
+    }
+}
+
+/// Returns the loader's service table if one with a compatible major version
+/// is present.
+pub fn table() -> Option<&'static ServiceTable> {
+    let table = unsafe { &*(SERVICE_TABLE_ADDR as *const ServiceTable) };
+    if table.is_compatible() {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+pub fn rgb(r: u8, g: u8, b: u8) -> Rgb {
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+pub fn draw_rect(x: u8, y: u8, width: u8, height: u8, color: Rgb) {
+    if let Some(table) = table() {
+        unsafe { (table.draw_rect)(x, y, width, height, color) }
+    }
+}
+
+pub fn draw_text(
+    text: &str,
+    x: u8,
+    y: u8,
+    size_scalar: u32,
+    text_color: Rgb,
+    background_color: Rgb,
+) {
+    if let Some(table) = table() {
+        unsafe {
+            (table.draw_text)(
+                text.as_ptr(),
+                text.len(),
+                x,
+                y,
+                size_scalar,
+                text_color,
+                background_color,
+            )
+        }
+    }
+}
+
+pub fn read_file(name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32> {
+    let table = table().ok_or(ERR_INVALID)?;
+    let ret = unsafe {
+        (table.read_file)(
+            name.as_ptr(),
+            name.len(),
+            offset,
+            buf.as_mut_ptr(),
+            buf.len(),
+        )
+    };
+    if ret < 0 {
+        Err(ret)
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+pub fn exit(code: i32) -> ! {
+    match table() {
+        Some(table) if table.provides(core::mem::offset_of!(ServiceTable, exit)) => unsafe {
+            (table.exit)(code)
+        },
+        _ => panic!("loader does not provide exit"),
+    }
+}
@@ -0,0 +1,59 @@
+use core::mem::{align_of, offset_of, size_of};
+
+use rp_loader_abi::*;
+
+const PTR: usize = size_of::<usize>();
+
+#[test]
+fn service_table_layout() {
+    // The three header words, then the entries a pointer apart.
+    let header = (3 * 4usize).div_ceil(PTR) * PTR;
+    assert_eq!(offset_of!(ServiceTable, magic), 0);
+    assert_eq!(offset_of!(ServiceTable, abi_version), 4);
+    assert_eq!(offset_of!(ServiceTable, size), 8);
+    assert_eq!(offset_of!(ServiceTable, draw_rect), header);
+    assert_eq!(offset_of!(ServiceTable, draw_text), header + PTR);
+    assert_eq!(offset_of!(ServiceTable, read_file), header + 2 * PTR);
+    assert_eq!(offset_of!(ServiceTable, exit), header + 3 * PTR);
+    assert_eq!(size_of::<ServiceTable>(), header + 4 * PTR);
+    assert_eq!(align_of::<ServiceTable>(), PTR);
+}
+
+#[test]
+fn app_header_layout() {
+    // On the RP2040 these are the five words the loader reads.
+    assert_eq!(offset_of!(AppHeader, magic), 0);
+    assert_eq!(offset_of!(AppHeader, abi_version), 4);
+    assert_eq!(offset_of!(AppHeader, entry), 8usize.next_multiple_of(PTR));
+    assert_eq!(
+        offset_of!(AppHeader, bss_start),
+        offset_of!(AppHeader, entry) + PTR
+    );
+    assert_eq!(
+        offset_of!(AppHeader, bss_end),
+        offset_of!(AppHeader, bss_start) + PTR
+    );
+    assert_eq!(size_of::<AppHeader>(), offset_of!(AppHeader, bss_end) + PTR);
+}
+
+#[test]
+fn version_words() {
+    assert_eq!(
+        split_version(ABI_VERSION),
+        (ABI_VERSION_MAJOR, ABI_VERSION_MINOR)
+    );
+    assert_eq!(split_version(0x0001_0002), (1, 2));
+    assert_eq!(split_version(0xFFFF_0000), (0xFFFF, 0));
+}
+
+#[test]
+fn bss_fields_since_1_2() {
+    assert!(!AppHeader::has_bss(0x0001_0000));
+    assert!(!AppHeader::has_bss(0x0001_0001));
+    assert!(AppHeader::has_bss(0x0001_0002));
+    assert!(AppHeader::has_bss(0x0001_0010));
+    // Other major versions may lay the header out differently.
+    assert!(!AppHeader::has_bss(0x0002_0000));
+    assert!(!AppHeader::has_bss(0x0002_0002));
+    assert!(!AppHeader::has_bss(0x0000_0002));
+}
@@ -7,13 +7,22 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! The flash layout the loader uses at run time lives in `src/layout.rs`.
+//! The `FLASH` and `FLASHFS` regions are checked against it here, so a
+//! change to one without the other fails the build.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+#[path = "src/layout.rs"]
+mod layout;
+
 fn main() {
+    check_layout(include_str!("memory.x"));
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
@@ -28,4 +37,69 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=src/layout.rs");
+}
+
+fn check_layout(memory: &str) {
+    let (origin, length) = region(memory, "FLASH");
+    assert_eq!(
+        origin + length,
+        layout::FLASH_BASE + layout::LOADER_SIZE,
+        "memory.x: FLASH has to end at LOADER_SIZE from src/layout.rs"
+    );
+    assert_eq!(
+        region(memory, "FLASHFS"),
+        (layout::FLASH_BASE + layout::FS_START, layout::FS_SIZE),
+        "memory.x: FLASHFS has to match FS_START and FS_SIZE from src/layout.rs"
+    );
+}
+
+/// `ORIGIN` and `LENGTH` of the region `name`.
+fn region(memory: &str, name: &str) -> (u32, u32) {
+    let line = memory
+        .lines()
+        .find(|line| line.split_whitespace().next() == Some(name))
+        .unwrap_or_else(|| panic!("memory.x: no {name} region"));
+    let (_, attributes) = line.split_once(':').unwrap();
+    let mut origin = None;
+    let mut length = None;
+    for attribute in attributes.split(',') {
+        let (key, value) = attribute.split_once('=').unwrap();
+        match key.trim() {
+            "ORIGIN" => origin = Some(evaluate(value)),
+            "LENGTH" => length = Some(evaluate(value)),
+            key => panic!("memory.x: unknown attribute {key} of {name}"),
+        }
+    }
+    (origin.unwrap(), length.unwrap())
+}
+
+/// Sums and differences of hex or decimal numbers, optionally in K or M,
+/// which is all `memory.x` uses.
+fn evaluate(expression: &str) -> u32 {
+    let mut total: i64 = 0;
+    let mut sign = 1;
+    for token in expression.split_whitespace() {
+        match token {
+            "+" => sign = 1,
+            "-" => sign = -1,
+            _ => total += sign * number(token),
+        }
+    }
+    u32::try_from(total).expect("memory.x: address out of range")
+}
+
+fn number(token: &str) -> i64 {
+    let (digits, scale) = if let Some(digits) = token.strip_suffix('K') {
+        (digits, 1024)
+    } else if let Some(digits) = token.strip_suffix('M') {
+        (digits, 1024 * 1024)
+    } else {
+        (token, 1)
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    value.unwrap_or_else(|_| panic!("memory.x: cannot read {token}")) * scale
 }
@@ -1,7 +1,18 @@
 MEMORY {
-    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    BOOT2    : ORIGIN = 0x10000000, LENGTH = 0x100
+    /* The loader keeps the first 1M (LOADER_SIZE in src/layout.rs), then
+     * comes the littlefs volume. The rest of the chip, whatever its size, is
+     * the app region, except for the sector holding the service table. */
+    FLASH    : ORIGIN = 0x10000100, LENGTH = 1024K - 0x100
+    /* Must match SERVICE_TABLE_ADDR in abi/src/lib.rs. It stays at the end
+     * of the first 2M where ABI 1.x apps expect it. */
+    SERVICES : ORIGIN = 0x101FFF00, LENGTH = 0x100
+    /* Nothing is linked here, littlefs formats it on first boot. build.rs
+     * checks it and FLASH against src/layout.rs */
+    FLASHFS  : ORIGIN = 0x10100000, LENGTH = 256K
+    RAM      : ORIGIN = 0x20000000, LENGTH = 128K
+    /* Must match APP_RAM_START and APP_RAM_SIZE in abi/src/lib.rs */
+    APPRAM   : ORIGIN = 0x20020000, LENGTH = 128K
 }
 
 EXTERN(BOOT2_FIRMWARE)
@@ -12,4 +23,12 @@ SECTIONS {
     {
         KEEP(*(.boot2));
     } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+} INSERT BEFORE .text;
+
+SECTIONS {
+    /* ### Loader service table */
+    .services ORIGIN(SERVICES) :
+    {
+        KEEP(*(.services));
+    } > SERVICES
+} INSERT AFTER .rodata;
@@ -0,0 +1,493 @@
+use core::{mem, ptr, slice};
+
+use alloc::vec::Vec;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use rp_loader_abi::{AppHeader, APP_MAGIC, APP_RAM_SIZE, APP_RAM_START};
+
+use crate::{
+    buttons::{Button, Buttons},
+    elf::{self, Elf, ElfError},
+    error::LoaderError,
+    flashfs::{self, FlashFs},
+    fs::{self, Dir, Entry, File, Storage},
+    heap,
+    ram::Allocator,
+    services::{self, FlashSession, Host, Session},
+    ui,
+    wasm::{self, Platform, WasmError},
+    Display,
+};
+
+/// Position-independent images only need word alignment for their data, the
+/// page alignment the linker records does not matter in RAM.
+const ELF_ALIGN: usize = 8;
+
+pub enum LaunchError {
+    NotFound,
+    TooLarge,
+    Io,
+    BadHeader,
+    NoMemory,
+    Elf(ElfError),
+    Wasm(WasmError),
+    /// The loader itself failed, the app never started.
+    Loader(LoaderError),
+}
+
+impl From<ElfError> for LaunchError {
+    fn from(err: ElfError) -> Self {
+        LaunchError::Elf(err)
+    }
+}
+
+impl From<LoaderError> for LaunchError {
+    fn from(err: LoaderError) -> Self {
+        LaunchError::Loader(err)
+    }
+}
+
+impl LaunchError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            LaunchError::NotFound => "App not found.",
+            LaunchError::TooLarge => "App does not fit in RAM.",
+            LaunchError::Io => "Failed to read app.",
+            LaunchError::BadHeader => "Not a compatible app.",
+            LaunchError::NoMemory => "Not enough free app RAM.",
+            LaunchError::Elf(ElfError::Io) => "Failed to read app.",
+            LaunchError::Elf(ElfError::UnsupportedRelocation(_)) => "Unsupported relocation.",
+            LaunchError::Elf(ElfError::UndefinedSymbol) => "Undefined symbol in app.",
+            LaunchError::Elf(ElfError::Unsupported) => "App is not a PIE ARM ELF.",
+            LaunchError::Elf(_) => "Malformed ELF app.",
+            LaunchError::Wasm(err) => err.message(),
+            LaunchError::Loader(err) => err.message(),
+        }
+    }
+}
+
+extern "C" {
+    fn rp_loader_enter(entry: unsafe extern "C" fn() -> i32) -> i32;
+    fn rp_loader_exit(code: i32) -> !;
+}
+
+/// Stack pointer saved by `rp_loader_enter`, zero while no app is running.
+#[no_mangle]
+static mut RP_LOADER_EXIT_SP: u32 = 0;
+
+// `rp_loader_enter` saves the callee-saved registers and the stack pointer
+// before calling the app. `rp_loader_exit` unwinds straight back to that
+// point, so an app can leave from anywhere in its call stack.
+core::arch::global_asm!(
+    ".section .text.rp_loader_enter, \"ax\"",
+    ".global rp_loader_enter",
+    ".global rp_loader_exit",
+    ".thumb_func",
+    "rp_loader_enter:",
+    "    push {{r3-r7, lr}}",
+    "    mov r4, r8",
+    "    mov r5, r9",
+    "    mov r6, r10",
+    "    mov r7, r11",
+    "    push {{r4-r7}}",
+    "    ldr r1, =RP_LOADER_EXIT_SP",
+    "    mov r2, sp",
+    "    str r2, [r1]",
+    "    blx r0",
+    "    b 1f",
+    ".thumb_func",
+    "rp_loader_exit:",
+    "    ldr r1, =RP_LOADER_EXIT_SP",
+    "    ldr r1, [r1]",
+    "    mov sp, r1",
+    "1:",
+    "    ldr r1, =RP_LOADER_EXIT_SP",
+    "    movs r2, #0",
+    "    str r2, [r1]",
+    "    pop {{r4-r7}}",
+    "    mov r8, r4",
+    "    mov r9, r5",
+    "    mov r10, r6",
+    "    mov r11, r7",
+    "    pop {{r3-r7, pc}}",
+    ".pool",
+);
+
+pub fn is_runnable(extension: &str) -> bool {
+    matches!(extension, "BIN" | "ELF" | "WSM")
+}
+
+/// An app file, on the card or on the flash volume.
+pub trait Image {
+    fn length(&self) -> u32;
+    /// Reads from `offset` on and returns the number of bytes read, zero at
+    /// the end of the file.
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, LaunchError>;
+}
+
+enum Loaded {
+    Native {
+        header: &'static AppHeader,
+        /// Address of the image in app RAM, to be freed once the app has
+        /// exited.
+        base: usize,
+    },
+    /// All of app RAM is the heap for the module and the interpreter.
+    Wasm { module: Vec<u8>, base: usize },
+}
+
+impl Loaded {
+    fn free(self, ram: &mut Allocator) {
+        match self {
+            Loaded::Native { base, .. } => ram.free(base),
+            Loaded::Wasm { module, base } => {
+                drop(module);
+                ram.free(base);
+            }
+        }
+    }
+}
+
+/// Runs the app `entry` in `dir` with the display and card lent to it
+/// through the service table, and returns its exit code.
+pub fn launch<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    storage: &mut Storage<D, T>,
+    dir: &Dir,
+    ram: &mut Allocator,
+    entry: &Entry,
+) -> Result<i32, LaunchError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut file = storage
+        .open_entry(dir, entry)
+        .map_err(|_| LaunchError::NotFound)?;
+    let mut image = CardImage {
+        storage,
+        file: &mut file,
+    };
+    let loaded = load(&mut image, &entry.extension, ram);
+    storage.close(file);
+
+    let loaded = loaded?;
+    if let Err(err) = ui::clear(display) {
+        loaded.free(ram);
+        return Err(err.into());
+    }
+    let mut session = Session {
+        display,
+        storage,
+        dir,
+    };
+    start(loaded, &mut session, buttons, ram)
+}
+
+/// Runs the app `name` from the flash volume, which takes the place of the
+/// card for the app's file reads.
+pub fn launch_flash(
+    display: &mut Display,
+    buttons: &Buttons,
+    volume: &FlashFs,
+    ram: &mut Allocator,
+    name: &str,
+) -> Result<i32, LaunchError> {
+    let extension = fs::extension(name);
+    let loaded = flashfs::with_file(volume, name, |image| load(image, &extension, ram))
+        .map_err(|_| LaunchError::NotFound)?;
+
+    let loaded = loaded?;
+    if let Err(err) = ui::clear(display) {
+        loaded.free(ram);
+        return Err(err.into());
+    }
+    let mut session = FlashSession { display, volume };
+    start(loaded, &mut session, buttons, ram)
+}
+
+/// Loads a `.BIN` image linked at `APP_RAM_START`, a position-independent
+/// `.ELF` image at an address picked by `ram`, or a `.WSM` module.
+fn load(
+    image: &mut dyn Image,
+    extension: &str,
+    ram: &mut Allocator,
+) -> Result<Loaded, LaunchError> {
+    if extension == "WSM" {
+        return load_wasm(image, ram);
+    }
+    let (base, size) = if extension == "ELF" {
+        load_elf(image, ram)?
+    } else {
+        load_bin(image, ram)?
+    };
+    match check_header(base, size) {
+        Ok(header) => Ok(Loaded::Native { header, base }),
+        Err(err) => {
+            ram.free(base);
+            Err(err)
+        }
+    }
+}
+
+/// Runs a loaded app with `host` behind its services, and returns its exit
+/// code.
+fn start(
+    loaded: Loaded,
+    host: &mut dyn Host,
+    buttons: &Buttons,
+    ram: &mut Allocator,
+) -> Result<i32, LaunchError> {
+    match loaded {
+        Loaded::Native { header, base } => {
+            unsafe { services::install(host) };
+            let code = run(header);
+            services::uninstall();
+            ram.free(base);
+            Ok(code)
+        }
+        Loaded::Wasm { module, base } => {
+            let mut platform = WasmPlatform { host, buttons };
+            let result = wasm::run(&module, &mut platform, wasm::Limits::default())
+                .map_err(LaunchError::Wasm);
+            drop(module);
+            ram.free(base);
+            result
+        }
+    }
+}
+
+/// WASM apps are interpreted, all of app RAM becomes the heap for the module
+/// and the interpreter while one runs.
+fn load_wasm(image: &mut dyn Image, ram: &mut Allocator) -> Result<Loaded, LaunchError> {
+    let base = ram
+        .claim(APP_RAM_START, APP_RAM_SIZE)
+        .ok_or(LaunchError::NoMemory)?;
+    unsafe { heap::reset(base, APP_RAM_SIZE) };
+    match read_wasm(image) {
+        Ok(module) => Ok(Loaded::Wasm { module, base }),
+        Err(err) => {
+            ram.free(base);
+            Err(err)
+        }
+    }
+}
+
+fn read_wasm(image: &mut dyn Image) -> Result<Vec<u8>, LaunchError> {
+    let length = image.length() as usize;
+    let mut module = Vec::new();
+    module
+        .try_reserve_exact(length)
+        .map_err(|_| LaunchError::TooLarge)?;
+    module.resize(length, 0);
+
+    let mut loaded = 0;
+    while loaded < length {
+        match image.read_at(loaded as u32, &mut module[loaded..])? {
+            0 => break,
+            count => loaded += count,
+        }
+    }
+    module.truncate(loaded);
+    Ok(module)
+}
+
+struct WasmPlatform<'a> {
+    host: &'a mut dyn Host,
+    buttons: &'a Buttons,
+}
+
+impl<'a> Platform for WasmPlatform<'a> {
+    // Modules are untrusted, what they draw is kept on the panel.
+    fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, rgb: u32) {
+        if let Some((x, y, width, height)) = services::clip_rect(x, y, width, height) {
+            self.host
+                .draw_rect(x, y, width, height, services::color(rgb));
+        }
+    }
+
+    fn draw_text(&mut self, text: &str, x: u8, y: u8, size_scalar: u32, fg: u32, bg: u32) {
+        let Some(size_scalar) = services::text_scale(x, y, size_scalar) else {
+            return;
+        };
+        self.host.draw_text(
+            text,
+            x,
+            y,
+            size_scalar,
+            services::color(fg),
+            services::color(bg),
+        );
+    }
+
+    fn buttons(&mut self) -> u32 {
+        [
+            (Button::Up, wasm::BUTTON_UP),
+            (Button::Down, wasm::BUTTON_DOWN),
+            (Button::Select, wasm::BUTTON_SELECT),
+            (Button::Back, wasm::BUTTON_BACK),
+        ]
+        .into_iter()
+        .filter(|&(button, _)| self.buttons.is_held(button))
+        .fold(0, |mask, (_, bit)| mask | bit)
+    }
+
+    fn read_file(&mut self, name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32> {
+        self.host.read_file(name, offset, buf)
+    }
+}
+
+fn load_bin(image: &mut dyn Image, ram: &mut Allocator) -> Result<(usize, usize), LaunchError> {
+    let length = image.length() as usize;
+    let size = length.max(bss_end(image)?);
+    if size > APP_RAM_SIZE {
+        return Err(LaunchError::TooLarge);
+    }
+    let base = ram
+        .claim(APP_RAM_START, size)
+        .ok_or(LaunchError::NoMemory)?;
+
+    let buf = unsafe { slice::from_raw_parts_mut(base as *mut u8, size) };
+    let mut loaded = 0;
+    while loaded < length {
+        match image.read_at(loaded as u32, &mut buf[loaded..length]) {
+            Ok(0) => break,
+            Ok(count) => loaded += count,
+            Err(err) => {
+                ram.free(base);
+                return Err(err);
+            }
+        }
+    }
+    // `.bss` still holds whatever the last app or the volume check left.
+    buf[loaded..].fill(0);
+    Ok((base, size))
+}
+
+/// End of `.bss` as an offset into app RAM, zero if the header predates
+/// the `.bss` fields.
+fn bss_end(image: &mut dyn Image) -> Result<usize, LaunchError> {
+    let mut header = [0u8; 5 * 4];
+    let mut read = 0;
+    while read < header.len() {
+        match image.read_at(read as u32, &mut header[read..])? {
+            0 => return Ok(0),
+            count => read += count,
+        }
+    }
+    let word = |index: usize| {
+        let bytes = &header[index * 4..index * 4 + 4];
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+    };
+    if word(0) as u32 != APP_MAGIC || !AppHeader::has_bss(word(1) as u32) {
+        return Ok(0);
+    }
+    let (start, end) = (word(3), word(4));
+    let ram = APP_RAM_START..=APP_RAM_START + APP_RAM_SIZE;
+    if !ram.contains(&start) || !ram.contains(&end) || start > end {
+        return Err(LaunchError::BadHeader);
+    }
+    Ok(end - APP_RAM_START)
+}
+
+fn load_elf(image: &mut dyn Image, ram: &mut Allocator) -> Result<(usize, usize), LaunchError> {
+    let mut src = ElfSource(image);
+    let elf = Elf::parse(&mut src)?;
+    let size = elf.image_size(&mut src)? as usize;
+    if size > APP_RAM_SIZE {
+        return Err(LaunchError::TooLarge);
+    }
+    let base = ram.alloc(size, ELF_ALIGN).ok_or(LaunchError::NoMemory)?;
+
+    let buf = unsafe { slice::from_raw_parts_mut(base as *mut u8, size) };
+    if let Err(err) = elf.load(&mut src, buf, base as u32) {
+        ram.free(base);
+        return Err(err.into());
+    }
+    Ok((base, size))
+}
+
+fn check_header(base: usize, size: usize) -> Result<&'static AppHeader, LaunchError> {
+    if size < mem::size_of::<AppHeader>() {
+        return Err(LaunchError::BadHeader);
+    }
+    // Validate the entry word before treating the image as an `AppHeader`, an
+    // invalid function pointer must never be materialised.
+    let [_, _, entry] = unsafe { ptr::read(base as *const [u32; 3]) };
+    let entry = entry as usize & !1;
+    if !(base..base + size).contains(&entry) {
+        return Err(LaunchError::BadHeader);
+    }
+    let header = unsafe { &*(base as *const AppHeader) };
+    if !header.is_compatible() {
+        return Err(LaunchError::BadHeader);
+    }
+    Ok(header)
+}
+
+/// A file on the card.
+struct CardImage<'a, 'b, D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    storage: &'a mut Storage<'b, D, T>,
+    file: &'a mut File,
+}
+
+impl<'a, 'b, D, T> Image for CardImage<'a, 'b, D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn length(&self) -> u32 {
+        self.file.length()
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, LaunchError> {
+        if offset >= self.file.length() {
+            return Ok(0);
+        }
+        self.file
+            .seek_from_start(offset)
+            .map_err(|_| LaunchError::Io)?;
+        self.storage
+            .read(self.file, buf)
+            .map_err(|_| LaunchError::Io)
+    }
+}
+
+/// Gives the ELF loader exactly the bytes it asks for.
+struct ElfSource<'a>(&'a mut dyn Image);
+
+impl<'a> elf::Source for ElfSource<'a> {
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ElfError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let count = self
+                .0
+                .read_at(offset + done as u32, &mut buf[done..])
+                .map_err(|_| ElfError::Io)?;
+            if count == 0 {
+                return Err(ElfError::OutOfBounds);
+            }
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a loaded app until it returns from its entry or calls the exit
+/// service, and returns its exit code.
+fn run(header: &AppHeader) -> i32 {
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    unsafe { rp_loader_enter(header.entry) }
+}
+
+pub unsafe extern "C" fn exit(code: i32) -> ! {
+    if RP_LOADER_EXIT_SP == 0 {
+        panic!("exit called while no app is running");
+    }
+    rp_loader_exit(code)
+}
@@ -8,7 +8,11 @@ use rp_pico::hal::{
     Spi,
 };
 
-use crate::artemis;
+use crate::{
+    artemis,
+    dma::{self, SpiDma},
+    error::LoaderError,
+};
 
 const CHARS: [u8; 475] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x08, 0x40, 0x10, 0x00, 0x52, 0x94, 0x00, 0x00, 0x00, 0x52,
@@ -43,10 +47,18 @@ const CHARS: [u8; 475] = [
     0x80, 0x41, 0x08, 0x22, 0x11, 0x00, 0x00, 0x11, 0x51, 0x00, 0x00,
 ];
 
-const ARTEMIS_COLOR: Color = Color(0x37, 0xD8, 0xDB);
+pub const ARTEMIS_COLOR: Color = Color(0x37, 0xD8, 0xDB);
 
 pub const FONT_WIDTH: u8 = 5;
 pub const FONT_HEIGHT: u8 = 8;
+/// Largest text scale, one character is then as tall as the panel. Larger
+/// ones are drawn at this size.
+pub const MAX_TEXT_SCALE: u32 = 240 / FONT_HEIGHT as u32;
+
+/// One full row of RGB565 pixels.
+const LINE_BYTES: usize = 2 * 240;
+/// Parts of a streamed window, eight rows.
+const STREAM_BYTES: usize = 8 * LINE_BYTES;
 
 pub struct Atm0130<SPI, SS, DC, RS>
 where
@@ -59,6 +71,8 @@ where
     ss: Pin<SS, Output<PushPull>>,
     data_cmd: Pin<DC, Output<PushPull>>,
     reset: Pin<RS, Output<PushPull>>,
+    /// Moves pixel data, the blocking path is used without it.
+    dma: Option<SpiDma>,
 }
 
 impl<SPI, SS, DC, RS> Atm0130<SPI, SS, DC, RS>
@@ -73,156 +87,172 @@ where
         ss: Pin<SS, Output<PushPull>>,
         data_cmd: Pin<DC, Output<PushPull>>,
         reset: Pin<RS, Output<PushPull>>,
+        dma: Option<SpiDma>,
     ) -> Self {
         Self {
             spi_enabled: spi,
             ss,
             data_cmd,
             reset,
+            dma,
         }
     }
 
-    pub fn begin(&mut self, delay: &mut Delay) {
-        self.ss.set_high().unwrap();
-        self.reset_lcd(delay);
-        self.ss.set_low().unwrap();
+    pub fn begin(&mut self, delay: &mut Delay) -> Result<(), LoaderError> {
+        gpio(self.ss.set_high())?;
+        self.reset_lcd(delay)?;
+        gpio(self.ss.set_low())?;
 
-        self.write_reg(0x11);
+        self.write_reg(0x11)?;
         delay.delay_ms(100);
 
-        self.write_reg(0x36); //MADCTL
-        self.write_data(0x00);
+        self.write_reg(0x36)?; //MADCTL
+        self.write_data(0x00)?;
         //MY=0
         //MX=0
         //MV=0
         //ML=0
         //RGB=0
         //MH=0
-        self.write_reg(0x3A);
-        self.write_data(0x55); //65K color , 16bit / pixel
+        self.write_reg(0x3A)?;
+        self.write_data(0x55)?; //65K color , 16bit / pixel
 
         ////--------------------------------ST7789V Frame rate
 
-        self.write_reg(0xb2);
-        self.write_data(0x0c);
-        self.write_data(0x0c);
-        self.write_data(0x00);
-        self.write_data(0x33);
-        self.write_data(0x33);
+        self.write_reg(0xb2)?;
+        self.write_data(0x0c)?;
+        self.write_data(0x0c)?;
+        self.write_data(0x00)?;
+        self.write_data(0x33)?;
+        self.write_data(0x33)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xb7);
-        self.write_data(0x75);
+        self.write_reg(0xb7)?;
+        self.write_data(0x75)?;
 
         delay.delay_ms(2);
         ////---------------------------------ST7789V Power
-        self.write_reg(0xc2);
-        self.write_data(0x01);
+        self.write_reg(0xc2)?;
+        self.write_data(0x01)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xc3);
-        self.write_data(0x10);
+        self.write_reg(0xc3)?;
+        self.write_data(0x10)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xc4);
-        self.write_data(0x20);
+        self.write_reg(0xc4)?;
+        self.write_data(0x20)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xc6);
-        self.write_data(0x0f);
+        self.write_reg(0xc6)?;
+        self.write_data(0x0f)?;
 
-        self.write_reg(0xb0);
-        self.write_data(0x00);
-        self.write_data(0xf0); //RRRR RGGGG GGGB BBBB
+        self.write_reg(0xb0)?;
+        self.write_data(0x00)?;
+        self.write_data(0xf0)?; //RRRR RGGGG GGGB BBBB
 
         delay.delay_ms(2);
 
-        self.write_reg(0xD0);
-        self.write_data(0xA4);
-        self.write_data(0xA1);
+        self.write_reg(0xD0)?;
+        self.write_data(0xA4)?;
+        self.write_data(0xA1)?;
         delay.delay_ms(2);
 
         ////--------------------------------ST7789V gamma
-        self.write_reg(0x21);
+        self.write_reg(0x21)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xbb);
-        self.write_data(0x3b);
+        self.write_reg(0xbb)?;
+        self.write_data(0x3b)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xE0); //Set Gamma
-        self.write_data(0xF0);
-        self.write_data(0x0b);
-        self.write_data(0x11);
-        self.write_data(0x0e);
-        self.write_data(0x0d);
-        self.write_data(0x19);
-        self.write_data(0x36);
-        self.write_data(0x33);
-        self.write_data(0x4b);
-        self.write_data(0x07);
-        self.write_data(0x14);
-        self.write_data(0x14);
-        self.write_data(0x2c);
-        self.write_data(0x2e);
+        self.write_reg(0xE0)?; //Set Gamma
+        self.write_data(0xF0)?;
+        self.write_data(0x0b)?;
+        self.write_data(0x11)?;
+        self.write_data(0x0e)?;
+        self.write_data(0x0d)?;
+        self.write_data(0x19)?;
+        self.write_data(0x36)?;
+        self.write_data(0x33)?;
+        self.write_data(0x4b)?;
+        self.write_data(0x07)?;
+        self.write_data(0x14)?;
+        self.write_data(0x14)?;
+        self.write_data(0x2c)?;
+        self.write_data(0x2e)?;
 
         delay.delay_ms(2);
 
-        self.write_reg(0xE1); //Set Gamma
-        self.write_data(0xF0);
-        self.write_data(0x0d);
-        self.write_data(0x12);
-        self.write_data(0x0b);
-        self.write_data(0x09);
-        self.write_data(0x03);
-        self.write_data(0x32);
-        self.write_data(0x44);
-        self.write_data(0x48);
-        self.write_data(0x39);
-        self.write_data(0x16);
-        self.write_data(0x16);
-        self.write_data(0x2d);
-        self.write_data(0x30);
-
-        self.write_reg(0x2A);
-        self.write_data(0x00);
-        self.write_data(0x00);
-        self.write_data(0x00);
-        self.write_data(0xEF);
-
-        self.write_reg(0x2B);
-        self.write_data(0x00);
-        self.write_data(0x00);
-        self.write_data(0x00);
-        self.write_data(0xEF);
-
-        self.write_reg(0x29); //Display on
+        self.write_reg(0xE1)?; //Set Gamma
+        self.write_data(0xF0)?;
+        self.write_data(0x0d)?;
+        self.write_data(0x12)?;
+        self.write_data(0x0b)?;
+        self.write_data(0x09)?;
+        self.write_data(0x03)?;
+        self.write_data(0x32)?;
+        self.write_data(0x44)?;
+        self.write_data(0x48)?;
+        self.write_data(0x39)?;
+        self.write_data(0x16)?;
+        self.write_data(0x16)?;
+        self.write_data(0x2d)?;
+        self.write_data(0x30)?;
+
+        self.write_reg(0x2A)?;
+        self.write_data(0x00)?;
+        self.write_data(0x00)?;
+        self.write_data(0x00)?;
+        self.write_data(0xEF)?;
+
+        self.write_reg(0x2B)?;
+        self.write_data(0x00)?;
+        self.write_data(0x00)?;
+        self.write_data(0x00)?;
+        self.write_data(0xEF)?;
+
+        self.write_reg(0x29)?; //Display on
 
         delay.delay_ms(2);
-        self.write_reg(0x2c);
-        self.ss.set_high().unwrap();
+        self.write_reg(0x2c)?;
+        gpio(self.ss.set_high())?;
+        Ok(())
     }
 
-    pub fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, color: Color) {
+    pub fn draw_rect(
+        &mut self,
+        x: u8,
+        y: u8,
+        width: u8,
+        height: u8,
+        color: Color,
+    ) -> Result<(), LoaderError> {
         let fig_color = color.to_u16();
         let color_h = (fig_color >> 8) as u8;
         let color_l = (fig_color & 0x00FF) as u8;
 
-        self.ss.set_low().unwrap();
-        self.set_window(x, y, width, height);
+        gpio(self.ss.set_low())?;
+        self.set_window(x, y, width, height)?;
 
-        self.data_cmd.set_high().unwrap();
+        gpio(self.data_cmd.set_high())?;
         let count: u32 = width as u32 * height as u32;
-        for _ in 0..count {
-            self.spi_enabled.write(&[color_h, color_l]).unwrap();
+        match &self.dma {
+            Some(dma) => dma.repeat(fig_color, count),
+            None => {
+                for _ in 0..count {
+                    spi(self.spi_enabled.write(&[color_h, color_l]))?;
+                }
+            }
         }
-        self.ss.set_high().unwrap();
+        gpio(self.ss.set_high())?;
+        Ok(())
     }
 
     pub fn draw_text(
@@ -233,11 +263,17 @@ where
         size_scalar: u32,
         text_color: Color,
         background_color: Color,
-    ) {
+    ) -> Result<(), LoaderError> {
+        let advance = (FONT_WIDTH + 1) * size_scalar.min(MAX_TEXT_SCALE) as u8;
         for char in text.chars().rev() {
-            self.draw_char(char as i8, x, y, size_scalar, text_color, background_color);
-            x += (FONT_WIDTH + 1) * size_scalar as u8;
+            self.draw_char(char as i8, x, y, size_scalar, text_color, background_color)?;
+            // The rest of the text is past the right edge.
+            x = match x.checked_add(advance) {
+                Some(next) => next,
+                None => break,
+            };
         }
+        Ok(())
     }
 
     pub fn draw_char(
@@ -248,7 +284,7 @@ where
         size_scalar: u32,
         text_color: Color,
         background_color: Color,
-    ) {
+    ) -> Result<(), LoaderError> {
         let mut char_queue = [0u8; 5];
         if (0x20..=0x7E).contains(&c) {
             c -= 0x20;
@@ -260,12 +296,14 @@ where
                 _elem = 0xFF;
             }
         }
-        self.ss.set_low().unwrap();
+        let size_scalar = size_scalar.min(MAX_TEXT_SCALE);
+        let scale = size_scalar as u8;
+        gpio(self.ss.set_low())?;
 
         let width = FONT_WIDTH;
         let height = FONT_HEIGHT;
 
-        self.set_window(x, y, width * size_scalar as u8, height * size_scalar as u8);
+        self.set_window(x, y, width * scale, height * scale)?;
         for i in 0..height {
             for _ in 0..size_scalar {
                 for j in 0..width {
@@ -274,9 +312,9 @@ where
                         char_queue[now_bit as usize / height as usize] & 0x80 >> (now_bit % height);
                     for _ in 0..size_scalar {
                         if b > 0 {
-                            self.put_pixel(text_color);
+                            self.put_pixel(text_color)?;
                         } else {
-                            self.put_pixel(background_color);
+                            self.put_pixel(background_color)?;
                         }
                     }
                 }
@@ -284,18 +322,14 @@ where
         }
 
         // if not edge
-        if x + (FONT_WIDTH + 1) * size_scalar as u8 <= 240 {
-            self.set_window(
-                x + FONT_WIDTH * size_scalar as u8,
-                y,
-                size_scalar as u8,
-                height * size_scalar as u8,
-            );
-            for _ in 0..FONT_HEIGHT * size_scalar as u8 {
-                self.put_pixel(background_color);
+        if x as u32 + (FONT_WIDTH + 1) as u32 * size_scalar <= 240 {
+            self.set_window(x + FONT_WIDTH * scale, y, scale, height * scale)?;
+            for _ in 0..FONT_HEIGHT * scale {
+                self.put_pixel(background_color)?;
             }
         }
-        self.ss.set_high().unwrap();
+        gpio(self.ss.set_high())?;
+        Ok(())
     }
 
     pub fn draw_text_fast(
@@ -305,11 +339,15 @@ where
         y: u8,
         text_color: Color,
         background_color: Color,
-    ) {
+    ) -> Result<(), LoaderError> {
         for char in text.chars().rev() {
-            self.draw_char_fast(char as i8, x, y, text_color, background_color);
-            x += FONT_WIDTH + 1;
+            self.draw_char_fast(char as i8, x, y, text_color, background_color)?;
+            x = match x.checked_add(FONT_WIDTH + 1) {
+                Some(next) => next,
+                None => break,
+            };
         }
+        Ok(())
     }
 
     pub fn draw_char_fast(
@@ -319,7 +357,7 @@ where
         y: u8,
         text_color: Color,
         background_color: Color,
-    ) {
+    ) -> Result<(), LoaderError> {
         let mut char_queue = [0u8; 5];
         if (0x20..=0x7E).contains(&c) {
             c -= 0x20;
@@ -331,12 +369,12 @@ where
                 _elem = 0xFF;
             }
         }
-        self.ss.set_low().unwrap();
+        gpio(self.ss.set_low())?;
 
         let width = FONT_WIDTH;
         let height = FONT_HEIGHT;
 
-        self.set_window(x, y, width, height);
+        self.set_window(x, y, width, height)?;
 
         let mut buff = [0u8; FONT_HEIGHT as usize * FONT_WIDTH as usize * 2];
         for i in 0..height {
@@ -355,96 +393,197 @@ where
             }
         }
 
-        self.data_cmd.set_high().unwrap();
-        self.spi_enabled.write(&buff).unwrap();
+        gpio(self.data_cmd.set_high())?;
+        spi(self.spi_enabled.write(&buff))?;
 
         // if not edge
-        if x + FONT_WIDTH < 240 {
-            self.set_window(x + FONT_WIDTH, y, 1, height);
+        if x < 240 - FONT_WIDTH {
+            self.set_window(x + FONT_WIDTH, y, 1, height)?;
             for _ in 0..FONT_HEIGHT {
-                self.put_pixel(background_color);
+                self.put_pixel(background_color)?;
             }
         }
-        self.ss.set_high().unwrap();
+        gpio(self.ss.set_high())?;
+        Ok(())
     }
 
-    pub fn draw_logo(&mut self, x: u8, y: u8) {
-        self.ss.set_low().unwrap();
-        self.set_window(x, y, artemis::IMG_WIDTH, artemis::IMG_HEIGHT);
-        self.data_cmd.set_high().unwrap();
-        for color in artemis::IMG_DATA {
-            self.spi_enabled
-                .write(&[(color >> 8) as u8, (color & 0xFF) as u8])
-                .unwrap();
+    pub fn draw_logo(&mut self, x: u8, y: u8) -> Result<(), LoaderError> {
+        gpio(self.ss.set_low())?;
+        self.set_window(x, y, artemis::IMG_WIDTH, artemis::IMG_HEIGHT)?;
+        gpio(self.data_cmd.set_high())?;
+        match &self.dma {
+            Some(dma) => {
+                let width = artemis::IMG_WIDTH as usize;
+                dma.write_chunks::<LINE_BYTES, _>(artemis::IMG_HEIGHT as usize, |row, line| {
+                    let colors = &artemis::IMG_DATA[row * width..(row + 1) * width];
+                    for (pixel, color) in line.chunks_exact_mut(2).zip(colors) {
+                        pixel.copy_from_slice(&color.to_be_bytes());
+                    }
+                    width * 2
+                });
+            }
+            None => {
+                for color in artemis::IMG_DATA {
+                    let pixel = [(color >> 8) as u8, (color & 0xFF) as u8];
+                    spi(self.spi_enabled.write(&pixel))?;
+                }
+            }
         }
-        self.ss.set_high().unwrap();
+        gpio(self.ss.set_high())?;
+        Ok(())
     }
 
-    pub fn draw_img(&mut self, x: u8, y: u8, width: u8, height: u8, data: &[u8]) {
-        self.ss.set_low().unwrap();
-        self.set_window(x, y, width, height);
-        self.data_cmd.set_high().unwrap();
-        for i in 0..height as usize {
-            for j in 0..width as usize {
-                let index =
-                    (height as usize - i - 1) * height as usize * 3 + (width as usize - j - 1) * 3;
-                let color = Color(data[index], data[index + 1], data[index + 2]).to_u16();
-                self.spi_enabled
-                    .write(&[(color >> 8) as u8, (color & 0xFF) as u8])
-                    .unwrap();
+    pub fn draw_img(
+        &mut self,
+        x: u8,
+        y: u8,
+        width: u8,
+        height: u8,
+        data: &[u8],
+    ) -> Result<(), LoaderError> {
+        gpio(self.ss.set_low())?;
+        self.set_window(x, y, width, height)?;
+        gpio(self.data_cmd.set_high())?;
+        let color_at = |i: usize, j: usize| {
+            let index =
+                (height as usize - i - 1) * height as usize * 3 + (width as usize - j - 1) * 3;
+            Color(data[index], data[index + 1], data[index + 2]).to_u16()
+        };
+        match &self.dma {
+            Some(dma) => dma.write_chunks::<LINE_BYTES, _>(height as usize, |i, line| {
+                for (j, pixel) in line.chunks_exact_mut(2).take(width as usize).enumerate() {
+                    pixel.copy_from_slice(&color_at(i, j).to_be_bytes());
+                }
+                width as usize * 2
+            }),
+            None => {
+                for i in 0..height as usize {
+                    for j in 0..width as usize {
+                        let color = color_at(i, j);
+                        let pixel = [(color >> 8) as u8, (color & 0xFF) as u8];
+                        spi(self.spi_enabled.write(&pixel))?;
+                    }
+                }
             }
         }
 
-        self.ss.set_high().unwrap();
+        gpio(self.ss.set_high())?;
+        Ok(())
     }
 
-    pub fn draw_info(&mut self, text: &str) {
+    /// Sends big-endian RGB565 pixels in panel order, which is mirrored
+    /// horizontally: the first pixel of each row ends up on the right.
+    pub fn draw_pixels(
+        &mut self,
+        x: u8,
+        y: u8,
+        width: u8,
+        height: u8,
+        data: &[u8],
+    ) -> Result<(), LoaderError> {
+        gpio(self.ss.set_low())?;
+        self.set_window(x, y, width, height)?;
+        gpio(self.data_cmd.set_high())?;
+        match &self.dma {
+            Some(dma) if data.len() >= dma::MIN_LEN => dma.write(data),
+            _ => spi(self.spi_enabled.write(data))?,
+        }
+        gpio(self.ss.set_high())
+    }
+
+    /// Fills the window from `fill`, which is handed the next part of it at a
+    /// time as big-endian RGB565 bytes. With DMA the part before is sent
+    /// meanwhile.
+    pub fn stream_pixels<F>(
+        &mut self,
+        x: u8,
+        y: u8,
+        width: u8,
+        height: u8,
+        mut fill: F,
+    ) -> Result<(), LoaderError>
+    where
+        F: FnMut(&mut [u8]),
+    {
+        gpio(self.ss.set_low())?;
+        self.set_window(x, y, width, height)?;
+        gpio(self.data_cmd.set_high())?;
+        let bytes = width as usize * height as usize * 2;
+        let count = bytes.div_ceil(STREAM_BYTES);
+        let len = |index: usize| (bytes - index * STREAM_BYTES).min(STREAM_BYTES);
+        match &self.dma {
+            Some(dma) => dma.write_chunks::<STREAM_BYTES, _>(count, |index, buf| {
+                fill(&mut buf[..len(index)]);
+                len(index)
+            }),
+            None => {
+                let mut buf = [0; STREAM_BYTES];
+                for index in 0..count {
+                    let part = &mut buf[..len(index)];
+                    fill(part);
+                    spi(self.spi_enabled.write(part))?;
+                }
+            }
+        }
+        gpio(self.ss.set_high())
+    }
+
+    /// The panel is mounted mirrored, so rows are normally sent right to
+    /// left. Flipping the column order takes them left to right instead.
+    pub fn set_flip_columns(&mut self, flip: bool) -> Result<(), LoaderError> {
+        gpio(self.ss.set_low())?;
+        self.write_reg(0x36)?; //MADCTL
+        self.write_data(if flip { 0x40 } else { 0x00 })?; //MX
+        gpio(self.ss.set_high())
+    }
+
+    pub fn draw_info(&mut self, text: &str) -> Result<(), LoaderError> {
         let text_size = text_size(text, 1);
         let x = 120 - text_size.0 / 2;
         let y = 120 - text_size.1 / 2;
-        self.draw_text(text, x, y, 1, ARTEMIS_COLOR, Color(0, 0, 0));
+        self.draw_text(text, x, y, 1, ARTEMIS_COLOR, Color(0, 0, 0))
     }
 
-    fn put_pixel(&mut self, color: Color) {
-        self.data_cmd.set_high().unwrap();
-        self.spi_enabled
-            .write(&[(color.to_u16() >> 8) as u8, (color.to_u16() & 0xFF) as u8])
-            .unwrap();
+    fn put_pixel(&mut self, color: Color) -> Result<(), LoaderError> {
+        gpio(self.data_cmd.set_high())?;
+        let pixel = [(color.to_u16() >> 8) as u8, (color.to_u16() & 0xFF) as u8];
+        spi(self.spi_enabled.write(&pixel))
     }
 
-    fn set_window(&mut self, x: u8, y: u8, width: u8, height: u8) {
-        self.write_reg(0x2A);
-        self.write_data(0x00);
-        self.write_data(x);
-        self.write_data(0x00);
-        self.write_data(x + width - 1);
+    fn set_window(&mut self, x: u8, y: u8, width: u8, height: u8) -> Result<(), LoaderError> {
+        self.write_reg(0x2A)?;
+        self.write_data(0x00)?;
+        self.write_data(x)?;
+        self.write_data(0x00)?;
+        self.write_data(last(x, width))?;
 
-        self.write_reg(0x2B);
-        self.write_data(0x00);
-        self.write_data(y);
-        self.write_data(0x00);
-        self.write_data(y + height - 1);
+        self.write_reg(0x2B)?;
+        self.write_data(0x00)?;
+        self.write_data(y)?;
+        self.write_data(0x00)?;
+        self.write_data(last(y, height))?;
 
-        self.write_reg(0x2c);
+        self.write_reg(0x2c)
     }
 
-    fn reset_lcd(&mut self, delay: &mut Delay) {
-        self.reset.set_high().unwrap();
+    fn reset_lcd(&mut self, delay: &mut Delay) -> Result<(), LoaderError> {
+        gpio(self.reset.set_high())?;
         delay.delay_ms(20);
-        self.reset.set_low().unwrap();
+        gpio(self.reset.set_low())?;
         delay.delay_ms(20);
-        self.reset.set_high().unwrap();
+        gpio(self.reset.set_high())?;
         delay.delay_ms(20);
+        Ok(())
     }
 
-    fn write_reg(&mut self, data: u8) {
-        self.data_cmd.set_low().unwrap();
-        self.spi_enabled.write(&[data]).unwrap();
+    fn write_reg(&mut self, data: u8) -> Result<(), LoaderError> {
+        gpio(self.data_cmd.set_low())?;
+        spi(self.spi_enabled.write(&[data]))
     }
 
-    fn write_data(&mut self, data: u8) {
-        self.data_cmd.set_high().unwrap();
-        self.spi_enabled.write(&[data]).unwrap();
+    fn write_data(&mut self, data: u8) -> Result<(), LoaderError> {
+        gpio(self.data_cmd.set_high())?;
+        spi(self.spi_enabled.write(&[data]))
     }
 }
 
@@ -452,16 +591,31 @@ where
 pub struct Color(pub u8, pub u8, pub u8);
 
 impl Color {
-    fn to_u16(self) -> u16 {
+    pub fn to_u16(self) -> u16 {
         let mut color = self.2 as u16 >> 3;
         color |= (self.1 as u16 & 0xFC) << 3;
         color | (self.0 as u16 & 0xF8) << 8
     }
 }
 
+/// Saturates at 255 for text wider than that.
 pub fn text_size(str: &str, size_scalar: u32) -> (u8, u8) {
-    (
-        str.len() as u8 * (FONT_WIDTH + 1) * size_scalar as u8,
-        FONT_HEIGHT * size_scalar as u8,
-    )
+    let scale = size_scalar.min(MAX_TEXT_SCALE) as u8;
+    let width = str
+        .len()
+        .saturating_mul(((FONT_WIDTH + 1) * scale) as usize);
+    (width.min(u8::MAX as usize) as u8, FONT_HEIGHT * scale)
+}
+
+/// Last row or column of a window, clamped to the panel controller's range.
+fn last(start: u8, len: u8) -> u8 {
+    start.saturating_add(len.max(1) - 1)
+}
+
+fn gpio<E>(result: Result<(), E>) -> Result<(), LoaderError> {
+    result.map_err(|_| LoaderError::Gpio)
+}
+
+fn spi<E>(result: Result<(), E>) -> Result<(), LoaderError> {
+    result.map_err(|_| LoaderError::Spi)
 }
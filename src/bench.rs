@@ -0,0 +1,228 @@
+//! SD card benchmark, to compare cards and bus clocks.
+//!
+//! Every clock in `CLOCKS_HZ` gets the same run: command latency, sequential
+//! and random reads of single blocks and of multi-block runs, and writing a
+//! scratch file. Reads go straight to the card past the block cache. The
+//! results are shown and saved to `BENCH.TXT` in the root directory.
+
+use core::fmt::Write;
+
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx, TimeSource};
+use heapless::Vec;
+use rp_pico::hal::Timer;
+
+use crate::{
+    buttons::Buttons,
+    cache::READ_AHEAD_BLOCKS,
+    error::LoaderError,
+    fs::{Dir, FsError, Storage},
+    ui::{self, push_line, Line},
+    Card, Display,
+};
+
+pub const RESULTS: &str = "BENCH.TXT";
+const SCRATCH: &str = "BENCH.TMP";
+
+/// Requested clocks, the dividers may only get close below them.
+const CLOCKS_HZ: [u32; 5] = [4_000_000, 8_000_000, 12_500_000, 20_000_000, 25_000_000];
+
+const COMMANDS: u32 = 100;
+const SEQUENTIAL_BLOCKS: u32 = 512;
+const RANDOM_READS: u32 = 64;
+const RANDOM_RUNS: u32 = 16;
+/// Multi-block reads are as long as the cache read-ahead.
+const RUN_BLOCKS: usize = READ_AHEAD_BLOCKS;
+const WRITE_BLOCKS: u32 = 128;
+
+/// Seven lines for every clock and a few more.
+type Lines = Vec<Line, 48>;
+
+pub fn run<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    timer: &Timer,
+    card: &Card,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let blocks = card.num_blocks()?.0;
+    let writable = storage.is_writable();
+    let mut lines = Lines::new();
+    push_line(
+        &mut lines,
+        format_args!("Card: {} MiB", blocks / (1024 * 1024 / Block::LEN_U32)),
+    );
+    if !writable {
+        push_line(&mut lines, format_args!("exFAT: writes skipped"));
+    }
+
+    let mut seed = timer.get_counter_low() | 1;
+    for hz in CLOCKS_HZ {
+        let mut text = Line::new();
+        write!(text, "Testing at {} kHz.", hz / 1000).ok();
+        ui::message(display, &text)?;
+
+        card.set_clock(hz);
+        push_line(
+            &mut lines,
+            format_args!("Clock {} kHz", card.clock() / 1000),
+        );
+        let measured = measure(timer, card, blocks, &mut seed, &mut lines).and_then(|()| {
+            if writable {
+                write_speed(timer, storage, root, &mut lines)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(err) = measured {
+            push_line(&mut lines, format_args!(" Failed: {}", err.message()));
+            // Back to a clock known to work before the next one.
+            card.init()?;
+        }
+    }
+    // Also restores the usual clock.
+    card.init()?;
+
+    if writable {
+        storage.remove(root, SCRATCH).ok();
+        ui::message(display, "Saving results.")?;
+        match save(storage, root, &lines) {
+            Ok(()) => {}
+            Err(FsError::Io) => return Err(FsError::Io.into()),
+            Err(err) => push_line(
+                &mut lines,
+                format_args!("Not saved: {}", LoaderError::Fs(err).message()),
+            ),
+        }
+    }
+    ui::info(display, buttons, delay, "Benchmark", &lines)
+}
+
+/// Command latency and read throughput at the current clock.
+fn measure(
+    timer: &Timer,
+    card: &Card,
+    blocks: u32,
+    seed: &mut u32,
+    lines: &mut Lines,
+) -> Result<(), LoaderError> {
+    let elapsed = timed(timer, || (0..COMMANDS).try_for_each(|_| card.check()))?;
+    push_line(lines, format_args!(" Command: {} us", elapsed / COMMANDS));
+
+    let mut buf: [Block; RUN_BLOCKS] = core::array::from_fn(|_| Block::new());
+    let last = blocks.saturating_sub(RUN_BLOCKS as u32).max(1);
+    // Away from the FAT, which some cards keep in faster memory.
+    let first = (blocks / 2).min(last - SEQUENTIAL_BLOCKS.min(last));
+
+    let elapsed = timed(timer, || {
+        (0..SEQUENTIAL_BLOCKS)
+            .try_for_each(|i| card.read(&mut buf[..1], BlockIdx(first + i), "bench"))
+    })?;
+    throughput(lines, "Sequential 1", SEQUENTIAL_BLOCKS, elapsed);
+
+    let runs = SEQUENTIAL_BLOCKS / RUN_BLOCKS as u32;
+    let elapsed = timed(timer, || {
+        (0..runs).try_for_each(|i| {
+            let start = first + i * RUN_BLOCKS as u32;
+            card.read(&mut buf, BlockIdx(start), "bench")
+        })
+    })?;
+    throughput(lines, "Sequential run", SEQUENTIAL_BLOCKS, elapsed);
+
+    let elapsed = timed(timer, || {
+        (0..RANDOM_READS).try_for_each(|_| {
+            let start = next(seed) % last;
+            card.read(&mut buf[..1], BlockIdx(start), "bench")
+        })
+    })?;
+    throughput(lines, "Random 1", RANDOM_READS, elapsed);
+
+    let elapsed = timed(timer, || {
+        (0..RANDOM_RUNS).try_for_each(|_| {
+            let start = next(seed) % last;
+            card.read(&mut buf, BlockIdx(start), "bench")
+        })
+    })?;
+    throughput(
+        lines,
+        "Random run",
+        RANDOM_RUNS * RUN_BLOCKS as u32,
+        elapsed,
+    );
+    Ok(())
+}
+
+/// Writes the scratch file, including creating and closing it.
+fn write_speed<D, T>(
+    timer: &Timer,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    lines: &mut Lines,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let data = [0x55; Block::LEN];
+    let elapsed = timed(timer, || {
+        let mut file = storage.create(root, SCRATCH)?;
+        let written =
+            (0..WRITE_BLOCKS).try_for_each(|_| storage.write(&mut file, &data).map(|_| ()));
+        storage.close(file);
+        written
+    })?;
+    throughput(lines, "Write", WRITE_BLOCKS, elapsed);
+    Ok(())
+}
+
+fn save<D, T>(storage: &mut Storage<D, T>, root: &Dir, lines: &Lines) -> Result<(), FsError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut file = storage.create(root, RESULTS)?;
+    let written = lines.iter().try_for_each(|line| {
+        storage.write(&mut file, line.as_bytes())?;
+        storage.write(&mut file, b"\r\n").map(|_| ())
+    });
+    storage.close(file);
+    written
+}
+
+/// Runs `f` and returns how long it took in microseconds.
+fn timed<E, F>(timer: &Timer, f: F) -> Result<u32, E>
+where
+    F: FnOnce() -> Result<(), E>,
+{
+    let start = timer.get_counter_low();
+    f()?;
+    Ok(timer.get_counter_low().wrapping_sub(start).max(1))
+}
+
+fn throughput(lines: &mut Lines, label: &str, blocks: u32, elapsed: u32) {
+    let bytes = blocks as u64 * Block::LEN as u64;
+    push_line(
+        lines,
+        format_args!(
+            " {}: {} KiB/s",
+            label,
+            bytes * 1_000_000 / elapsed as u64 / 1024
+        ),
+    );
+}
+
+/// xorshift32, good enough to spread reads over the card.
+fn next(state: &mut u32) -> u32 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    x
+}
@@ -0,0 +1,171 @@
+//! Windows bitmaps: 24-bit, 16-bit and 8-bit palettised, uncompressed, stored
+//! bottom-up or top-down.
+//!
+//! Only the rows that end up on the panel are read, each one straight from
+//! its offset in the file.
+
+use embedded_sdmmc::{BlockDevice, TimeSource};
+
+use crate::{
+    atm0130::Color,
+    image::{Canvas, ImageError, Source},
+    Display,
+};
+
+const FILE_HEADER_LEN: u32 = 14;
+/// BITMAPINFOHEADER, later versions only add fields after it.
+const INFO_HEADER_LEN: u32 = 40;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+const RGB565_MASKS: [u32; 3] = [0xF800, 0x07E0, 0x001F];
+const RGB555_MASKS: [u32; 3] = [0x7C00, 0x03E0, 0x001F];
+
+#[derive(Clone, Copy)]
+enum Format {
+    Bgr24,
+    Rgb565,
+    Rgb555,
+    Indexed,
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    bottom_up: bool,
+    format: Format,
+    data: u32,
+    /// Palette entries, only for [`Format::Indexed`].
+    colors: u32,
+    palette: u32,
+}
+
+pub fn draw<D, T>(display: &mut Display, source: &mut Source<D, T>) -> Result<(), ImageError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let header = header(source)?;
+
+    let mut palette = [0u16; 256];
+    if let Format::Indexed = header.format {
+        source.seek(header.palette)?;
+        for color in palette.iter_mut().take(header.colors as usize) {
+            let mut bgrx = [0; 4];
+            source.read(&mut bgrx)?;
+            *color = Color(bgrx[2], bgrx[1], bgrx[0]).to_u16();
+        }
+    }
+
+    let bits = match header.format {
+        Format::Bgr24 => 24,
+        Format::Rgb565 | Format::Rgb555 => 16,
+        Format::Indexed => 8,
+    };
+    let stride = header
+        .width
+        .checked_mul(bits)
+        .ok_or(ImageError::Malformed)?
+        .div_ceil(32)
+        * 4;
+
+    let mut canvas = Canvas::new(header.width, header.height);
+    for row in 0..canvas.rows() {
+        let y = canvas.source_row(row);
+        let stored = if header.bottom_up {
+            header.height - 1 - y
+        } else {
+            y
+        };
+        let offset = stored
+            .checked_mul(stride)
+            .and_then(|start| start.checked_add(header.data))
+            .ok_or(ImageError::Malformed)?;
+        source.seek(offset)?;
+        for x in 0..header.width {
+            let color = match header.format {
+                Format::Bgr24 => {
+                    let mut bgr = [0; 3];
+                    source.read(&mut bgr)?;
+                    Color(bgr[2], bgr[1], bgr[0]).to_u16()
+                }
+                Format::Rgb565 => source.u16_le()?,
+                Format::Rgb555 => {
+                    let pixel = source.u16_le()?;
+                    ((pixel & 0x7FE0) << 1) | ((pixel & 0x0200) >> 4) | (pixel & 0x001F)
+                }
+                Format::Indexed => palette[source.byte()? as usize],
+            };
+            canvas.put(x, color);
+        }
+        canvas.draw_row(display, row)?;
+    }
+    Ok(())
+}
+
+fn header<D, T>(source: &mut Source<D, T>) -> Result<Header, ImageError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    source.seek(0)?;
+    let mut magic = [0; 2];
+    source.read(&mut magic)?;
+    if &magic != b"BM" {
+        return Err(ImageError::Unsupported);
+    }
+    source.skip(8)?;
+    let data = source.u32_le()?;
+
+    let info_len = source.u32_le()?;
+    if info_len < INFO_HEADER_LEN {
+        // The old OS/2 header.
+        return Err(ImageError::Unsupported);
+    }
+    let width = source.u32_le()? as i32;
+    let height = source.u32_le()? as i32;
+    let _planes = source.u16_le()?;
+    let bits = source.u16_le()?;
+    let compression = source.u32_le()?;
+    source.skip(12)?;
+    let used = source.u32_le()?;
+    source.skip(4)?;
+
+    if width <= 0 || height == 0 {
+        return Err(ImageError::Malformed);
+    }
+
+    // The masks follow the 40-byte header whichever version it is.
+    let format = match (bits, compression) {
+        (24, BI_RGB) => Format::Bgr24,
+        (16, BI_RGB) => Format::Rgb555,
+        (16, BI_BITFIELDS) => {
+            let masks = [source.u32_le()?, source.u32_le()?, source.u32_le()?];
+            match masks {
+                RGB565_MASKS => Format::Rgb565,
+                RGB555_MASKS => Format::Rgb555,
+                _ => return Err(ImageError::Unsupported),
+            }
+        }
+        (8, BI_RGB) => Format::Indexed,
+        _ => return Err(ImageError::Unsupported),
+    };
+    let colors = match used {
+        0 => 256,
+        1..=256 => used,
+        _ => return Err(ImageError::Malformed),
+    };
+
+    Ok(Header {
+        width: width as u32,
+        height: height.unsigned_abs(),
+        bottom_up: height > 0,
+        format,
+        data,
+        colors,
+        palette: FILE_HEADER_LEN
+            .checked_add(info_len)
+            .ok_or(ImageError::Malformed)?,
+    })
+}
@@ -0,0 +1,194 @@
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use heapless::Vec;
+
+use crate::{
+    buttons::Buttons,
+    error::LoaderError,
+    fs::{Dir, Entry, Storage, MAX_ENTRIES},
+    ui::{self, Line},
+    Display,
+};
+
+const MAX_DEPTH: usize = 8;
+
+/// A file picked in the browser. `dir` is `None` for the root directory,
+/// otherwise it is open and has to be closed by the caller.
+pub struct Selection {
+    pub dir: Option<Dir>,
+    pub entry: Entry,
+}
+
+/// A directory picked with `choose_dir`, with the same ownership as in
+/// `Selection`.
+pub struct Folder {
+    pub dir: Option<Dir>,
+    pub label: Line,
+}
+
+enum Picked {
+    File(Selection),
+    Dir(Folder),
+}
+
+/// Lets the user walk the directory tree below `root` and pick a file for
+/// which `filter` returns `true`. Back leaves a subdirectory, or returns
+/// `None` in the root.
+pub fn browse<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    title: &str,
+    filter: fn(&str) -> bool,
+) -> Result<Option<Selection>, LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    match walk(display, buttons, delay, storage, root, title, Some(filter))? {
+        Some(Picked::File(selection)) => Ok(Some(selection)),
+        _ => Ok(None),
+    }
+}
+
+/// Like `browse`, but only lists directories and picks the one the user is
+/// in.
+pub fn choose_dir<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    title: &str,
+) -> Result<Option<Folder>, LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    match walk(display, buttons, delay, storage, root, title, None)? {
+        Some(Picked::Dir(folder)) => Ok(Some(folder)),
+        _ => Ok(None),
+    }
+}
+
+/// Picks a file `filter` accepts, or a directory without one.
+fn walk<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    title: &str,
+    filter: Option<fn(&str) -> bool>,
+) -> Result<Option<Picked>, LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut path: Vec<Entry, MAX_DEPTH> = Vec::new();
+    loop {
+        let dir = match open_path(storage, root, &path) {
+            Ok(dir) => dir,
+            Err(()) => {
+                ui::message(display, "Cannot open directory.")?;
+                buttons.wait(delay);
+                if path.pop().is_none() {
+                    return Ok(None);
+                }
+                continue;
+            }
+        };
+        let entries = storage.list(dir.as_ref().unwrap_or(root), filter.unwrap_or(|_| false));
+        let title = path.last().map_or(title, |entry| entry.label.as_str());
+
+        let mut labels: Vec<Line, { MAX_ENTRIES + 2 }> = Vec::new();
+        if filter.is_none() {
+            let mut here = Line::new();
+            here.push_str("[Use this folder]").ok();
+            labels.push(here).ok();
+        }
+        if !path.is_empty() {
+            let mut up = Line::new();
+            up.push_str("..").ok();
+            labels.push(up).ok();
+        }
+        let skipped = labels.len();
+        for entry in entries.iter() {
+            let mut label = entry.label.clone();
+            if entry.is_dir && label.push('/').is_err() {
+                label.pop();
+                label.push('/').ok();
+            }
+            labels.push(label).ok();
+        }
+
+        let selected = ui::select(display, buttons, delay, title, &labels)?;
+        if filter.is_none() && selected == Some(0) {
+            let mut label = Line::new();
+            label
+                .push_str(path.last().map_or("/", |entry| entry.label.as_str()))
+                .ok();
+            return Ok(Some(Picked::Dir(Folder { dir, label })));
+        }
+
+        let index = match selected {
+            Some(index) if index >= skipped => Some(index - skipped),
+            _ => None,
+        };
+        match index.map(|index| &entries[index]) {
+            Some(entry) if entry.is_dir => {
+                if path.push(entry.clone()).is_err() {
+                    ui::message(display, "Too deep.")?;
+                    buttons.wait(delay);
+                }
+            }
+            Some(entry) => {
+                return Ok(Some(Picked::File(Selection {
+                    dir,
+                    entry: entry.clone(),
+                })))
+            }
+            None => {
+                if selected.is_none() && path.is_empty() {
+                    return Ok(None);
+                }
+                path.pop();
+            }
+        }
+        if let Some(dir) = dir {
+            storage.close_dir(dir);
+        }
+    }
+}
+
+/// Opens the directory at `path` below `root`, or returns `None` for the root
+/// itself.
+fn open_path<D, T>(
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    path: &[Entry],
+) -> Result<Option<Dir>, ()>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut current: Option<Dir> = None;
+    for entry in path {
+        let next = storage.open_dir(current.as_ref().unwrap_or(root), entry);
+        let previous = match next {
+            Ok(next) => current.replace(next),
+            Err(_) => {
+                if let Some(current) = current {
+                    storage.close_dir(current);
+                }
+                return Err(());
+            }
+        };
+        if let Some(previous) = previous {
+            storage.close_dir(previous);
+        }
+    }
+    Ok(current)
+}
@@ -0,0 +1,79 @@
+use cortex_m::{delay::Delay, prelude::*};
+use embedded_hal::digital::v2::InputPin;
+use rp_pico::hal::gpio::{
+    bank0::{Gpio16, Gpio17, Gpio18, Gpio19},
+    Input, Pin, PullUp,
+};
+
+const POLL_MS: u32 = 10;
+const DEBOUNCE_MS: u32 = 20;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Up,
+    Down,
+    Select,
+    Back,
+}
+
+/// Push buttons wired between the GPIO and ground.
+pub struct Buttons {
+    up: Pin<Gpio16, Input<PullUp>>,
+    down: Pin<Gpio17, Input<PullUp>>,
+    select: Pin<Gpio18, Input<PullUp>>,
+    back: Pin<Gpio19, Input<PullUp>>,
+}
+
+impl Buttons {
+    pub fn new(
+        up: Pin<Gpio16, Input<PullUp>>,
+        down: Pin<Gpio17, Input<PullUp>>,
+        select: Pin<Gpio18, Input<PullUp>>,
+        back: Pin<Gpio19, Input<PullUp>>,
+    ) -> Self {
+        Self {
+            up,
+            down,
+            select,
+            back,
+        }
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        match button {
+            Button::Up => self.up.is_low().unwrap_or(false),
+            Button::Down => self.down.is_low().unwrap_or(false),
+            Button::Select => self.select.is_low().unwrap_or(false),
+            Button::Back => self.back.is_low().unwrap_or(false),
+        }
+    }
+
+    pub fn pressed(&self) -> Option<Button> {
+        [Button::Up, Button::Down, Button::Select, Button::Back]
+            .into_iter()
+            .find(|&button| self.is_held(button))
+    }
+
+    /// Blocks until a button is pressed and released again.
+    pub fn wait(&self, delay: &mut Delay) -> Button {
+        self.wait_until(delay, || false).unwrap()
+    }
+
+    /// Like `wait`, but returns `None` as soon as `stop` returns `true`.
+    pub fn wait_until<F: FnMut() -> bool>(&self, delay: &mut Delay, mut stop: F) -> Option<Button> {
+        loop {
+            if let Some(button) = self.pressed() {
+                delay.delay_ms(DEBOUNCE_MS);
+                while self.pressed().is_some() {
+                    delay.delay_ms(POLL_MS);
+                }
+                delay.delay_ms(DEBOUNCE_MS);
+                return Some(button);
+            }
+            if stop() {
+                return None;
+            }
+            delay.delay_ms(POLL_MS);
+        }
+    }
+}
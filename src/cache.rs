@@ -0,0 +1,204 @@
+//! Block cache between the filesystems and the SD card.
+//!
+//! `embedded_sdmmc::Controller` reads a single block per call, so the same
+//! FAT and directory sectors are fetched again and again, and streaming a
+//! file costs one command per block. Single blocks are kept in a small LRU,
+//! and a read that continues a run of blocks fetches the next ones with one
+//! multi-block read instead.
+
+use core::cell::RefCell;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+/// Blocks kept for FAT and directory sectors.
+pub const CACHE_BLOCKS: usize = 8;
+/// Blocks fetched at once while a file is streamed.
+pub const READ_AHEAD_BLOCKS: usize = 16;
+
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    /// Blocks served without touching the device.
+    pub hits: u32,
+    /// Blocks that had to be read, including the first of a read-ahead.
+    pub misses: u32,
+    /// Multi-block reads started for sequential access.
+    pub read_aheads: u32,
+}
+
+struct Slot {
+    index: Option<u32>,
+    used: u32,
+    block: Block,
+}
+
+struct State {
+    slots: [Slot; CACHE_BLOCKS],
+    /// Bumped on every access, the slot with the lowest `used` goes first.
+    clock: u32,
+    ahead: [Block; READ_AHEAD_BLOCKS],
+    ahead_start: u32,
+    ahead_len: usize,
+    /// The two most recent single-block reads. Streaming a FAT file reads a
+    /// FAT sector between two clusters, so the run may continue from either.
+    recent: [Option<u32>; 2],
+    num_blocks: Option<u32>,
+    stats: Stats,
+}
+
+/// Caches reads from `D`. Writes go straight through and update the cached
+/// copies.
+pub struct BlockCache<D> {
+    device: D,
+    state: RefCell<State>,
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            state: RefCell::new(State {
+                slots: core::array::from_fn(|_| Slot {
+                    index: None,
+                    used: 0,
+                    block: Block::new(),
+                }),
+                clock: 0,
+                ahead: core::array::from_fn(|_| Block::new()),
+                ahead_start: 0,
+                ahead_len: 0,
+                recent: [None; 2],
+                num_blocks: None,
+                stats: Stats::default(),
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.state.borrow().stats
+    }
+
+    fn read_one(&self, state: &mut State, block: &mut Block, index: u32) -> Result<(), D::Error> {
+        state.clock = state.clock.wrapping_add(1);
+        let clock = state.clock;
+        let sequential = state
+            .recent
+            .iter()
+            .any(|&recent| recent.is_some_and(|recent| recent.wrapping_add(1) == index));
+        state.recent = [Some(index), state.recent[0]];
+
+        if let Some(slot) = state
+            .slots
+            .iter_mut()
+            .find(|slot| slot.index == Some(index))
+        {
+            slot.used = clock;
+            block.contents = slot.block.contents;
+            state.stats.hits += 1;
+            return Ok(());
+        }
+        if let Some(offset) = state.ahead_offset(index) {
+            block.contents = state.ahead[offset].contents;
+            state.stats.hits += 1;
+            return Ok(());
+        }
+
+        state.stats.misses += 1;
+        let count = if sequential {
+            self.ahead_count(state, index)
+        } else {
+            1
+        };
+        if count > 1 {
+            state.ahead_len = 0;
+            self.device
+                .read(&mut state.ahead[..count], BlockIdx(index), "read-ahead")?;
+            state.ahead_start = index;
+            state.ahead_len = count;
+            state.stats.read_aheads += 1;
+            block.contents = state.ahead[0].contents;
+            return Ok(());
+        }
+
+        let victim = (0..CACHE_BLOCKS)
+            .min_by_key(|&i| (state.slots[i].index.is_some(), state.slots[i].used))
+            .unwrap_or(0);
+        let slot = &mut state.slots[victim];
+        slot.index = None;
+        self.device.read(
+            core::slice::from_mut(&mut slot.block),
+            BlockIdx(index),
+            "cache",
+        )?;
+        slot.index = Some(index);
+        slot.used = clock;
+        block.contents = slot.block.contents;
+        Ok(())
+    }
+
+    /// Read-ahead stops at the end of the card.
+    fn ahead_count(&self, state: &mut State, index: u32) -> usize {
+        if state.num_blocks.is_none() {
+            state.num_blocks = self.device.num_blocks().ok().map(|count| count.0);
+        }
+        match state.num_blocks {
+            Some(count) => (count.saturating_sub(index) as usize).min(READ_AHEAD_BLOCKS),
+            None => 1,
+        }
+    }
+}
+
+impl State {
+    fn ahead_offset(&self, index: u32) -> Option<usize> {
+        let offset = index.checked_sub(self.ahead_start)? as usize;
+        (offset < self.ahead_len).then_some(offset)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    type Error = D::Error;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, reason: &str) -> Result<(), D::Error> {
+        let mut state = self.state.borrow_mut();
+        // Multi-block reads already take a single command.
+        if blocks.len() > 1 {
+            state.stats.misses += blocks.len() as u32;
+            return self.device.read(blocks, start, reason);
+        }
+        for (offset, block) in blocks.iter_mut().enumerate() {
+            self.read_one(&mut state, block, start.0 + offset as u32)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), D::Error> {
+        let mut state = self.state.borrow_mut();
+        let result = self.device.write(blocks, start);
+        for (offset, block) in blocks.iter().enumerate() {
+            let index = start.0 + offset as u32;
+            // A failed write may have left anything on the card.
+            if let Some(slot) = state
+                .slots
+                .iter_mut()
+                .find(|slot| slot.index == Some(index))
+            {
+                if result.is_ok() {
+                    slot.block.contents = block.contents;
+                } else {
+                    slot.index = None;
+                }
+            }
+            if let Some(offset) = state.ahead_offset(index) {
+                if result.is_ok() {
+                    state.ahead[offset].contents = block.contents;
+                } else {
+                    state.ahead_len = 0;
+                }
+            }
+        }
+        result
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, D::Error> {
+        self.device.num_blocks()
+    }
+}
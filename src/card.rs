@@ -0,0 +1,77 @@
+use cortex_m::{delay::Delay, prelude::*};
+#[cfg(not(feature = "cd-polling"))]
+use embedded_hal::digital::v2::InputPin;
+#[cfg(not(feature = "cd-polling"))]
+use rp_pico::hal::gpio::{bank0::Gpio22, Input, Pin, PullUp};
+
+#[cfg(feature = "cd-polling")]
+use crate::Card;
+
+const POLL_MS: u32 = 50;
+/// Time for the contacts to settle after the switch closes.
+#[cfg(not(feature = "cd-polling"))]
+const SETTLE_MS: u32 = 250;
+
+/// Card detect switch of the SD socket, it closes to ground while a card is
+/// inserted.
+#[cfg(not(feature = "cd-polling"))]
+pub struct CardDetect {
+    pin: Pin<Gpio22, Input<PullUp>>,
+}
+
+#[cfg(not(feature = "cd-polling"))]
+impl CardDetect {
+    pub fn new(pin: Pin<Gpio22, Input<PullUp>>) -> Self {
+        Self { pin }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.pin.is_low().unwrap_or(true)
+    }
+
+    /// The switch tells insertion and removal apart by itself.
+    pub fn is_inserted(&self) -> bool {
+        self.is_present()
+    }
+
+    pub fn wait_inserted(&self, delay: &mut Delay) {
+        while !self.is_present() {
+            delay.delay_ms(POLL_MS);
+        }
+        delay.delay_ms(SETTLE_MS);
+    }
+}
+
+/// Stands in for the switch on sockets without one. The card counts as
+/// present while it answers CMD13. A card swapped in between two polls is
+/// not in SPI mode yet and fails CMD13 too, so it ends the session like a
+/// removal.
+#[cfg(feature = "cd-polling")]
+pub struct CardDetect<'a> {
+    card: &'a Card,
+}
+
+#[cfg(feature = "cd-polling")]
+impl<'a> CardDetect<'a> {
+    pub fn new(card: &'a Card) -> Self {
+        Self { card }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.card.check().is_ok()
+    }
+
+    /// Only for waiting between sessions, as it brings up whatever card is in
+    /// the socket.
+    pub fn is_inserted(&self) -> bool {
+        self.is_present() || self.card.init().is_ok()
+    }
+
+    /// A card that got through `init` is already powered up, there is no
+    /// switch to settle.
+    pub fn wait_inserted(&self, delay: &mut Delay) {
+        while !self.is_inserted() {
+            delay.delay_ms(POLL_MS);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Wall clock kept by the RTC, used to timestamp files on the card.
+
+use core::cell::RefCell;
+
+use cortex_m::interrupt::{self, Mutex};
+use embedded_sdmmc::{BlockDevice, TimeSource, Timestamp};
+use rp_pico::hal::{
+    clocks::RtcClock,
+    pac,
+    rtc::{DateTime, DayOfWeek, RealTimeClock},
+};
+
+use crate::fs::{Dir, Storage};
+
+pub const TIME_FILE: &str = "TIME.CFG";
+
+/// Oldest time FAT can store, used until something better is known.
+const EPOCH: DateTime = DateTime {
+    year: 1980,
+    month: 1,
+    day: 1,
+    day_of_week: DayOfWeek::Tuesday,
+    hour: 0,
+    minute: 0,
+    second: 0,
+};
+
+/// Newest year a FAT timestamp can hold, 1980 plus seven bits.
+const LATEST_YEAR: u16 = 2107;
+
+static RTC: Mutex<RefCell<Option<RealTimeClock>>> = Mutex::new(RefCell::new(None));
+
+/// Starts the RTC. If it was already running before a warm reset its time is
+/// carried over and `true` is returned.
+pub fn init(rtc: pac::RTC, clock: RtcClock, resets: &mut pac::RESETS) -> bool {
+    let running = resets.reset.read().rtc().bit_is_clear() && rtc.ctrl.read().rtc_active().bit();
+    let carried = if running { read_running(&rtc) } else { None };
+
+    let is_carried = carried.is_some();
+
+    let rtc = RealTimeClock::new(rtc, clock, resets, carried.unwrap_or(EPOCH)).unwrap();
+    interrupt::free(|cs| RTC.borrow(cs).replace(Some(rtc)));
+    is_carried
+}
+
+/// Time left in the RTC registers, read before the peripheral is reset.
+fn read_running(rtc: &pac::RTC) -> Option<DateTime> {
+    // RTC_0 has to be read first, it latches RTC_1.
+    let rtc_0 = rtc.rtc_0.read();
+    let rtc_1 = rtc.rtc_1.read();
+    let year = rtc_1.year().bits();
+    if year < EPOCH.year {
+        return None;
+    }
+    datetime(
+        year,
+        rtc_1.month().bits(),
+        rtc_1.day().bits(),
+        rtc_0.hour().bits(),
+        rtc_0.min().bits(),
+        rtc_0.sec().bits(),
+    )
+}
+
+pub fn now() -> DateTime {
+    interrupt::free(|cs| {
+        RTC.borrow(cs)
+            .borrow()
+            .as_ref()
+            .and_then(|rtc| rtc.now().ok())
+    })
+    .unwrap_or(EPOCH)
+}
+
+pub fn set(time: DateTime) {
+    interrupt::free(|cs| {
+        if let Some(rtc) = RTC.borrow(cs).borrow_mut().as_mut() {
+            rtc.set_datetime(time).ok();
+        }
+    });
+}
+
+/// Sets the clock from the card: `TIME.CFG` if it holds a valid time,
+/// otherwise the newest modification time in the root directory, so time
+/// never runs behind files that already exist. A file stamped in the future
+/// does not override the configured time. The clock is only ever moved
+/// forward.
+pub fn set_from_card<D, T>(storage: &mut Storage<D, T>, dir: &Dir)
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut newest = configured_time(storage, dir);
+    if newest.is_none() {
+        storage.for_each_modified(dir, |mtime| {
+            let time = from_timestamp(mtime);
+            if newest
+                .as_ref()
+                .is_none_or(|newest| key(&time) > key(newest))
+            {
+                newest = Some(time);
+            }
+        });
+    }
+
+    if let Some(time) = newest {
+        if key(&time) > key(&now()) {
+            set(time);
+        }
+    }
+}
+
+/// The time in `TIME.CFG`, if the file exists and parses.
+fn configured_time<D, T>(storage: &mut Storage<D, T>, dir: &Dir) -> Option<DateTime>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut file = storage.open(dir, TIME_FILE).ok()?;
+    let mut buf = [0u8; 32];
+    let count = storage.read(&mut file, &mut buf).unwrap_or(0);
+    storage.close(file);
+    core::str::from_utf8(&buf[..count])
+        .ok()
+        .and_then(|text| parse(text.trim()))
+}
+
+/// Parses `YYYY-MM-DD HH:MM:SS`. Years FAT cannot store are rejected.
+pub fn parse(text: &str) -> Option<DateTime> {
+    let (date, time) = text.split_once(|c| c == ' ' || c == 'T')?;
+    let mut date = date.split('-').map(|part| part.parse::<u16>().ok());
+    let mut time = time.split(':').map(|part| part.parse::<u8>().ok());
+    let year = date.next()??;
+    let month = date.next()?? as u8;
+    let day = date.next()?? as u8;
+    let hour = time.next()??;
+    let minute = time.next()??;
+    let second = time.next()??;
+    if date.next().is_some() || time.next().is_some() {
+        return None;
+    }
+    datetime(year, month, day, hour, minute, second)
+}
+
+fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<DateTime> {
+    let valid = (EPOCH.year..=LATEST_YEAR).contains(&year)
+        && (1..=12).contains(&month)
+        && day >= 1
+        && day <= days_in_month(year, month)
+        && hour < 24
+        && minute < 60
+        && second < 60;
+    valid.then(|| DateTime {
+        year,
+        month,
+        day,
+        day_of_week: day_of_week(year, month, day),
+        hour,
+        minute,
+        second,
+    })
+}
+
+fn from_timestamp(timestamp: &Timestamp) -> DateTime {
+    datetime(
+        1970 + timestamp.year_since_1970 as u16,
+        timestamp.zero_indexed_month + 1,
+        timestamp.zero_indexed_day + 1,
+        timestamp.hours,
+        timestamp.minutes,
+        timestamp.seconds,
+    )
+    .unwrap_or(EPOCH)
+}
+
+fn key(time: &DateTime) -> (u16, u8, u8, u8, u8, u8) {
+    (
+        time.year,
+        time.month,
+        time.day,
+        time.hour,
+        time.minute,
+        time.second,
+    )
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Sakamoto's method.
+fn day_of_week(year: u16, month: u8, day: u8) -> DayOfWeek {
+    const OFFSETS: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let year = if month < 3 { year - 1 } else { year };
+    let day =
+        (year + year / 4 - year / 100 + year / 400 + OFFSETS[month as usize - 1] + day as u16) % 7;
+    match day {
+        0 => DayOfWeek::Sunday,
+        1 => DayOfWeek::Monday,
+        2 => DayOfWeek::Tuesday,
+        3 => DayOfWeek::Wednesday,
+        4 => DayOfWeek::Thursday,
+        5 => DayOfWeek::Friday,
+        _ => DayOfWeek::Saturday,
+    }
+}
+
+#[derive(Default)]
+pub struct RtcTimeSource;
+
+impl TimeSource for RtcTimeSource {
+    fn get_timestamp(&self) -> Timestamp {
+        let time = now();
+        Timestamp {
+            year_since_1970: (time.year - 1970) as u8,
+            zero_indexed_month: time.month - 1,
+            zero_indexed_day: time.day - 1,
+            hours: time.hour,
+            minutes: time.minute,
+            seconds: time.second,
+        }
+    }
+}
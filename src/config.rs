@@ -0,0 +1,53 @@
+//! Loader settings from `LOADER.CFG` in the root of the first volume.
+//!
+//! One `key = value` per line, `#` starts a comment. Unknown keys are ignored.
+
+use embedded_sdmmc::{BlockDevice, TimeSource};
+
+use crate::fs::{Dir, Storage};
+
+pub const CONFIG_FILE: &str = "LOADER.CFG";
+
+#[derive(Default)]
+pub struct Config {
+    /// `volume = N`: MBR partition 0-3 holding the images.
+    pub volume: Option<u8>,
+    /// `fps = N`: frame rate videos are played at.
+    pub fps: Option<u8>,
+}
+
+impl Config {
+    pub fn load<D, T>(storage: &mut Storage<D, T>, dir: &Dir) -> Self
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let mut file = match storage.open(dir, CONFIG_FILE) {
+            Ok(file) => file,
+            Err(_) => return Self::default(),
+        };
+        let mut buf = [0u8; 256];
+        let count = storage.read(&mut file, &mut buf).unwrap_or(0);
+        storage.close(file);
+        core::str::from_utf8(&buf[..count])
+            .map(Self::parse)
+            .unwrap_or_default()
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut config = Self::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            if key.eq_ignore_ascii_case("volume") {
+                config.volume = value.parse().ok();
+            } else if key.eq_ignore_ascii_case("fps") {
+                config.fps = value.parse().ok().filter(|&fps| fps > 0);
+            }
+        }
+        config
+    }
+}
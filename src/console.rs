@@ -0,0 +1,126 @@
+//! Serial console on UART0 (GP0 TX, GP1 RX, 115200 8N1).
+//!
+//! `time` prints the clock and `time YYYY-MM-DD HH:MM:SS` sets it.
+//!
+//! Replies are queued and fed to the TX FIFO as it drains, so the interrupt
+//! handler never waits on the UART with interrupts disabled.
+
+use core::{cell::RefCell, fmt::Write, mem};
+
+use cortex_m::interrupt::{self, Mutex};
+use heapless::{Deque, String};
+use rp_pico::hal::{
+    gpio::{
+        bank0::{Gpio0, Gpio1},
+        FunctionUart, Pin,
+    },
+    pac::{self, interrupt},
+    uart::{Enabled, UartPeripheral},
+};
+
+use crate::clock;
+
+pub type Uart0 =
+    UartPeripheral<Enabled, pac::UART0, (Pin<Gpio0, FunctionUart>, Pin<Gpio1, FunctionUart>)>;
+
+struct Console {
+    uart: Uart0,
+    line: String<64>,
+    /// Output waiting for room in the TX FIFO.
+    output: Deque<u8, 128>,
+}
+
+static CONSOLE: Mutex<RefCell<Option<Console>>> = Mutex::new(RefCell::new(None));
+
+pub fn init(mut uart: Uart0) {
+    uart.enable_rx_interrupt();
+    interrupt::free(|cs| {
+        CONSOLE.borrow(cs).replace(Some(Console {
+            uart,
+            line: String::new(),
+            output: Deque::new(),
+        }))
+    });
+    unsafe { pac::NVIC::unmask(pac::Interrupt::UART0_IRQ) };
+}
+
+impl Console {
+    fn receive(&mut self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = mem::take(&mut self.line);
+                if !line.trim().is_empty() {
+                    self.execute(line.trim());
+                }
+            }
+            _ => {
+                if self.line.push(byte as char).is_err() {
+                    self.line.clear();
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let mut reply: String<64> = String::new();
+        match line.split_once(' ') {
+            None if line == "time" => print_time(&mut reply),
+            Some(("time", time)) => match clock::parse(time.trim()) {
+                Some(time) => {
+                    clock::set(time);
+                    print_time(&mut reply);
+                }
+                None => reply.push_str("usage: time YYYY-MM-DD HH:MM:SS").unwrap(),
+            },
+            _ => reply.push_str("unknown command").unwrap(),
+        }
+        reply.push_str("\r\n").ok();
+        for &byte in reply.as_bytes() {
+            // A host that keeps typing without reading loses replies.
+            if self.output.push_back(byte).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Moves queued output into the TX FIFO until it is full. The TX
+    /// interrupt stays enabled while anything is left.
+    fn flush(&mut self) {
+        while let Some(&byte) = self.output.front() {
+            if self.uart.write_raw(&[byte]).is_err() {
+                break;
+            }
+            self.output.pop_front();
+        }
+        if self.output.is_empty() {
+            self.uart.disable_tx_interrupt();
+        } else {
+            self.uart.enable_tx_interrupt();
+        }
+    }
+}
+
+fn print_time(reply: &mut String<64>) {
+    let time = clock::now();
+    write!(
+        reply,
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    )
+    .unwrap();
+}
+
+#[interrupt]
+fn UART0_IRQ() {
+    interrupt::free(|cs| {
+        if let Some(console) = CONSOLE.borrow(cs).borrow_mut().as_mut() {
+            let mut buf = [0u8; 16];
+            while let Ok(count) = console.uart.read_raw(&mut buf) {
+                for &byte in &buf[..count] {
+                    console.receive(byte);
+                }
+            }
+            console.flush();
+        }
+    });
+}
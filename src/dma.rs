@@ -0,0 +1,207 @@
+//! DMA between memory and the SPI data registers.
+//!
+//! The HAL owns the SPI blocks, so like `flash` this goes through the raw
+//! registers and only points DMA channels at the data register. Every
+//! transfer uses a TX and an RX channel: the RX side either stores what comes
+//! back or drains it into a scratch byte, so the FIFO is empty and the bus
+//! idle once the RX channel finishes, and the blocking HAL calls can carry on.
+
+use core::{
+    ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+use rp_pico::hal::pac;
+
+const DMA_BASE: usize = 0x5000_0000;
+const CH_STRIDE: usize = 0x40;
+const CH_READ_ADDR: usize = 0x00;
+const CH_WRITE_ADDR: usize = 0x04;
+const CH_TRANS_COUNT: usize = 0x08;
+const CH_CTRL_TRIG: usize = 0x0C;
+
+const CTRL_EN: u32 = 1 << 0;
+const CTRL_INCR_READ: u32 = 1 << 4;
+const CTRL_INCR_WRITE: u32 = 1 << 5;
+const CTRL_RING_SIZE_SHIFT: u32 = 6;
+const CTRL_CHAIN_TO_SHIFT: u32 = 11;
+const CTRL_TREQ_SEL_SHIFT: u32 = 15;
+const CTRL_BUSY: u32 = 1 << 24;
+
+const SPI0_BASE: usize = 0x4003_C000;
+const SPI1_BASE: usize = 0x4004_0000;
+const SSPDR: usize = 0x08;
+const SSPDMACR: usize = 0x24;
+const SSPDMACR_RXDMAE: u32 = 1 << 0;
+const SSPDMACR_TXDMAE: u32 = 1 << 1;
+
+const DREQ_SPI0_TX: u32 = 16;
+const DREQ_SPI1_TX: u32 = 18;
+
+/// Below this the channel setup costs more than it saves.
+pub const MIN_LEN: usize = 32;
+
+/// Clocked out while reading.
+static FILL: u8 = 0xFF;
+/// Sink for the bytes clocked in while writing.
+static mut DISCARD: u8 = 0;
+
+/// One of the twelve DMA channels, handed out once by [`split`].
+pub struct Channel(u8);
+
+/// Takes the DMA block out of reset and hands out the channels the loader
+/// uses.
+pub fn split(_dma: pac::DMA, resets: &mut pac::RESETS) -> [Channel; 4] {
+    resets.reset.modify(|_, w| w.dma().clear_bit());
+    while resets.reset_done.read().dma().bit_is_clear() {}
+    [Channel(0), Channel(1), Channel(2), Channel(3)]
+}
+
+#[derive(Clone, Copy)]
+pub enum Bus {
+    Spi0,
+    /// The SD card's bus, unused with SDIO.
+    #[cfg_attr(feature = "sdio", allow(dead_code))]
+    Spi1,
+}
+
+impl Bus {
+    fn base(self) -> usize {
+        match self {
+            Bus::Spi0 => SPI0_BASE,
+            Bus::Spi1 => SPI1_BASE,
+        }
+    }
+
+    fn dreq_tx(self) -> u32 {
+        match self {
+            Bus::Spi0 => DREQ_SPI0_TX,
+            Bus::Spi1 => DREQ_SPI1_TX,
+        }
+    }
+
+    fn dreq_rx(self) -> u32 {
+        self.dreq_tx() + 1
+    }
+}
+
+/// A pair of channels feeding one SPI block. The caller keeps the HAL side of
+/// the bus idle for as long as a method runs.
+pub struct SpiDma {
+    bus: Bus,
+    tx: Channel,
+    rx: Channel,
+}
+
+impl SpiDma {
+    pub fn new(bus: Bus, tx: Channel, rx: Channel) -> Self {
+        unsafe {
+            let dmacr = (bus.base() + SSPDMACR) as *mut u32;
+            ptr::write_volatile(dmacr, SSPDMACR_TXDMAE | SSPDMACR_RXDMAE);
+        }
+        Self { bus, tx, rx }
+    }
+
+    /// Clocks out 0xFF while filling `buf` with what the device sends.
+    pub fn read(&self, buf: &mut [u8]) {
+        unsafe {
+            self.start(&FILL, false, 0, buf.as_mut_ptr(), true, buf.len());
+        }
+        self.wait();
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        unsafe {
+            self.start(
+                data.as_ptr(),
+                true,
+                0,
+                ptr::addr_of_mut!(DISCARD),
+                false,
+                data.len(),
+            );
+        }
+        self.wait();
+    }
+
+    /// Sends the big-endian `value` `count` times.
+    pub fn repeat(&self, value: u16, count: u32) {
+        // The read address wraps within the two aligned bytes of `pattern`.
+        let pattern = value.to_be();
+        let bytes = count as usize * 2;
+        unsafe {
+            let source = ptr::addr_of!(pattern) as *const u8;
+            self.start(source, true, 1, ptr::addr_of_mut!(DISCARD), false, bytes);
+        }
+        self.wait();
+    }
+
+    /// Sends `count` chunks of at most `N` bytes. `fill` writes chunk `index`
+    /// into the buffer and returns its length, while the previous chunk is
+    /// still being sent from the other buffer.
+    pub fn write_chunks<const N: usize, F>(&self, count: usize, mut fill: F)
+    where
+        F: FnMut(usize, &mut [u8; N]) -> usize,
+    {
+        let mut buffers = [[0u8; N]; 2];
+        for index in 0..count {
+            let buf = &mut buffers[index % 2];
+            let len = fill(index, buf).min(N);
+            self.wait();
+            unsafe {
+                let sink = ptr::addr_of_mut!(DISCARD);
+                self.start(buf.as_ptr(), true, 0, sink, false, len);
+            }
+        }
+        self.wait();
+    }
+
+    /// Starts the RX channel first, it idles until the TX channel has pushed
+    /// the first byte. `ring` wraps the TX read address within 2^ring bytes.
+    unsafe fn start(
+        &self,
+        source: *const u8,
+        incr_source: bool,
+        ring: u32,
+        dest: *mut u8,
+        incr_dest: bool,
+        len: usize,
+    ) {
+        let data = (self.bus.base() + SSPDR) as u32;
+        compiler_fence(Ordering::SeqCst);
+
+        let mut ctrl = CTRL_EN | self.bus.dreq_rx() << CTRL_TREQ_SEL_SHIFT;
+        if incr_dest {
+            ctrl |= CTRL_INCR_WRITE;
+        }
+        configure(&self.rx, data, dest as u32, len, ctrl);
+
+        let mut ctrl =
+            CTRL_EN | ring << CTRL_RING_SIZE_SHIFT | self.bus.dreq_tx() << CTRL_TREQ_SEL_SHIFT;
+        if incr_source {
+            ctrl |= CTRL_INCR_READ;
+        }
+        configure(&self.tx, source as u32, data, len, ctrl);
+    }
+
+    /// Once the RX channel is done every byte has gone both ways.
+    fn wait(&self) {
+        while is_busy(&self.tx) || is_busy(&self.rx) {}
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+unsafe fn configure(channel: &Channel, read: u32, write: u32, len: usize, ctrl: u32) {
+    let base = DMA_BASE + channel.0 as usize * CH_STRIDE;
+    ptr::write_volatile((base + CH_READ_ADDR) as *mut u32, read);
+    ptr::write_volatile((base + CH_WRITE_ADDR) as *mut u32, write);
+    ptr::write_volatile((base + CH_TRANS_COUNT) as *mut u32, len as u32);
+    // Chaining to itself turns chaining off.
+    let ctrl = ctrl | (channel.0 as u32) << CTRL_CHAIN_TO_SHIFT;
+    ptr::write_volatile((base + CH_CTRL_TRIG) as *mut u32, ctrl);
+}
+
+fn is_busy(channel: &Channel) -> bool {
+    let base = DMA_BASE + channel.0 as usize * CH_STRIDE;
+    unsafe { ptr::read_volatile((base + CH_CTRL_TRIG) as *const u32) & CTRL_BUSY != 0 }
+}
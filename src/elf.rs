@@ -0,0 +1,271 @@
+//! Loader for position-independent ARM ELF images (`ET_DYN`, linked at 0).
+//!
+//! Nothing in here touches the hardware, `tools/elf-test` loads linked
+//! fixtures with it on the host.
+
+const EI_CLASS_32: u8 = 1;
+const EI_DATA_LE: u8 = 1;
+const ET_DYN: u16 = 3;
+const EM_ARM: u16 = 40;
+
+const PT_LOAD: u32 = 1;
+
+const SHT_RELA: u32 = 4;
+const SHT_REL: u32 = 9;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+
+const R_ARM_NONE: u8 = 0;
+const R_ARM_ABS32: u8 = 2;
+const R_ARM_GLOB_DAT: u8 = 21;
+const R_ARM_JUMP_SLOT: u8 = 22;
+const R_ARM_RELATIVE: u8 = 23;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+const REL_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Io,
+    BadFormat,
+    /// Not a position-independent ARM executable.
+    Unsupported,
+    UnsupportedRelocation(u8),
+    UndefinedSymbol,
+    OutOfBounds,
+}
+
+/// Random access to the bytes of an ELF file.
+pub trait Source {
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ElfError>;
+}
+
+impl Source for &[u8] {
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ElfError> {
+        let start = offset as usize;
+        let data = start
+            .checked_add(buf.len())
+            .and_then(|end| self.get(start..end))
+            .ok_or(ElfError::OutOfBounds)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+struct Segment {
+    kind: u32,
+    offset: u32,
+    vaddr: u32,
+    file_size: u32,
+    mem_size: u32,
+}
+
+struct Section {
+    kind: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+}
+
+pub struct Elf {
+    phoff: u32,
+    phnum: u16,
+    shoff: u32,
+    shnum: u16,
+}
+
+impl Elf {
+    pub fn parse<S: Source>(src: &mut S) -> Result<Self, ElfError> {
+        let mut header = [0u8; EHDR_SIZE];
+        src.read_at(0, &mut header)?;
+
+        if header[..4] != *b"\x7FELF" || header[4] != EI_CLASS_32 || header[5] != EI_DATA_LE {
+            return Err(ElfError::BadFormat);
+        }
+        if u16_at(&header, 18) != EM_ARM || u16_at(&header, 16) != ET_DYN {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = Self {
+            phoff: u32_at(&header, 28),
+            phnum: u16_at(&header, 44),
+            shoff: u32_at(&header, 32),
+            shnum: u16_at(&header, 48),
+        };
+        if elf.phnum > 0 && u16_at(&header, 42) as usize != PHDR_SIZE
+            || elf.shnum > 0 && u16_at(&header, 46) as usize != SHDR_SIZE
+        {
+            return Err(ElfError::BadFormat);
+        }
+        Ok(elf)
+    }
+
+    /// Bytes of RAM needed to hold all loadable segments.
+    pub fn image_size<S: Source>(&self, src: &mut S) -> Result<u32, ElfError> {
+        let mut size = 0;
+        for index in 0..self.phnum {
+            let segment = self.segment(src, index)?;
+            if segment.kind == PT_LOAD {
+                let end = segment
+                    .vaddr
+                    .checked_add(segment.mem_size)
+                    .ok_or(ElfError::BadFormat)?;
+                size = size.max(end);
+            }
+        }
+        Ok(size)
+    }
+
+    /// Copies the loadable segments into `image` and applies the dynamic
+    /// relocations for an image placed at `base`.
+    pub fn load<S: Source>(
+        &self,
+        src: &mut S,
+        image: &mut [u8],
+        base: u32,
+    ) -> Result<(), ElfError> {
+        for index in 0..self.phnum {
+            let segment = self.segment(src, index)?;
+            if segment.kind != PT_LOAD {
+                continue;
+            }
+            if segment.file_size > segment.mem_size {
+                return Err(ElfError::BadFormat);
+            }
+            let start = segment.vaddr as usize;
+            let loaded = start + segment.file_size as usize;
+            let end = start + segment.mem_size as usize;
+            if end > image.len() {
+                return Err(ElfError::OutOfBounds);
+            }
+            src.read_at(segment.offset, &mut image[start..loaded])?;
+            image[loaded..end].fill(0);
+        }
+
+        for index in 0..self.shnum {
+            let section = self.section(src, index as u32)?;
+            match section.kind {
+                SHT_REL => self.relocate(src, &section, image, base)?,
+                SHT_RELA => return Err(ElfError::Unsupported),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn relocate<S: Source>(
+        &self,
+        src: &mut S,
+        section: &Section,
+        image: &mut [u8],
+        base: u32,
+    ) -> Result<(), ElfError> {
+        let mut chunk = [0u8; 64 * REL_SIZE];
+        let mut done = 0;
+        while done < section.size {
+            let len = ((section.size - done) as usize).min(chunk.len()) / REL_SIZE * REL_SIZE;
+            if len == 0 {
+                return Err(ElfError::BadFormat);
+            }
+            src.read_at(section.offset + done, &mut chunk[..len])?;
+
+            for entry in chunk[..len].chunks_exact(REL_SIZE) {
+                let offset = u32_at(entry, 0) as usize;
+                let info = u32_at(entry, 4);
+                let kind = info as u8;
+                if image.len() < 4 || offset > image.len() - 4 {
+                    return Err(ElfError::OutOfBounds);
+                }
+
+                let addend = u32_at(image, offset);
+                let value = match kind {
+                    R_ARM_NONE => continue,
+                    R_ARM_RELATIVE => addend.wrapping_add(base),
+                    R_ARM_ABS32 => self
+                        .symbol(src, section.link, info >> 8, base)?
+                        .wrapping_add(addend),
+                    R_ARM_GLOB_DAT | R_ARM_JUMP_SLOT => {
+                        self.symbol(src, section.link, info >> 8, base)?
+                    }
+                    other => return Err(ElfError::UnsupportedRelocation(other)),
+                };
+                image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            done += len as u32;
+        }
+        Ok(())
+    }
+
+    /// Run-time address of symbol `index` in the symbol table `symtab`.
+    fn symbol<S: Source>(
+        &self,
+        src: &mut S,
+        symtab: u32,
+        index: u32,
+        base: u32,
+    ) -> Result<u32, ElfError> {
+        if index == 0 {
+            return Ok(0);
+        }
+        let table = self.section(src, symtab)?;
+        let offset = index as usize * SYM_SIZE;
+        if offset + SYM_SIZE > table.size as usize {
+            return Err(ElfError::BadFormat);
+        }
+        let mut symbol = [0u8; SYM_SIZE];
+        src.read_at(table.offset + offset as u32, &mut symbol)?;
+
+        let value = u32_at(&symbol, 4);
+        match u16_at(&symbol, 14) {
+            SHN_UNDEF => Err(ElfError::UndefinedSymbol),
+            SHN_ABS => Ok(value),
+            _ => Ok(value.wrapping_add(base)),
+        }
+    }
+
+    fn segment<S: Source>(&self, src: &mut S, index: u16) -> Result<Segment, ElfError> {
+        let mut header = [0u8; PHDR_SIZE];
+        src.read_at(
+            self.phoff + (index as usize * PHDR_SIZE) as u32,
+            &mut header,
+        )?;
+        Ok(Segment {
+            kind: u32_at(&header, 0),
+            offset: u32_at(&header, 4),
+            vaddr: u32_at(&header, 8),
+            file_size: u32_at(&header, 16),
+            mem_size: u32_at(&header, 20),
+        })
+    }
+
+    fn section<S: Source>(&self, src: &mut S, index: u32) -> Result<Section, ElfError> {
+        if index >= self.shnum as u32 {
+            return Err(ElfError::BadFormat);
+        }
+        let mut header = [0u8; SHDR_SIZE];
+        src.read_at(self.shoff + index * SHDR_SIZE as u32, &mut header)?;
+        Ok(Section {
+            kind: u32_at(&header, 4),
+            offset: u32_at(&header, 16),
+            size: u32_at(&header, 20),
+            link: u32_at(&header, 24),
+        })
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
@@ -0,0 +1,100 @@
+//! Failures that stop the loader from reaching the menu.
+//!
+//! Each one is shown as an error screen with a code, which is also blinked
+//! on the LED when the display itself is what failed.
+
+use crate::{fs::FsError, sdproto::SdError};
+
+#[derive(Debug, Clone, Copy)]
+pub enum LoaderError {
+    /// A write to the display SPI bus failed.
+    Spi,
+    /// Driving a display control pin failed.
+    Gpio,
+    Sd(SdError),
+    Fs(FsError),
+    /// The card has no FAT or exFAT volume the loader can mount.
+    NoVolume,
+    /// The card has FAT volumes, but only in a GPT, which the FAT driver
+    /// cannot mount from. Unlike `NoVolume` the card is not offered for
+    /// formatting.
+    GptFat,
+}
+
+impl From<SdError> for LoaderError {
+    fn from(err: SdError) -> Self {
+        LoaderError::Sd(err)
+    }
+}
+
+impl From<FsError> for LoaderError {
+    fn from(err: FsError) -> Self {
+        LoaderError::Fs(err)
+    }
+}
+
+impl LoaderError {
+    /// Display errors are 1x, SD card errors 2x, filesystem errors 3x.
+    pub fn code(&self) -> u8 {
+        match self {
+            LoaderError::Spi => 10,
+            LoaderError::Gpio => 11,
+            LoaderError::Sd(SdError::NotInitialised) => 20,
+            LoaderError::Sd(SdError::Timeout) => 21,
+            LoaderError::Sd(SdError::Response(_)) => 22,
+            #[cfg(not(feature = "sdio"))]
+            LoaderError::Sd(SdError::DataToken(_)) => 23,
+            LoaderError::Sd(SdError::WriteRejected(_)) => 24,
+            LoaderError::Sd(SdError::Unsupported) => 25,
+            #[cfg(not(feature = "sdio"))]
+            LoaderError::Sd(SdError::Bus) => 26,
+            LoaderError::Sd(SdError::Crc) => 27,
+            LoaderError::Fs(FsError::NotFound) => 30,
+            LoaderError::Fs(FsError::Io) => 31,
+            LoaderError::Fs(FsError::Invalid) => 32,
+            LoaderError::NoVolume => 33,
+            LoaderError::Fs(FsError::Exists) => 34,
+            LoaderError::Fs(FsError::Full) => 35,
+            LoaderError::Fs(FsError::ReadOnly) => 36,
+            LoaderError::Fs(FsError::TooLarge) => 37,
+            LoaderError::GptFat => 38,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            LoaderError::Spi => "Display SPI write failed.",
+            LoaderError::Gpio => "Display pin failed.",
+            LoaderError::Sd(SdError::NotInitialised) => "SD card not initialised.",
+            LoaderError::Sd(SdError::Timeout) => "SD card not responding.",
+            LoaderError::Sd(SdError::Response(_)) => "SD card rejected a command.",
+            #[cfg(not(feature = "sdio"))]
+            LoaderError::Sd(SdError::DataToken(_)) => "SD card read failed.",
+            LoaderError::Sd(SdError::WriteRejected(_)) => "SD card write rejected.",
+            LoaderError::Sd(SdError::Unsupported) => "Unsupported SD card.",
+            #[cfg(not(feature = "sdio"))]
+            LoaderError::Sd(SdError::Bus) => "SD card SPI failed.",
+            LoaderError::Sd(SdError::Crc) => "SD card transfer corrupted.",
+            LoaderError::Fs(FsError::NotFound) => "File or directory missing.",
+            LoaderError::Fs(FsError::Io) => "Cannot read SD card.",
+            LoaderError::Fs(FsError::Invalid) => "Corrupt filesystem.",
+            LoaderError::NoVolume => "No usable volume on SD card.",
+            LoaderError::Fs(FsError::Exists) => "Name already taken.",
+            LoaderError::Fs(FsError::Full) => "SD card is full.",
+            LoaderError::Fs(FsError::ReadOnly) => "Volume cannot be changed.",
+            LoaderError::Fs(FsError::TooLarge) => "File is 4 GiB or larger.",
+            LoaderError::GptFat => "GPT not supported for FAT.",
+        }
+    }
+
+    /// Status byte the card answered with, if any.
+    pub fn detail(&self) -> Option<u8> {
+        match self {
+            LoaderError::Sd(SdError::Response(status))
+            | LoaderError::Sd(SdError::WriteRejected(status)) => Some(*status),
+            #[cfg(not(feature = "sdio"))]
+            LoaderError::Sd(SdError::DataToken(status)) => Some(*status),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Read-only exFAT.
+//!
+//! Only 512 byte sectors are supported. Names are compared ASCII
+//! case-insensitively, the volume's up-case table is not read.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+use heapless::String;
+
+/// Longest name exFAT allows, in UTF-16 units.
+pub const MAX_NAME: usize = 255;
+const ENTRY_SIZE: usize = 32;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+const ENTRY_IN_USE: u8 = 0x80;
+
+const ATTR_HIDDEN: u16 = 0x02;
+const ATTR_DIRECTORY: u16 = 0x10;
+const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+const FIRST_CLUSTER: u32 = 2;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Device(E),
+    NotExFat,
+    Corrupt,
+    NotFound,
+}
+
+/// Where the data of a file or directory lives.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Handle {
+    first_cluster: u32,
+    length: u64,
+    /// Clusters follow each other and are not recorded in the FAT.
+    contiguous: bool,
+}
+
+pub struct DirEntry {
+    /// Non-ASCII characters are replaced by `?`.
+    pub name: String<MAX_NAME>,
+    pub attributes: u16,
+    /// Last modification as a FAT date in the upper and time in the lower half.
+    pub modified: u32,
+    pub size: u64,
+    pub handle: Handle,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.attributes & ATTR_HIDDEN != 0
+    }
+}
+
+pub struct File {
+    handle: Handle,
+    position: u64,
+    /// Last cluster looked up and its index in the chain, so sequential reads
+    /// do not walk the FAT from the start.
+    cluster: u32,
+    cluster_index: u32,
+}
+
+impl File {
+    pub fn length(&self) -> u64 {
+        self.handle.length
+    }
+
+    pub fn eof(&self) -> bool {
+        self.position >= self.handle.length
+    }
+
+    pub fn seek_from_start(&mut self, offset: u64) -> Result<(), ()> {
+        if offset > self.handle.length {
+            return Err(());
+        }
+        self.position = offset;
+        Ok(())
+    }
+}
+
+pub struct Volume {
+    fat_start: u32,
+    heap_start: u32,
+    cluster_count: u32,
+    cluster_shift: u8,
+    root_cluster: u32,
+    cache: [Block; 1],
+    cached: Option<u32>,
+}
+
+impl Volume {
+    /// Mounts the exFAT file system whose boot sector is at block `start`.
+    pub fn mount<D: BlockDevice>(device: &D, start: u32) -> Result<Self, Error<D::Error>> {
+        let mut block = [Block::new()];
+        device
+            .read(&mut block, BlockIdx(start), "exfat")
+            .map_err(Error::Device)?;
+        let boot = &block[0].contents;
+        if &boot[3..11] != b"EXFAT   " {
+            return Err(Error::NotExFat);
+        }
+        let sector_shift = boot[108];
+        let cluster_shift = boot[109];
+        if sector_shift != 9 || cluster_shift > 16 {
+            return Err(Error::NotExFat);
+        }
+        Ok(Self {
+            fat_start: start + u32_at(boot, 80),
+            heap_start: start + u32_at(boot, 88),
+            cluster_count: u32_at(boot, 92),
+            cluster_shift,
+            root_cluster: u32_at(boot, 96),
+            cache: [Block::new()],
+            cached: None,
+        })
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        Block::LEN_U32 << self.cluster_shift
+    }
+
+    /// The root directory has no stream entry, its length is only known by
+    /// following the FAT.
+    pub fn root_dir(&self) -> Handle {
+        Handle {
+            first_cluster: self.root_cluster,
+            length: u64::MAX,
+            contiguous: false,
+        }
+    }
+
+    pub fn open(&self, handle: &Handle) -> File {
+        File {
+            handle: *handle,
+            position: 0,
+            cluster: handle.first_cluster,
+            cluster_index: 0,
+        }
+    }
+
+    /// Calls `f` for every file and directory in `dir`.
+    pub fn iterate_dir<D, F>(
+        &mut self,
+        device: &D,
+        dir: &Handle,
+        mut f: F,
+    ) -> Result<(), Error<D::Error>>
+    where
+        D: BlockDevice,
+        F: FnMut(&DirEntry),
+    {
+        let mut file = self.open(dir);
+        let mut raw = [0u8; ENTRY_SIZE];
+        let mut pending: Option<(DirEntry, u8, usize)> = None;
+        loop {
+            if self.read(device, &mut file, &mut raw)? < ENTRY_SIZE {
+                return Ok(());
+            }
+            match raw[0] {
+                ENTRY_END => return Ok(()),
+                ENTRY_FILE => {
+                    let entry = DirEntry {
+                        name: String::new(),
+                        attributes: u16::from_le_bytes([raw[4], raw[5]]),
+                        modified: u32_at(&raw, 12),
+                        size: 0,
+                        handle: Handle {
+                            first_cluster: 0,
+                            length: 0,
+                            contiguous: false,
+                        },
+                    };
+                    pending = Some((entry, raw[1], 0));
+                    continue;
+                }
+                kind if kind & ENTRY_IN_USE == 0 => {
+                    pending = None;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let (entry, remaining, name_length) = match pending.as_mut() {
+                Some(pending) if pending.1 > 0 => pending,
+                _ => continue,
+            };
+            *remaining -= 1;
+            match raw[0] {
+                ENTRY_STREAM => {
+                    *name_length = raw[3] as usize;
+                    let valid_length = u64_at(&raw, 8);
+                    let length = u64_at(&raw, 24);
+                    entry.handle = Handle {
+                        first_cluster: u32_at(&raw, 20),
+                        length: if entry.is_dir() { length } else { valid_length },
+                        contiguous: raw[1] & STREAM_NO_FAT_CHAIN != 0,
+                    };
+                    entry.size = valid_length;
+                }
+                ENTRY_NAME => {
+                    for unit in raw[2..].chunks_exact(2) {
+                        if entry.name.len() >= *name_length {
+                            break;
+                        }
+                        let c = match u16::from_le_bytes([unit[0], unit[1]]) {
+                            unit @ 0x20..=0x7E => unit as u8 as char,
+                            _ => '?',
+                        };
+                        entry.name.push(c).ok();
+                    }
+                }
+                _ => {}
+            }
+            if *remaining == 0 {
+                if let Some((entry, _, _)) = pending.take() {
+                    f(&entry);
+                }
+            }
+        }
+    }
+
+    /// Looks `name` up in `dir`.
+    pub fn find<D: BlockDevice>(
+        &mut self,
+        device: &D,
+        dir: &Handle,
+        name: &str,
+    ) -> Result<DirEntry, Error<D::Error>> {
+        let mut found = None;
+        self.iterate_dir(device, dir, |entry| {
+            if found.is_none() && entry.name.eq_ignore_ascii_case(name) {
+                found = Some(DirEntry {
+                    name: entry.name.clone(),
+                    ..*entry
+                });
+            }
+        })?;
+        found.ok_or(Error::NotFound)
+    }
+
+    /// Reads from the current position of `file`, returns 0 at its end.
+    pub fn read<D: BlockDevice>(
+        &mut self,
+        device: &D,
+        file: &mut File,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<D::Error>> {
+        let cluster_size = self.cluster_size() as u64;
+        let mut done = 0;
+        while done < buf.len() && !file.eof() {
+            let index = (file.position / cluster_size) as u32;
+            let cluster = match self.cluster_at(device, file, index) {
+                Ok(cluster) => cluster,
+                // Directories without a length end with their chain.
+                Err(Error::NotFound) if file.handle.length == u64::MAX => break,
+                Err(err) => return Err(err),
+            };
+            let in_cluster = (file.position % cluster_size) as u32;
+            let sector = self.cluster_sector(cluster) + in_cluster / Block::LEN_U32;
+            let offset = (in_cluster % Block::LEN_U32) as usize;
+
+            let count = (Block::LEN - offset)
+                .min(buf.len() - done)
+                .min((file.handle.length - file.position).min(Block::LEN as u64) as usize);
+            let data = self.read_block(device, sector)?;
+            buf[done..done + count].copy_from_slice(&data[offset..offset + count]);
+            done += count;
+            file.position += count as u64;
+        }
+        Ok(done)
+    }
+
+    fn cluster_at<D: BlockDevice>(
+        &mut self,
+        device: &D,
+        file: &mut File,
+        index: u32,
+    ) -> Result<u32, Error<D::Error>> {
+        let cluster = if file.handle.contiguous {
+            file.handle.first_cluster + index
+        } else {
+            self.follow(device, file, index)?
+        };
+        if cluster < FIRST_CLUSTER || cluster >= FIRST_CLUSTER + self.cluster_count {
+            return Err(Error::Corrupt);
+        }
+        Ok(cluster)
+    }
+
+    fn follow<D: BlockDevice>(
+        &mut self,
+        device: &D,
+        file: &mut File,
+        index: u32,
+    ) -> Result<u32, Error<D::Error>> {
+        if index < file.cluster_index {
+            file.cluster = file.handle.first_cluster;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < index {
+            file.cluster = self.next_cluster(device, file.cluster)?;
+            file.cluster_index += 1;
+        }
+        Ok(file.cluster)
+    }
+
+    /// Follows the FAT, `NotFound` marks the end of the chain.
+    fn next_cluster<D: BlockDevice>(
+        &mut self,
+        device: &D,
+        cluster: u32,
+    ) -> Result<u32, Error<D::Error>> {
+        let offset = cluster as usize * 4;
+        let sector = self.fat_start + (offset / Block::LEN) as u32;
+        let data = self.read_block(device, sector)?;
+        let next = u32_at(data, offset % Block::LEN);
+        match next {
+            0xFFFF_FFFF => Err(Error::NotFound),
+            next if next >= FIRST_CLUSTER && next < FIRST_CLUSTER + self.cluster_count => Ok(next),
+            _ => Err(Error::Corrupt),
+        }
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.heap_start + ((cluster - FIRST_CLUSTER) << self.cluster_shift)
+    }
+
+    fn read_block<D: BlockDevice>(
+        &mut self,
+        device: &D,
+        sector: u32,
+    ) -> Result<&[u8], Error<D::Error>> {
+        if self.cached != Some(sector) {
+            self.cached = None;
+            device
+                .read(&mut self.cache, BlockIdx(sector), "exfat")
+                .map_err(Error::Device)?;
+            self.cached = Some(sector);
+        }
+        Ok(&self.cache[0].contents[..])
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u32_at(bytes, offset) as u64 | (u32_at(bytes, offset + 4) as u64) << 32
+}
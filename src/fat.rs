@@ -0,0 +1,129 @@
+//! Raw FAT structures for the changes `embedded_sdmmc` has no call for,
+//! like renaming an entry or turning one into a directory.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+pub const ENTRY_SIZE: usize = 32;
+/// First name byte of a deleted entry.
+pub const DELETED: u8 = 0xE5;
+
+const ATTR: usize = 11;
+const ATTR_DIRECTORY: u8 = 0x10;
+const CLUSTER_HIGH: usize = 20;
+const CLUSTER_LOW: usize = 26;
+const SIZE: usize = 28;
+
+/// Characters allowed in a short name besides letters and digits.
+const NAME_SYMBOLS: &[u8] = b"!#$%&'()-@^_`{}~";
+
+/// Where the clusters of a FAT volume are.
+#[derive(Clone, Copy)]
+pub struct Layout {
+    /// Absolute block of the boot sector.
+    pub start: u32,
+    pub blocks_per_cluster: u32,
+    /// Absolute block of cluster 2.
+    pub data_start: u32,
+}
+
+impl Layout {
+    /// Reads the boot sector of the volume starting at block `start`.
+    pub fn read<D: BlockDevice>(device: &D, start: u32) -> Result<Self, D::Error> {
+        let mut block = [Block::new()];
+        device.read(&mut block, BlockIdx(start), "layout")?;
+        let boot = &block[0].contents;
+
+        let reserved = u16_at(boot, 14) as u32;
+        let fats = boot[16] as u32;
+        let root_blocks = (u16_at(boot, 17) as u32 * ENTRY_SIZE as u32).div_ceil(Block::LEN_U32);
+        let fat_size = match u16_at(boot, 22) {
+            0 => u32_at(boot, 36),
+            size => size as u32,
+        };
+        Ok(Self {
+            start,
+            blocks_per_cluster: (boot[13] as u32).max(1),
+            data_start: start + reserved + fats * fat_size + root_blocks,
+        })
+    }
+
+    pub fn cluster_block(&self, cluster: u32) -> BlockIdx {
+        BlockIdx(self.data_start + cluster.saturating_sub(2) * self.blocks_per_cluster)
+    }
+}
+
+/// The 11-byte directory form of an 8.3 name, `None` if `name` is not one.
+/// Lower case letters are taken as upper case.
+pub fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let fields = [(base, 0), (extension, 8)];
+    for (field, start) in fields {
+        for (i, c) in field.bytes().enumerate() {
+            let c = c.to_ascii_uppercase();
+            if !(c.is_ascii_uppercase() || c.is_ascii_digit() || NAME_SYMBOLS.contains(&c)) {
+                return None;
+            }
+            short[start + i] = c;
+        }
+    }
+    Some(short)
+}
+
+/// The 11-byte form of a volume label, `None` if `name` cannot be one. Takes
+/// the same characters as a short name but no dot.
+pub fn volume_label(name: &str) -> Option<[u8; 11]> {
+    if name.is_empty() || name.len() > 11 {
+        return None;
+    }
+    let mut label = [b' '; 11];
+    for (byte, c) in label.iter_mut().zip(name.bytes()) {
+        let c = c.to_ascii_uppercase();
+        if !(c.is_ascii_uppercase() || c.is_ascii_digit() || NAME_SYMBOLS.contains(&c)) {
+            return None;
+        }
+        *byte = c;
+    }
+    Some(label)
+}
+
+/// First cluster of a directory entry.
+pub fn cluster(entry: &[u8]) -> u32 {
+    (u16_at(entry, CLUSTER_HIGH) as u32) << 16 | u16_at(entry, CLUSTER_LOW) as u32
+}
+
+/// Turns the file entry `entry` into a directory entry.
+pub fn make_directory(entry: &mut [u8]) {
+    entry[ATTR] = ATTR_DIRECTORY;
+    entry[SIZE..SIZE + 4].fill(0);
+}
+
+/// Writes the `.` and `..` entries for the directory `entry` to the start of
+/// `block`. `parent` is the first cluster of the parent directory, zero for
+/// the root.
+pub fn dot_entries(block: &mut [u8], entry: &[u8], parent: u32) {
+    let (dot, rest) = block.split_at_mut(ENTRY_SIZE);
+    let dot_dot = &mut rest[..ENTRY_SIZE];
+    dot.copy_from_slice(&entry[..ENTRY_SIZE]);
+    dot[..11].copy_from_slice(b".          ");
+    dot_dot.copy_from_slice(dot);
+    dot_dot[..11].copy_from_slice(b"..         ");
+    dot_dot[CLUSTER_HIGH..CLUSTER_HIGH + 2].copy_from_slice(&((parent >> 16) as u16).to_le_bytes());
+    dot_dot[CLUSTER_LOW..CLUSTER_LOW + 2].copy_from_slice(&(parent as u16).to_le_bytes());
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
@@ -0,0 +1,348 @@
+//! Changes to the image volume from the loader: deleting, renaming and
+//! backing up files, creating folders and formatting the whole card. Each
+//! one is confirmed first.
+
+use core::fmt::Write;
+
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use heapless::String;
+
+use crate::{
+    browser,
+    buttons::Buttons,
+    error::LoaderError,
+    fat,
+    format::{self, FormatError},
+    fs::{Dir, Entry, FsError, Storage},
+    ui, Display,
+};
+
+/// Where backups go, in the root directory.
+pub const BACKUP_DIR: &str = "BACKUP";
+
+/// Given to freshly formatted cards.
+pub const DEFAULT_LABEL: &str = "LOADER";
+
+const ACTIONS: [&str; 5] = [
+    "Delete file",
+    "Rename file",
+    "Back up file",
+    "New folder",
+    "Format card",
+];
+
+/// An 8.3 name with its dot.
+type Name = String<12>;
+
+/// Returns `true` once the card was formatted, its volumes then have to be
+/// read again. `serial` becomes the serial number of a new volume.
+pub fn manage<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    serial: u32,
+) -> Result<bool, LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    while let Some(action) = ui::select(display, buttons, delay, "Files", &ACTIONS)? {
+        match action {
+            4 => {
+                if format_card(display, buttons, delay, storage.controller.device(), serial)? {
+                    return Ok(true);
+                }
+            }
+            _ if !storage.is_writable() => {
+                ui::message(display, "exFAT volumes are read-only.")?;
+                buttons.wait(delay);
+            }
+            0 => with_file(display, buttons, delay, storage, root, "Delete", delete)?,
+            1 => with_file(display, buttons, delay, storage, root, "Rename", rename)?,
+            2 => with_file(
+                display,
+                buttons,
+                delay,
+                storage,
+                root,
+                "Back up",
+                |display, buttons, delay, storage, dir, entry| {
+                    back_up(display, buttons, delay, storage, root, dir, entry)
+                },
+            )?,
+            _ => make_dir(display, buttons, delay, storage, root)?,
+        }
+    }
+    Ok(false)
+}
+
+/// Erases the card and makes it one FAT32 volume, after asking for a label
+/// and for confirmation. Returns `true` if the card was formatted.
+pub fn format_card(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    device: &impl BlockDevice,
+    serial: u32,
+) -> Result<bool, LoaderError> {
+    let mut name = String::<11>::new();
+    name.push_str(DEFAULT_LABEL).ok();
+    let label = loop {
+        name = match ui::edit_name(display, buttons, delay, "Volume label", &name)? {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        if let Some(label) = fat::volume_label(&name) {
+            break label;
+        }
+        ui::message(display, "Letters and digits, no dots.")?;
+        buttons.wait(delay);
+    };
+    let lines = [
+        "Erase the whole card and",
+        "make it one FAT32 volume",
+        name.as_str(),
+        "",
+        "Everything on it is lost.",
+    ];
+    if !ui::confirm(display, buttons, delay, "Format card", &lines)? {
+        return Ok(false);
+    }
+
+    ui::message(display, "Formatting.")?;
+    let total = device
+        .num_blocks()
+        .ok()
+        .and_then(|count| format::Geometry::new(count.0))
+        .map_or(1, |geometry| geometry.blocks_written());
+    let mut shown = 0;
+    let formatted = format::format(device, &label, serial, |written| {
+        let percent = written as u64 * 100 / total as u64;
+        // Redrawing for every batch would slow the card down.
+        if percent >= shown + 5 {
+            shown = percent;
+            let mut text = ui::Line::new();
+            write!(text, "Formatting. {}%", percent).ok();
+            ui::message(display, &text).ok();
+        }
+    });
+    let text = match formatted {
+        Ok(_) => "Card formatted.",
+        Err(FormatError::TooSmall) => "Card too small for FAT32.",
+        Err(FormatError::Device(_)) => return Err(FsError::Io.into()),
+    };
+    ui::message(display, text)?;
+    buttons.wait(delay);
+    Ok(formatted.is_ok())
+}
+
+/// Lets the user pick a file and hands it to `change` together with the
+/// directory it is in.
+fn with_file<D, T, F>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    title: &str,
+    mut change: F,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    F: FnMut(
+        &mut Display,
+        &Buttons,
+        &mut Delay,
+        &mut Storage<D, T>,
+        &Dir,
+        &Entry,
+    ) -> Result<(), LoaderError>,
+{
+    let selection = match browser::browse(display, buttons, delay, storage, root, title, any)? {
+        Some(selection) => selection,
+        None => return Ok(()),
+    };
+    let dir = selection.dir.as_ref().unwrap_or(root);
+    let changed = change(display, buttons, delay, storage, dir, &selection.entry);
+    if let Some(dir) = selection.dir {
+        storage.close_dir(dir);
+    }
+    changed
+}
+
+fn delete<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    dir: &Dir,
+    entry: &Entry,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let lines = [
+        "Delete this file?",
+        entry.label.as_str(),
+        "",
+        "This cannot be undone.",
+    ];
+    if !ui::confirm(display, buttons, delay, "Delete", &lines)? {
+        return Ok(());
+    }
+    let deleted = storage.delete(dir, entry);
+    report(display, buttons, delay, deleted, "File deleted.")
+}
+
+fn rename<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    dir: &Dir,
+    entry: &Entry,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let current = entry.short_name().unwrap_or_default();
+    let name = match ask_name(display, buttons, delay, "Rename", &current)? {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let lines = ["Rename", entry.label.as_str(), "to", name.as_str()];
+    if !ui::confirm(display, buttons, delay, "Rename", &lines)? {
+        return Ok(());
+    }
+    let renamed = storage.rename(dir, entry, &name);
+    report(display, buttons, delay, renamed, "File renamed.")
+}
+
+fn back_up<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    dir: &Dir,
+    entry: &Entry,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let lines = [
+        "Copy this file to /BACKUP?",
+        entry.label.as_str(),
+        "",
+        "A copy already there is replaced.",
+    ];
+    if !ui::confirm(display, buttons, delay, "Back up", &lines)? {
+        return Ok(());
+    }
+    ui::message(display, "Copying.")?;
+    let copied = match storage.make_dir(root, BACKUP_DIR) {
+        Ok(()) | Err(FsError::Exists) => {
+            storage.open_dir_named(root, BACKUP_DIR).and_then(|backup| {
+                let copied = storage.copy(dir, entry, &backup);
+                storage.close_dir(backup);
+                copied
+            })
+        }
+        Err(err) => Err(err),
+    };
+    report(display, buttons, delay, copied, "Copied to /BACKUP.")
+}
+
+fn make_dir<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let folder = match browser::choose_dir(display, buttons, delay, storage, root, "New folder")? {
+        Some(folder) => folder,
+        None => return Ok(()),
+    };
+    let parent = folder.dir.as_ref().unwrap_or(root);
+    let made = match ask_name(display, buttons, delay, "New folder", "")? {
+        Some(name) => {
+            let lines = [
+                "Create the folder",
+                name.as_str(),
+                "in",
+                folder.label.as_str(),
+            ];
+            if ui::confirm(display, buttons, delay, "New folder", &lines)? {
+                Some(storage.make_dir(parent, &name))
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+    if let Some(dir) = folder.dir {
+        storage.close_dir(dir);
+    }
+    match made {
+        Some(made) => report(display, buttons, delay, made, "Folder created."),
+        None => Ok(()),
+    }
+}
+
+/// Asks for an 8.3 name until the user gives a valid one or backs out.
+fn ask_name(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    title: &str,
+    initial: &str,
+) -> Result<Option<Name>, LoaderError> {
+    let mut name = Name::new();
+    name.push_str(initial).ok();
+    loop {
+        name = match ui::edit_name(display, buttons, delay, title, &name)? {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if fat::short_name(&name).is_some() {
+            return Ok(Some(name));
+        }
+        ui::message(display, "Use an 8.3 name like PHOTO1.BMP")?;
+        buttons.wait(delay);
+    }
+}
+
+/// Shows how a change went. Failing to read or write the card ends the
+/// session like anywhere else.
+pub fn report(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    result: Result<(), FsError>,
+    done: &str,
+) -> Result<(), LoaderError> {
+    let text = match result {
+        Ok(()) => done,
+        Err(FsError::Io) => return Err(FsError::Io.into()),
+        Err(err) => LoaderError::Fs(err).message(),
+    };
+    ui::message(display, text)?;
+    buttons.wait(delay);
+    Ok(())
+}
+
+fn any(_extension: &str) -> bool {
+    true
+}
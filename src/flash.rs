@@ -0,0 +1,289 @@
+//! Identification of the external QSPI flash, and erasing and programming
+//! the littlefs region behind the loader.
+//!
+//! The JEDEC ID and the SFDP header are read with raw SSI commands. This needs
+//! XIP to be off, so the command sequence runs from RAM with interrupts
+//! disabled and restores XIP with the boot2 stage afterwards. Erasing and
+//! programming go through the boot ROM the same way.
+
+use core::ptr;
+
+use rp_loader_abi::SERVICE_TABLE_ADDR;
+use rp_pico::hal::rom_data;
+
+pub use crate::layout::{FLASH_BASE, FS_SIZE, FS_START, LOADER_SIZE};
+
+/// Smallest erasable unit.
+pub const SECTOR_SIZE: u32 = 4096;
+/// The sector holding the service table. It sits inside the app region but
+/// belongs to the loader.
+const SERVICES_SECTOR: u32 = (SERVICE_TABLE_ADDR as u32 - FLASH_BASE) & !(SECTOR_SIZE - 1);
+
+const _: () = assert!(SERVICES_SECTOR >= FS_START + FS_SIZE);
+/// Largest unit programmed at once.
+pub const PAGE_SIZE: u32 = 256;
+/// The ROM erases 64K blocks with this command where it can.
+const BLOCK_SIZE: u32 = 64 * 1024;
+const CMD_BLOCK_ERASE: u8 = 0xD8;
+
+/// Assumed when neither SFDP nor the JEDEC capacity byte make sense.
+const DEFAULT_SIZE: u32 = 2 * 1024 * 1024;
+
+const CMD_READ_JEDEC_ID: u8 = 0x9F;
+const CMD_READ_SFDP: u8 = 0x5A;
+
+const SFDP_SIGNATURE: u32 = 0x5044_4653;
+const SFDP_BASIC_TABLE_ID: u8 = 0x00;
+
+const XIP_SSI_BASE: usize = 0x1800_0000;
+const SSI_SR: usize = XIP_SSI_BASE + 0x28;
+const SSI_DR0: usize = XIP_SSI_BASE + 0x60;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+/// Keeps the RX FIFO from overflowing while TX runs ahead.
+const SSI_FIFO_DEPTH: usize = 14;
+
+const IO_QSPI_SS_CTRL: usize = 0x4001_800C;
+const OUTOVER_SHIFT: u32 = 8;
+const OUTOVER_MASK: u32 = 0x3 << OUTOVER_SHIFT;
+const OUTOVER_LOW: u32 = 0x2 << OUTOVER_SHIFT;
+const OUTOVER_HIGH: u32 = 0x3 << OUTOVER_SHIFT;
+
+const BOOT2_SIZE: usize = 256;
+
+#[derive(Clone, Copy)]
+pub struct FlashInfo {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8,
+    /// Size in bytes.
+    pub size: u32,
+    /// Whether `size` came from the SFDP basic parameter table.
+    pub from_sfdp: bool,
+}
+
+impl FlashInfo {
+    pub fn manufacturer_name(&self) -> &'static str {
+        match self.manufacturer {
+            0xEF => "Winbond",
+            0xC8 => "GigaDevice",
+            0xC2 => "Macronix",
+            0x9D => "ISSI",
+            0x20 => "Micron/XMC",
+            0x1F => "Adesto",
+            0x01 => "Spansion",
+            0xBF => "SST",
+            0x68 => "Boya",
+            0x85 => "Puya",
+            0x5E => "Zbit",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn app_region(&self) -> (u32, u32) {
+        let start = FS_START + FS_SIZE;
+        (FLASH_BASE + start, FLASH_BASE + self.size.max(start))
+    }
+
+    /// Bytes in [`app_region`](Self::app_region) usable by apps, leaving out
+    /// the service table's sector.
+    pub fn app_region_size(&self) -> u32 {
+        let size = self.size.saturating_sub(FS_START + FS_SIZE);
+        if self.size > SERVICES_SECTOR {
+            size - SECTOR_SIZE
+        } else {
+            size
+        }
+    }
+
+    /// Whether the chip reaches past the littlefs region.
+    pub fn has_fs_region(&self) -> bool {
+        self.size >= FS_START + FS_SIZE
+    }
+}
+
+/// Reads the JEDEC ID and SFDP header of the boot flash.
+pub fn detect() -> FlashInfo {
+    let mut jedec = [CMD_READ_JEDEC_ID, 0, 0, 0];
+    // Command, three address bytes and one dummy byte, then the data.
+    let mut sfdp_header = [0u8; 5 + 16];
+    sfdp_header[0] = CMD_READ_SFDP;
+    let mut density = [0u8; 5 + 8];
+    density[0] = CMD_READ_SFDP;
+
+    cortex_m::interrupt::free(|_| unsafe {
+        let rom = RomFuncs::new();
+        do_commands(&rom, &mut jedec, &mut sfdp_header);
+        if let Some(table) = basic_table_pointer(&sfdp_header[5..]) {
+            density[1..4].copy_from_slice(&table.to_be_bytes()[1..]);
+            do_commands(&rom, &mut [], &mut density);
+        }
+    });
+
+    let (manufacturer, memory_type, capacity) = (jedec[1], jedec[2], jedec[3]);
+    let (size, from_sfdp) =
+        match basic_table_pointer(&sfdp_header[5..]).and_then(|_| density_bytes(&density[5..])) {
+            Some(size) => (size, true),
+            None => (size_from_capacity(capacity).unwrap_or(DEFAULT_SIZE), false),
+        };
+    FlashInfo {
+        manufacturer,
+        memory_type,
+        capacity,
+        size,
+        from_sfdp,
+    }
+}
+
+/// Erases `len` bytes at `offset` into the flash, both multiples of
+/// `SECTOR_SIZE`.
+pub fn erase(offset: u32, len: u32) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let rom = RomFuncs::new();
+        do_erase(&rom, offset, len);
+    });
+}
+
+/// Programs `data` at `offset` into the flash, both multiples of
+/// `PAGE_SIZE`. The range has to be erased.
+pub fn program(offset: u32, data: &[u8]) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let rom = RomFuncs::new();
+        do_program(&rom, offset, data);
+    });
+}
+
+/// Offset of the JEDEC basic flash parameter table, from the SFDP header and
+/// the first parameter header.
+fn basic_table_pointer(header: &[u8]) -> Option<u32> {
+    let signature = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if signature != SFDP_SIGNATURE || header[8] != SFDP_BASIC_TABLE_ID {
+        return None;
+    }
+    Some(u32::from_le_bytes([header[12], header[13], header[14], 0]))
+}
+
+/// Decodes the density DWORD (the second one) of the basic parameter table.
+fn density_bytes(table: &[u8]) -> Option<u32> {
+    let density = u32::from_le_bytes([table[4], table[5], table[6], table[7]]);
+    let bits = if density & 0x8000_0000 == 0 {
+        density as u64 + 1
+    } else {
+        1u64.checked_shl(density & 0x7FFF_FFFF)?
+    };
+    let bytes = bits / 8;
+    if (64 * 1024..=1 << 31).contains(&bytes) {
+        Some(bytes as u32)
+    } else {
+        None
+    }
+}
+
+/// Most vendors encode the size as a power of two in the capacity byte.
+fn size_from_capacity(capacity: u8) -> Option<u32> {
+    if (0x10..=0x1F).contains(&capacity) {
+        Some(1 << capacity)
+    } else {
+        None
+    }
+}
+
+struct RomFuncs {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_flush_cache: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    boot2: [u32; BOOT2_SIZE / 4],
+}
+
+impl RomFuncs {
+    /// Everything needed while XIP is off has to be fetched beforehand.
+    unsafe fn new() -> Self {
+        let mut boot2 = [0u32; BOOT2_SIZE / 4];
+        ptr::copy_nonoverlapping(FLASH_BASE as *const u32, boot2.as_mut_ptr(), boot2.len());
+        Self {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            boot2,
+        }
+    }
+}
+
+/// Sends each buffer as one command, replacing its contents with the bytes
+/// clocked in. Runs from RAM, the flash is not mapped while it executes.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn do_commands(rom: &RomFuncs, first: &mut [u8], second: &mut [u8]) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    transfer(first.as_mut_ptr(), first.len());
+    transfer(second.as_mut_ptr(), second.len());
+    enter_xip(rom);
+}
+
+/// Clocks one command through the SSI. A panic or a call into core from here
+/// would jump into the unmapped flash, so the buffer is walked with raw
+/// pointers and wrapping counters instead of indexing.
+#[inline(always)]
+unsafe fn transfer(buf: *mut u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    cs_force(OUTOVER_LOW);
+    let mut tx = 0;
+    let mut rx = 0;
+    while rx < len {
+        let status = ptr::read_volatile(SSI_SR as *const u32);
+        if status & SSI_SR_TFNF != 0 && tx < len && tx.wrapping_sub(rx) < SSI_FIFO_DEPTH {
+            ptr::write_volatile(SSI_DR0 as *mut u32, *buf.add(tx) as u32);
+            tx = tx.wrapping_add(1);
+        }
+        if status & SSI_SR_RFNE != 0 {
+            *buf.add(rx) = ptr::read_volatile(SSI_DR0 as *const u32) as u8;
+            rx = rx.wrapping_add(1);
+        }
+    }
+    cs_force(OUTOVER_HIGH);
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn do_erase(rom: &RomFuncs, offset: u32, len: u32) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(offset, len as usize, BLOCK_SIZE, CMD_BLOCK_ERASE);
+    enter_xip(rom);
+}
+
+/// `data` has to be in RAM, the flash is not mapped while this runs.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn do_program(rom: &RomFuncs, offset: u32, data: &[u8]) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_program)(offset, data.as_ptr(), data.len());
+    enter_xip(rom);
+}
+
+#[inline(always)]
+unsafe fn enter_xip(rom: &RomFuncs) {
+    (rom.flash_flush_cache)();
+    // boot2 puts the SSI back into the fast XIP mode the loader booted with.
+    let boot2: unsafe extern "C" fn() = core::mem::transmute(rom.boot2.as_ptr() as usize + 1);
+    boot2();
+}
+
+#[inline(always)]
+unsafe fn cs_force(outover: u32) {
+    let ctrl = ptr::read_volatile(IO_QSPI_SS_CTRL as *const u32);
+    ptr::write_volatile(
+        IO_QSPI_SS_CTRL as *mut u32,
+        (ctrl & !OUTOVER_MASK) | outover,
+    );
+    // Wait for the override to reach the pad before clocking.
+    ptr::read_volatile(IO_QSPI_SS_CTRL as *const u32);
+}
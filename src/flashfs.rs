@@ -0,0 +1,532 @@
+//! The littlefs volume in the flash behind the loader, for boards without a
+//! card and for files that should stay on the board.
+//!
+//! Reads come straight through XIP, erasing and programming go through the
+//! boot ROM, see `flash.rs`. All files live in the root directory. Apps can
+//! be copied here from the card and run from here while no card is in.
+
+use core::{fmt::Write as _, ptr, slice};
+
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use heapless::{String, Vec};
+use littlefs2::{
+    consts, driver,
+    fs::{Allocation, File, Filesystem},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+#[cfg(not(feature = "sdio"))]
+use crate::sdproto::{ClockMemory, CLOCK_MEMORY_LEN};
+use crate::{
+    app::{self, Image, LaunchError},
+    browser,
+    buttons::Buttons,
+    error::LoaderError,
+    fat,
+    files::report,
+    flash::{self, FlashInfo, FLASH_BASE, FS_SIZE, FS_START},
+    fs::{self, Dir, FsError, Storage},
+    ram::Allocator,
+    ui::{self, Line},
+    Display,
+};
+
+pub type FlashFs<'a> = Filesystem<'a, FlashStorage>;
+
+/// Files listed at most.
+pub const MAX_FILES: usize = 64;
+
+/// Bytes moved at once when copying.
+const COPY_CHUNK: usize = 512;
+/// Where `save_clocks` keeps the card clocks.
+#[cfg(not(feature = "sdio"))]
+const CLOCKS_FILE: &str = "SDCLOCKS.BIN";
+
+const ACTIONS: [&str; 4] = ["Copy from card", "Copy to card", "Run app", "Delete file"];
+
+/// The flash region as a littlefs block device.
+pub struct FlashStorage(());
+
+impl FlashStorage {
+    /// `None` if the chip ends before the region does.
+    pub fn new(info: &FlashInfo) -> Option<Self> {
+        info.has_fs_region().then_some(Self(()))
+    }
+}
+
+impl driver::Storage for FlashStorage {
+    // Reads are memory mapped, the size only sets littlefs' granularity.
+    const READ_SIZE: usize = 16;
+    const WRITE_SIZE: usize = flash::PAGE_SIZE as usize;
+    const BLOCK_SIZE: usize = flash::SECTOR_SIZE as usize;
+    const BLOCK_COUNT: usize = (FS_SIZE / flash::SECTOR_SIZE) as usize;
+    // Moves metadata on after this many erases, to spread the wear.
+    const BLOCK_CYCLES: isize = 500;
+    type CACHE_SIZE = consts::U256;
+    // One 64-bit word, a bit for each of the 64 blocks.
+    type LOOKAHEAD_SIZE = consts::U1;
+
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let offset = in_region(off, buf.len())?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (FLASH_BASE + offset) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        Ok(buf.len())
+    }
+
+    fn write(&mut self, off: usize, data: &[u8]) -> io::Result<usize> {
+        flash::program(in_region(off, data.len())?, data);
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, off: usize, len: usize) -> io::Result<usize> {
+        flash::erase(in_region(off, len)?, len as u32);
+        Ok(len)
+    }
+}
+
+/// Offset into the flash of `len` bytes at `off` into the region. Anything
+/// reaching outside would hit the loader or the apps.
+fn in_region(off: usize, len: usize) -> io::Result<u32> {
+    match off.checked_add(len) {
+        Some(end) if end <= FS_SIZE as usize => Ok(FS_START + off as u32),
+        _ => Err(io::Error::Invalid),
+    }
+}
+
+pub fn allocate() -> Allocation<FlashStorage> {
+    Filesystem::allocate()
+}
+
+/// Makes sure the region holds a volume, `false` if it still does not. A
+/// blank region is formatted straight away, as on the first boot. Anything
+/// else may be a damaged volume with files on it, so it is only formatted
+/// once the user agrees.
+pub fn prepare(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut FlashStorage,
+) -> Result<bool, LoaderError> {
+    if Filesystem::is_mountable(storage) {
+        return Ok(true);
+    }
+    if !is_blank() {
+        let lines = [
+            "The flash volume does not",
+            "mount, it may be damaged.",
+            "",
+            "Format it? Every file on",
+            "it is lost.",
+        ];
+        if !ui::confirm(display, buttons, delay, "Flash volume", &lines)? {
+            return Ok(false);
+        }
+        ui::message(display, "Formatting.")?;
+    }
+    Ok(Filesystem::format(storage).is_ok())
+}
+
+pub fn mount<'a>(
+    alloc: &'a mut Allocation<FlashStorage>,
+    storage: &'a mut FlashStorage,
+) -> Result<FlashFs<'a>, FsError> {
+    Filesystem::mount(alloc, storage).map_err(fs_error)
+}
+
+/// Whether the region is still erased, as it is until the first format.
+fn is_blank() -> bool {
+    let region =
+        unsafe { slice::from_raw_parts((FLASH_BASE + FS_START) as *const u8, FS_SIZE as usize) };
+    region.iter().all(|&byte| byte == 0xFF)
+}
+
+/// A file on the volume.
+pub struct Entry {
+    pub name: Line,
+    /// Upper case, empty if the name has none.
+    pub extension: String<4>,
+    pub size: u32,
+}
+
+/// Files whose extension `filter` accepts, by name. Names too long to show
+/// are left out.
+pub fn list(volume: &FlashFs, filter: fn(&str) -> bool) -> Result<Vec<Entry, MAX_FILES>, FsError> {
+    let mut entries: Vec<Entry, MAX_FILES> = Vec::new();
+    volume
+        .read_dir_and_then(&PathBuf::from("/"), |dir| {
+            for item in dir {
+                let item = item?;
+                let metadata = item.metadata();
+                let mut name = Line::new();
+                if !metadata.is_file() || name.push_str(item.file_name().as_str()).is_err() {
+                    continue;
+                }
+                let extension = fs::extension(&name);
+                if filter(&extension) {
+                    let size = metadata.len() as u32;
+                    entries
+                        .push(Entry {
+                            name,
+                            extension,
+                            size,
+                        })
+                        .ok();
+                }
+            }
+            Ok(())
+        })
+        .map_err(fs_error)?;
+    entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Free and total bytes.
+pub fn usage(volume: &FlashFs) -> Result<(u32, u32), FsError> {
+    let free = volume.available_space().map_err(fs_error)?;
+    Ok((free as u32, volume.total_space() as u32))
+}
+
+/// Reads the file `name` from `offset` on, for the app file service.
+pub fn read_at(
+    volume: &FlashFs,
+    name: &str,
+    offset: u32,
+    buf: &mut [u8],
+) -> Result<usize, FsError> {
+    let path = path(name)?;
+    volume
+        .open_file_and_then(&path, |file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read(buf)
+        })
+        .map_err(fs_error)
+}
+
+/// Opens the file `name` and hands it to `f` to load an app from.
+pub fn with_file<R>(
+    volume: &FlashFs,
+    name: &str,
+    f: impl FnOnce(&mut dyn Image) -> R,
+) -> Result<R, FsError> {
+    let path = path(name)?;
+    volume
+        .open_file_and_then(&path, |file| {
+            let mut file = file;
+            Ok(f(&mut file))
+        })
+        .map_err(fs_error)
+}
+
+impl Image for &File<'_, '_, FlashStorage> {
+    fn length(&self) -> u32 {
+        self.len().map_or(0, |len| len as u32)
+    }
+
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<usize, LaunchError> {
+        self.seek(SeekFrom::Start(offset))
+            .and_then(|_| self.read(buf))
+            .map_err(|_| LaunchError::Io)
+    }
+}
+
+/// Copies the file `entry` in `dir` on the card to the volume, under the
+/// name it is listed with. A file of that name is replaced.
+pub fn copy_from_card<D, T>(
+    volume: &FlashFs,
+    storage: &mut Storage<D, T>,
+    dir: &Dir,
+    entry: &fs::Entry,
+) -> Result<(), FsError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let path = path(&entry.label)?;
+    let mut file = storage.open_entry(dir, entry)?;
+    let mut card_error = None;
+    let copied = volume.create_file_and_then(&path, |out| {
+        let mut buf = [0; COPY_CHUNK];
+        while !file.eof() {
+            let count = storage.read(&mut file, &mut buf).map_err(|err| {
+                card_error = Some(err);
+                io::Error::Io
+            })?;
+            out.write_all(&buf[..count])?;
+        }
+        Ok(())
+    });
+    storage.close(file);
+    copied.map_err(|err| {
+        // Half a file is worse than none.
+        volume.remove(&path).ok();
+        card_error.unwrap_or_else(|| fs_error(err))
+    })
+}
+
+/// Copies the file `name` on the volume to `dir` on the card, which needs
+/// an 8.3 name. A file of that name is replaced.
+pub fn copy_to_card<D, T>(
+    volume: &FlashFs,
+    name: &str,
+    storage: &mut Storage<D, T>,
+    dir: &Dir,
+) -> Result<(), FsError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fat::short_name(name).ok_or(FsError::Invalid)?;
+    let path = path(name)?;
+    let mut out = storage.create(dir, name)?;
+    let mut card_error = None;
+    let copied = volume.open_file_and_then(&path, |file| {
+        let mut buf = [0; COPY_CHUNK];
+        loop {
+            let count = file.read(&mut buf)?;
+            if count == 0 {
+                return Ok(());
+            }
+            storage.write(&mut out, &buf[..count]).map_err(|err| {
+                card_error = Some(err);
+                io::Error::Io
+            })?;
+        }
+    });
+    storage.close(out);
+    copied.map_err(|err| {
+        storage.remove(dir, name).ok();
+        card_error.unwrap_or_else(|| fs_error(err))
+    })
+}
+
+/// Reads the SPI clocks kept by `save_clocks`, `None` before the first save.
+#[cfg(not(feature = "sdio"))]
+pub fn load_clocks(volume: &FlashFs) -> Option<ClockMemory> {
+    let mut bytes = [0; CLOCK_MEMORY_LEN];
+    let count = read_at(volume, CLOCKS_FILE, 0, &mut bytes).ok()?;
+    Some(ClockMemory::from_bytes(&bytes[..count]))
+}
+
+/// Keeps the SPI clocks lowered for each card, so a card that needed a
+/// slower clock starts there again after a reset.
+#[cfg(not(feature = "sdio"))]
+pub fn save_clocks(volume: &FlashFs, memory: &ClockMemory) -> Result<(), FsError> {
+    let path = path(CLOCKS_FILE)?;
+    volume
+        .create_file_and_then(&path, |file| file.write_all(&memory.to_bytes()))
+        .map_err(fs_error)
+}
+
+pub fn remove(volume: &FlashFs, name: &str) -> Result<(), FsError> {
+    let path = path(name)?;
+    volume.remove(&path).map_err(fs_error)
+}
+
+/// The Flash files screen: copying between the card and the volume, running
+/// apps from it and deleting files. `root` is where copies to the card go.
+pub fn manage<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    volume: &FlashFs,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+    ram: &mut Allocator,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    while let Some(action) = ui::select(display, buttons, delay, "Flash files", &ACTIONS)? {
+        match action {
+            0 => copy_in(display, buttons, delay, volume, storage, root)?,
+            1 => {
+                let name = match choose(display, buttons, delay, volume, "Copy to card", any)? {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let lines = [
+                    "Copy this file to the card?",
+                    name.as_str(),
+                    "",
+                    "A copy already there is replaced.",
+                ];
+                if ui::confirm(display, buttons, delay, "Copy to card", &lines)? {
+                    ui::message(display, "Copying.")?;
+                    let copied = copy_to_card(volume, &name, storage, root);
+                    report(display, buttons, delay, copied, "Copied to the card.")?;
+                }
+            }
+            2 => {
+                if let Some(name) =
+                    choose(display, buttons, delay, volume, "Run app", app::is_runnable)?
+                {
+                    run_app(display, buttons, delay, volume, ram, &name)?;
+                }
+            }
+            _ => {
+                let name = match choose(display, buttons, delay, volume, "Delete", any)? {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let lines = [
+                    "Delete this file from flash?",
+                    name.as_str(),
+                    "",
+                    "This cannot be undone.",
+                ];
+                if ui::confirm(display, buttons, delay, "Delete", &lines)? {
+                    let deleted = remove(volume, &name);
+                    report(display, buttons, delay, deleted, "File deleted.")?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Offers the apps on the volume until `inserted` says a card is in. Returns
+/// straight away if there are none.
+pub fn run_until_inserted<F: FnMut() -> bool>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    volume: &FlashFs,
+    ram: &mut Allocator,
+    mut inserted: F,
+) -> Result<(), LoaderError> {
+    loop {
+        let apps = list(volume, app::is_runnable).unwrap_or_default();
+        if apps.is_empty() {
+            return Ok(());
+        }
+        let labels: Vec<&str, MAX_FILES> = apps.iter().map(|app| app.name.as_str()).collect();
+        match ui::select_until(
+            display,
+            buttons,
+            delay,
+            "Apps in flash",
+            &labels,
+            &mut inserted,
+        )? {
+            Some(index) => run_app(display, buttons, delay, volume, ram, &apps[index].name)?,
+            None if inserted() => return Ok(()),
+            None => {}
+        }
+    }
+}
+
+/// Runs the app `name` from the volume and shows how it ended.
+fn run_app(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    volume: &FlashFs,
+    ram: &mut Allocator,
+    name: &str,
+) -> Result<(), LoaderError> {
+    let result = app::launch_flash(display, buttons, volume, ram, name);
+    // The app may have left the panel in any state.
+    display.begin(delay)?;
+    match result {
+        Ok(code) => {
+            let mut text: String<32> = String::new();
+            write!(text, "Exited with {}", code).ok();
+            ui::message(display, &text)?;
+        }
+        Err(LaunchError::Loader(err)) => return Err(err),
+        Err(err) => ui::message(display, err.message())?,
+    }
+    buttons.wait(delay);
+    Ok(())
+}
+
+/// Lets the user pick a file on the card and copies it to the volume.
+fn copy_in<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    volume: &FlashFs,
+    storage: &mut Storage<D, T>,
+    root: &Dir,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let selection =
+        match browser::browse(display, buttons, delay, storage, root, "Copy to flash", any)? {
+            Some(selection) => selection,
+            None => return Ok(()),
+        };
+    let dir = selection.dir.as_ref().unwrap_or(root);
+    let lines = [
+        "Copy this file to flash?",
+        selection.entry.label.as_str(),
+        "",
+        "A copy already there is replaced.",
+    ];
+    let copied = if ui::confirm(display, buttons, delay, "Copy to flash", &lines)? {
+        ui::message(display, "Copying.")?;
+        Some(copy_from_card(volume, storage, dir, &selection.entry))
+    } else {
+        None
+    };
+    if let Some(dir) = selection.dir {
+        storage.close_dir(dir);
+    }
+    match copied {
+        Some(copied) => report(display, buttons, delay, copied, "Copied to flash."),
+        None => Ok(()),
+    }
+}
+
+/// Lets the user pick a file on the volume whose extension `filter` takes.
+fn choose(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    volume: &FlashFs,
+    title: &str,
+    filter: fn(&str) -> bool,
+) -> Result<Option<Line>, LoaderError> {
+    let entries = list(volume, filter)?;
+    if entries.is_empty() {
+        ui::message(display, "No such files in flash.")?;
+        buttons.wait(delay);
+        return Ok(None);
+    }
+    let labels: Vec<&str, MAX_FILES> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    let selected = ui::select(display, buttons, delay, title, &labels)?;
+    Ok(selected.map(|index| entries[index].name.clone()))
+}
+
+/// Files are in the root, so a name is its own path. Names are kept short
+/// enough to list.
+fn path(name: &str) -> Result<PathBuf, FsError> {
+    if name.is_empty() || name.len() > ui::COLUMNS || name.contains(['/', '\0']) {
+        return Err(FsError::Invalid);
+    }
+    Ok(PathBuf::from(name))
+}
+
+fn fs_error(err: io::Error) -> FsError {
+    match err {
+        io::Error::NoSuchEntry => FsError::NotFound,
+        io::Error::EntryAlreadyExisted => FsError::Exists,
+        io::Error::NoSpace => FsError::Full,
+        io::Error::Io | io::Error::Corruption => FsError::Io,
+        _ => FsError::Invalid,
+    }
+}
+
+fn any(_extension: &str) -> bool {
+    true
+}
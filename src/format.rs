@@ -0,0 +1,235 @@
+//! Formats a card as one FAT32 volume: an MBR with a single partition, the
+//! boot sector with its FSInfo and backups, both FATs and the root
+//! directory holding the volume label.
+//!
+//! The volume starts 4 MiB in and its clusters are aligned to that, which is
+//! what the SD association formatter does too. Sizes follow the usual FAT32
+//! defaults for the card size.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+use crate::fat::ENTRY_SIZE;
+
+/// First block of the volume.
+pub const VOLUME_START: u32 = 8192;
+/// FAT32 needs at least this many clusters, fewer would make it FAT16.
+pub const MIN_CLUSTERS: u32 = 65_525;
+
+const RESERVED_BLOCKS: u32 = 32;
+const FATS: u32 = 2;
+const ROOT_CLUSTER: u32 = 2;
+const FSINFO_BLOCK: u32 = 1;
+const BACKUP_BOOT_BLOCK: u32 = 6;
+const MBR_TYPE_FAT32_LBA: u8 = 0x0C;
+const MEDIA_FIXED: u8 = 0xF8;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const ATTR_VOLUME_ID: u8 = 0x08;
+/// Blocks written with one call.
+const BATCH_BLOCKS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError<E> {
+    /// The card cannot hold enough clusters for FAT32.
+    TooSmall,
+    Device(E),
+}
+
+impl<E> From<E> for FormatError<E> {
+    fn from(err: E) -> Self {
+        FormatError::Device(err)
+    }
+}
+
+/// Where everything goes on a card of a given size. Blocks are relative to
+/// the start of the volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub blocks: u32,
+    pub blocks_per_cluster: u32,
+    pub reserved: u32,
+    pub fat_blocks: u32,
+    pub clusters: u32,
+}
+
+impl Geometry {
+    /// `None` if the card is too small for FAT32.
+    pub fn new(card_blocks: u32) -> Option<Self> {
+        let blocks = card_blocks.checked_sub(VOLUME_START)?;
+        let blocks_per_cluster = cluster_blocks(card_blocks);
+        // From the FAT specification, a slight overestimate.
+        let per_fat_block = 128 * blocks_per_cluster + FATS / 2;
+        let fat_blocks = blocks.checked_sub(RESERVED_BLOCKS)?.div_ceil(per_fat_block);
+        // Grows the reserved area until the first cluster is aligned.
+        let used = RESERVED_BLOCKS + FATS * fat_blocks;
+        let reserved =
+            RESERVED_BLOCKS + (blocks_per_cluster - used % blocks_per_cluster) % blocks_per_cluster;
+        let clusters = blocks.checked_sub(reserved + FATS * fat_blocks)? / blocks_per_cluster;
+        (clusters >= MIN_CLUSTERS).then_some(Self {
+            blocks,
+            blocks_per_cluster,
+            reserved,
+            fat_blocks,
+            clusters,
+        })
+    }
+
+    pub fn data_start(&self) -> u32 {
+        self.reserved + FATS * self.fat_blocks
+    }
+
+    /// Everything `format` writes, for progress reports: the FATs, the root
+    /// directory and eight blocks of MBR, boot sectors and FSInfo.
+    pub fn blocks_written(&self) -> u32 {
+        FATS * self.fat_blocks + self.blocks_per_cluster + 8
+    }
+}
+
+/// Cluster sizes Windows picks for FAT32.
+fn cluster_blocks(card_blocks: u32) -> u32 {
+    const MIB: u32 = 1024 * 1024 / Block::LEN_U32;
+    match card_blocks {
+        blocks if blocks <= 256 * MIB => 1,
+        blocks if blocks <= 8 * 1024 * MIB => 8,
+        blocks if blocks <= 16 * 1024 * MIB => 16,
+        blocks if blocks <= 32 * 1024 * MIB => 32,
+        _ => 64,
+    }
+}
+
+/// Formats the whole card. `label` is the 11-byte label padded with spaces
+/// and `serial` the volume serial number. `progress` is called with the
+/// blocks written so far, out of `Geometry::blocks_written`.
+///
+/// The MBR and the boot sector are cleared first and written last, so a
+/// card that fails half way is not taken for a valid volume.
+pub fn format<D, F>(
+    device: &D,
+    label: &[u8; 11],
+    serial: u32,
+    mut progress: F,
+) -> Result<Geometry, FormatError<D::Error>>
+where
+    D: BlockDevice,
+    F: FnMut(u32),
+{
+    let geometry = Geometry::new(device.num_blocks()?.0).ok_or(FormatError::TooSmall)?;
+    let mut batch: [Block; BATCH_BLOCKS] = core::array::from_fn(|_| Block::new());
+    let mut written = 0;
+
+    // No longer a card with volumes from here on. A GPT header left behind
+    // would still be found by some readers.
+    for block in [0, 1, VOLUME_START] {
+        device.write(&batch[..1], BlockIdx(block))?;
+        written += 1;
+    }
+
+    for fat in 0..FATS {
+        let start = VOLUME_START + geometry.reserved + fat * geometry.fat_blocks;
+        let mut offset = 0;
+        while offset < geometry.fat_blocks {
+            let count = (geometry.fat_blocks - offset).min(BATCH_BLOCKS as u32);
+            for block in batch.iter_mut() {
+                block.contents.fill(0);
+            }
+            if offset == 0 {
+                let entries = [MEDIA_FIXED as u32 | 0x0FFF_FF00, END_OF_CHAIN, END_OF_CHAIN];
+                for (i, entry) in entries.iter().enumerate() {
+                    put_u32(&mut batch[0].contents, i * 4, *entry);
+                }
+            }
+            device.write(&batch[..count as usize], BlockIdx(start + offset))?;
+            offset += count;
+            written += count;
+            progress(written);
+        }
+    }
+
+    // The root directory holds nothing but the label.
+    let root = VOLUME_START + geometry.data_start();
+    batch[0].contents.fill(0);
+    let entry = &mut batch[0].contents[..ENTRY_SIZE];
+    entry[..11].copy_from_slice(label);
+    entry[11] = ATTR_VOLUME_ID;
+    device.write(&batch[..1], BlockIdx(root))?;
+    batch[0].contents.fill(0);
+    for offset in 1..geometry.blocks_per_cluster {
+        device.write(&batch[..1], BlockIdx(root + offset))?;
+    }
+    written += geometry.blocks_per_cluster;
+    progress(written);
+
+    // FSInfo and the backup copies, then the boot sector itself.
+    let boot = boot_sector(&geometry, label, serial);
+    let fsinfo = fsinfo(&geometry);
+    for (block, offset) in [
+        (&fsinfo, FSINFO_BLOCK),
+        (&boot, BACKUP_BOOT_BLOCK),
+        (&fsinfo, BACKUP_BOOT_BLOCK + FSINFO_BLOCK),
+        (&boot, 0),
+    ] {
+        batch[0].contents.copy_from_slice(block);
+        device.write(&batch[..1], BlockIdx(VOLUME_START + offset))?;
+    }
+    batch[0].contents.copy_from_slice(&mbr(&geometry));
+    device.write(&batch[..1], BlockIdx(0))?;
+    progress(written + 5);
+    Ok(geometry)
+}
+
+fn mbr(geometry: &Geometry) -> [u8; Block::LEN] {
+    let mut mbr = [0; Block::LEN];
+    let entry = &mut mbr[446..462];
+    // Addressed by LBA only, the CHS fields say so.
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = MBR_TYPE_FAT32_LBA;
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    put_u32(entry, 8, VOLUME_START);
+    put_u32(entry, 12, geometry.blocks);
+    mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+    mbr
+}
+
+fn boot_sector(geometry: &Geometry, label: &[u8; 11], serial: u32) -> [u8; Block::LEN] {
+    let mut boot = [0; Block::LEN];
+    boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"MSWIN4.1");
+    put_u16(&mut boot, 11, Block::LEN as u16);
+    boot[13] = geometry.blocks_per_cluster as u8;
+    put_u16(&mut boot, 14, geometry.reserved as u16);
+    boot[16] = FATS as u8;
+    boot[21] = MEDIA_FIXED;
+    put_u16(&mut boot, 24, 63);
+    put_u16(&mut boot, 26, 255);
+    put_u32(&mut boot, 28, VOLUME_START);
+    put_u32(&mut boot, 32, geometry.blocks);
+    put_u32(&mut boot, 36, geometry.fat_blocks);
+    put_u32(&mut boot, 44, ROOT_CLUSTER);
+    put_u16(&mut boot, 48, FSINFO_BLOCK as u16);
+    put_u16(&mut boot, 50, BACKUP_BOOT_BLOCK as u16);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    put_u32(&mut boot, 67, serial);
+    boot[71..82].copy_from_slice(label);
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510..].copy_from_slice(&[0x55, 0xAA]);
+    boot
+}
+
+fn fsinfo(geometry: &Geometry) -> [u8; Block::LEN] {
+    let mut info = [0; Block::LEN];
+    put_u32(&mut info, 0, 0x4161_5252);
+    put_u32(&mut info, 484, 0x6141_7272);
+    // Only the root directory is in use.
+    put_u32(&mut info, 488, geometry.clusters - 1);
+    put_u32(&mut info, 492, ROOT_CLUSTER + 1);
+    put_u32(&mut info, 508, 0xAA55_0000);
+    info
+}
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
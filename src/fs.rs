@@ -0,0 +1,770 @@
+//! Files on the image volume, which is either FAT through `embedded_sdmmc`
+//! or exFAT through our own reader.
+
+use core::fmt::Write;
+
+use embedded_sdmmc::{
+    filesystem::Mode, Block, BlockDevice, BlockIdx, Controller, Directory, ShortFileName,
+    TimeSource, Timestamp, VolumeIdx,
+};
+use heapless::{String, Vec};
+
+use crate::{
+    exfat,
+    fat::{self, Layout},
+    fsck::{self, Chain, CheckError},
+    lfn::{self, Decoder, Step},
+    partition::{FsKind, Partition},
+    ui::{self, Line},
+};
+
+pub const MAX_ENTRIES: usize = 64;
+/// A long name needs at most 20 entries, so at most two blocks before the
+/// one holding the short name entry.
+const MAX_LFN_BLOCKS: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FsError {
+    NotFound,
+    Io,
+    Invalid,
+    /// The name is already taken.
+    Exists,
+    Full,
+    /// Only FAT volumes can be changed.
+    ReadOnly,
+    /// An exFAT file of 4 GiB or more, file offsets are 32 bits.
+    TooLarge,
+}
+
+/// There is only ever one of these and the heap belongs to WASM apps, so the
+/// exFAT block cache is not boxed.
+#[allow(clippy::large_enum_variant)]
+pub enum Volume {
+    Fat(embedded_sdmmc::Volume, Layout),
+    ExFat(exfat::Volume),
+}
+
+pub enum Dir {
+    Fat(Directory),
+    ExFat(exfat::Handle),
+}
+
+pub enum File {
+    Fat(embedded_sdmmc::File),
+    ExFat(exfat::File),
+}
+
+impl File {
+    pub fn length(&self) -> u32 {
+        match self {
+            File::Fat(file) => file.length(),
+            // `open_exfat` refuses anything longer.
+            File::ExFat(file) => file.length() as u32,
+        }
+    }
+
+    pub fn eof(&self) -> bool {
+        match self {
+            File::Fat(file) => file.eof(),
+            File::ExFat(file) => file.eof(),
+        }
+    }
+
+    pub fn seek_from_start(&mut self, offset: u32) -> Result<(), FsError> {
+        match self {
+            File::Fat(file) => file.seek_from_start(offset),
+            File::ExFat(file) => file.seek_from_start(offset as u64),
+        }
+        .map_err(|_| FsError::Invalid)
+    }
+}
+
+/// Files of 4 GiB and more are refused instead of being cut short at 32 bits.
+fn open_exfat(volume: &exfat::Volume, handle: &exfat::Handle) -> Result<File, FsError> {
+    let file = volume.open(handle);
+    if file.length() > u32::MAX as u64 {
+        return Err(FsError::TooLarge);
+    }
+    Ok(File::ExFat(file))
+}
+
+#[derive(Clone)]
+enum Id {
+    Fat(ShortFileName),
+    ExFat(exfat::Handle),
+}
+
+#[derive(Clone)]
+pub struct Entry {
+    /// Long name if there is one, the 8.3 name otherwise.
+    pub label: Line,
+    /// Upper case, empty if the name has none or it is longer than this.
+    pub extension: String<4>,
+    pub is_dir: bool,
+    id: Id,
+}
+
+impl Entry {
+    /// The 8.3 name, `None` on exFAT.
+    pub fn short_name(&self) -> Option<String<12>> {
+        match &self.id {
+            Id::Fat(name) => Some(short_str(name)),
+            Id::ExFat(_) => None,
+        }
+    }
+}
+
+/// Mounts the file system on `partition`.
+pub fn mount<D, T>(
+    controller: &mut Controller<D, T>,
+    partition: &Partition,
+) -> Result<Volume, FsError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    match partition.fs {
+        FsKind::ExFat => exfat::Volume::mount(controller.device(), partition.start)
+            .map(Volume::ExFat)
+            .map_err(|_| FsError::Invalid),
+        _ => {
+            let volume = controller
+                .get_volume(VolumeIdx(partition.index as usize))
+                .map_err(|_| FsError::Invalid)?;
+            let layout =
+                Layout::read(controller.device(), partition.start).map_err(|_| FsError::Io)?;
+            Ok(Volume::Fat(volume, layout))
+        }
+    }
+}
+
+pub struct Storage<'a, D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    pub controller: &'a mut Controller<D, T>,
+    pub volume: &'a mut Volume,
+}
+
+impl<'a, D, T> Storage<'a, D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    pub fn open_root(&mut self) -> Result<Dir, FsError> {
+        match self.volume {
+            Volume::Fat(volume, _) => self
+                .controller
+                .open_root_dir(volume)
+                .map(Dir::Fat)
+                .map_err(|_| FsError::Io),
+            Volume::ExFat(volume) => Ok(Dir::ExFat(volume.root_dir())),
+        }
+    }
+
+    /// Opens the subdirectory `entry` of `parent`.
+    pub fn open_dir(&mut self, parent: &Dir, entry: &Entry) -> Result<Dir, FsError> {
+        match (&mut *self.volume, parent, &entry.id) {
+            (Volume::Fat(volume, _), Dir::Fat(parent), Id::Fat(name)) => self
+                .controller
+                .open_dir(volume, parent, &short_str(name))
+                .map(Dir::Fat)
+                .map_err(|_| FsError::NotFound),
+            (Volume::ExFat(_), Dir::ExFat(_), Id::ExFat(handle)) => Ok(Dir::ExFat(*handle)),
+            _ => Err(FsError::Invalid),
+        }
+    }
+
+    pub fn close_dir(&mut self, dir: Dir) {
+        if let (Volume::Fat(volume, _), Dir::Fat(dir)) = (&*self.volume, dir) {
+            self.controller.close_dir(volume, dir);
+        }
+    }
+
+    pub fn open(&mut self, dir: &Dir, name: &str) -> Result<File, FsError> {
+        match (&mut *self.volume, dir) {
+            (Volume::Fat(volume, _), Dir::Fat(dir)) => self
+                .controller
+                .open_file_in_dir(volume, dir, name, Mode::ReadOnly)
+                .map(File::Fat)
+                .map_err(|_| FsError::NotFound),
+            (Volume::ExFat(volume), Dir::ExFat(dir)) => {
+                let entry = volume
+                    .find(self.controller.device(), dir, name)
+                    .map_err(|_| FsError::NotFound)?;
+                if entry.is_dir() {
+                    return Err(FsError::NotFound);
+                }
+                open_exfat(volume, &entry.handle)
+            }
+            _ => Err(FsError::Invalid),
+        }
+    }
+
+    /// Opens a file listed by `list`.
+    pub fn open_entry(&mut self, dir: &Dir, entry: &Entry) -> Result<File, FsError> {
+        match (&*self.volume, &entry.id) {
+            (Volume::Fat(..), Id::Fat(name)) => self.open(dir, &short_str(name)),
+            (Volume::ExFat(volume), Id::ExFat(handle)) => open_exfat(volume, handle),
+            _ => Err(FsError::Invalid),
+        }
+    }
+
+    pub fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        match (&mut *self.volume, file) {
+            (Volume::Fat(volume, _), File::Fat(file)) => self
+                .controller
+                .read(volume, file, buf)
+                .map_err(|_| FsError::Io),
+            (Volume::ExFat(volume), File::ExFat(file)) => volume
+                .read(self.controller.device(), file, buf)
+                .map_err(|_| FsError::Io),
+            _ => Err(FsError::Invalid),
+        }
+    }
+
+    pub fn close(&mut self, file: File) {
+        if let (Volume::Fat(volume, _), File::Fat(file)) = (&*self.volume, file) {
+            self.controller.close_file(volume, file).ok();
+        }
+    }
+
+    /// Calls `f` with the modification time of everything in `dir`.
+    pub fn for_each_modified<F: FnMut(&Timestamp)>(&mut self, dir: &Dir, mut f: F) {
+        match (&mut *self.volume, dir) {
+            (Volume::Fat(volume, _), Dir::Fat(dir)) => self
+                .controller
+                .iterate_dir(volume, dir, |entry| f(&entry.mtime))
+                .ok(),
+            (Volume::ExFat(volume), Dir::ExFat(dir)) => volume
+                .iterate_dir(self.controller.device(), dir, |entry| {
+                    f(&from_fat(entry.modified))
+                })
+                .ok(),
+            _ => None,
+        };
+    }
+
+    /// Subdirectories and the files whose extension `filter` accepts,
+    /// directories first.
+    pub fn list(&mut self, dir: &Dir, filter: fn(&str) -> bool) -> Vec<Entry, MAX_ENTRIES> {
+        let mut entries = match (&mut *self.volume, dir) {
+            (Volume::Fat(volume, _), Dir::Fat(dir)) => {
+                list_fat(self.controller, volume, dir, filter)
+            }
+            (Volume::ExFat(volume), Dir::ExFat(dir)) => {
+                let mut entries = Vec::new();
+                volume
+                    .iterate_dir(self.controller.device(), dir, |entry| {
+                        let is_dir = entry.is_dir();
+                        let extension = extension(&entry.name);
+                        if entry.is_hidden() || !(is_dir || filter(&extension)) {
+                            return;
+                        }
+                        let mut label = Line::new();
+                        for c in entry.name.chars().take(ui::COLUMNS) {
+                            label.push(c).ok();
+                        }
+                        let item = Entry {
+                            label,
+                            extension,
+                            is_dir,
+                            id: Id::ExFat(entry.handle),
+                        };
+                        entries.push(item).ok();
+                    })
+                    .ok();
+                entries
+            }
+            _ => Vec::new(),
+        };
+        entries.sort_unstable_by(|a, b| {
+            b.is_dir
+                .cmp(&a.is_dir)
+                .then_with(|| a.label.as_str().cmp(b.label.as_str()))
+        });
+        entries
+    }
+
+    /// Whether the volume can be changed, exFAT is only read.
+    pub fn is_writable(&self) -> bool {
+        matches!(self.volume, Volume::Fat(..))
+    }
+
+    /// Opens the subdirectory called `name` in `parent`.
+    pub fn open_dir_named(&mut self, parent: &Dir, name: &str) -> Result<Dir, FsError> {
+        match (&mut *self.volume, parent) {
+            (Volume::Fat(volume, _), Dir::Fat(parent)) => self
+                .controller
+                .open_dir(volume, parent, name)
+                .map(Dir::Fat)
+                .map_err(|_| FsError::NotFound),
+            (Volume::ExFat(volume), Dir::ExFat(parent)) => {
+                let entry = volume
+                    .find(self.controller.device(), parent, name)
+                    .map_err(|_| FsError::NotFound)?;
+                if !entry.is_dir() {
+                    return Err(FsError::NotFound);
+                }
+                Ok(Dir::ExFat(entry.handle))
+            }
+            _ => Err(FsError::Invalid),
+        }
+    }
+
+    /// Deletes the file `entry` in `dir` along with its long name.
+    pub fn delete(&mut self, dir: &Dir, entry: &Entry) -> Result<(), FsError> {
+        let (volume, dir, name) = match (&mut *self.volume, dir, &entry.id) {
+            (Volume::Fat(volume, _), Dir::Fat(dir), Id::Fat(name)) => (volume, dir, name),
+            _ => return Err(FsError::ReadOnly),
+        };
+        let short = short_bytes(name);
+        let name = short_str(name);
+        let found = self
+            .controller
+            .find_directory_entry(volume, dir, &name)
+            .map_err(write_error)?;
+        self.controller
+            .delete_file_in_dir(volume, dir, &name)
+            .map_err(write_error)?;
+        erase_long_name(
+            self.controller.device(),
+            found.entry_block,
+            found.entry_offset as usize,
+            &short,
+        )
+        .map_err(|_| FsError::Io)
+    }
+
+    /// Gives `entry` in `dir` the 8.3 name `new_name`. A long name it had is
+    /// dropped.
+    pub fn rename(&mut self, dir: &Dir, entry: &Entry, new_name: &str) -> Result<(), FsError> {
+        let (volume, dir, name) = match (&mut *self.volume, dir, &entry.id) {
+            (Volume::Fat(volume, _), Dir::Fat(dir), Id::Fat(name)) => (volume, dir, name),
+            _ => return Err(FsError::ReadOnly),
+        };
+        let short = fat::short_name(new_name).ok_or(FsError::Invalid)?;
+        match self.controller.find_directory_entry(volume, dir, new_name) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(embedded_sdmmc::Error::FileNotFound) => {}
+            Err(err) => return Err(write_error(err)),
+        }
+        let found = self
+            .controller
+            .find_directory_entry(volume, dir, &short_str(name))
+            .map_err(write_error)?;
+
+        let device = self.controller.device();
+        let offset = found.entry_offset as usize;
+        let mut block = [Block::new()];
+        device
+            .read(&mut block, found.entry_block, "rename")
+            .map_err(|_| FsError::Io)?;
+        let mut old = [0; 11];
+        old.copy_from_slice(&block[0].contents[offset..offset + 11]);
+        block[0].contents[offset..offset + 11].copy_from_slice(&short);
+        device
+            .write(&block, found.entry_block)
+            .map_err(|_| FsError::Io)?;
+        erase_long_name(device, found.entry_block, offset, &old).map_err(|_| FsError::Io)
+    }
+
+    /// Creates the empty directory `name` in `parent`.
+    pub fn make_dir(&mut self, parent: &Dir, name: &str) -> Result<(), FsError> {
+        let (volume, layout, parent) = match (&mut *self.volume, parent) {
+            (Volume::Fat(volume, layout), Dir::Fat(parent)) => (volume, *layout, parent),
+            _ => return Err(FsError::ReadOnly),
+        };
+        fat::short_name(name).ok_or(FsError::Invalid)?;
+        match self.controller.find_directory_entry(volume, parent, name) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(embedded_sdmmc::Error::FileNotFound) => {}
+            Err(err) => return Err(write_error(err)),
+        }
+
+        // There is no call for it, so a file of one zeroed cluster is made
+        // and its entry turned into a directory afterwards.
+        let mut file = self
+            .controller
+            .open_file_in_dir(volume, parent, name, Mode::ReadWriteCreate)
+            .map_err(write_error)?;
+        let zeros = [0; Block::LEN];
+        let mut written = Ok(0);
+        for _ in 0..layout.blocks_per_cluster {
+            written = self.controller.write(volume, &mut file, &zeros);
+            if written.is_err() {
+                break;
+            }
+        }
+        self.controller
+            .close_file(volume, file)
+            .map_err(write_error)?;
+        if let Err(err) = written {
+            self.controller
+                .delete_file_in_dir(volume, parent, name)
+                .ok();
+            return Err(write_error(err));
+        }
+
+        // The root has no `.` entry, `..` then points at cluster zero.
+        let mut dot = None;
+        self.controller
+            .iterate_dir(volume, parent, |entry| {
+                if entry.name.base_name() == b"." {
+                    dot = Some((entry.entry_block, entry.entry_offset as usize));
+                }
+            })
+            .map_err(write_error)?;
+        let created = self
+            .controller
+            .find_directory_entry(volume, parent, name)
+            .map_err(write_error)?;
+
+        let device = self.controller.device();
+        let mut block = [Block::new()];
+        let parent_cluster = match dot {
+            Some((idx, offset)) => {
+                device
+                    .read(&mut block, idx, "mkdir")
+                    .map_err(|_| FsError::Io)?;
+                fat::cluster(&block[0].contents[offset..])
+            }
+            None => 0,
+        };
+
+        let offset = created.entry_offset as usize;
+        device
+            .read(&mut block, created.entry_block, "mkdir")
+            .map_err(|_| FsError::Io)?;
+        let mut entry = [0; fat::ENTRY_SIZE];
+        entry.copy_from_slice(&block[0].contents[offset..offset + fat::ENTRY_SIZE]);
+        fat::make_directory(&mut entry);
+        block[0].contents[offset..offset + fat::ENTRY_SIZE].copy_from_slice(&entry);
+        device
+            .write(&block, created.entry_block)
+            .map_err(|_| FsError::Io)?;
+
+        // The rest of the cluster is already zero.
+        block[0].contents.fill(0);
+        fat::dot_entries(&mut block[0].contents, &entry, parent_cluster);
+        device
+            .write(&block, layout.cluster_block(fat::cluster(&entry)))
+            .map_err(|_| FsError::Io)
+    }
+
+    /// Creates the file `name` in `dir` for writing, emptying one that is
+    /// already there.
+    pub fn create(&mut self, dir: &Dir, name: &str) -> Result<File, FsError> {
+        match (&mut *self.volume, dir) {
+            (Volume::Fat(volume, _), Dir::Fat(dir)) => self
+                .controller
+                .open_file_in_dir(volume, dir, name, Mode::ReadWriteCreateOrTruncate)
+                .map(File::Fat)
+                .map_err(write_error),
+            _ => Err(FsError::ReadOnly),
+        }
+    }
+
+    pub fn write(&mut self, file: &mut File, data: &[u8]) -> Result<usize, FsError> {
+        match (&mut *self.volume, file) {
+            (Volume::Fat(volume, _), File::Fat(file)) => self
+                .controller
+                .write(volume, file, data)
+                .map_err(write_error),
+            _ => Err(FsError::ReadOnly),
+        }
+    }
+
+    /// Deletes the file `name` in `dir`. Only meant for files the loader
+    /// wrote itself, a long name is left behind.
+    pub fn remove(&mut self, dir: &Dir, name: &str) -> Result<(), FsError> {
+        match (&mut *self.volume, dir) {
+            (Volume::Fat(volume, _), Dir::Fat(dir)) => self
+                .controller
+                .delete_file_in_dir(volume, dir, name)
+                .map_err(write_error),
+            _ => Err(FsError::ReadOnly),
+        }
+    }
+
+    /// How the cluster chain of the file `entry` in `dir` compares with its
+    /// size. exFAT files are not checked and always come back intact.
+    pub fn check_chain(&mut self, dir: &Dir, entry: &Entry) -> Result<Chain, FsError> {
+        let (volume, layout, dir, name) = match (&*self.volume, dir, &entry.id) {
+            (Volume::Fat(volume, layout), Dir::Fat(dir), Id::Fat(name)) => {
+                (volume, *layout, dir, short_str(name))
+            }
+            _ => return Ok(Chain::Intact),
+        };
+        let found = self
+            .controller
+            .find_directory_entry(volume, dir, &name)
+            .map_err(write_error)?;
+        let device = self.controller.device();
+        let mut block = [Block::new()];
+        device
+            .read(&mut block, found.entry_block, "fsck")
+            .map_err(|_| FsError::Io)?;
+        let fat = fsck::Fat::read(device, layout.start).map_err(|err| match err {
+            CheckError::Unsupported => FsError::Invalid,
+            CheckError::Device(_) => FsError::Io,
+        })?;
+        let offset = found.entry_offset as usize;
+        fsck::chain(
+            device,
+            &fat,
+            &block[0].contents[offset..offset + fat::ENTRY_SIZE],
+        )
+        .map_err(|_| FsError::Io)
+    }
+
+    /// Copies the file `entry` in `dir` to `target` under its 8.3 name,
+    /// replacing a file of that name there.
+    pub fn copy(&mut self, dir: &Dir, entry: &Entry, target: &Dir) -> Result<(), FsError> {
+        let (volume, dir, target, name) = match (&mut *self.volume, dir, target, &entry.id) {
+            (Volume::Fat(volume, _), Dir::Fat(dir), Dir::Fat(target), Id::Fat(name)) => {
+                (volume, dir, target, short_str(name))
+            }
+            _ => return Err(FsError::ReadOnly),
+        };
+        let mut source = self
+            .controller
+            .open_file_in_dir(volume, dir, &name, Mode::ReadOnly)
+            .map_err(write_error)?;
+        let mut copy = match self.controller.open_file_in_dir(
+            volume,
+            target,
+            &name,
+            Mode::ReadWriteCreateOrTruncate,
+        ) {
+            Ok(copy) => copy,
+            Err(err) => {
+                self.controller.close_file(volume, source).ok();
+                return Err(write_error(err));
+            }
+        };
+
+        let mut buf = [0; Block::LEN];
+        let copied = loop {
+            let count = match self.controller.read(volume, &mut source, &mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(count) => count,
+                Err(err) => break Err(write_error(err)),
+            };
+            if let Err(err) = self.controller.write(volume, &mut copy, &buf[..count]) {
+                break Err(write_error(err));
+            }
+        };
+        self.controller.close_file(volume, source).ok();
+        let closed = self
+            .controller
+            .close_file(volume, copy)
+            .map_err(write_error);
+        if copied.is_err() {
+            // Half a copy would pass for a backup.
+            self.controller
+                .delete_file_in_dir(volume, target, &name)
+                .ok();
+        }
+        copied.and(closed)
+    }
+}
+
+fn short_str(name: &ShortFileName) -> String<12> {
+    let mut text = String::new();
+    write!(text, "{}", name).unwrap();
+    text
+}
+
+/// The name as stored in the directory entry, padded with spaces.
+fn short_bytes(name: &ShortFileName) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    for (byte, &c) in short.iter_mut().zip(name.base_name()) {
+        *byte = c;
+    }
+    for (byte, &c) in short[8..].iter_mut().zip(name.extension()) {
+        *byte = c;
+    }
+    short
+}
+
+fn write_error<E: core::fmt::Debug>(err: embedded_sdmmc::Error<E>) -> FsError {
+    match err {
+        embedded_sdmmc::Error::DeviceError(_) => FsError::Io,
+        embedded_sdmmc::Error::FileNotFound => FsError::NotFound,
+        embedded_sdmmc::Error::FileAlreadyExists => FsError::Exists,
+        embedded_sdmmc::Error::NotEnoughSpace => FsError::Full,
+        _ => FsError::Invalid,
+    }
+}
+
+fn list_fat<D, T>(
+    controller: &mut Controller<D, T>,
+    volume: &embedded_sdmmc::Volume,
+    dir: &Directory,
+    filter: fn(&str) -> bool,
+) -> Vec<Entry, MAX_ENTRIES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut found: Vec<(Entry, BlockIdx, u32), MAX_ENTRIES> = Vec::new();
+    controller
+        .iterate_dir(volume, dir, |entry| {
+            let is_dir = entry.attributes.is_directory();
+            let hidden = entry.attributes.is_hidden() || entry.attributes.is_volume();
+            let dot = entry.name.base_name().first() == Some(&b'.');
+            let mut extension = String::new();
+            for &c in entry.name.extension() {
+                extension.push(c as char).ok();
+            }
+            if hidden || dot || !(is_dir || filter(&extension)) {
+                return;
+            }
+            let mut label = Line::new();
+            write!(label, "{}", entry.name).unwrap();
+            let item = Entry {
+                label,
+                extension,
+                is_dir,
+                id: Id::Fat(entry.name.clone()),
+            };
+            found
+                .push((item, entry.entry_block, entry.entry_offset))
+                .ok();
+        })
+        .ok();
+
+    let mut cache = BlockCache::new();
+    let mut entries = Vec::new();
+    for (mut entry, block, offset) in found {
+        if let Some(label) = long_name(controller.device(), &mut cache, block, offset as usize) {
+            entry.label = label;
+        }
+        entries.push(entry).ok();
+    }
+    entries
+}
+
+/// Upper case extension of `name`, empty if it has none or a longer one.
+pub fn extension(name: &str) -> String<4> {
+    let mut extension = String::new();
+    if let Some((_, ext)) = name.rsplit_once('.') {
+        for c in ext.chars() {
+            if extension.push(c.to_ascii_uppercase()).is_err() {
+                return String::new();
+            }
+        }
+    }
+    extension
+}
+
+/// Converts a FAT date in the upper and time in the lower half.
+fn from_fat(stamp: u32) -> Timestamp {
+    let date = (stamp >> 16) as u16;
+    let time = stamp as u16;
+    Timestamp {
+        year_since_1970: ((date >> 9) + 10) as u8,
+        zero_indexed_month: (((date >> 5) & 0x0F) as u8).saturating_sub(1),
+        zero_indexed_day: ((date & 0x1F) as u8).saturating_sub(1),
+        hours: (time >> 11) as u8,
+        minutes: ((time >> 5) & 0x3F) as u8,
+        seconds: ((time & 0x1F) * 2) as u8,
+    }
+}
+
+struct BlockCache {
+    idx: Option<BlockIdx>,
+    block: [Block; 1],
+}
+
+impl BlockCache {
+    fn new() -> Self {
+        Self {
+            idx: None,
+            block: [Block::new()],
+        }
+    }
+
+    fn read<D: BlockDevice>(&mut self, device: &D, idx: BlockIdx) -> Option<&[u8]> {
+        if self.idx != Some(idx) {
+            self.idx = None;
+            device.read(&mut self.block, idx, "lfn").ok()?;
+            self.idx = Some(idx);
+        }
+        Some(&self.block[0].contents[..])
+    }
+}
+
+/// Reads the long name belonging to the short name entry at `offset` in
+/// `block`. The checksum in every long name entry guards against reading
+/// unrelated entries when the name crosses into a block of another cluster.
+fn long_name<D: BlockDevice>(
+    device: &D,
+    cache: &mut BlockCache,
+    block: BlockIdx,
+    mut offset: usize,
+) -> Option<Line> {
+    let data = cache.read(device, block)?;
+    let mut decoder: Decoder<{ ui::COLUMNS }> = Decoder::new(&data[offset..offset + 11]);
+
+    let mut idx = block;
+    for _ in 0..MAX_LFN_BLOCKS {
+        let data = cache.read(device, idx)?;
+        while offset >= lfn::ENTRY_SIZE {
+            offset -= lfn::ENTRY_SIZE;
+            match decoder.push(&data[offset..offset + lfn::ENTRY_SIZE]) {
+                Step::More => {}
+                Step::Done => return Some(decoder.into_name()),
+                Step::Invalid => return None,
+            }
+        }
+        idx = BlockIdx(idx.0.checked_sub(1)?);
+        offset = Block::LEN;
+    }
+    None
+}
+
+/// Marks the long name entries in front of the short name entry at `offset`
+/// in `block` as deleted. `short` is the name field the entries were made
+/// for, which may already have been overwritten.
+fn erase_long_name<D: BlockDevice>(
+    device: &D,
+    block: BlockIdx,
+    mut offset: usize,
+    short: &[u8],
+) -> Result<(), D::Error> {
+    let mut decoder: Decoder<{ ui::COLUMNS }> = Decoder::new(short);
+    let mut data = [Block::new()];
+
+    let mut idx = block;
+    for _ in 0..MAX_LFN_BLOCKS {
+        device.read(&mut data, idx, "lfn")?;
+        let mut step = Step::More;
+        let mut changed = false;
+        while offset >= lfn::ENTRY_SIZE {
+            offset -= lfn::ENTRY_SIZE;
+            let entry = &mut data[0].contents[offset..offset + lfn::ENTRY_SIZE];
+            step = decoder.push(entry);
+            if let Step::Invalid = step {
+                break;
+            }
+            entry[0] = fat::DELETED;
+            changed = true;
+            if let Step::Done = step {
+                break;
+            }
+        }
+        if changed {
+            device.write(&data, idx)?;
+        }
+        match (step, idx.0.checked_sub(1)) {
+            (Step::More, Some(previous)) => idx = BlockIdx(previous),
+            _ => return Ok(()),
+        }
+        offset = Block::LEN;
+    }
+    Ok(())
+}
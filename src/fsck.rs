@@ -0,0 +1,507 @@
+//! Read-only check of a FAT volume, for cards pulled out during a write and
+//! images that were only half copied.
+//!
+//! The check compares the FAT copies, follows the cluster chain of every
+//! file and directory and compares it with the size in the directory entry,
+//! and marks every cluster it passes in a bitmap to find the ones used twice.
+//! A bitmap too small for the volume covers it in windows, with one walk of
+//! the directory tree for each.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+use heapless::{String, Vec};
+
+use crate::fat::{self, ENTRY_SIZE};
+
+/// Bytes of app RAM the loader lends the bitmap, one bit for each cluster.
+pub const BITMAP_BYTES: usize = 64 * 1024;
+/// Files named in the report.
+pub const MAX_DAMAGED: usize = 8;
+
+/// Directories waiting to be read. Deeper or wider trees are only partly
+/// checked and the report says so.
+const MAX_PENDING: usize = 64;
+/// A directory holds at most 65536 entries.
+const MAX_DIR_BYTES: u32 = 65536 * ENTRY_SIZE as u32;
+/// FAT blocks compared with one read.
+const BATCH_BLOCKS: usize = 4;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckError<E> {
+    /// Not a FAT16 or FAT32 volume.
+    Unsupported,
+    Device(E),
+}
+
+impl<E> From<E> for CheckError<E> {
+    fn from(err: E) -> Self {
+        CheckError::Device(err)
+    }
+}
+
+/// How a cluster chain compares with the size of its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chain {
+    Intact,
+    /// Ends before the file does, as after an interrupted copy.
+    Short,
+    /// Has more clusters than the file needs.
+    Long,
+    /// Runs into a free, bad or nonexistent cluster, or around in a loop.
+    Broken,
+}
+
+/// Where the parts of a FAT volume are, read from its boot sector.
+#[derive(Debug, Clone, Copy)]
+pub struct Fat {
+    /// Absolute block of the first FAT.
+    pub fat_start: u32,
+    pub fat_blocks: u32,
+    pub fats: u32,
+    /// The fixed root directory of FAT16, empty on FAT32.
+    pub root_start: u32,
+    pub root_blocks: u32,
+    /// First cluster of the FAT32 root directory, zero on FAT16.
+    pub root_cluster: u32,
+    pub data_start: u32,
+    pub blocks_per_cluster: u32,
+    pub clusters: u32,
+    pub fat32: bool,
+}
+
+impl Fat {
+    /// Reads the boot sector of the volume starting at block `start`.
+    pub fn read<D: BlockDevice>(device: &D, start: u32) -> Result<Self, CheckError<D::Error>> {
+        let mut block = [Block::new()];
+        device.read(&mut block, BlockIdx(start), "fsck")?;
+        let boot = &block[0].contents;
+
+        let blocks_per_cluster = boot[13] as u32;
+        let reserved = u16_at(boot, 14) as u32;
+        let fats = boot[16] as u32;
+        let valid = u16_at(boot, 11) == Block::LEN as u16
+            && blocks_per_cluster.is_power_of_two()
+            && reserved > 0
+            && fats > 0
+            && boot[510..] == [0x55, 0xAA];
+        if !valid {
+            return Err(CheckError::Unsupported);
+        }
+
+        let root_blocks = (u16_at(boot, 17) as u32 * ENTRY_SIZE as u32).div_ceil(Block::LEN_U32);
+        let fat_blocks = match u16_at(boot, 22) {
+            0 => u32_at(boot, 36),
+            size => size as u32,
+        };
+        let total = match u16_at(boot, 19) {
+            0 => u32_at(boot, 32),
+            total => total as u32,
+        };
+        // A damaged boot sector can hold anything, sums that overflow are
+        // refused like any other nonsense in it.
+        let data = fats
+            .checked_mul(fat_blocks)
+            .and_then(|all_fats| all_fats.checked_add(reserved))
+            .and_then(|blocks| blocks.checked_add(root_blocks));
+        let (Some(data), Some(_)) = (data, start.checked_add(total)) else {
+            return Err(CheckError::Unsupported);
+        };
+        let clusters = total.checked_sub(data).ok_or(CheckError::Unsupported)? / blocks_per_cluster;
+        // The type follows from the cluster count alone.
+        let fat32 = match clusters {
+            0..=4084 => return Err(CheckError::Unsupported),
+            4085..=65524 => false,
+            _ => true,
+        };
+        let entries = fat_blocks
+            .checked_mul(Block::LEN_U32)
+            .ok_or(CheckError::Unsupported)?
+            / if fat32 { 4 } else { 2 };
+        // All of the volume comes before `start + total`, which did not
+        // overflow.
+        Ok(Self {
+            fat_start: start + reserved,
+            fat_blocks,
+            fats,
+            root_start: start + reserved + fats * fat_blocks,
+            root_blocks,
+            root_cluster: if fat32 { u32_at(boot, 44) } else { 0 },
+            data_start: start + data,
+            blocks_per_cluster,
+            clusters: clusters.min(entries.saturating_sub(2)),
+            fat32,
+        })
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.blocks_per_cluster * Block::LEN_U32
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// FAT blocks that differ between the first FAT and a copy.
+    pub fat_mismatches: u32,
+    pub files: u32,
+    pub dirs: u32,
+    /// Files and directories whose chain is not `Chain::Intact`.
+    pub bad_chains: u32,
+    /// Clusters in more than one chain, or twice in the same one.
+    pub cross_linked: u32,
+    /// Directories left out because too many were waiting.
+    pub skipped_dirs: u32,
+    /// 8.3 names of the first damaged files and directories.
+    pub damaged: Vec<String<12>, MAX_DAMAGED>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.fat_mismatches == 0 && self.bad_chains == 0 && self.cross_linked == 0
+    }
+
+    fn name(&mut self, entry: &[u8]) {
+        let name = display_name(entry);
+        if !self.damaged.contains(&name) {
+            self.damaged.push(name).ok();
+        }
+    }
+}
+
+/// Checks the volume starting at block `start`. `bitmap` is scratch memory
+/// with a bit for every cluster, a smaller one takes more passes.
+pub fn check<D: BlockDevice>(
+    device: &D,
+    start: u32,
+    bitmap: &mut [u8],
+) -> Result<Report, CheckError<D::Error>> {
+    let fat = Fat::read(device, start)?;
+    let mut report = Report {
+        fat_mismatches: compare_fats(device, &fat)?,
+        ..Report::default()
+    };
+
+    let window = (bitmap.len() as u32 * 8).max(1);
+    let mut first = 2;
+    while first < fat.clusters + 2 {
+        let mut walk = Walk {
+            device,
+            fat,
+            cache: FatCache::new(),
+            bitmap: &mut *bitmap,
+            first,
+            report: &mut report,
+            counting: first == 2,
+        };
+        walk.bitmap.fill(0);
+        walk.tree()?;
+        first = first.saturating_add(window);
+    }
+    Ok(report)
+}
+
+/// How the chain of the directory entry `entry` compares with its size, for
+/// a file about to be used.
+pub fn chain<D: BlockDevice>(device: &D, fat: &Fat, entry: &[u8]) -> Result<Chain, D::Error> {
+    let mut report = Report::default();
+    let mut walk = Walk {
+        device,
+        fat: *fat,
+        cache: FatCache::new(),
+        bitmap: &mut [],
+        first: 2,
+        report: &mut report,
+        counting: false,
+    };
+    let (chain, _) = walk.file_chain(entry)?;
+    Ok(chain)
+}
+
+/// Counts the blocks of the first FAT that some copy does not match.
+fn compare_fats<D: BlockDevice>(device: &D, fat: &Fat) -> Result<u32, D::Error> {
+    let mut first: [Block; BATCH_BLOCKS] = core::array::from_fn(|_| Block::new());
+    let mut copy: [Block; BATCH_BLOCKS] = core::array::from_fn(|_| Block::new());
+    let mut mismatches = 0;
+    let mut offset = 0;
+    while offset < fat.fat_blocks {
+        let count = (fat.fat_blocks - offset).min(BATCH_BLOCKS as u32) as usize;
+        device.read(
+            &mut first[..count],
+            BlockIdx(fat.fat_start + offset),
+            "fsck",
+        )?;
+        let mut differs = [false; BATCH_BLOCKS];
+        for index in 1..fat.fats {
+            let start = fat.fat_start + index * fat.fat_blocks + offset;
+            device.read(&mut copy[..count], BlockIdx(start), "fsck")?;
+            for (i, differs) in differs.iter_mut().enumerate().take(count) {
+                *differs |= first[i].contents != copy[i].contents;
+            }
+        }
+        mismatches += differs.iter().filter(|&&differs| differs).count() as u32;
+        offset += count as u32;
+    }
+    Ok(mismatches)
+}
+
+enum Link {
+    Next(u32),
+    End,
+    Free,
+    Bad,
+}
+
+struct FatCache {
+    idx: Option<u32>,
+    block: [Block; 1],
+}
+
+impl FatCache {
+    fn new() -> Self {
+        Self {
+            idx: None,
+            block: [Block::new()],
+        }
+    }
+}
+
+/// One walk of the directory tree, marking the clusters from `first` on
+/// that fit in `bitmap`.
+struct Walk<'a, D: BlockDevice> {
+    device: &'a D,
+    fat: Fat,
+    cache: FatCache,
+    bitmap: &'a mut [u8],
+    first: u32,
+    report: &'a mut Report,
+    /// Only the first walk counts files and chains, the others add to the
+    /// cross-links.
+    counting: bool,
+}
+
+impl<D: BlockDevice> Walk<'_, D> {
+    fn tree(&mut self) -> Result<(), D::Error> {
+        let mut pending: Vec<u32, MAX_PENDING> = Vec::new();
+        if self.fat.fat32 {
+            pending.push(self.fat.root_cluster).ok();
+        } else {
+            for block in self.fat.root_start..self.fat.root_start + self.fat.root_blocks {
+                if !self.dir_block(block, &mut pending)? {
+                    break;
+                }
+            }
+        }
+        while let Some(cluster) = pending.pop() {
+            self.dir(cluster, &mut pending)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the directory starting at `cluster` and follows its chain.
+    fn dir(&mut self, first: u32, pending: &mut Vec<u32, MAX_PENDING>) -> Result<(), D::Error> {
+        let limit = (MAX_DIR_BYTES / self.fat.cluster_bytes()).max(1);
+        let mut cluster = first;
+        let mut reading = true;
+        for _ in 0..limit {
+            if !self.fat.is_cluster(cluster) {
+                return Ok(());
+            }
+            if !self.mark(cluster) && cluster == first {
+                // Already seen, reading it again could go around forever.
+                return Ok(());
+            }
+            if reading {
+                let start = self.fat.data_start + (cluster - 2) * self.fat.blocks_per_cluster;
+                for block in start..start + self.fat.blocks_per_cluster {
+                    reading = self.dir_block(block, pending)?;
+                    if !reading {
+                        break;
+                    }
+                }
+            }
+            match self.link(cluster)? {
+                Link::Next(next) => cluster = next,
+                Link::End | Link::Free | Link::Bad => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the entries in one directory block. `false` once the end of
+    /// the directory is reached.
+    fn dir_block(
+        &mut self,
+        block: u32,
+        pending: &mut Vec<u32, MAX_PENDING>,
+    ) -> Result<bool, D::Error> {
+        let mut data = [Block::new()];
+        self.device.read(&mut data, BlockIdx(block), "fsck")?;
+        for entry in data[0].contents.chunks_exact(ENTRY_SIZE) {
+            match entry[0] {
+                0 => return Ok(false),
+                fat::DELETED | b'.' => continue,
+                _ => {}
+            }
+            let attributes = entry[11];
+            if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME || attributes & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+
+            let (chain, crossed) = if attributes & ATTR_DIRECTORY != 0 {
+                if self.counting {
+                    self.report.dirs += 1;
+                }
+                let cluster = fat::cluster(entry);
+                let chain = self.dir_chain(cluster)?;
+                if chain == Chain::Intact && pending.push(cluster).is_err() && self.counting {
+                    self.report.skipped_dirs += 1;
+                }
+                (chain, false)
+            } else {
+                if self.counting {
+                    self.report.files += 1;
+                }
+                self.file_chain(entry)?
+            };
+            if chain != Chain::Intact && self.counting {
+                self.report.bad_chains += 1;
+                self.report.name(entry);
+            }
+            if crossed {
+                self.report.name(entry);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Follows the chain of the file `entry`, marking its clusters. Also
+    /// says whether one of them was already taken.
+    fn file_chain(&mut self, entry: &[u8]) -> Result<(Chain, bool), D::Error> {
+        let size = u32_at(entry, 28);
+        let needed = size.div_ceil(self.fat.cluster_bytes());
+        let mut cluster = fat::cluster(entry);
+        if cluster == 0 {
+            let chain = if needed == 0 {
+                Chain::Intact
+            } else {
+                Chain::Short
+            };
+            return Ok((chain, false));
+        }
+
+        let mut crossed = false;
+        let mut length = 0;
+        loop {
+            if !self.fat.is_cluster(cluster) {
+                return Ok((Chain::Broken, crossed));
+            }
+            crossed |= !self.mark(cluster);
+            length += 1;
+            // Past the end of the file is enough to know, and ends loops.
+            if length > needed {
+                return Ok((Chain::Long, crossed));
+            }
+            match self.link(cluster)? {
+                Link::Next(next) => cluster = next,
+                Link::End if length < needed => return Ok((Chain::Short, crossed)),
+                Link::End => return Ok((Chain::Intact, crossed)),
+                Link::Free | Link::Bad => return Ok((Chain::Broken, crossed)),
+            }
+        }
+    }
+
+    /// Follows a directory chain without marking it, `dir` does that when
+    /// it reads the directory.
+    fn dir_chain(&mut self, first: u32) -> Result<Chain, D::Error> {
+        let limit = (MAX_DIR_BYTES / self.fat.cluster_bytes()).max(1);
+        let mut cluster = first;
+        for _ in 0..limit {
+            if !self.fat.is_cluster(cluster) {
+                return Ok(Chain::Broken);
+            }
+            match self.link(cluster)? {
+                Link::Next(next) => cluster = next,
+                Link::End => return Ok(Chain::Intact),
+                Link::Free | Link::Bad => return Ok(Chain::Broken),
+            }
+        }
+        Ok(Chain::Long)
+    }
+
+    /// Marks `cluster` as used, `false` if it already was. Clusters outside
+    /// the window are left to another walk.
+    fn mark(&mut self, cluster: u32) -> bool {
+        let Some(bit) = cluster.checked_sub(self.first) else {
+            return true;
+        };
+        let Some(byte) = self.bitmap.get_mut(bit as usize / 8) else {
+            return true;
+        };
+        let mask = 1 << (bit % 8);
+        if *byte & mask != 0 {
+            self.report.cross_linked += 1;
+            return false;
+        }
+        *byte |= mask;
+        true
+    }
+
+    fn link(&mut self, cluster: u32) -> Result<Link, D::Error> {
+        let width = if self.fat.fat32 { 4 } else { 2 };
+        let offset = cluster * width;
+        let idx = self.fat.fat_start + offset / Block::LEN_U32;
+        if self.cache.idx != Some(idx) {
+            self.cache.idx = None;
+            self.device
+                .read(&mut self.cache.block, BlockIdx(idx), "fsck")?;
+            self.cache.idx = Some(idx);
+        }
+        let data = &self.cache.block[0].contents;
+        let at = (offset % Block::LEN_U32) as usize;
+        let (next, bad) = if self.fat.fat32 {
+            (u32_at(data, at) & 0x0FFF_FFFF, 0x0FFF_FFF7)
+        } else {
+            (u16_at(data, at) as u32, 0xFFF7)
+        };
+        Ok(match next {
+            0 => Link::Free,
+            next if next == bad => Link::Bad,
+            next if next > bad => Link::End,
+            next => Link::Next(next),
+        })
+    }
+}
+
+/// `NAME.EXT` from the name field of a directory entry.
+fn display_name(entry: &[u8]) -> String<12> {
+    let mut name = String::new();
+    for &c in entry[..8].iter().filter(|&&c| c != b' ') {
+        name.push(c as char).ok();
+    }
+    if entry[8] != b' ' {
+        name.push('.').ok();
+        for &c in entry[8..11].iter().filter(|&&c| c != b' ') {
+            name.push(c as char).ok();
+        }
+    }
+    name
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
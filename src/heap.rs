@@ -0,0 +1,45 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::RefCell,
+    ptr::{self, NonNull},
+};
+
+use cortex_m::interrupt::{self, Mutex};
+use linked_list_allocator::Heap;
+
+/// Only WASM apps allocate. The heap lives in app RAM while one runs.
+struct LoaderHeap(Mutex<RefCell<Heap>>);
+
+#[global_allocator]
+static HEAP: LoaderHeap = LoaderHeap(Mutex::new(RefCell::new(Heap::empty())));
+
+/// Makes the `size` bytes at `start` the heap. Nothing allocated from the
+/// previous heap may still be alive.
+pub unsafe fn reset(start: usize, size: usize) {
+    interrupt::free(|cs| {
+        let mut heap = HEAP.0.borrow(cs).borrow_mut();
+        *heap = Heap::empty();
+        heap.init(start as *mut u8, size);
+    });
+}
+
+unsafe impl GlobalAlloc for LoaderHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupt::free(|cs| {
+            self.0
+                .borrow(cs)
+                .borrow_mut()
+                .allocate_first_fit(layout)
+                .map_or(ptr::null_mut(), |block| block.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupt::free(|cs| {
+            self.0
+                .borrow(cs)
+                .borrow_mut()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        });
+    }
+}
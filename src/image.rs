@@ -0,0 +1,325 @@
+//! Image viewer.
+//!
+//! Decoders stream pixels from the card into a [`Canvas`], which scales the
+//! picture down to fit the panel, centres it and sends it a row at a time.
+//! Transparent pixels are blended with the black background.
+
+use core::slice;
+
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+
+use crate::{
+    atm0130::Color,
+    bmp,
+    buttons::Buttons,
+    error::LoaderError,
+    fs::{File, FsError, Storage},
+    png, qoi,
+    ram::Allocator,
+    ui::{self, SCREEN_SIZE},
+    Display,
+};
+
+const CHUNK: usize = 512;
+/// Inflate window and two scanlines, taken from app RAM while a PNG is shown.
+const PNG_WORK: usize = png::WINDOW + 32 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ImageError {
+    /// A format or variant the decoders do not handle.
+    Unsupported,
+    /// The file ends early or its header contradicts itself.
+    Malformed,
+    /// No room in app RAM for the decoder.
+    NoMemory,
+    Loader(LoaderError),
+}
+
+impl From<LoaderError> for ImageError {
+    fn from(err: LoaderError) -> Self {
+        ImageError::Loader(err)
+    }
+}
+
+impl From<FsError> for ImageError {
+    fn from(err: FsError) -> Self {
+        ImageError::Loader(err.into())
+    }
+}
+
+impl From<qoi::Error<ImageError>> for ImageError {
+    fn from(err: qoi::Error<ImageError>) -> Self {
+        match err {
+            qoi::Error::Format => ImageError::Malformed,
+            qoi::Error::Read(err) => err,
+        }
+    }
+}
+
+impl From<png::Error<ImageError>> for ImageError {
+    fn from(err: png::Error<ImageError>) -> Self {
+        match err {
+            png::Error::Format => ImageError::Malformed,
+            png::Error::Unsupported => ImageError::Unsupported,
+            png::Error::Read(err) => err,
+        }
+    }
+}
+
+impl ImageError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ImageError::Unsupported => "Image format not supported.",
+            ImageError::Malformed => "Image file is damaged.",
+            ImageError::NoMemory => "Not enough free app RAM.",
+            ImageError::Loader(err) => err.message(),
+        }
+    }
+}
+
+pub fn is_image(extension: &str) -> bool {
+    matches!(extension, "BMP" | "PNG" | "QOI")
+}
+
+/// Draws `file`, whatever its extension says it is, and waits for a button.
+pub fn show<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    ram: &mut Allocator,
+    file: &mut File,
+) -> Result<(), ImageError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    ui::clear(display)?;
+    let mut source = Source::new(storage, file);
+    let mut magic = [0; 4];
+    source.read(&mut magic)?;
+    source.seek(0)?;
+    if magic.starts_with(b"BM") {
+        bmp::draw(display, &mut source)?;
+    } else if magic == qoi::MAGIC {
+        draw_qoi(display, &mut source)?;
+    } else if magic == png::SIGNATURE[..4] {
+        let base = ram.alloc(PNG_WORK, 4).ok_or(ImageError::NoMemory)?;
+        let work = unsafe { slice::from_raw_parts_mut(base as *mut u8, PNG_WORK) };
+        let drawn = draw_png(display, &mut source, work);
+        ram.free(base);
+        drawn?;
+    } else {
+        return Err(ImageError::Unsupported);
+    }
+    buttons.wait(delay);
+    Ok(())
+}
+
+fn draw_qoi<D, T>(display: &mut Display, source: &mut Source<D, T>) -> Result<(), ImageError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut image = qoi::Decoder::new(|| source.byte())?;
+    let mut canvas = Canvas::new(image.width(), image.height());
+    // Every pixel has to be decoded, but none past the last row shown.
+    for y in 0..=canvas.source_row(canvas.rows() - 1) {
+        let row = canvas.row_of(y);
+        for x in 0..image.width() {
+            let pixel = image.next_pixel()?;
+            if row.is_some() {
+                canvas.put(x, blend(pixel));
+            }
+        }
+        if let Some(row) = row {
+            canvas.draw_row(display, row)?;
+        }
+    }
+    Ok(())
+}
+
+fn draw_png<D, T>(
+    display: &mut Display,
+    source: &mut Source<D, T>,
+    work: &mut [u8],
+) -> Result<(), ImageError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut image = png::Decoder::new(|| source.byte(), work)?;
+    let mut canvas = Canvas::new(image.width(), image.height());
+    for y in 0..=canvas.source_row(canvas.rows() - 1) {
+        image.next_row()?;
+        if let Some(row) = canvas.row_of(y) {
+            for x in 0..image.width() {
+                canvas.put(x, blend(image.pixel(x)));
+            }
+            canvas.draw_row(display, row)?;
+        }
+    }
+    Ok(())
+}
+
+/// RGBA over black, as RGB565.
+fn blend([r, g, b, a]: [u8; 4]) -> u16 {
+    let scale = |channel: u8| (channel as u16 * a as u16 / 255) as u8;
+    Color(scale(r), scale(g), scale(b)).to_u16()
+}
+
+/// Buffered bytes from an image file. Running out of them means the file was
+/// cut short.
+pub struct Source<'s, 'a, D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    storage: &'s mut Storage<'a, D, T>,
+    file: &'s mut File,
+    buf: [u8; CHUNK],
+    len: usize,
+    pos: usize,
+}
+
+impl<'s, 'a, D, T> Source<'s, 'a, D, T>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    pub fn new(storage: &'s mut Storage<'a, D, T>, file: &'s mut File) -> Self {
+        Self {
+            storage,
+            file,
+            buf: [0; CHUNK],
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    pub fn seek(&mut self, offset: u32) -> Result<(), ImageError> {
+        if offset > self.file.length() {
+            return Err(ImageError::Malformed);
+        }
+        self.file.seek_from_start(offset)?;
+        self.len = 0;
+        self.pos = 0;
+        Ok(())
+    }
+
+    pub fn byte(&mut self) -> Result<u8, ImageError> {
+        if self.pos == self.len {
+            if self.file.eof() {
+                return Err(ImageError::Malformed);
+            }
+            self.len = self.storage.read(self.file, &mut self.buf)?;
+            self.pos = 0;
+            if self.len == 0 {
+                return Err(ImageError::Malformed);
+            }
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<(), ImageError> {
+        for byte in buf {
+            *byte = self.byte()?;
+        }
+        Ok(())
+    }
+
+    pub fn skip(&mut self, count: u32) -> Result<(), ImageError> {
+        for _ in 0..count {
+            self.byte()?;
+        }
+        Ok(())
+    }
+
+    pub fn u16_le(&mut self) -> Result<u16, ImageError> {
+        let mut bytes = [0; 2];
+        self.read(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32_le(&mut self) -> Result<u32, ImageError> {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+/// Where a `width` x `height` picture lands on the panel. Pictures that do not
+/// fit are shrunk by keeping every `step`th pixel in both directions.
+pub struct Canvas {
+    step: u32,
+    columns: u32,
+    rows: u32,
+    x: u8,
+    y: u8,
+    /// The output row being built, in panel order.
+    line: [u8; 2 * SCREEN_SIZE as usize],
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        let size = SCREEN_SIZE as u32;
+        let step = width.max(height).max(1).div_ceil(size);
+        let columns = width.div_ceil(step);
+        let rows = height.div_ceil(step);
+        // Rows are mirrored on the panel, so is the left margin.
+        let x = (size - columns).div_ceil(2) as u8;
+        let y = ((size - rows) / 2) as u8;
+        Self {
+            step,
+            columns,
+            rows,
+            x,
+            y,
+            line: [0; 2 * SCREEN_SIZE as usize],
+        }
+    }
+
+    /// Rows the picture takes on the panel.
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    /// The picture row shown as `row`.
+    pub fn source_row(&self, row: u32) -> u32 {
+        row * self.step
+    }
+
+    /// The output row picture row `y` is shown as, if any.
+    pub fn row_of(&self, y: u32) -> Option<u32> {
+        let row = y / self.step;
+        (y.is_multiple_of(self.step) && row < self.rows).then_some(row)
+    }
+
+    /// Stores the RGB565 `color` of picture column `x` if it is shown.
+    pub fn put(&mut self, x: u32, color: u16) {
+        if !x.is_multiple_of(self.step) {
+            return;
+        }
+        let column = x / self.step;
+        if column < self.columns {
+            let index = (self.columns - 1 - column) as usize * 2;
+            self.line[index..index + 2].copy_from_slice(&color.to_be_bytes());
+        }
+    }
+
+    /// Sends the stored pixels as output row `row`.
+    pub fn draw_row(&self, display: &mut Display, row: u32) -> Result<(), LoaderError> {
+        if row >= self.rows {
+            return Ok(());
+        }
+        display.draw_pixels(
+            self.x,
+            self.y + row as u8,
+            self.columns as u8,
+            1,
+            &self.line[..self.columns as usize * 2],
+        )
+    }
+}
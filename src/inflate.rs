@@ -0,0 +1,373 @@
+//! Streaming zlib decompression (RFC 1950 and 1951).
+//!
+//! Output is produced on demand into the caller's buffer. Back-references
+//! are resolved from a window the caller provides, so memory use does not
+//! depend on the size of the data. Huffman codes are decoded a bit at a time
+//! from canonical counts, which needs no lookup tables.
+
+const MAX_BITS: usize = 15;
+const MAX_LITERALS: usize = 288;
+const MAX_DISTANCES: usize = 30;
+const END_OF_BLOCK: u16 = 256;
+const ADLER_MOD: u32 = 65521;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order the code length code lengths are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Not a valid zlib stream, or its checksum does not match.
+    Format,
+    /// A back-reference reaches further than the window.
+    TooFar,
+    Read(E),
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Header,
+    Block,
+    Stored(u16),
+    Codes,
+    Check,
+    Done,
+}
+
+/// A canonical Huffman code.
+struct Huffman {
+    /// Number of codes of each length.
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code.
+    symbols: [u16; MAX_LITERALS],
+}
+
+impl Huffman {
+    const fn new() -> Self {
+        Self {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; MAX_LITERALS],
+        }
+    }
+
+    /// Incomplete codes are allowed, over-subscribed ones are not.
+    fn build(&mut self, lengths: &[u8]) -> bool {
+        self.counts = [0; MAX_BITS + 1];
+        for &len in lengths {
+            self.counts[len as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - self.counts[len] as i32;
+            if left < 0 {
+                return false;
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + self.counts[len];
+        }
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                self.symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        true
+    }
+}
+
+pub struct Inflate<'w> {
+    window: &'w mut [u8],
+    /// Where the next byte goes in `window`.
+    pos: usize,
+    /// How much of `window` holds output, back-references cannot go further.
+    filled: usize,
+    bits: Bits,
+    state: State,
+    last: bool,
+    literals: Huffman,
+    distances: Huffman,
+    /// Bytes of the current back-reference still to copy.
+    copy: u16,
+    distance: u16,
+    adler: (u32, u32),
+}
+
+impl<'w> Inflate<'w> {
+    /// The window length must be a power of two. 32 KiB covers every stream.
+    pub fn new(window: &'w mut [u8]) -> Self {
+        debug_assert!(window.len().is_power_of_two());
+        Self {
+            window,
+            pos: 0,
+            filled: 0,
+            bits: Bits { value: 0, count: 0 },
+            state: State::Header,
+            last: false,
+            literals: Huffman::new(),
+            distances: Huffman::new(),
+            copy: 0,
+            distance: 0,
+            adler: (1, 0),
+        }
+    }
+
+    /// Fills `out` with the next decompressed bytes, pulling compressed ones
+    /// from `input`. Returns fewer than asked for only at the end of the
+    /// stream.
+    pub fn read<E, R>(&mut self, input: &mut R, out: &mut [u8]) -> Result<usize, Error<E>>
+    where
+        R: FnMut() -> Result<u8, E>,
+    {
+        let mask = self.window.len() - 1;
+        let mut len = 0;
+        while len < out.len() {
+            if self.copy > 0 {
+                let byte = self.window[self.pos.wrapping_sub(self.distance as usize) & mask];
+                self.copy -= 1;
+                out[len] = self.emit(byte);
+                len += 1;
+                continue;
+            }
+            match self.state {
+                State::Header => {
+                    let method = self.bits.take(input, 8)?;
+                    let flags = self.bits.take(input, 8)?;
+                    let dictionary = flags & 0x20 != 0;
+                    if method & 0x0F != 8 || (method << 8 | flags) % 31 != 0 || dictionary {
+                        return Err(Error::Format);
+                    }
+                    self.state = State::Block;
+                }
+                State::Block if self.last => self.state = State::Check,
+                State::Block => {
+                    self.last = self.bits.take(input, 1)? == 1;
+                    self.state = match self.bits.take(input, 2)? {
+                        0 => self.stored(input)?,
+                        1 => self.fixed(),
+                        2 => self.dynamic(input)?,
+                        _ => return Err(Error::Format),
+                    };
+                }
+                State::Stored(0) => self.state = State::Block,
+                State::Stored(left) => {
+                    let byte = self.bits.take(input, 8)? as u8;
+                    self.state = State::Stored(left - 1);
+                    out[len] = self.emit(byte);
+                    len += 1;
+                }
+                State::Codes => {
+                    let symbol = self.bits.decode(input, &self.literals)?;
+                    if symbol < END_OF_BLOCK {
+                        out[len] = self.emit(symbol as u8);
+                        len += 1;
+                    } else if symbol == END_OF_BLOCK {
+                        self.state = State::Block;
+                    } else {
+                        self.reference(input, symbol)?;
+                    }
+                }
+                State::Check => {
+                    self.bits.align();
+                    let mut checksum = 0;
+                    for _ in 0..4 {
+                        checksum = checksum << 8 | self.bits.take(input, 8)?;
+                    }
+                    let (a, b) = self.adler;
+                    if checksum != b << 16 | a {
+                        return Err(Error::Format);
+                    }
+                    self.state = State::Done;
+                }
+                State::Done => break,
+            }
+        }
+        Ok(len)
+    }
+
+    fn emit(&mut self, byte: u8) -> u8 {
+        self.window[self.pos] = byte;
+        self.pos = (self.pos + 1) & (self.window.len() - 1);
+        self.filled = (self.filled + 1).min(self.window.len());
+
+        let (mut a, mut b) = self.adler;
+        a += byte as u32;
+        if a >= ADLER_MOD {
+            a -= ADLER_MOD;
+        }
+        b += a;
+        if b >= ADLER_MOD {
+            b -= ADLER_MOD;
+        }
+        self.adler = (a, b);
+        byte
+    }
+
+    fn stored<E, R>(&mut self, input: &mut R) -> Result<State, Error<E>>
+    where
+        R: FnMut() -> Result<u8, E>,
+    {
+        self.bits.align();
+        let len = self.bits.take(input, 16)? as u16;
+        let inverse = self.bits.take(input, 16)? as u16;
+        if len != !inverse {
+            return Err(Error::Format);
+        }
+        Ok(State::Stored(len))
+    }
+
+    fn fixed(&mut self) -> State {
+        let mut lengths = [8u8; MAX_LITERALS];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        self.literals.build(&lengths);
+        self.distances.build(&[5; MAX_DISTANCES]);
+        State::Codes
+    }
+
+    fn dynamic<E, R>(&mut self, input: &mut R) -> Result<State, Error<E>>
+    where
+        R: FnMut() -> Result<u8, E>,
+    {
+        let literal_count = self.bits.take(input, 5)? as usize + 257;
+        let distance_count = self.bits.take(input, 5)? as usize + 1;
+        let code_count = self.bits.take(input, 4)? as usize + 4;
+        if literal_count > MAX_LITERALS || distance_count > MAX_DISTANCES {
+            return Err(Error::Format);
+        }
+
+        let mut lengths = [0u8; MAX_LITERALS + MAX_DISTANCES];
+        for &index in &CODE_LENGTH_ORDER[..code_count] {
+            lengths[index] = self.bits.take(input, 3)? as u8;
+        }
+        // The distance table is free until the end, so it holds the code
+        // length code meanwhile.
+        if !self.distances.build(&lengths[..CODE_LENGTH_ORDER.len()]) {
+            return Err(Error::Format);
+        }
+        lengths[..CODE_LENGTH_ORDER.len()].fill(0);
+
+        let total = literal_count + distance_count;
+        let mut index = 0;
+        while index < total {
+            let symbol = self.bits.decode(input, &self.distances)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if index > 0 => (lengths[index - 1], 3 + self.bits.take(input, 2)?),
+                17 => (0, 3 + self.bits.take(input, 3)?),
+                18 => (0, 11 + self.bits.take(input, 7)?),
+                _ => return Err(Error::Format),
+            };
+            let end = index + repeat as usize;
+            if end > total {
+                return Err(Error::Format);
+            }
+            lengths[index..end].fill(value);
+            index = end;
+        }
+
+        let (literals, distances) = lengths[..total].split_at(literal_count);
+        if literals[END_OF_BLOCK as usize] == 0
+            || !self.literals.build(literals)
+            || !self.distances.build(distances)
+        {
+            return Err(Error::Format);
+        }
+        Ok(State::Codes)
+    }
+
+    fn reference<E, R>(&mut self, input: &mut R, symbol: u16) -> Result<(), Error<E>>
+    where
+        R: FnMut() -> Result<u8, E>,
+    {
+        let index = (symbol - END_OF_BLOCK - 1) as usize;
+        if index >= LENGTH_BASE.len() {
+            return Err(Error::Format);
+        }
+        let extra = self.bits.take(input, LENGTH_EXTRA[index] as u32)?;
+        let length = LENGTH_BASE[index] + extra as u16;
+
+        let index = self.bits.decode(input, &self.distances)? as usize;
+        if index >= DISTANCE_BASE.len() {
+            return Err(Error::Format);
+        }
+        let extra = self.bits.take(input, DISTANCE_EXTRA[index] as u32)?;
+        let distance = DISTANCE_BASE[index] + extra as u16;
+        if distance as usize > self.filled {
+            return Err(Error::TooFar);
+        }
+        self.copy = length;
+        self.distance = distance;
+        Ok(())
+    }
+}
+
+/// Compressed bits not consumed yet.
+struct Bits {
+    value: u32,
+    count: u32,
+}
+
+impl Bits {
+    /// Takes `count` bits, least significant first.
+    fn take<E, R>(&mut self, input: &mut R, count: u32) -> Result<u32, Error<E>>
+    where
+        R: FnMut() -> Result<u8, E>,
+    {
+        while self.count < count {
+            self.value |= (input().map_err(Error::Read)? as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.value & ((1 << count) - 1);
+        self.value >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits up to the next byte boundary.
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.value >>= partial;
+        self.count -= partial;
+    }
+
+    /// Reads one symbol of `code`. Huffman codes are packed most significant
+    /// bit first.
+    fn decode<E, R>(&mut self, input: &mut R, code: &Huffman) -> Result<u16, Error<E>>
+    where
+        R: FnMut() -> Result<u8, E>,
+    {
+        // First code of the current length and where its symbols start.
+        let mut first: u32 = 0;
+        let mut index: u32 = 0;
+        let mut value: u32 = 0;
+        for len in 1..=MAX_BITS {
+            value |= self.take(input, 1)?;
+            let count = code.counts[len] as u32;
+            if value < first + count {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err(Error::Format)
+    }
+}
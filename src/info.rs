@@ -0,0 +1,258 @@
+use heapless::Vec;
+
+use crate::{
+    cache::Stats,
+    flash::{FlashInfo, FLASH_BASE, FS_SIZE, FS_START, LOADER_SIZE},
+    fsck::{Report, MAX_DAMAGED},
+    partition::{Partition, Scheme, Usage, MAX_PARTITIONS},
+    sdproto::{CardType, Cid, Csd},
+    ui::{push_line, Line},
+    video::FRAME_BYTES,
+};
+
+pub fn flash(info: &FlashInfo) -> Vec<Line, 8> {
+    let mut lines = Vec::new();
+    push_line(
+        &mut lines,
+        format_args!(
+            "Vendor: {} ({:02X})",
+            info.manufacturer_name(),
+            info.manufacturer
+        ),
+    );
+    push_line(
+        &mut lines,
+        format_args!(
+            "JEDEC ID: {:02X} {:02X} {:02X}",
+            info.manufacturer, info.memory_type, info.capacity
+        ),
+    );
+    push_line(
+        &mut lines,
+        format_args!(
+            "Size: {} KiB ({})",
+            info.size / 1024,
+            if info.from_sfdp { "SFDP" } else { "JEDEC" }
+        ),
+    );
+    push_line(
+        &mut lines,
+        format_args!("Loader: {} KiB", LOADER_SIZE / 1024),
+    );
+    if info.has_fs_region() {
+        let start = FLASH_BASE + FS_START;
+        push_line(
+            &mut lines,
+            format_args!("Flash FS: {:08X}-{:08X}", start, start + FS_SIZE),
+        );
+    }
+    let (start, end) = info.app_region();
+    push_line(&mut lines, format_args!("Apps: {:08X}-{:08X}", start, end));
+    push_line(
+        &mut lines,
+        format_args!("App space: {} KiB", info.app_region_size() / 1024),
+    );
+    lines
+}
+
+/// One line per partition, the volume holding the images is marked with `*`.
+pub fn partitions(partitions: &[Partition], images: u8) -> Vec<Line, MAX_PARTITIONS> {
+    let mut lines = Vec::new();
+    for partition in partitions {
+        let scheme = match partition.scheme {
+            Scheme::Mbr => "MBR",
+            Scheme::Gpt => "GPT",
+        };
+        let marker = if partition.is_mountable() && partition.index == images {
+            '*'
+        } else {
+            ' '
+        };
+        push_line(
+            &mut lines,
+            format_args!(
+                "{}{} {} {} {} MiB {}",
+                marker,
+                scheme,
+                partition.index,
+                partition.fs.name(),
+                partition.size_mib(),
+                partition.label
+            ),
+        );
+    }
+    if lines.is_empty() {
+        push_line(&mut lines, format_args!("No partitions"));
+    }
+    lines
+}
+
+/// Card registers, bus clock and the image volume. Registers that could not be
+/// read are left out.
+pub fn card(
+    card_type: Option<CardType>,
+    cid: Option<&Cid>,
+    csd: Option<&Csd>,
+    speed_class: Option<u8>,
+    clock: u32,
+    volume: &Partition,
+    usage: Option<&Usage>,
+) -> Vec<Line, 20> {
+    let mut lines = Vec::new();
+    if let Some(card_type) = card_type {
+        push_line(&mut lines, format_args!("Type: {}", card_type.name()));
+    }
+    if let Some(csd) = csd {
+        push_line(
+            &mut lines,
+            format_args!(
+                "Capacity: {} MiB (CSD v{})",
+                csd.capacity() / (1024 * 1024),
+                csd.version()
+            ),
+        );
+        push_line(
+            &mut lines,
+            format_args!("Classes: {:03X}", csd.command_classes()),
+        );
+        push_line(
+            &mut lines,
+            format_args!("Max clock: {} kHz", csd.max_clock() / 1000),
+        );
+    }
+    push_line(&mut lines, format_args!("Bus clock: {} kHz", clock / 1000));
+    match speed_class {
+        Some(class) => push_line(&mut lines, format_args!("Speed class: {}", class)),
+        None => push_line(&mut lines, format_args!("Speed class: none")),
+    }
+    if let Some(cid) = cid {
+        push_line(
+            &mut lines,
+            format_args!(
+                "Vendor: {} ({:02X})",
+                cid.manufacturer_name(),
+                cid.manufacturer()
+            ),
+        );
+        let (major, minor) = cid.revision();
+        push_line(
+            &mut lines,
+            format_args!(
+                "Product: {} {} rev {}.{}",
+                cid.oem(),
+                cid.product(),
+                major,
+                minor
+            ),
+        );
+        push_line(&mut lines, format_args!("Serial: {:08X}", cid.serial()));
+        let (year, month) = cid.date();
+        push_line(&mut lines, format_args!("Made: {}-{:02}", year, month));
+    }
+    push_line(
+        &mut lines,
+        format_args!(
+            "Volume: {} {} {}",
+            volume.index,
+            volume.fs.name(),
+            volume.label
+        ),
+    );
+    if let Some(usage) = usage {
+        push_line(
+            &mut lines,
+            format_args!("Cluster: {} bytes", usage.cluster_size),
+        );
+        let total = usage.clusters as u64 * usage.cluster_size as u64 / (1024 * 1024);
+        match usage.free_mib() {
+            Some(free) => push_line(&mut lines, format_args!("Free: {} of {} MiB", free, total)),
+            None => push_line(&mut lines, format_args!("Size: {} MiB", total)),
+        }
+    }
+    lines
+}
+
+/// Appends the block cache counters since the card was mounted.
+pub fn cache<const N: usize>(lines: &mut Vec<Line, N>, stats: &Stats) {
+    push_line(
+        lines,
+        format_args!("Cache: {} hits {} misses", stats.hits, stats.misses),
+    );
+    push_line(lines, format_args!("Read-aheads: {}", stats.read_aheads));
+}
+
+/// Space on the flash volume, in bytes.
+pub fn flash_usage<const N: usize>(lines: &mut Vec<Line, N>, free: u32, total: u32) {
+    push_line(
+        lines,
+        format_args!("FS free: {} of {} KiB", free / 1024, total / 1024),
+    );
+}
+
+/// Playback results. `elapsed` is in microseconds.
+pub fn playback(shown: u32, frames: u32, late: u32, frame_rate: u8, elapsed: u32) -> Vec<Line, 8> {
+    let mut lines = Vec::new();
+    let elapsed = elapsed.max(1) as u64;
+    let tenths = shown as u64 * 10_000_000 / elapsed;
+    let bytes = shown as u64 * FRAME_BYTES as u64;
+    push_line(&mut lines, format_args!("Frames: {} of {}", shown, frames));
+    push_line(&mut lines, format_args!("Target: {} fps", frame_rate));
+    push_line(
+        &mut lines,
+        format_args!("Achieved: {}.{} fps", tenths / 10, tenths % 10),
+    );
+    push_line(&mut lines, format_args!("Late frames: {}", late));
+    push_line(
+        &mut lines,
+        format_args!("Throughput: {} KiB/s", bytes * 1_000_000 / elapsed / 1024),
+    );
+    lines
+}
+
+/// Volume check results, with the damaged files named.
+pub fn check(report: &Report) -> Vec<Line, { 8 + MAX_DAMAGED }> {
+    let mut lines = Vec::new();
+    push_line(
+        &mut lines,
+        format_args!("Files: {}  Folders: {}", report.files, report.dirs),
+    );
+    push_line(
+        &mut lines,
+        format_args!(
+            "FAT copies: {}",
+            match report.fat_mismatches {
+                0 => "same",
+                _ => "differ",
+            }
+        ),
+    );
+    if report.fat_mismatches > 0 {
+        push_line(
+            &mut lines,
+            format_args!(" {} blocks differ", report.fat_mismatches),
+        );
+    }
+    push_line(
+        &mut lines,
+        format_args!("Bad chains: {}", report.bad_chains),
+    );
+    push_line(
+        &mut lines,
+        format_args!("Cross-linked clusters: {}", report.cross_linked),
+    );
+    if report.skipped_dirs > 0 {
+        push_line(
+            &mut lines,
+            format_args!("Not checked: {} folders", report.skipped_dirs),
+        );
+    }
+    if report.is_clean() {
+        push_line(&mut lines, format_args!("No problems found."));
+    } else {
+        push_line(&mut lines, format_args!("Damaged:"));
+        for name in &report.damaged {
+            push_line(&mut lines, format_args!(" {}", name));
+        }
+    }
+    lines
+}
@@ -0,0 +1,12 @@
+//! Where the loader and its littlefs volume sit in the boot flash.
+//!
+//! `build.rs` includes this file as well and fails the build if the `FLASH`
+//! and `FLASHFS` regions in `memory.x` disagree with it.
+
+pub const FLASH_BASE: u32 = 0x1000_0000;
+/// Flash reserved for the loader, where the `FLASH` region ends.
+pub const LOADER_SIZE: u32 = 1024 * 1024;
+/// The littlefs volume right after the loader, the `FLASHFS` region.
+/// Everything after it is the app region.
+pub const FS_START: u32 = LOADER_SIZE;
+pub const FS_SIZE: u32 = 256 * 1024;
@@ -0,0 +1,88 @@
+//! VFAT long file name entries.
+//!
+//! A long name is stored in 32-byte entries placed right before the short
+//! name entry, nearest first, each carrying 13 UCS-2 characters and the
+//! checksum of the short name.
+
+use heapless::String;
+
+pub const ENTRY_SIZE: usize = 32;
+
+const ATTR_LFN: u8 = 0x0F;
+const LAST_ENTRY: u8 = 0x40;
+const SEQUENCE_MASK: u8 = 0x1F;
+
+/// Byte offsets of the characters within an entry.
+const CHAR_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+pub enum Step {
+    More,
+    Done,
+    /// The entries do not form a long name for this short name.
+    Invalid,
+}
+
+pub struct Decoder<const N: usize> {
+    checksum: u8,
+    expected: u8,
+    ended: bool,
+    name: String<N>,
+}
+
+impl<const N: usize> Decoder<N> {
+    /// `short` is the 11-byte name field of the short name entry.
+    pub fn new(short: &[u8]) -> Self {
+        Self {
+            checksum: checksum(short),
+            expected: 1,
+            ended: false,
+            name: String::new(),
+        }
+    }
+
+    /// Feeds the entry before the previously fed one.
+    pub fn push(&mut self, entry: &[u8]) -> Step {
+        let sequence = entry[0];
+        if entry[11] != ATTR_LFN
+            || entry[13] != self.checksum
+            || sequence & SEQUENCE_MASK != self.expected
+        {
+            return Step::Invalid;
+        }
+
+        for &offset in CHAR_OFFSETS.iter() {
+            let unit = u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+            if unit == 0x0000 {
+                self.ended = true;
+            }
+            if self.ended {
+                break;
+            }
+            // The font only has printable ASCII.
+            let c = match unit {
+                0x20..=0x7E => unit as u8 as char,
+                _ => '?',
+            };
+            // Names longer than `N` are cut, decoding still has to finish to
+            // validate the sequence.
+            self.name.push(c).ok();
+        }
+
+        if sequence & LAST_ENTRY != 0 {
+            Step::Done
+        } else {
+            self.expected += 1;
+            Step::More
+        }
+    }
+
+    pub fn into_name(self) -> String<N> {
+        self.name
+    }
+}
+
+pub fn checksum(short: &[u8]) -> u8 {
+    short[..11]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
@@ -4,11 +4,20 @@
 #![no_std]
 #![no_main]
 
-use atm0130::Color;
+extern crate alloc;
+
+use app::LaunchError;
+use cache::BlockCache;
+use core::{fmt::Write, slice};
+use cortex_m::delay::Delay;
 use defmt_rtt as _;
+use dma::{Bus, SpiDma};
 use embedded_hal::digital::v2::OutputPin;
 use fugit::RateExtU32;
+use heapless::String;
+use image::ImageError;
 use panic_probe as _;
+use rp_loader_abi::{APP_RAM_SIZE, APP_RAM_START};
 
 use rp_pico as bsp;
 use rp_pico::entry;
@@ -17,35 +26,102 @@ use rp_pico::entry;
 use bsp::hal::{
     self,
     clocks::{init_clocks_and_plls, Clock},
+    gpio::{bank0, Pin, PushPullOutput},
     pac,
     sio::Sio,
+    uart::{DataBits, StopBits, UartConfig, UartPeripheral},
     watchdog::Watchdog,
 };
 
-use embedded_sdmmc::filesystem::Mode;
-use embedded_sdmmc::{Controller, SdMmcSpi, TimeSource, Timestamp, VolumeIdx};
+use embedded_sdmmc::Controller;
+use error::LoaderError;
+use flashfs::{FlashFs, FlashStorage};
+use fsck::{Chain, CheckError};
 
+mod app;
 mod artemis;
 mod atm0130;
-
-#[derive(Default)]
-pub struct DummyTimesource();
-
-impl TimeSource for DummyTimesource {
-    // In theory you could use the RTC of the rp2040 here, if you had
-    // any external time synchronizing device.
-    fn get_timestamp(&self) -> Timestamp {
-        Timestamp {
-            year_since_1970: 0,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
-        }
-    }
+mod bench;
+mod bmp;
+mod browser;
+mod buttons;
+mod cache;
+mod card;
+mod clock;
+mod config;
+mod console;
+mod dma;
+mod elf;
+mod error;
+mod exfat;
+mod fat;
+mod files;
+mod flash;
+mod flashfs;
+mod format;
+mod fs;
+mod fsck;
+mod heap;
+mod image;
+mod inflate;
+mod info;
+mod layout;
+mod lfn;
+mod pager;
+mod partition;
+mod png;
+mod qoi;
+mod ram;
+#[cfg(not(feature = "sdio"))]
+mod sdcard;
+#[cfg(feature = "sdio")]
+mod sdio;
+mod sdproto;
+mod services;
+mod ui;
+mod video;
+mod wasm;
+
+#[derive(Clone, Copy)]
+enum Screen {
+    Apps,
+    Text,
+    Images,
+    Videos,
+    Files,
+    FlashFiles,
+    Check,
+    Partitions,
+    CardInfo,
+    Benchmark,
+    FlashInfo,
 }
 
+const MENU: [(&str, Screen); 11] = [
+    ("Apps", Screen::Apps),
+    ("Text files", Screen::Text),
+    ("Images", Screen::Images),
+    ("Videos", Screen::Videos),
+    ("Files", Screen::Files),
+    ("Flash files", Screen::FlashFiles),
+    ("Check volume", Screen::Check),
+    ("Partitions", Screen::Partitions),
+    ("SD card", Screen::CardInfo),
+    ("Benchmark", Screen::Benchmark),
+    ("Flash info", Screen::FlashInfo),
+];
+
+pub type Display = atm0130::Atm0130<pac::SPI0, bank0::Gpio5, bank0::Gpio14, bank0::Gpio15>;
+#[cfg(not(feature = "sdio"))]
+pub type Card = sdcard::SdCard;
+#[cfg(feature = "sdio")]
+pub type Card = sdio::SdCard;
+type LedPin = Pin<bank0::Gpio25, PushPullOutput>;
+
+const BLINK_MS: u32 = 300;
+const BLINK_LONG_MS: u32 = 1000;
+const BLINK_PAUSE_MS: u32 = 3000;
+
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -67,7 +143,10 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let mut delay = Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+
+    let rtc_carried = clock::init(pac.RTC, clocks.rtc_clock, &mut pac.RESETS);
+    let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS);
 
     let pins = bsp::Pins::new(
         pac.IO_BANK0,
@@ -78,6 +157,31 @@ fn main() -> ! {
 
     let mut led_pin = pins.led.into_push_pull_output();
 
+    let uart_pins = (
+        pins.gpio0.into_mode::<hal::gpio::FunctionUart>(),
+        pins.gpio1.into_mode::<hal::gpio::FunctionUart>(),
+    );
+    let uart = UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS).enable(
+        UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
+        clocks.peripheral_clock.freq(),
+    );
+    // The loader works without the console.
+    if let Ok(uart) = uart {
+        console::init(uart);
+    }
+
+    let buttons = buttons::Buttons::new(
+        pins.gpio16.into_pull_up_input(),
+        pins.gpio17.into_pull_up_input(),
+        pins.gpio18.into_pull_up_input(),
+        pins.gpio19.into_pull_up_input(),
+    );
+
+    // SPI blocks come out of reset in `init`, which also clears their DMA
+    // enables, so the channels are attached after it.
+    #[cfg_attr(feature = "sdio", allow(unused_variables))]
+    let [dma0, dma1, dma2, dma3] = dma::split(pac.DMA, &mut pac.RESETS);
+
     // Initialize display
     let _atm0130_sclk = pins.gpio2.into_mode::<hal::gpio::FunctionSpi>();
     let _atm0130_mosi = pins.gpio3.into_mode::<hal::gpio::FunctionSpi>();
@@ -94,69 +198,548 @@ fn main() -> ! {
         &embedded_hal::spi::MODE_0,
     );
 
-    let mut display = atm0130::Atm0130::init(spi, ss, dc, res);
-
-    display.begin(&mut delay);
+    let mut display =
+        atm0130::Atm0130::init(spi, ss, dc, res, Some(SpiDma::new(Bus::Spi0, dma0, dma1)));
 
-    let black = Color(0, 0, 0);
-    display.draw_rect(0, 0, 240, 240, black);
+    let flash_info = flash::detect();
 
-    display.draw_logo(120 - artemis::IMG_WIDTH / 2, 120 - artemis::IMG_HEIGHT / 2);
-
-    delay.delay_ms(1000);
+    // Nothing can be shown without the display, the LED has to tell instead.
+    if let Err(err) = splash(&mut display, &mut delay) {
+        blink_code(&mut led_pin, &mut delay, err.code());
+    }
 
-    display.draw_rect(0, 0, 240, 240, Color(0, 0, 0));
-    display.draw_info("Reading SD card.");
+    // Formatting on the first boot takes a moment, the splash is up by then.
+    let mut flash_alloc = flashfs::allocate();
+    let mut flash_storage = FlashStorage::new(&flash_info);
+    // Without a volume the loader still works from the card alone.
+    let flash_volume: Option<FlashFs> = match flash_storage.as_mut() {
+        Some(storage) => match flashfs::prepare(&mut display, &buttons, &mut delay, storage) {
+            Ok(true) => flashfs::mount(&mut flash_alloc, storage).ok(),
+            _ => None,
+        },
+        None => None,
+    };
+    // `prepare` may have asked about formatting.
+    ui::message(&mut display, "Reading SD card.").ok();
 
     // Initialize sd card
-    let _sd_sclk = pins.gpio10.into_mode::<hal::gpio::FunctionSpi>();
-    let _sd_mosi = pins.gpio11.into_mode::<hal::gpio::FunctionSpi>();
-    let _sd_miso = pins.gpio12.into_mode::<hal::gpio::FunctionSpi>();
-    let cs = pins.gpio13.into_push_pull_output();
-    let spi = hal::Spi::<_, _, 8>::new(pac.SPI1);
-
-    let spi = spi.init(
-        &mut pac.RESETS,
-        clocks.peripheral_clock.freq(),
-        20.MHz(),
-        &embedded_hal::spi::MODE_0,
-    );
-
-    let mut sdspi = SdMmcSpi::new(spi, cs);
-    let block = sdspi.acquire().unwrap();
-    let mut controller = Controller::new(block, DummyTimesource::default());
-
-    let mut volume = controller.get_volume(VolumeIdx(0)).unwrap();
-    let dir = controller.open_root_dir(&volume).unwrap();
-
-    display.draw_rect(0, 0, 240, 240, Color(0, 0, 0));
-
-    if let Ok(mut file) =
-        controller.open_file_in_dir(&mut volume, &dir, "README.TXT", Mode::ReadOnly)
-    {
-        let mut buf = [0u8; 32];
-        let read_count = controller.read(&volume, &mut file, &mut buf).unwrap();
-
-        controller.close_file(&volume, file).unwrap();
-
-        if read_count >= 2 {
-            let text = unsafe { core::str::from_utf8_unchecked_mut(&mut buf[..read_count]) };
-            led_pin.set_low().unwrap();
+    #[cfg(not(feature = "sdio"))]
+    let card = {
+        let _sd_sclk = pins.gpio10.into_mode::<hal::gpio::FunctionSpi>();
+        let _sd_mosi = pins.gpio11.into_mode::<hal::gpio::FunctionSpi>();
+        let _sd_miso = pins.gpio12.into_mode::<hal::gpio::FunctionSpi>();
+        let cs = pins.gpio13.into_push_pull_output();
+        let spi = hal::Spi::<_, _, 8>::new(pac.SPI1);
+
+        // The card is identified at 400 kHz, `init` raises the clock after
+        // that, up to what SPI mode allows.
+        let spi = spi.init(
+            &mut pac.RESETS,
+            clocks.peripheral_clock.freq(),
+            sdproto::INIT_HZ.Hz(),
+            &embedded_hal::spi::MODE_0,
+        );
+
+        sdcard::SdCard::new(
+            spi,
+            cs,
+            clocks.peripheral_clock.freq().to_Hz(),
+            25_000_000,
+            Some(SpiDma::new(Bus::Spi1, dma2, dma3)),
+        )
+    };
+    #[cfg(feature = "sdio")]
+    let card = {
+        let _sd_clk = pins.gpio10.into_mode::<hal::gpio::FunctionPio1>();
+        let _sd_cmd = pins.gpio11.into_mode::<hal::gpio::FunctionPio1>();
+        let _sd_dat0 = pins.gpio6.into_mode::<hal::gpio::FunctionPio0>();
+        let _sd_dat1 = pins.gpio7.into_mode::<hal::gpio::FunctionPio0>();
+        let _sd_dat2 = pins.gpio8.into_mode::<hal::gpio::FunctionPio0>();
+        let _sd_dat3 = pins.gpio9.into_mode::<hal::gpio::FunctionPio0>();
+        sdio::SdCard::new(
+            pac.PIO0,
+            pac.PIO1,
+            &mut pac.RESETS,
+            clocks.system_clock.freq().to_Hz(),
+            12_500_000,
+        )
+    };
+    #[cfg(not(feature = "cd-polling"))]
+    let card_detect = card::CardDetect::new(pins.gpio22.into_pull_up_input());
+    #[cfg(feature = "cd-polling")]
+    let card_detect = card::CardDetect::new(&card);
+    let mut ram = ram::Allocator::new(APP_RAM_START, APP_RAM_SIZE);
+
+    // Cards that needed a slower clock before the last reset start there.
+    #[cfg(not(feature = "sdio"))]
+    let mut saved_clocks = {
+        let memory = flash_volume
+            .as_ref()
+            .and_then(flashfs::load_clocks)
+            .unwrap_or_default();
+        card.restore_clock_memory(memory);
+        memory
+    };
 
-            display.draw_rect(0, 0, 240, 240, black);
-            let size = 1;
+    loop {
+        // Stands in for a try block, any failure ends up on the error screen
+        // below and the card is read again from scratch.
+        let mut session = || -> Result<(), LoaderError> {
+            if !card_detect.is_present() {
+                if let Some(volume) = &flash_volume {
+                    flashfs::run_until_inserted(
+                        &mut display,
+                        &buttons,
+                        &mut delay,
+                        volume,
+                        &mut ram,
+                        || card_detect.is_inserted(),
+                    )?;
+                }
+            }
+            if !card_detect.is_present() {
+                ui::message(&mut display, "Insert SD card")?;
+                card_detect.wait_inserted(&mut delay);
+                ui::message(&mut display, "Reading SD card.")?;
+            }
+
+            // A different card may have been inserted since the last time.
+            card.init()?;
+            let mut controller = Controller::new(BlockCache::new(&card), clock::RtcTimeSource);
+
+            let partitions = partition::scan(controller.device()).unwrap_or_default();
+
+            // The config lives on the first volume and may point the images elsewhere.
+            let boot = partitions
+                .iter()
+                .find(|partition| partition.is_mountable())
+                .ok_or_else(|| {
+                    // FAT volumes in a GPT still hold files, the card must
+                    // not be offered for formatting as if it were blank.
+                    if partitions.iter().any(|partition| partition.fs.is_fat()) {
+                        LoaderError::GptFat
+                    } else {
+                        LoaderError::NoVolume
+                    }
+                })?;
+            let mut volume = fs::mount(&mut controller, boot)?;
+            let mut storage = fs::Storage {
+                controller: &mut controller,
+                volume: &mut volume,
+            };
+            let mut dir = storage.open_root()?;
+            let config = config::Config::load(&mut storage, &dir);
+
+            let chosen = config.volume.and_then(|index| {
+                partitions
+                    .iter()
+                    .find(|partition| partition.is_mountable() && partition.index == index)
+            });
+            let images = match chosen {
+                Some(partition) if partition.start != boot.start => {
+                    storage.close_dir(dir);
+                    *storage.volume = fs::mount(storage.controller, partition)?;
+                    dir = storage.open_root()?;
+                    partition
+                }
+                _ => boot,
+            };
+
+            if !rtc_carried {
+                clock::set_from_card(&mut storage, &dir);
+            }
+
+            if let Ok(mut file) = storage.open(&dir, "README.TXT") {
+                let shown = pager::show(
+                    &mut display,
+                    &buttons,
+                    &mut delay,
+                    &mut storage,
+                    &mut file,
+                    "README.TXT",
+                );
+                storage.close(file);
+                shown?;
+            }
+
+            // Runs until the card is pulled, then waits for the next one.
+            while card_detect.is_present() {
+                #[cfg(not(feature = "sdio"))]
+                keep_clocks(&card, flash_volume.as_ref(), &mut saved_clocks);
+                let labels = MENU.map(|(label, _)| label);
+                let screen = match ui::select_until(
+                    &mut display,
+                    &buttons,
+                    &mut delay,
+                    "Loader",
+                    &labels,
+                    || !card_detect.is_present(),
+                )? {
+                    Some(selected) => MENU[selected].1,
+                    None => continue,
+                };
+
+                match screen {
+                    Screen::Apps => {
+                        let selection = match browser::browse(
+                            &mut display,
+                            &buttons,
+                            &mut delay,
+                            &mut storage,
+                            &dir,
+                            "Apps",
+                            app::is_runnable,
+                        )? {
+                            Some(selection) => selection,
+                            None => continue,
+                        };
+
+                        // A copy that was cut short would load as garbage.
+                        let app_dir = selection.dir.as_ref().unwrap_or(&dir);
+                        let chain = storage.check_chain(app_dir, &selection.entry)?;
+                        let confirmed = chain == Chain::Intact
+                            || ui::confirm(
+                                &mut display,
+                                &buttons,
+                                &mut delay,
+                                "Damaged file",
+                                &[
+                                    "Its clusters do not match",
+                                    "its size, it may be cut short.",
+                                    "",
+                                    "Run it anyway?",
+                                ],
+                            )?;
+                        if !confirmed {
+                            if let Some(app_dir) = selection.dir {
+                                storage.close_dir(app_dir);
+                            }
+                            continue;
+                        }
+
+                        let result = app::launch(
+                            &mut display,
+                            &buttons,
+                            &mut storage,
+                            app_dir,
+                            &mut ram,
+                            &selection.entry,
+                        );
+                        if let Some(app_dir) = selection.dir {
+                            storage.close_dir(app_dir);
+                        }
+
+                        // The app may have left the panel and the card in any state.
+                        display.begin(&mut delay)?;
+                        storage.close_dir(dir);
+                        *storage.volume = fs::mount(storage.controller, images)?;
+                        dir = storage.open_root()?;
+
+                        match result {
+                            Ok(code) => {
+                                let mut text: String<32> = String::new();
+                                write!(text, "Exited with {}", code).ok();
+                                ui::message(&mut display, &text)?;
+                            }
+                            Err(LaunchError::Loader(err)) => return Err(err),
+                            Err(err) => ui::message(&mut display, err.message())?,
+                        }
+                        buttons.wait(&mut delay);
+                    }
+                    Screen::Text => {
+                        let selection = match browser::browse(
+                            &mut display,
+                            &buttons,
+                            &mut delay,
+                            &mut storage,
+                            &dir,
+                            "Text files",
+                            pager::is_text,
+                        )? {
+                            Some(selection) => selection,
+                            None => continue,
+                        };
+                        let text_dir = selection.dir.as_ref().unwrap_or(&dir);
+                        let shown = match storage.open_entry(text_dir, &selection.entry) {
+                            Ok(mut file) => {
+                                let shown = pager::show(
+                                    &mut display,
+                                    &buttons,
+                                    &mut delay,
+                                    &mut storage,
+                                    &mut file,
+                                    &selection.entry.label,
+                                );
+                                storage.close(file);
+                                shown
+                            }
+                            Err(err) => Err(err.into()),
+                        };
+                        if let Some(text_dir) = selection.dir {
+                            storage.close_dir(text_dir);
+                        }
+                        shown?;
+                    }
+                    Screen::Images => {
+                        let selection = match browser::browse(
+                            &mut display,
+                            &buttons,
+                            &mut delay,
+                            &mut storage,
+                            &dir,
+                            "Images",
+                            image::is_image,
+                        )? {
+                            Some(selection) => selection,
+                            None => continue,
+                        };
+                        let image_dir = selection.dir.as_ref().unwrap_or(&dir);
+                        let shown = match storage.open_entry(image_dir, &selection.entry) {
+                            Ok(mut file) => {
+                                let shown = image::show(
+                                    &mut display,
+                                    &buttons,
+                                    &mut delay,
+                                    &mut storage,
+                                    &mut ram,
+                                    &mut file,
+                                );
+                                storage.close(file);
+                                shown
+                            }
+                            Err(err) => Err(err.into()),
+                        };
+                        if let Some(image_dir) = selection.dir {
+                            storage.close_dir(image_dir);
+                        }
+                        match shown {
+                            Ok(()) => {}
+                            Err(ImageError::Loader(err)) => return Err(err),
+                            Err(err) => {
+                                ui::message(&mut display, err.message())?;
+                                buttons.wait(&mut delay);
+                            }
+                        }
+                    }
+                    Screen::Videos => {
+                        let selection = match browser::browse(
+                            &mut display,
+                            &buttons,
+                            &mut delay,
+                            &mut storage,
+                            &dir,
+                            "Videos",
+                            video::is_video,
+                        )? {
+                            Some(selection) => selection,
+                            None => continue,
+                        };
+                        let video_dir = selection.dir.as_ref().unwrap_or(&dir);
+                        let played = match storage.open_entry(video_dir, &selection.entry) {
+                            Ok(mut file) => {
+                                let played = video::play(
+                                    &mut display,
+                                    &buttons,
+                                    &mut delay,
+                                    &timer,
+                                    &mut storage,
+                                    &mut file,
+                                    config.fps.unwrap_or(video::FRAME_RATE),
+                                );
+                                storage.close(file);
+                                played
+                            }
+                            Err(err) => Err(err.into()),
+                        };
+                        if let Some(video_dir) = selection.dir {
+                            storage.close_dir(video_dir);
+                        }
+                        played?;
+                    }
+                    Screen::Files => {
+                        let serial = timer.get_counter_low();
+                        let formatted = files::manage(
+                            &mut display,
+                            &buttons,
+                            &mut delay,
+                            &mut storage,
+                            &dir,
+                            serial,
+                        )?;
+                        // The volumes are read again from scratch.
+                        if formatted {
+                            return Ok(());
+                        }
+                    }
+                    Screen::FlashFiles => match &flash_volume {
+                        Some(volume) => flashfs::manage(
+                            &mut display,
+                            &buttons,
+                            &mut delay,
+                            volume,
+                            &mut storage,
+                            &dir,
+                            &mut ram,
+                        )?,
+                        None => {
+                            ui::message(&mut display, "No flash volume.")?;
+                            buttons.wait(&mut delay);
+                        }
+                    },
+                    Screen::Check => {
+                        ui::message(&mut display, "Checking volume.")?;
+                        // The bitmap borrows app RAM, which is free between apps.
+                        let checked = ram.alloc(fsck::BITMAP_BYTES, 4).map(|base| {
+                            let bitmap = unsafe {
+                                slice::from_raw_parts_mut(base as *mut u8, fsck::BITMAP_BYTES)
+                            };
+                            let checked =
+                                fsck::check(storage.controller.device(), images.start, bitmap);
+                            ram.free(base);
+                            checked
+                        });
+                        match checked {
+                            None => {
+                                ui::message(&mut display, "Not enough memory.")?;
+                                buttons.wait(&mut delay);
+                            }
+                            Some(Ok(report)) => ui::info(
+                                &mut display,
+                                &buttons,
+                                &mut delay,
+                                "Check volume",
+                                &info::check(&report),
+                            )?,
+                            Some(Err(CheckError::Unsupported)) => {
+                                ui::message(&mut display, "Only FAT volumes are checked.")?;
+                                buttons.wait(&mut delay);
+                            }
+                            Some(Err(CheckError::Device(err))) => return Err(err.into()),
+                        }
+                    }
+                    Screen::Partitions => ui::info(
+                        &mut display,
+                        &buttons,
+                        &mut delay,
+                        "Partitions",
+                        &info::partitions(&partitions, images.index),
+                    )?,
+                    Screen::CardInfo => {
+                        ui::message(&mut display, "Reading SD card.")?;
+                        let cid = card.cid().ok();
+                        let csd = card.csd().ok();
+                        let speed_class = card.speed_class().ok().flatten();
+                        let usage = partition::usage(storage.controller.device(), images).ok();
+                        let mut lines = info::card(
+                            card.card_type(),
+                            cid.as_ref(),
+                            csd.as_ref(),
+                            speed_class,
+                            card.clock(),
+                            images,
+                            usage.as_ref(),
+                        );
+                        info::cache(&mut lines, &storage.controller.device().stats());
+                        ui::info(&mut display, &buttons, &mut delay, "SD card", &lines)?;
+                    }
+                    Screen::Benchmark => bench::run(
+                        &mut display,
+                        &buttons,
+                        &mut delay,
+                        &timer,
+                        &card,
+                        &mut storage,
+                        &dir,
+                    )?,
+                    Screen::FlashInfo => {
+                        let mut lines = info::flash(&flash_info);
+                        if let Some(Ok((free, total))) = flash_volume.as_ref().map(flashfs::usage) {
+                            info::flash_usage(&mut lines, free, total);
+                        }
+                        ui::info(&mut display, &buttons, &mut delay, "Flash", &lines)?;
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        let result = session();
+        #[cfg(not(feature = "sdio"))]
+        keep_clocks(&card, flash_volume.as_ref(), &mut saved_clocks);
+        if let Err(err) = result {
+            // A card the loader cannot read may be formatted from here.
+            let formattable = matches!(
+                err,
+                LoaderError::NoVolume | LoaderError::Fs(fs::FsError::Invalid)
+            );
+            let hint = if formattable {
+                "Select: format  Other: retry"
+            } else {
+                "Press a button to retry."
+            };
+            let shown = display
+                .begin(&mut delay)
+                .and_then(|()| ui::error(&mut display, &err, hint));
+            if shown.is_err() {
+                blink_code(&mut led_pin, &mut delay, err.code());
+            }
+            let pressed = buttons.wait_until(&mut delay, || !card_detect.is_present());
+            if formattable && pressed == Some(buttons::Button::Select) {
+                let serial = timer.get_counter_low();
+                if let Err(err) =
+                    files::format_card(&mut display, &buttons, &mut delay, &card, serial)
+                {
+                    ui::error(&mut display, &err, "Press a button to retry.").ok();
+                    buttons.wait_until(&mut delay, || !card_detect.is_present());
+                }
+            }
+        }
+    }
+}
 
-            let text_size = atm0130::text_size(text, size);
-            let x = 120 - text_size.0 / 2;
-            let y = 120 - text_size.1 / 2;
-            display.draw_rect(x, y, text_size.0, text_size.1, black);
-            display.draw_text(text, x, y, size, Color(255, 255, 255), black);
+/// Saves the card clocks to the flash volume once they have changed. Without
+/// the volume they are found again by falling back after the next reset.
+#[cfg(not(feature = "sdio"))]
+fn keep_clocks(card: &Card, volume: Option<&FlashFs>, saved: &mut sdproto::ClockMemory) {
+    let memory = card.clock_memory();
+    if memory == *saved {
+        return;
+    }
+    if let Some(volume) = volume {
+        if flashfs::save_clocks(volume, &memory).is_ok() {
+            *saved = memory;
         }
     }
-    delay.delay_ms(2000);
+}
 
+fn splash(display: &mut Display, delay: &mut Delay) -> Result<(), LoaderError> {
+    display.begin(delay)?;
+    ui::clear(display)?;
+    display.draw_logo(120 - artemis::IMG_WIDTH / 2, 120 - artemis::IMG_HEIGHT / 2)?;
+    delay.delay_ms(1000);
+    ui::message(display, "Reading SD card.")
+}
+
+/// Blinks the tens of `code`, pauses, then blinks the ones, forever. A zero
+/// is one long blink.
+fn blink_code(led: &mut LedPin, delay: &mut Delay, code: u8) -> ! {
     loop {
-        cortex_m::asm::wfi();
+        for digit in [code / 10, code % 10] {
+            if digit == 0 {
+                led.set_high().ok();
+                delay.delay_ms(BLINK_LONG_MS);
+                led.set_low().ok();
+                delay.delay_ms(BLINK_MS);
+            }
+            for _ in 0..digit {
+                led.set_high().ok();
+                delay.delay_ms(BLINK_MS);
+                led.set_low().ok();
+                delay.delay_ms(BLINK_MS);
+            }
+            delay.delay_ms(BLINK_LONG_MS);
+        }
+        delay.delay_ms(BLINK_PAUSE_MS);
     }
 }
 
@@ -0,0 +1,246 @@
+//! Text file viewer.
+//!
+//! The file is streamed in chunks and laid out a page at a time, wrapping at
+//! word boundaries. Only the offsets where pages start are kept, so Up can go
+//! back without holding the text in memory.
+
+use core::fmt::Write;
+
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use heapless::Vec;
+
+use crate::{
+    buttons::{Button, Buttons},
+    error::LoaderError,
+    fs::{File, FsError, Storage},
+    ui::{self, Line, BLACK, COLUMNS, ROWS, WHITE},
+    Display,
+};
+
+const CHUNK: usize = 256;
+/// Pages past this are not reachable, about 800 KiB of text.
+const MAX_PAGES: usize = 1024;
+const TAB_WIDTH: usize = 4;
+/// Stands in for characters the font does not have and bytes that are not
+/// valid UTF-8.
+const UNKNOWN: char = '?';
+
+pub fn is_text(extension: &str) -> bool {
+    extension == "TXT"
+}
+
+/// Shows `file` until Select or Back is pressed. Up and Down turn pages.
+pub fn show<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    storage: &mut Storage<D, T>,
+    file: &mut File,
+    title: &str,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut reader = Reader::new(file);
+    let mut pages: Vec<u32, MAX_PAGES> = Vec::new();
+    pages.push(0).ok();
+
+    ui::clear(display)?;
+    loop {
+        let start = pages.last().copied().unwrap_or(0);
+        reader.seek(storage, start)?;
+
+        let mut heading = Line::new();
+        write!(heading, "{} {}", title, pages.len()).ok();
+        ui::draw_title(display, &heading)?;
+        for row in 0..ROWS {
+            let line = reader.next_line(storage)?.unwrap_or_default();
+            ui::draw_line(display, row, &line, WHITE, BLACK)?;
+        }
+        let end = reader.at_end(storage)?;
+
+        match buttons.wait(delay) {
+            Button::Up => {
+                if pages.len() > 1 {
+                    pages.pop();
+                }
+            }
+            Button::Down => {
+                if !end {
+                    pages.push(reader.offset()).ok();
+                }
+            }
+            Button::Select | Button::Back => return Ok(()),
+        }
+    }
+}
+
+/// Buffered reading with a position that can be moved back within the file.
+struct Reader<'a> {
+    file: &'a mut File,
+    buf: [u8; CHUNK],
+    /// File offset of `buf[0]`.
+    start: u32,
+    len: usize,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(file: &'a mut File) -> Self {
+        Self {
+            file,
+            buf: [0; CHUNK],
+            start: 0,
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    fn offset(&self) -> u32 {
+        self.start + self.pos as u32
+    }
+
+    /// Stays within the buffer when it can.
+    fn seek<D, T>(&mut self, storage: &mut Storage<D, T>, offset: u32) -> Result<(), FsError>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if offset >= self.start && offset <= self.start + self.len as u32 {
+            self.pos = (offset - self.start) as usize;
+            return Ok(());
+        }
+        self.file.seek_from_start(offset)?;
+        self.start = offset;
+        self.len = 0;
+        self.pos = 0;
+        self.fill(storage)
+    }
+
+    fn fill<D, T>(&mut self, storage: &mut Storage<D, T>) -> Result<(), FsError>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        self.start += self.len as u32;
+        self.pos = 0;
+        self.len = if self.file.eof() {
+            0
+        } else {
+            storage.read(self.file, &mut self.buf)?
+        };
+        Ok(())
+    }
+
+    fn peek_byte<D, T>(&mut self, storage: &mut Storage<D, T>) -> Result<Option<u8>, FsError>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if self.pos == self.len {
+            self.fill(storage)?;
+        }
+        Ok(self.buf[..self.len].get(self.pos).copied())
+    }
+
+    fn at_end<D, T>(&mut self, storage: &mut Storage<D, T>) -> Result<bool, FsError>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        Ok(self.peek_byte(storage)?.is_none())
+    }
+
+    /// Decodes one character. A broken sequence is replaced and only its
+    /// first byte consumed, so the next character is not lost.
+    fn next_char<D, T>(&mut self, storage: &mut Storage<D, T>) -> Result<Option<char>, FsError>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        let first = match self.peek_byte(storage)? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        self.pos += 1;
+        let len = match first {
+            0x00..=0x7F => return Ok(Some(first as char)),
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return Ok(Some(char::REPLACEMENT_CHARACTER)),
+        };
+        let mut sequence = [first, 0, 0, 0];
+        for byte in &mut sequence[1..len] {
+            match self.peek_byte(storage)? {
+                Some(next @ 0x80..=0xBF) => {
+                    *byte = next;
+                    self.pos += 1;
+                }
+                _ => return Ok(Some(char::REPLACEMENT_CHARACTER)),
+            }
+        }
+        // Catches overlong forms and surrogates.
+        let decoded = core::str::from_utf8(&sequence[..len])
+            .ok()
+            .and_then(|text| text.chars().next());
+        Ok(Some(decoded.unwrap_or(char::REPLACEMENT_CHARACTER)))
+    }
+
+    /// Lays out the next line, breaking after the last space that fits.
+    /// `None` at the end of the file.
+    fn next_line<D, T>(&mut self, storage: &mut Storage<D, T>) -> Result<Option<Line>, FsError>
+    where
+        D: BlockDevice,
+        T: TimeSource,
+    {
+        if self.at_end(storage)? {
+            return Ok(None);
+        }
+        let mut line = Line::new();
+        // Length of the line and file offset after its last space.
+        let mut wrap: Option<(usize, u32)> = None;
+        loop {
+            let before = self.offset();
+            let c = match self.next_char(storage)? {
+                Some(c) => c,
+                None => break,
+            };
+            match c {
+                '\n' => break,
+                '\r' => continue,
+                _ if line.len() == COLUMNS => {
+                    if c != ' ' {
+                        match wrap {
+                            Some((len, offset)) => {
+                                line.truncate(len);
+                                self.seek(storage, offset)?;
+                            }
+                            // A word wider than the screen is cut.
+                            None => self.seek(storage, before)?,
+                        }
+                    }
+                    break;
+                }
+                '\t' => {
+                    while line.len() < COLUMNS && line.push(' ').is_ok() {
+                        if line.len() % TAB_WIDTH == 0 {
+                            break;
+                        }
+                    }
+                }
+                c if c.is_control() => continue,
+                c => {
+                    let shown = if (' '..='~').contains(&c) { c } else { UNKNOWN };
+                    line.push(shown).ok();
+                }
+            }
+            if matches!(c, ' ' | '\t') {
+                wrap = Some((line.len(), self.offset()));
+            }
+        }
+        Ok(Some(line))
+    }
+}
@@ -0,0 +1,318 @@
+//! MBR and GPT partition tables.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+use heapless::{String, Vec};
+
+pub const MAX_PARTITIONS: usize = 8;
+/// Only the four primary MBR entries can be mounted as FAT volumes, exFAT
+/// is read by our own reader and can live anywhere.
+pub const MAX_VOLUMES: u8 = 4;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_GPT: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_ENTRY_SIZE: usize = 128;
+/// The table is normally 128 entries in 32 blocks, that is all we look at.
+const GPT_MAX_BLOCKS: u32 = 32;
+const GPT_NAME_CHARS: usize = 36;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FsKind {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+    Unknown,
+}
+
+impl FsKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsKind::Fat12 => "FAT12",
+            FsKind::Fat16 => "FAT16",
+            FsKind::Fat32 => "FAT32",
+            FsKind::ExFat => "exFAT",
+            FsKind::Unknown => "?",
+        }
+    }
+
+    pub fn is_fat(&self) -> bool {
+        matches!(self, FsKind::Fat12 | FsKind::Fat16 | FsKind::Fat32)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+pub struct Partition {
+    pub scheme: Scheme,
+    /// Position in the partition table, for MBR this is the `VolumeIdx`.
+    pub index: u8,
+    pub start: u32,
+    pub blocks: u32,
+    pub fs: FsKind,
+    /// Volume label, or the GPT partition name if the volume has none.
+    pub label: String<16>,
+}
+
+impl Partition {
+    pub fn is_mountable(&self) -> bool {
+        match self.fs {
+            FsKind::ExFat => true,
+            fs => fs.is_fat() && self.scheme == Scheme::Mbr && self.index < MAX_VOLUMES,
+        }
+    }
+
+    pub fn size_mib(&self) -> u32 {
+        self.blocks / (1024 * 1024 / Block::LEN_U32)
+    }
+}
+
+pub struct Usage {
+    pub cluster_size: u32,
+    pub clusters: u32,
+    /// `None` if it cannot be worked out without walking the whole volume.
+    pub free_clusters: Option<u32>,
+}
+
+impl Usage {
+    pub fn free_mib(&self) -> Option<u32> {
+        let free = self.free_clusters? as u64 * self.cluster_size as u64;
+        Some((free / (1024 * 1024)) as u32)
+    }
+}
+
+/// Lists the partitions on the card. A protective MBR is followed to the GPT.
+pub fn scan<D: BlockDevice>(device: &D) -> Result<Vec<Partition, MAX_PARTITIONS>, D::Error> {
+    let mut block = [Block::new()];
+    device.read(&mut block, BlockIdx(0), "mbr")?;
+    let mbr = &block[0].contents;
+
+    let mut partitions = Vec::new();
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(partitions);
+    }
+
+    let mut entries = [(0u8, 0u32, 0u32); 4];
+    for (index, entry) in entries.iter_mut().enumerate() {
+        let raw = &mbr[MBR_TABLE + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        *entry = (raw[4], u32_at(raw, 8), u32_at(raw, 12));
+    }
+    if entries.iter().any(|&(kind, _, _)| kind == MBR_TYPE_GPT) {
+        scan_gpt(device, &mut partitions)?;
+        return Ok(partitions);
+    }
+
+    for (index, &(kind, start, blocks)) in entries.iter().enumerate() {
+        if kind == 0 || blocks == 0 || MBR_TYPES_EXTENDED.contains(&kind) {
+            continue;
+        }
+        let (fs, label) = probe(device, start)?;
+        partitions
+            .push(Partition {
+                scheme: Scheme::Mbr,
+                index: index as u8,
+                start,
+                blocks,
+                fs,
+                label,
+            })
+            .ok();
+    }
+    Ok(partitions)
+}
+
+fn scan_gpt<D: BlockDevice>(
+    device: &D,
+    partitions: &mut Vec<Partition, MAX_PARTITIONS>,
+) -> Result<(), D::Error> {
+    let mut block = [Block::new()];
+    device.read(&mut block, BlockIdx(1), "gpt")?;
+    let header = &block[0].contents;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(());
+    }
+    let table_lba = u32_at(header, 72);
+    let count = u32_at(header, 80) as usize;
+    if u32_at(header, 84) as usize != GPT_ENTRY_SIZE {
+        return Ok(());
+    }
+
+    let per_block = Block::LEN / GPT_ENTRY_SIZE;
+    let blocks = count.div_ceil(per_block) as u32;
+    for offset in 0..blocks.min(GPT_MAX_BLOCKS) {
+        device.read(&mut block, BlockIdx(table_lba + offset), "gpt")?;
+        for (slot, raw) in block[0].contents.chunks_exact(GPT_ENTRY_SIZE).enumerate() {
+            let index = offset as usize * per_block + slot;
+            if index >= count {
+                return Ok(());
+            }
+            if raw[..16].iter().all(|&byte| byte == 0) {
+                continue;
+            }
+            let start = u32_at(raw, 32);
+            let blocks = u32_at(raw, 40).wrapping_sub(start).wrapping_add(1);
+
+            let mut name = String::new();
+            for unit in raw[56..56 + 2 * GPT_NAME_CHARS].chunks_exact(2) {
+                match u16::from_le_bytes([unit[0], unit[1]]) {
+                    0 => break,
+                    unit @ 0x20..=0x7E => name.push(unit as u8 as char).ok(),
+                    _ => name.push('?').ok(),
+                };
+            }
+
+            let (fs, label) = probe(device, start)?;
+            let partition = Partition {
+                scheme: Scheme::Gpt,
+                index: index.min(u8::MAX as usize) as u8,
+                start,
+                blocks,
+                fs,
+                label: if label.is_empty() { name } else { label },
+            };
+            if partitions.push(partition).is_err() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the boot sector at `start` and works out the file system and label.
+fn probe<D: BlockDevice>(device: &D, start: u32) -> Result<(FsKind, String<16>), D::Error> {
+    let mut block = [Block::new()];
+    device.read(&mut block, BlockIdx(start), "probe")?;
+    let boot = &block[0].contents;
+
+    let (fs, label) = if &boot[3..11] == b"EXFAT   " {
+        (FsKind::ExFat, None)
+    } else if boot[510..512] != MBR_SIGNATURE {
+        (FsKind::Unknown, None)
+    } else if &boot[0x52..0x57] == b"FAT32" {
+        (FsKind::Fat32, Some(&boot[0x47..0x52]))
+    } else if &boot[0x36..0x3B] == b"FAT16" {
+        (FsKind::Fat16, Some(&boot[0x2B..0x36]))
+    } else if &boot[0x36..0x3B] == b"FAT12" {
+        (FsKind::Fat12, Some(&boot[0x2B..0x36]))
+    } else {
+        (FsKind::Unknown, None)
+    };
+
+    let mut text = String::new();
+    if let Some(label) = label {
+        let label = core::str::from_utf8(label).unwrap_or("").trim_end();
+        if label != "NO NAME" {
+            text.push_str(label).ok();
+        }
+    }
+    Ok((fs, text))
+}
+
+/// Cluster size and free space of the file system on `partition`. FAT32 uses
+/// the free count in FSInfo if it is valid, FAT16 counts free FAT entries and
+/// exFAT only records how much is in use in percent.
+pub fn usage<D: BlockDevice>(device: &D, partition: &Partition) -> Result<Usage, D::Error> {
+    let mut block = [Block::new()];
+    device.read(&mut block, BlockIdx(partition.start), "usage")?;
+    let boot = &block[0].contents;
+
+    if partition.fs == FsKind::ExFat {
+        let clusters = u32_at(boot, 92);
+        let in_use = boot[112];
+        return Ok(Usage {
+            cluster_size: Block::LEN_U32 << boot[109].min(16),
+            clusters,
+            free_clusters: (in_use <= 100)
+                .then(|| (clusters as u64 * (100 - in_use) as u64 / 100) as u32),
+        });
+    }
+
+    let sectors_per_cluster = boot[13] as u32;
+    let reserved = u16_at(boot, 14) as u32;
+    let fats = boot[16] as u32;
+    let root_sectors = (u16_at(boot, 17) as u32 * 32).div_ceil(Block::LEN_U32);
+    let total = match u16_at(boot, 19) {
+        0 => u32_at(boot, 32),
+        total => total as u32,
+    };
+    let fat_size = match u16_at(boot, 22) {
+        0 => u32_at(boot, 36),
+        size => size as u32,
+    };
+    let data = total.saturating_sub(reserved + fats * fat_size + root_sectors);
+    let clusters = data / sectors_per_cluster.max(1);
+    let fsinfo = u16_at(boot, 48) as u32;
+    let cluster_size = sectors_per_cluster * Block::LEN_U32;
+
+    let free_clusters = match partition.fs {
+        FsKind::Fat32 => {
+            device.read(&mut block, BlockIdx(partition.start + fsinfo), "fsinfo")?;
+            let info = &block[0].contents;
+            let free = u32_at(info, 488);
+            let valid = u32_at(info, 0) == 0x4161_5252 && u32_at(info, 484) == 0x6141_7272;
+            match (valid && free <= clusters).then_some(free) {
+                Some(free) => Some(free),
+                None => Some(count_free(device, partition.start + reserved, clusters, 4)?),
+            }
+        }
+        FsKind::Fat16 => Some(count_free(device, partition.start + reserved, clusters, 2)?),
+        _ => None,
+    };
+    Ok(Usage {
+        cluster_size,
+        clusters,
+        free_clusters,
+    })
+}
+
+/// Counts zero entries in the FAT starting at block `fat`. The first two
+/// entries are reserved.
+fn count_free<D: BlockDevice>(
+    device: &D,
+    fat: u32,
+    clusters: u32,
+    entry_size: usize,
+) -> Result<u32, D::Error> {
+    let mut block = [Block::new()];
+    let entries = clusters as usize + 2;
+    let per_block = Block::LEN / entry_size;
+    let mut free = 0;
+    for index in 0..entries.div_ceil(per_block) {
+        device.read(&mut block, BlockIdx(fat + index as u32), "fat")?;
+        let first = index * per_block;
+        for (slot, entry) in block[0].contents.chunks_exact(entry_size).enumerate() {
+            let cluster = first + slot;
+            if cluster < 2 || cluster >= entries {
+                continue;
+            }
+            // FAT32 entries only use the low 28 bits.
+            let is_free = match entry_size {
+                4 => u32_at(entry, 0) & 0x0FFF_FFFF == 0,
+                _ => u16_at(entry, 0) == 0,
+            };
+            free += u32::from(is_free);
+        }
+    }
+    Ok(free)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
@@ -0,0 +1,333 @@
+//! PNG decoding, one scanline at a time.
+//!
+//! Every colour type and bit depth is handled, interlaced images are not.
+//! Sixteen-bit samples are cut to their high byte.
+
+use crate::inflate::{self, Inflate};
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// Part of the work buffer given to the inflate window, the two scanlines
+/// being unfiltered take the rest.
+pub const WINDOW: usize = 32 * 1024;
+
+const GRAY: u8 = 0;
+const RGB: u8 = 2;
+const INDEXED: u8 = 3;
+const GRAY_ALPHA: u8 = 4;
+const RGBA: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Not a PNG file, or a damaged one.
+    Format,
+    /// Interlaced, or too wide for the work buffer.
+    Unsupported,
+    Read(E),
+}
+
+impl<E> From<inflate::Error<Error<E>>> for Error<E> {
+    fn from(err: inflate::Error<Error<E>>) -> Self {
+        match err {
+            inflate::Error::Format | inflate::Error::TooFar => Error::Format,
+            inflate::Error::Read(err) => err,
+        }
+    }
+}
+
+pub struct Decoder<'w, R> {
+    read: R,
+    width: u32,
+    height: u32,
+    color: u8,
+    depth: u8,
+    /// RGBA, the alpha comes from a tRNS chunk.
+    palette: [[u8; 4]; 256],
+    inflate: Inflate<'w>,
+    /// The previous and the current scanline.
+    lines: &'w mut [u8],
+    stride: usize,
+    /// Distance to the same channel of the previous pixel, for the filters.
+    pixel_bytes: usize,
+    /// Which half of `lines` holds the current scanline.
+    current: usize,
+    y: u32,
+    /// IDAT bytes left before the next chunk header.
+    chunk_left: u32,
+}
+
+impl<'w, R, E> Decoder<'w, R>
+where
+    R: FnMut() -> Result<u8, E>,
+{
+    /// Reads the chunks up to the image data. `work` holds the inflate window
+    /// and two scanlines.
+    pub fn new(mut read: R, work: &'w mut [u8]) -> Result<Self, Error<E>> {
+        let mut signature = [0; 8];
+        for byte in &mut signature {
+            *byte = read().map_err(Error::Read)?;
+        }
+        if signature != SIGNATURE {
+            return Err(Error::Format);
+        }
+
+        let mut header: Option<(u32, u32, u8, u8)> = None;
+        let mut palette = [[0, 0, 0, 255]; 256];
+        let mut colors = 0;
+        let chunk_left = loop {
+            let len = u32_be(&mut read)?;
+            let mut kind = [0; 4];
+            for byte in &mut kind {
+                *byte = read().map_err(Error::Read)?;
+            }
+            let mut skip = len;
+            match &kind {
+                b"IHDR" => {
+                    if len != 13 || header.is_some() {
+                        return Err(Error::Format);
+                    }
+                    let width = u32_be(&mut read)?;
+                    let height = u32_be(&mut read)?;
+                    let depth = read().map_err(Error::Read)?;
+                    let color = read().map_err(Error::Read)?;
+                    let compression = read().map_err(Error::Read)?;
+                    let filter = read().map_err(Error::Read)?;
+                    let interlace = read().map_err(Error::Read)?;
+                    let valid = match color {
+                        GRAY => matches!(depth, 1 | 2 | 4 | 8 | 16),
+                        INDEXED => matches!(depth, 1 | 2 | 4 | 8),
+                        RGB | GRAY_ALPHA | RGBA => matches!(depth, 8 | 16),
+                        _ => false,
+                    };
+                    if !valid || width == 0 || height == 0 || compression != 0 || filter != 0 {
+                        return Err(Error::Format);
+                    }
+                    if interlace != 0 {
+                        return Err(Error::Unsupported);
+                    }
+                    header = Some((width, height, color, depth));
+                    skip = 0;
+                }
+                b"PLTE" => {
+                    if len % 3 != 0 || len > 3 * 256 {
+                        return Err(Error::Format);
+                    }
+                    colors = len as usize / 3;
+                    for entry in &mut palette[..colors] {
+                        for channel in &mut entry[..3] {
+                            *channel = read().map_err(Error::Read)?;
+                        }
+                    }
+                    skip = 0;
+                }
+                // Only the palette alpha is used, a colour key is ignored.
+                b"tRNS" if matches!(header, Some((_, _, INDEXED, _))) => {
+                    if len > 256 {
+                        return Err(Error::Format);
+                    }
+                    for entry in &mut palette[..len as usize] {
+                        entry[3] = read().map_err(Error::Read)?;
+                    }
+                    skip = 0;
+                }
+                b"IDAT" => break len,
+                b"IEND" => return Err(Error::Format),
+                // Unknown critical chunks change how the image reads.
+                _ if kind[0].is_ascii_uppercase() => return Err(Error::Unsupported),
+                _ => {}
+            }
+            if header.is_none() {
+                return Err(Error::Format);
+            }
+            for _ in 0..skip + 4 {
+                read().map_err(Error::Read)?;
+            }
+        };
+
+        let (width, height, color, depth) = header.ok_or(Error::Format)?;
+        if color == INDEXED && colors == 0 {
+            return Err(Error::Format);
+        }
+        let channels = match color {
+            GRAY | INDEXED => 1,
+            GRAY_ALPHA => 2,
+            RGB => 3,
+            _ => 4,
+        };
+        let bits = width as u64 * channels * depth as u64;
+        let stride = bits.div_ceil(8) as usize;
+        if work.len() < WINDOW || (work.len() - WINDOW) / 2 < stride {
+            return Err(Error::Unsupported);
+        }
+        let (window, lines) = work.split_at_mut(WINDOW);
+        let lines = &mut lines[..2 * stride];
+        lines.fill(0);
+
+        Ok(Self {
+            read,
+            width,
+            height,
+            color,
+            depth,
+            palette,
+            inflate: Inflate::new(window),
+            lines,
+            stride,
+            pixel_bytes: (channels as usize * depth as usize).div_ceil(8),
+            current: 0,
+            y: 0,
+            chunk_left,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Decodes the next scanline, `false` once all of them have been.
+    pub fn next_row(&mut self) -> Result<bool, Error<E>> {
+        if self.y == self.height {
+            return Ok(false);
+        }
+        let Self {
+            read,
+            inflate,
+            chunk_left,
+            ..
+        } = self;
+        let mut input = || idat_byte(read, chunk_left);
+
+        let mut filter = [0];
+        if inflate.read(&mut input, &mut filter)? != 1 {
+            return Err(Error::Format);
+        }
+        self.current ^= 1;
+        let (first, second) = self.lines.split_at_mut(self.stride);
+        let (line, previous) = if self.current == 0 {
+            (first, &*second)
+        } else {
+            (second, &*first)
+        };
+        if inflate.read(&mut input, line)? != line.len() {
+            return Err(Error::Format);
+        }
+
+        let step = self.pixel_bytes;
+        match filter[0] {
+            0 => {}
+            // Sub
+            1 => {
+                for i in step..line.len() {
+                    line[i] = line[i].wrapping_add(line[i - step]);
+                }
+            }
+            // Up
+            2 => {
+                for (byte, &above) in line.iter_mut().zip(previous) {
+                    *byte = byte.wrapping_add(above);
+                }
+            }
+            // Average
+            3 => {
+                for i in 0..line.len() {
+                    let left = if i >= step { line[i - step] } else { 0 };
+                    let average = (left as u16 + previous[i] as u16) / 2;
+                    line[i] = line[i].wrapping_add(average as u8);
+                }
+            }
+            // Paeth
+            4 => {
+                for i in 0..line.len() {
+                    let (left, corner) = if i >= step {
+                        (line[i - step], previous[i - step])
+                    } else {
+                        (0, 0)
+                    };
+                    line[i] = line[i].wrapping_add(paeth(left, previous[i], corner));
+                }
+            }
+            _ => return Err(Error::Format),
+        }
+        self.y += 1;
+        Ok(true)
+    }
+
+    /// Pixel `x` of the last decoded scanline as RGBA.
+    pub fn pixel(&self, x: u32) -> [u8; 4] {
+        let line = &self.lines[self.current * self.stride..][..self.stride];
+        let x = x as usize;
+        if self.depth < 8 {
+            let bit = x * self.depth as usize;
+            let mask = (1u8 << self.depth) - 1;
+            let sample = line[bit / 8] >> (8 - self.depth as usize - bit % 8) & mask;
+            return match self.color {
+                INDEXED => self.palette[sample as usize],
+                _ => {
+                    let gray = (sample as u16 * 255 / mask as u16) as u8;
+                    [gray, gray, gray, 255]
+                }
+            };
+        }
+        let size = self.depth as usize / 8;
+        let sample = |channel: usize| line[x * self.pixel_bytes + channel * size];
+        match self.color {
+            GRAY => [sample(0), sample(0), sample(0), 255],
+            GRAY_ALPHA => [sample(0), sample(0), sample(0), sample(1)],
+            RGB => [sample(0), sample(1), sample(2), 255],
+            RGBA => [sample(0), sample(1), sample(2), sample(3)],
+            _ => self.palette[sample(0) as usize],
+        }
+    }
+}
+
+/// The next byte of image data, which may be split over several IDAT chunks.
+fn idat_byte<R, E>(read: &mut R, chunk_left: &mut u32) -> Result<u8, Error<E>>
+where
+    R: FnMut() -> Result<u8, E>,
+{
+    while *chunk_left == 0 {
+        // The CRC of the chunk just finished.
+        for _ in 0..4 {
+            read().map_err(Error::Read)?;
+        }
+        let len = u32_be(read)?;
+        let mut kind = [0; 4];
+        for byte in &mut kind {
+            *byte = read().map_err(Error::Read)?;
+        }
+        if &kind != b"IDAT" {
+            return Err(Error::Format);
+        }
+        *chunk_left = len;
+    }
+    *chunk_left -= 1;
+    read().map_err(Error::Read)
+}
+
+fn u32_be<R, E>(read: &mut R) -> Result<u32, Error<E>>
+where
+    R: FnMut() -> Result<u8, E>,
+{
+    let mut value = 0;
+    for _ in 0..4 {
+        value = value << 8 | read().map_err(Error::Read)? as u32;
+    }
+    Ok(value)
+}
+
+fn paeth(left: u8, above: u8, corner: u8) -> u8 {
+    let estimate = left as i16 + above as i16 - corner as i16;
+    let to_left = (estimate - left as i16).abs();
+    let to_above = (estimate - above as i16).abs();
+    let to_corner = (estimate - corner as i16).abs();
+    if to_left <= to_above && to_left <= to_corner {
+        left
+    } else if to_above <= to_corner {
+        above
+    } else {
+        corner
+    }
+}
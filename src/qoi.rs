@@ -0,0 +1,109 @@
+//! QOI decoding, one pixel at a time (<https://qoiformat.org>).
+
+pub const MAGIC: [u8; 4] = *b"qoif";
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_MASK: u8 = 0xC0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+    /// Not a QOI file.
+    Format,
+    Read(E),
+}
+
+pub struct Decoder<R> {
+    read: R,
+    width: u32,
+    height: u32,
+    /// Recently seen pixels by hash.
+    index: [[u8; 4]; 64],
+    pixel: [u8; 4],
+    /// Repeats of `pixel` still to come.
+    run: u8,
+}
+
+impl<R, E> Decoder<R>
+where
+    R: FnMut() -> Result<u8, E>,
+{
+    /// Reads the header from `read`, which then supplies the pixel data.
+    pub fn new(mut read: R) -> Result<Self, Error<E>> {
+        let mut header = [0; 14];
+        for byte in &mut header {
+            *byte = read().map_err(Error::Read)?;
+        }
+        let width = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let height = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let channels = header[12];
+        if header[..4] != MAGIC || width == 0 || height == 0 || !(3..=4).contains(&channels) {
+            return Err(Error::Format);
+        }
+        Ok(Self {
+            read,
+            width,
+            height,
+            index: [[0; 4]; 64],
+            pixel: [0, 0, 0, 255],
+            run: 0,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The next pixel as RGBA, rows top to bottom.
+    pub fn next_pixel(&mut self) -> Result<[u8; 4], Error<E>> {
+        if self.run > 0 {
+            self.run -= 1;
+            return Ok(self.pixel);
+        }
+        let op = self.byte()?;
+        let [r, g, b, a] = self.pixel;
+        self.pixel = match op {
+            OP_RGB => [self.byte()?, self.byte()?, self.byte()?, a],
+            OP_RGBA => [self.byte()?, self.byte()?, self.byte()?, self.byte()?],
+            _ => match op & OP_MASK {
+                OP_INDEX => self.index[op as usize],
+                OP_DIFF => [
+                    r.wrapping_add(op >> 4 & 3).wrapping_sub(2),
+                    g.wrapping_add(op >> 2 & 3).wrapping_sub(2),
+                    b.wrapping_add(op & 3).wrapping_sub(2),
+                    a,
+                ],
+                OP_LUMA => {
+                    let next = self.byte()?;
+                    let dg = (op & 0x3F).wrapping_sub(32);
+                    [
+                        r.wrapping_add(dg).wrapping_add(next >> 4).wrapping_sub(8),
+                        g.wrapping_add(dg),
+                        b.wrapping_add(dg).wrapping_add(next & 0x0F).wrapping_sub(8),
+                        a,
+                    ]
+                }
+                // The run length, this pixel is the first of it.
+                _ => {
+                    self.run = op & 0x3F;
+                    self.pixel
+                }
+            },
+        };
+        let [r, g, b, a] = self.pixel;
+        let hash = (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64;
+        self.index[hash] = self.pixel;
+        Ok(self.pixel)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error<E>> {
+        (self.read)().map_err(Error::Read)
+    }
+}
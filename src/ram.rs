@@ -0,0 +1,68 @@
+use heapless::Vec;
+
+const MAX_REGIONS: usize = 8;
+
+/// First-fit allocator handing out pieces of the app RAM region.
+pub struct Allocator {
+    start: usize,
+    end: usize,
+    /// `(address, size)` of every allocation, sorted by address.
+    regions: Vec<(usize, usize), MAX_REGIONS>,
+}
+
+impl Allocator {
+    pub fn new(start: usize, size: usize) -> Self {
+        Self {
+            start,
+            end: start + size,
+            regions: Vec::new(),
+        }
+    }
+
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut candidate = align_up(self.start, align);
+        for &(addr, len) in self.regions.iter() {
+            if candidate + size <= addr {
+                break;
+            }
+            candidate = align_up(addr + len, align);
+        }
+        if candidate + size > self.end {
+            return None;
+        }
+        self.insert(candidate, size)
+    }
+
+    /// Reserves `size` bytes at a fixed `addr`, for images that are not
+    /// position independent.
+    pub fn claim(&mut self, addr: usize, size: usize) -> Option<usize> {
+        if addr < self.start || addr + size > self.end {
+            return None;
+        }
+        let overlaps = self
+            .regions
+            .iter()
+            .any(|&(other, len)| addr < other + len && other < addr + size);
+        if overlaps {
+            return None;
+        }
+        self.insert(addr, size)
+    }
+
+    pub fn free(&mut self, addr: usize) {
+        if let Some(index) = self.regions.iter().position(|&(other, _)| other == addr) {
+            self.regions.swap_remove(index);
+            self.regions.sort_unstable();
+        }
+    }
+
+    fn insert(&mut self, addr: usize, size: usize) -> Option<usize> {
+        self.regions.push((addr, size)).ok()?;
+        self.regions.sort_unstable();
+        Some(addr)
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
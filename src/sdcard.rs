@@ -0,0 +1,488 @@
+//! SD card in SPI mode.
+//!
+//! Replaces `embedded_sdmmc::SdMmcSpi` so the loader can read the card
+//! registers and control the bus clock.
+//!
+//! The card is identified at 400 kHz and then run as fast as its CSD allows.
+//! CRCs are checked both ways, and transfers that fail the way a bus that is
+//! too fast does are repeated at the next slower clock, which is remembered
+//! for that card. With a flash volume that outlasts a reset, see
+//! `flashfs::save_clocks`.
+
+use core::cell::{Cell, RefCell};
+
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::OutputPin,
+};
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use fugit::RateExtU32;
+use rp_pico::hal::{
+    gpio::{bank0::Gpio13, Pin, PushPullOutput},
+    pac,
+    spi::Enabled,
+    Spi,
+};
+
+use crate::{
+    dma::{self, SpiDma},
+    sdproto::{
+        command_frame, crc16, slower, speed_class, CardType, Cid, ClockMemory, Csd, SdError,
+        ACMD13, ACMD41, CMD0, CMD10, CMD12, CMD13, CMD16, CMD17, CMD18, CMD24, CMD55, CMD58, CMD59,
+        CMD8, CMD9, IF_COND, INIT_HZ,
+    },
+};
+
+pub type SdSpi = Spi<Enabled, pac::SPI1, 8>;
+pub type SdCs = Pin<Gpio13, PushPullOutput>;
+
+const R1_READY: u8 = 0x00;
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_CRC_ERROR: u8 = 0x08;
+
+const DATA_START: u8 = 0xFE;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_CRC_ERROR: u8 = 0x0B;
+
+const CMD0_RETRIES: u32 = 32;
+const R1_RETRIES: u32 = 64;
+const TOKEN_RETRIES: u32 = 100_000;
+const BUSY_RETRIES: u32 = 1_000_000;
+/// ACMD41 is repeated every millisecond for up to a second.
+const INIT_RETRIES: u32 = 1000;
+const INIT_RETRY_CYCLES: u32 = 125_000;
+
+pub struct SdCard {
+    spi: RefCell<SdSpi>,
+    cs: RefCell<SdCs>,
+    peripheral_hz: u32,
+    target_hz: u32,
+    clock_hz: Cell<u32>,
+    /// Set by `set_clock`, errors then do not lower the clock.
+    fixed_clock: Cell<bool>,
+    card_type: Cell<Option<CardType>>,
+    cid: Cell<[u8; 16]>,
+    clocks: RefCell<ClockMemory>,
+    /// Moves data blocks, the blocking path is used without it.
+    dma: Option<SpiDma>,
+}
+
+impl SdCard {
+    /// `target_hz` is the highest clock used once the card is initialised.
+    pub fn new(
+        spi: SdSpi,
+        cs: SdCs,
+        peripheral_hz: u32,
+        target_hz: u32,
+        dma: Option<SpiDma>,
+    ) -> Self {
+        Self {
+            spi: RefCell::new(spi),
+            cs: RefCell::new(cs),
+            peripheral_hz,
+            target_hz,
+            clock_hz: Cell::new(0),
+            fixed_clock: Cell::new(false),
+            card_type: Cell::new(None),
+            cid: Cell::new([0; 16]),
+            clocks: RefCell::new(ClockMemory::new()),
+            dma,
+        }
+    }
+
+    /// Brings the card from power up or any previous state to transfer mode.
+    pub fn init(&self) -> Result<CardType, SdError> {
+        self.card_type.set(None);
+        self.fixed_clock.set(false);
+        self.change_clock(INIT_HZ);
+        {
+            let mut spi = self.spi.borrow_mut();
+            self.cs.borrow_mut().set_high().map_err(|_| SdError::Bus)?;
+            // At least 74 clocks with CS high put the card into SPI mode.
+            let mut idle = [0xFF; 10];
+            transfer(&mut spi, &mut idle)?;
+        }
+        let card_type = self.transaction(init_card)?;
+
+        let mut cid = [0; 16];
+        let mut csd = [0; 16];
+        self.transaction(|spi| {
+            read_register(spi, CMD10, &mut cid)?;
+            read_register(spi, CMD9, &mut csd)
+        })?;
+        self.cid.set(cid);
+        let hz = match self.clocks.borrow().get(&Cid(cid)) {
+            Some(hz) => hz,
+            None => Csd(csd).max_clock().max(INIT_HZ),
+        };
+        self.change_clock(hz.min(self.target_hz));
+        self.card_type.set(Some(card_type));
+        Ok(card_type)
+    }
+
+    pub fn card_type(&self) -> Option<CardType> {
+        self.card_type.get()
+    }
+
+    /// Actual SPI clock, the closest the divider gets below the requested one.
+    pub fn clock(&self) -> u32 {
+        self.clock_hz.get()
+    }
+
+    /// Runs the bus at `hz` until the next `init`, without falling back on
+    /// errors.
+    pub fn set_clock(&self, hz: u32) {
+        self.fixed_clock.set(true);
+        self.change_clock(hz);
+    }
+
+    /// Clocks lowered so far, for keeping them across resets.
+    pub fn clock_memory(&self) -> ClockMemory {
+        *self.clocks.borrow()
+    }
+
+    /// Takes over clocks kept from before a reset.
+    pub fn restore_clock_memory(&self, memory: ClockMemory) {
+        self.clocks.replace(memory);
+    }
+
+    fn change_clock(&self, hz: u32) {
+        let actual = self
+            .spi
+            .borrow_mut()
+            .set_baudrate(self.peripheral_hz.Hz(), hz.Hz());
+        self.clock_hz.set(actual.to_Hz());
+    }
+
+    pub fn cid(&self) -> Result<Cid, SdError> {
+        self.initialised()?;
+        let mut cid = [0; 16];
+        self.transaction(|spi| read_register(spi, CMD10, &mut cid))?;
+        Ok(Cid(cid))
+    }
+
+    pub fn csd(&self) -> Result<Csd, SdError> {
+        self.initialised()?;
+        let mut csd = [0; 16];
+        self.transaction(|spi| read_register(spi, CMD9, &mut csd))?;
+        Ok(Csd(csd))
+    }
+
+    /// Speed class from the SD status register.
+    pub fn speed_class(&self) -> Result<Option<u8>, SdError> {
+        self.initialised()?;
+        let mut status = [0; 64];
+        self.transaction(|spi| {
+            // A card that refuses the command sends no data block to wait for.
+            check_r1(app_command(spi, ACMD13, 0)?)?;
+            // R2, the second byte is the rest of the card status.
+            xfer(spi, 0xFF)?;
+            read_data(spi, None, &mut status)
+        })?;
+        Ok(speed_class(&status))
+    }
+
+    /// Asks for the card status, fails once the card is gone.
+    pub fn check(&self) -> Result<(), SdError> {
+        self.initialised()?;
+        self.transaction(|spi| {
+            check_r1(command(spi, CMD13, 0)?)?;
+            match xfer(spi, 0xFF)? {
+                0 => Ok(()),
+                status => Err(SdError::Response(status)),
+            }
+        })
+    }
+
+    fn initialised(&self) -> Result<CardType, SdError> {
+        self.card_type.get().ok_or(SdError::NotInitialised)
+    }
+
+    /// Standard capacity cards are addressed in bytes.
+    fn address(&self, block: u32) -> Result<u32, SdError> {
+        match self.initialised()? {
+            CardType::Sdhc => Ok(block),
+            _ => Ok(block * Block::LEN_U32),
+        }
+    }
+
+    /// Runs `f` again at slower clocks while it fails with errors a slower
+    /// clock may cure, and remembers the clock that worked for the card.
+    fn with_fallback<R, F>(&self, mut f: F) -> Result<R, SdError>
+    where
+        F: FnMut() -> Result<R, SdError>,
+    {
+        loop {
+            let err = match f() {
+                Err(err) if !self.fixed_clock.get() && is_transfer_error(err) => err,
+                result => return result,
+            };
+            let hz = slower(self.clock()).ok_or(err)?;
+            self.change_clock(hz);
+            self.clocks
+                .borrow_mut()
+                .set(&Cid(self.cid.get()), self.clock());
+        }
+    }
+
+    fn transaction<R, F>(&self, f: F) -> Result<R, SdError>
+    where
+        F: FnOnce(&mut SdSpi) -> Result<R, SdError>,
+    {
+        let mut spi = self.spi.borrow_mut();
+        let mut cs = self.cs.borrow_mut();
+        cs.set_low().map_err(|_| SdError::Bus)?;
+        let result = f(&mut spi);
+        cs.set_high().map_err(|_| SdError::Bus)?;
+        // The card only releases MISO on the next clock.
+        xfer(&mut spi, 0xFF)?;
+        result
+    }
+}
+
+impl BlockDevice for SdCard {
+    type Error = SdError;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, _reason: &str) -> Result<(), SdError> {
+        let address = self.address(start.0)?;
+        self.with_fallback(|| {
+            if let [block] = &mut *blocks {
+                return self.transaction(|spi| {
+                    check_r1(command(spi, CMD17, address)?)?;
+                    read_data(spi, self.dma.as_ref(), &mut block.contents)
+                });
+            }
+            // One command for the whole run, the card advances the address.
+            self.transaction(|spi| {
+                check_r1(command(spi, CMD18, address)?)?;
+                let result = blocks
+                    .iter_mut()
+                    .try_for_each(|block| read_data(spi, self.dma.as_ref(), &mut block.contents));
+                let stopped = stop_transmission(spi);
+                result.and(stopped)
+            })
+        })
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), SdError> {
+        for (offset, block) in blocks.iter().enumerate() {
+            let address = self.address(start.0 + offset as u32)?;
+            self.with_fallback(|| {
+                self.transaction(|spi| {
+                    check_r1(command(spi, CMD24, address)?)?;
+                    write_data(spi, self.dma.as_ref(), &block.contents)
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, SdError> {
+        let capacity = self.csd()?.capacity();
+        Ok(BlockCount((capacity / Block::LEN as u64) as u32))
+    }
+}
+
+/// The controller borrows the card while the loader keeps it across card
+/// swaps.
+impl BlockDevice for &SdCard {
+    type Error = SdError;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, reason: &str) -> Result<(), SdError> {
+        <SdCard as BlockDevice>::read(self, blocks, start, reason)
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), SdError> {
+        <SdCard as BlockDevice>::write(self, blocks, start)
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, SdError> {
+        <SdCard as BlockDevice>::num_blocks(self)
+    }
+}
+
+fn init_card(spi: &mut SdSpi) -> Result<CardType, SdError> {
+    let mut attempts = 0;
+    while command(spi, CMD0, 0)? != R1_IDLE {
+        attempts += 1;
+        if attempts == CMD0_RETRIES {
+            return Err(SdError::Timeout);
+        }
+    }
+
+    // Version 2 cards echo the check pattern, version 1 cards do not know CMD8.
+    let version2 = if command(spi, CMD8, IF_COND)? & R1_ILLEGAL_COMMAND == 0 {
+        let mut r7 = [0xFF; 4];
+        transfer(spi, &mut r7)?;
+        if r7[3] != 0xAA {
+            return Err(SdError::Unsupported);
+        }
+        true
+    } else {
+        false
+    };
+
+    // From here on the card checks the CRC of commands and written data.
+    check_r1(command(spi, CMD59, 1)?)?;
+
+    let arg = if version2 { 0x4000_0000 } else { 0 };
+    let mut attempts = 0;
+    loop {
+        match app_command(spi, ACMD41, arg)? {
+            R1_READY => break,
+            R1_IDLE => {}
+            r1 => return Err(SdError::Response(r1)),
+        }
+        attempts += 1;
+        if attempts == INIT_RETRIES {
+            return Err(SdError::Timeout);
+        }
+        cortex_m::asm::delay(INIT_RETRY_CYCLES);
+    }
+
+    if !version2 {
+        check_r1(command(spi, CMD16, Block::LEN_U32)?)?;
+        return Ok(CardType::Sd1);
+    }
+    check_r1(command(spi, CMD58, 0)?)?;
+    let mut ocr = [0xFF; 4];
+    transfer(spi, &mut ocr)?;
+    if ocr[0] & 0x40 != 0 {
+        Ok(CardType::Sdhc)
+    } else {
+        check_r1(command(spi, CMD16, Block::LEN_U32)?)?;
+        Ok(CardType::Sd2)
+    }
+}
+
+/// Sends a command and returns its R1 response.
+fn command(spi: &mut SdSpi, cmd: u8, arg: u32) -> Result<u8, SdError> {
+    if cmd != CMD0 {
+        wait_ready(spi)?;
+    }
+    let mut frame = command_frame(cmd, arg);
+    transfer(spi, &mut frame)?;
+
+    for _ in 0..R1_RETRIES {
+        let r1 = xfer(spi, 0xFF)?;
+        if r1 & 0x80 == 0 {
+            return Ok(r1);
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+/// Ends a multiple block read. The byte after CMD12 is a stuff byte, and the
+/// card may be busy for a while after its R1.
+fn stop_transmission(spi: &mut SdSpi) -> Result<(), SdError> {
+    let mut frame = command_frame(CMD12, 0);
+    transfer(spi, &mut frame)?;
+    xfer(spi, 0xFF)?;
+
+    for _ in 0..R1_RETRIES {
+        let r1 = xfer(spi, 0xFF)?;
+        if r1 & 0x80 == 0 {
+            check_r1(r1)?;
+            return wait_ready(spi);
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+fn app_command(spi: &mut SdSpi, acmd: u8, arg: u32) -> Result<u8, SdError> {
+    let r1 = command(spi, CMD55, 0)?;
+    if r1 & !R1_IDLE != 0 {
+        return Err(SdError::Response(r1));
+    }
+    command(spi, acmd, arg)
+}
+
+/// Errors that garbled bits on the bus explain.
+fn is_transfer_error(err: SdError) -> bool {
+    match err {
+        SdError::Crc | SdError::DataToken(_) => true,
+        SdError::Response(r1) => r1 & R1_CRC_ERROR != 0,
+        SdError::WriteRejected(response) => response == DATA_CRC_ERROR,
+        _ => false,
+    }
+}
+
+fn check_r1(r1: u8) -> Result<(), SdError> {
+    match r1 {
+        R1_READY => Ok(()),
+        r1 => Err(SdError::Response(r1)),
+    }
+}
+
+fn read_register(spi: &mut SdSpi, cmd: u8, buf: &mut [u8; 16]) -> Result<(), SdError> {
+    check_r1(command(spi, cmd, 0)?)?;
+    read_data(spi, None, buf)
+}
+
+fn read_data(spi: &mut SdSpi, dma: Option<&SpiDma>, buf: &mut [u8]) -> Result<(), SdError> {
+    let mut token = 0xFF;
+    for _ in 0..TOKEN_RETRIES {
+        token = xfer(spi, 0xFF)?;
+        if token != 0xFF {
+            break;
+        }
+    }
+    match token {
+        DATA_START => {}
+        0xFF => return Err(SdError::Timeout),
+        token => return Err(SdError::DataToken(token)),
+    }
+    match dma {
+        Some(dma) if buf.len() >= dma::MIN_LEN => dma.read(buf),
+        _ => {
+            buf.fill(0xFF);
+            transfer(spi, buf)?;
+        }
+    }
+    let mut crc = [0xFF; 2];
+    transfer(spi, &mut crc)?;
+    if u16::from_be_bytes(crc) != crc16(buf) {
+        return Err(SdError::Crc);
+    }
+    Ok(())
+}
+
+fn write_data(
+    spi: &mut SdSpi,
+    dma: Option<&SpiDma>,
+    data: &[u8; Block::LEN],
+) -> Result<(), SdError> {
+    let mut token = [0xFF, DATA_START];
+    transfer(spi, &mut token)?;
+    match dma {
+        Some(dma) => dma.write(data),
+        None => spi.write(data).map_err(|_| SdError::Bus)?,
+    }
+    let mut crc = crc16(data).to_be_bytes();
+    transfer(spi, &mut crc)?;
+    match xfer(spi, 0xFF)? & 0x1F {
+        DATA_ACCEPTED => wait_ready(spi),
+        response => Err(SdError::WriteRejected(response)),
+    }
+}
+
+/// Waits for the card to stop holding MISO low while it is busy.
+fn wait_ready(spi: &mut SdSpi) -> Result<(), SdError> {
+    for _ in 0..BUSY_RETRIES {
+        if xfer(spi, 0xFF)? == 0xFF {
+            return Ok(());
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+fn xfer(spi: &mut SdSpi, byte: u8) -> Result<u8, SdError> {
+    let mut buf = [byte];
+    transfer(spi, &mut buf)?;
+    Ok(buf[0])
+}
+
+fn transfer(spi: &mut SdSpi, buf: &mut [u8]) -> Result<(), SdError> {
+    spi.transfer(buf).map(|_| ()).map_err(|_| SdError::Bus)
+}
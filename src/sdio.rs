@@ -0,0 +1,737 @@
+//! SD card in 4-bit SDIO mode, driven by PIO.
+//!
+//! Built instead of the SPI driver with the `sdio` feature. SDIO needs all
+//! four data lines, so the socket is wired differently: CLK on GP10, CMD on
+//! GP11 and DAT0-DAT3 on GP6-GP9.
+//!
+//! One state machine of PIO1 toggles the clock and another one shifts
+//! commands and responses. PIO0 receives and sends data. The command and data
+//! machines run from the system clock and follow the clock pin, which keeps
+//! the programs short but limits the bus to a tenth of the system clock.
+//! Like `flash` and `dma` this uses the raw registers, the programs are
+//! assembled by hand below.
+
+use core::{cell::Cell, ptr};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use rp_pico::hal::pac;
+
+use crate::sdproto::{
+    check_status, command_words, crc16_4bit, crc7, r1_status, response_words, speed_class,
+    unpack_response, CardType, Cid, Csd, SdError, ACMD13, ACMD41, ACMD6, CMD0, CMD12, CMD13, CMD16,
+    CMD17, CMD18, CMD2, CMD24, CMD3, CMD55, CMD7, CMD8, CMD9, IF_COND, INIT_HZ,
+};
+
+pub const CLK_PIN: u8 = 10;
+pub const CMD_PIN: u8 = 11;
+/// DAT1-DAT3 follow on the next pins.
+pub const DAT0_PIN: u8 = 6;
+
+const PIO0_BASE: usize = 0x5020_0000;
+const PIO1_BASE: usize = 0x5030_0000;
+const CTRL: usize = 0x000;
+const FSTAT: usize = 0x004;
+const TXF0: usize = 0x010;
+const RXF0: usize = 0x020;
+const INSTR_MEM0: usize = 0x048;
+const SM0_CLKDIV: usize = 0x0C8;
+const SM_STRIDE: usize = 0x18;
+const SM_EXECCTRL: usize = 0x04;
+const SM_SHIFTCTRL: usize = 0x08;
+const SM_INSTR: usize = 0x10;
+const SM_PINCTRL: usize = 0x14;
+/// Atomic set and clear aliases of every peripheral register.
+const SET: usize = 0x2000;
+const CLR: usize = 0x3000;
+
+const CTRL_RESTART_SHIFT: u32 = 4;
+const CTRL_CLKDIV_RESTART_SHIFT: u32 = 8;
+const FSTAT_RXEMPTY_SHIFT: u32 = 8;
+const FSTAT_TXFULL_SHIFT: u32 = 16;
+const EXECCTRL_JMP_PIN_SHIFT: u32 = 24;
+const EXECCTRL_WRAP_TOP_SHIFT: u32 = 12;
+const EXECCTRL_WRAP_BOTTOM_SHIFT: u32 = 7;
+const SHIFTCTRL_FJOIN_RX: u32 = 1 << 31;
+const SHIFTCTRL_AUTOPULL: u32 = 1 << 17;
+const SHIFTCTRL_AUTOPUSH: u32 = 1 << 16;
+const PINCTRL_SET_COUNT_SHIFT: u32 = 26;
+const PINCTRL_OUT_COUNT_SHIFT: u32 = 20;
+const PINCTRL_IN_BASE_SHIFT: u32 = 15;
+const PINCTRL_SET_BASE_SHIFT: u32 = 5;
+/// Clock divider of 1, for the machines that follow the clock pin.
+const CLKDIV_ONE: u32 = 1 << 16;
+
+const PADS_BANK0_BASE: usize = 0x4001_C000;
+/// Input enabled, 8 mA, pull-up, Schmitt trigger, fast slew.
+const PAD_SD: u32 = 1 << 6 | 2 << 4 | 1 << 3 | 1 << 1 | 1;
+const SIO_GPIO_IN: usize = 0xD000_0004;
+
+const JMP_ALWAYS: u16 = 0;
+const JMP_NOT_X: u16 = 1;
+const JMP_X_DEC: u16 = 2;
+const JMP_PIN: u16 = 6;
+const OUT_PINS: u16 = 0;
+const OUT_X: u16 = 1;
+const OUT_NULL: u16 = 3;
+const SET_PINS: u16 = 0;
+const SET_X: u16 = 1;
+const SET_PINDIRS: u16 = 4;
+const PUSH_BLOCK: u16 = 0x8020;
+const PULL_BLOCK: u16 = 0x80A0;
+/// `mov y, y`
+const NOP: u16 = 0xA042;
+
+const fn jmp(condition: u16, address: u8) -> u16 {
+    condition << 5 | address as u16
+}
+
+const fn wait_clk(level: u16) -> u16 {
+    0x2000 | level << 7 | CLK_PIN as u16
+}
+
+/// Waits for the first input pin.
+const fn wait_in(level: u16) -> u16 {
+    0x2020 | level << 7
+}
+
+const fn in_pins(count: u16) -> u16 {
+    0x4000 | count & 0x1F
+}
+
+const fn out(destination: u16, count: u16) -> u16 {
+    0x6000 | destination << 5 | count & 0x1F
+}
+
+const fn set(destination: u16, data: u16) -> u16 {
+    0xE000 | destination << 5 | data
+}
+
+/// PIO1: the clock at 0, commands at 2.
+const CLK_START: u8 = 0;
+const CMD_START: u8 = 2;
+const PIO1_PROGRAM: [u16; 20] = [
+    set(SET_PINS, 1),
+    set(SET_PINS, 0),
+    // Bits to send minus one, then the bits. Output changes on the falling
+    // edge, the card samples on the rising one.
+    out(OUT_X, 32),
+    set(SET_PINDIRS, 1),
+    wait_clk(0),
+    out(OUT_PINS, 1),
+    wait_clk(1),
+    jmp(JMP_X_DEC, CMD_START + 2),
+    set(SET_PINDIRS, 0),
+    // Response bits after the start bit, zero for none.
+    out(OUT_X, 32),
+    jmp(JMP_NOT_X, CMD_START),
+    jmp(JMP_X_DEC, CMD_START + 10),
+    // Start bit.
+    wait_clk(0),
+    wait_clk(1),
+    jmp(JMP_PIN, CMD_START + 10),
+    wait_clk(0),
+    wait_clk(1),
+    in_pins(1),
+    jmp(JMP_X_DEC, CMD_START + 13),
+    PUSH_BLOCK,
+];
+
+/// PIO0: receiving at 0, sending at 8.
+const RX_START: u8 = 0;
+const TX_START: u8 = 8;
+const PIO0_PROGRAM: [u16; 23] = [
+    // Nibbles to receive minus one, then everything after the start bit.
+    PULL_BLOCK,
+    out(OUT_X, 32),
+    wait_in(0),
+    wait_clk(1),
+    wait_clk(0),
+    wait_clk(1),
+    in_pins(4),
+    jmp(JMP_X_DEC, RX_START + 4),
+    // Nibbles to send minus one, then the nibbles.
+    out(OUT_X, 32),
+    set(SET_PINDIRS, 0x0F),
+    wait_clk(0),
+    out(OUT_PINS, 4),
+    wait_clk(1),
+    jmp(JMP_X_DEC, TX_START + 2),
+    set(SET_PINDIRS, 0),
+    // The three bit CRC status on DAT0.
+    wait_in(0),
+    wait_clk(1),
+    set(SET_X, 2),
+    wait_clk(0),
+    wait_clk(1),
+    in_pins(1),
+    jmp(JMP_X_DEC, TX_START + 10),
+    PUSH_BLOCK,
+];
+
+struct Machine {
+    pio: usize,
+    sm: usize,
+    start: u8,
+    wrap_top: u8,
+    execctrl: u32,
+    shiftctrl: u32,
+    pinctrl: u32,
+    /// Runs after every reset to put the pins into their idle state.
+    idle: [u16; 2],
+}
+
+const CLK: Machine = Machine {
+    pio: PIO1_BASE,
+    sm: 0,
+    start: CLK_START,
+    wrap_top: CLK_START + 1,
+    execctrl: 0,
+    shiftctrl: 0,
+    pinctrl: 1 << PINCTRL_SET_COUNT_SHIFT | (CLK_PIN as u32) << PINCTRL_SET_BASE_SHIFT,
+    idle: [set(SET_PINS, 0), set(SET_PINDIRS, 1)],
+};
+
+const CMD: Machine = Machine {
+    pio: PIO1_BASE,
+    sm: 1,
+    start: CMD_START,
+    wrap_top: CMD_START + 17,
+    execctrl: (CMD_PIN as u32) << EXECCTRL_JMP_PIN_SHIFT,
+    shiftctrl: SHIFTCTRL_AUTOPULL | SHIFTCTRL_AUTOPUSH,
+    pinctrl: 1 << PINCTRL_SET_COUNT_SHIFT
+        | 1 << PINCTRL_OUT_COUNT_SHIFT
+        | (CMD_PIN as u32) << PINCTRL_IN_BASE_SHIFT
+        | (CMD_PIN as u32) << PINCTRL_SET_BASE_SHIFT
+        | CMD_PIN as u32,
+    idle: [set(SET_PINS, 1), set(SET_PINDIRS, 0)],
+};
+
+const RX: Machine = Machine {
+    pio: PIO0_BASE,
+    sm: 0,
+    start: RX_START,
+    wrap_top: RX_START + 7,
+    execctrl: 0,
+    shiftctrl: SHIFTCTRL_AUTOPUSH,
+    pinctrl: (DAT0_PIN as u32) << PINCTRL_IN_BASE_SHIFT,
+    idle: [NOP, NOP],
+};
+
+const TX: Machine = Machine {
+    pio: PIO0_BASE,
+    sm: 1,
+    start: TX_START,
+    wrap_top: TX_START + 14,
+    execctrl: 0,
+    shiftctrl: SHIFTCTRL_AUTOPULL,
+    pinctrl: 4 << PINCTRL_SET_COUNT_SHIFT
+        | 4 << PINCTRL_OUT_COUNT_SHIFT
+        | (DAT0_PIN as u32) << PINCTRL_IN_BASE_SHIFT
+        | (DAT0_PIN as u32) << PINCTRL_SET_BASE_SHIFT
+        | DAT0_PIN as u32,
+    idle: [set(SET_PINS, 0x0F), set(SET_PINDIRS, 0)],
+};
+
+/// Ten system clocks per bus clock, the data loops need about that many.
+const MIN_DIVIDER: u32 = 5;
+/// Bits after the start bit of R1, R3, R6 and R7, and of R2.
+const SHORT_BITS: usize = 47;
+const LONG_BITS: usize = 135;
+/// CRC16 of the four lines after the data.
+const CRC_NIBBLES: usize = 16;
+/// Idle nibbles and the start nibble, the data, CRC and end nibble.
+const WRITE_NIBBLES: u32 = 8 + 2 * Block::LEN as u32 + CRC_NIBBLES as u32 + 1;
+/// Leading idle nibbles and the start bit on every line.
+const WRITE_START: u32 = 0xFFFF_FFF0;
+const WRITE_END: u32 = 0xFFFF_FFFF;
+const WRITE_ACCEPTED: u32 = 0b010;
+/// Each machine has four words of FIFO.
+const FIFO_DEPTH: usize = 4;
+/// Received CRCs are checked after the transfer, a multi-block read is split
+/// into runs of this many blocks.
+const MAX_RUN: usize = 16;
+/// ACMD41 argument: 3.2-3.4 V.
+const OCR_VOLTAGE: u32 = 0x0030_0000;
+const OCR_HCS: u32 = 0x4000_0000;
+const OCR_READY: u32 = 0x8000_0000;
+const BUS_WIDTH_4: u32 = 2;
+
+const CMD_RETRIES: u32 = 100_000;
+/// The first word of a block waits for the card's access time.
+const DATA_RETRIES: u32 = 2_000_000;
+const WORD_RETRIES: u32 = 10_000;
+const BUSY_RETRIES: u32 = 5_000_000;
+/// Bus clocks until the card signals busy after a response or CRC status.
+const BUSY_START_CLOCKS: u32 = 8;
+/// ACMD41 is repeated every millisecond for up to a second.
+const INIT_RETRIES: u32 = 1000;
+const INIT_RETRY_CYCLES: u32 = 125_000;
+
+pub struct SdCard {
+    sys_hz: u32,
+    target_hz: u32,
+    clock_hz: Cell<u32>,
+    card_type: Cell<Option<CardType>>,
+    /// Relative card address, assigned during identification.
+    rca: Cell<u16>,
+    /// Both registers are only readable before the card is selected, so they
+    /// are kept from `init`.
+    cid: Cell<[u8; 16]>,
+    csd: Cell<[u8; 16]>,
+}
+
+impl SdCard {
+    /// Takes both PIO blocks out of reset. The pins have to be switched to
+    /// PIO0 for the data lines and PIO1 for CLK and CMD. `target_hz` is the
+    /// clock used once the card is initialised.
+    pub fn new(
+        _pio0: pac::PIO0,
+        _pio1: pac::PIO1,
+        resets: &mut pac::RESETS,
+        sys_hz: u32,
+        target_hz: u32,
+    ) -> Self {
+        resets
+            .reset
+            .modify(|_, w| w.pio0().clear_bit().pio1().clear_bit());
+        loop {
+            let done = resets.reset_done.read();
+            if done.pio0().bit_is_set() && done.pio1().bit_is_set() {
+                break;
+            }
+        }
+        Self {
+            sys_hz,
+            target_hz,
+            clock_hz: Cell::new(0),
+            card_type: Cell::new(None),
+            rca: Cell::new(0),
+            cid: Cell::new([0; 16]),
+            csd: Cell::new([0; 16]),
+        }
+    }
+
+    /// Brings the card from power up or any previous state to transfer mode
+    /// on the 4-bit bus.
+    pub fn init(&self) -> Result<CardType, SdError> {
+        self.card_type.set(None);
+        self.rca.set(0);
+        setup();
+        self.set_clock(INIT_HZ);
+        // At least 74 clocks before the first command.
+        cortex_m::asm::delay(INIT_RETRY_CYCLES);
+
+        self.command(CMD0, 0, 0, &mut [])?;
+        // Version 2 cards echo the check pattern, version 1 cards do not know
+        // CMD8 and stay silent.
+        let version2 = match self.short(CMD8, IF_COND) {
+            Ok(frame) => {
+                if r1_status(&frame, CMD8)? & 0xFFF != IF_COND {
+                    return Err(SdError::Unsupported);
+                }
+                true
+            }
+            Err(SdError::Timeout) => false,
+            Err(err) => return Err(err),
+        };
+
+        let arg = if version2 {
+            OCR_VOLTAGE | OCR_HCS
+        } else {
+            OCR_VOLTAGE
+        };
+        let mut attempts = 0;
+        let ocr = loop {
+            // A version 1 card reports the unknown CMD8 in this status.
+            r1_status(&self.short(CMD55, 0)?, CMD55)?;
+            // R3 carries no index and no CRC.
+            let frame = self.short(ACMD41, arg)?;
+            let ocr = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+            if ocr & OCR_READY != 0 {
+                break ocr;
+            }
+            attempts += 1;
+            if attempts == INIT_RETRIES {
+                return Err(SdError::Timeout);
+            }
+            cortex_m::asm::delay(INIT_RETRY_CYCLES);
+        };
+
+        self.cid.set(self.long(CMD2, 0)?);
+        let frame = self.short(CMD3, 0)?;
+        self.rca.set((r1_status(&frame, CMD3)? >> 16) as u16);
+        let rca = self.rca_arg();
+        self.csd.set(self.long(CMD9, rca)?);
+        self.r1(CMD7, rca)?;
+        self.wait_ready()?;
+        self.app(ACMD6, BUS_WIDTH_4)?;
+
+        let card_type = match (version2, ocr & OCR_HCS != 0) {
+            (true, true) => CardType::Sdhc,
+            (true, false) => CardType::Sd2,
+            (false, _) => CardType::Sd1,
+        };
+        if card_type != CardType::Sdhc {
+            self.r1(CMD16, Block::LEN_U32)?;
+        }
+        self.set_clock(self.target_hz);
+        self.card_type.set(Some(card_type));
+        Ok(card_type)
+    }
+
+    pub fn card_type(&self) -> Option<CardType> {
+        self.card_type.get()
+    }
+
+    /// Actual bus clock, the closest the divider gets below the requested one.
+    pub fn clock(&self) -> u32 {
+        self.clock_hz.get()
+    }
+
+    /// Changes the bus clock until the next `init`.
+    pub fn set_clock(&self, hz: u32) {
+        // 16.8 fixed point, and the clock program takes two cycles a period.
+        let divider = (self.sys_hz as u64 * 256)
+            .div_ceil(2 * hz as u64)
+            .max(MIN_DIVIDER as u64 * 256) as u32;
+        write(CLK.pio + sm_register(&CLK, 0), divider << 8);
+        write(
+            CLK.pio + SET + CTRL,
+            1 << (CTRL_CLKDIV_RESTART_SHIFT + CLK.sm as u32),
+        );
+        self.clock_hz
+            .set((self.sys_hz as u64 * 256 / (2 * divider as u64)) as u32);
+    }
+
+    pub fn cid(&self) -> Result<Cid, SdError> {
+        self.initialised()?;
+        Ok(Cid(self.cid.get()))
+    }
+
+    pub fn csd(&self) -> Result<Csd, SdError> {
+        self.initialised()?;
+        Ok(Csd(self.csd.get()))
+    }
+
+    /// Speed class from the SD status register.
+    pub fn speed_class(&self) -> Result<Option<u8>, SdError> {
+        self.initialised()?;
+        let mut status = [0; 64];
+        reset(&RX);
+        push(&RX, nibbles(status.len()))?;
+        self.app(ACMD13, 0)?;
+        let crc = cortex_m::interrupt::free(|_| receive(&mut status))?;
+        if crc != crc16_4bit(&status) {
+            return Err(SdError::Crc);
+        }
+        Ok(speed_class(&status))
+    }
+
+    /// Asks for the card status, fails once the card is gone.
+    pub fn check(&self) -> Result<(), SdError> {
+        self.initialised()?;
+        self.r1(CMD13, self.rca_arg()).map(|_| ())
+    }
+
+    fn initialised(&self) -> Result<CardType, SdError> {
+        self.card_type.get().ok_or(SdError::NotInitialised)
+    }
+
+    /// Standard capacity cards are addressed in bytes.
+    fn address(&self, block: u32) -> Result<u32, SdError> {
+        match self.initialised()? {
+            CardType::Sdhc => Ok(block),
+            _ => Ok(block * Block::LEN_U32),
+        }
+    }
+
+    fn rca_arg(&self) -> u32 {
+        (self.rca.get() as u32) << 16
+    }
+
+    /// Sends a command and unpacks a response of `bits` bits into `frame`. A
+    /// card that does not answer leaves the machine waiting for the start
+    /// bit, so it is reset.
+    fn command(&self, cmd: u8, arg: u32, bits: usize, frame: &mut [u8]) -> Result<(), SdError> {
+        let [first, second] = command_words(cmd, arg);
+        for word in [63, first, second, bits as u32] {
+            push(&CMD, word)?;
+        }
+        if bits == 0 {
+            return Ok(());
+        }
+        let mut words = [0; 5];
+        for word in &mut words[..response_words(bits)] {
+            *word = pop(&CMD, CMD_RETRIES).inspect_err(|_| reset(&CMD))?;
+        }
+        unpack_response(&words, bits, frame);
+        Ok(())
+    }
+
+    fn short(&self, cmd: u8, arg: u32) -> Result<[u8; 6], SdError> {
+        let mut frame = [0; 6];
+        self.command(cmd, arg, SHORT_BITS, &mut frame)?;
+        Ok(frame)
+    }
+
+    /// R2 with a CID or CSD, the CRC7 is part of the register.
+    fn long(&self, cmd: u8, arg: u32) -> Result<[u8; 16], SdError> {
+        let mut frame = [0; 17];
+        self.command(cmd, arg, LONG_BITS, &mut frame)?;
+        let mut register = [0; 16];
+        register.copy_from_slice(&frame[1..]);
+        if crc7(&register[..15]) != register[15] >> 1 {
+            return Err(SdError::Crc);
+        }
+        Ok(register)
+    }
+
+    /// Sends a command with an R1 response and checks the card status.
+    fn r1(&self, cmd: u8, arg: u32) -> Result<u32, SdError> {
+        let status = r1_status(&self.short(cmd, arg)?, cmd)?;
+        check_status(status)?;
+        Ok(status)
+    }
+
+    fn app(&self, acmd: u8, arg: u32) -> Result<u32, SdError> {
+        self.r1(CMD55, self.rca_arg())?;
+        self.r1(acmd, arg)
+    }
+
+    /// Waits for the card to release DAT0 after a write or an R1b response.
+    fn wait_ready(&self) -> Result<(), SdError> {
+        let clock = self.clock_hz.get().max(1);
+        cortex_m::asm::delay(BUSY_START_CLOCKS * (self.sys_hz / clock));
+        for _ in 0..BUSY_RETRIES {
+            let pins = unsafe { ptr::read_volatile(SIO_GPIO_IN as *const u32) };
+            if pins & 1 << DAT0_PIN != 0 {
+                return Ok(());
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    fn read_run(&self, blocks: &mut [Block], start: u32) -> Result<(), SdError> {
+        let address = self.address(start)?;
+        let count = blocks.len();
+        // The receiver has to be waiting before the card starts sending.
+        reset(&RX);
+        for _ in 0..count.min(FIFO_DEPTH) {
+            push(&RX, nibbles(Block::LEN))?;
+        }
+        let cmd = if count == 1 { CMD17 } else { CMD18 };
+        self.r1(cmd, address)?;
+
+        // The FIFO holds four words, so the CRCs are only compared once the
+        // whole run has arrived.
+        let mut crcs = [0; MAX_RUN];
+        let received = cortex_m::interrupt::free(|_| -> Result<(), SdError> {
+            for (index, block) in blocks.iter_mut().enumerate() {
+                if index + FIFO_DEPTH < count {
+                    push(&RX, nibbles(Block::LEN))?;
+                }
+                crcs[index] = receive(&mut block.contents)?;
+            }
+            Ok(())
+        });
+        if count > 1 {
+            let stopped = self.r1(CMD12, 0).and_then(|_| self.wait_ready());
+            received.and(stopped)?;
+        } else {
+            received?;
+        }
+        for (block, crc) in blocks.iter().zip(crcs) {
+            if crc16_4bit(&block.contents) != crc {
+                return Err(SdError::Crc);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for SdCard {
+    type Error = SdError;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, _reason: &str) -> Result<(), SdError> {
+        let mut index = start.0;
+        for run in blocks.chunks_mut(MAX_RUN) {
+            self.read_run(run, index)?;
+            index += run.len() as u32;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), SdError> {
+        for (offset, block) in blocks.iter().enumerate() {
+            let address = self.address(start.0 + offset as u32)?;
+            self.r1(CMD24, address)?;
+            match send(&block.contents)? {
+                WRITE_ACCEPTED => self.wait_ready()?,
+                status => return Err(SdError::WriteRejected(status as u8)),
+            }
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, SdError> {
+        let capacity = self.csd()?.capacity();
+        Ok(BlockCount((capacity / Block::LEN as u64) as u32))
+    }
+}
+
+/// The controller borrows the card while the loader keeps it across card
+/// swaps.
+impl BlockDevice for &SdCard {
+    type Error = SdError;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, reason: &str) -> Result<(), SdError> {
+        <SdCard as BlockDevice>::read(self, blocks, start, reason)
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), SdError> {
+        <SdCard as BlockDevice>::write(self, blocks, start)
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, SdError> {
+        <SdCard as BlockDevice>::num_blocks(self)
+    }
+}
+
+/// Loads the programs and starts all four machines with the clock running.
+fn setup() {
+    for pin in [
+        CLK_PIN,
+        CMD_PIN,
+        DAT0_PIN,
+        DAT0_PIN + 1,
+        DAT0_PIN + 2,
+        DAT0_PIN + 3,
+    ] {
+        write(PADS_BANK0_BASE + 4 + 4 * pin as usize, PAD_SD);
+    }
+    for (pio, program) in [
+        (PIO0_BASE, &PIO0_PROGRAM[..]),
+        (PIO1_BASE, &PIO1_PROGRAM[..]),
+    ] {
+        write(pio + CLR + CTRL, 0x0F);
+        for (index, &instruction) in program.iter().enumerate() {
+            write(pio + INSTR_MEM0 + 4 * index, instruction as u32);
+        }
+    }
+    for machine in [&CLK, &CMD, &RX, &TX] {
+        let execctrl = machine.execctrl
+            | (machine.wrap_top as u32) << EXECCTRL_WRAP_TOP_SHIFT
+            | (machine.start as u32) << EXECCTRL_WRAP_BOTTOM_SHIFT;
+        write(machine.pio + sm_register(machine, 0), CLKDIV_ONE);
+        write(machine.pio + sm_register(machine, SM_EXECCTRL), execctrl);
+        write(
+            machine.pio + sm_register(machine, SM_PINCTRL),
+            machine.pinctrl,
+        );
+        reset(machine);
+    }
+}
+
+/// Stops a machine, empties its FIFOs and shift registers and starts it again
+/// at the top of its program.
+fn reset(machine: &Machine) {
+    let bit = 1 << machine.sm;
+    let shiftctrl = machine.pio + sm_register(machine, SM_SHIFTCTRL);
+    write(machine.pio + CLR + CTRL, bit);
+    // Joining the FIFOs clears them. Without autopull the OUT below empties
+    // the OSR instead of waiting for a word.
+    let manual = machine.shiftctrl & !SHIFTCTRL_AUTOPULL;
+    write(shiftctrl, manual | SHIFTCTRL_FJOIN_RX);
+    write(shiftctrl, manual);
+    write(
+        machine.pio + SET + CTRL,
+        bit << CTRL_RESTART_SHIFT | bit << CTRL_CLKDIV_RESTART_SHIFT,
+    );
+    exec(machine, out(OUT_NULL, 32));
+    for instruction in machine.idle {
+        exec(machine, instruction);
+    }
+    exec(machine, jmp(JMP_ALWAYS, machine.start));
+    write(shiftctrl, machine.shiftctrl);
+    write(machine.pio + SET + CTRL, bit);
+}
+
+/// Receives `buf` and returns the CRC the card sent after it.
+fn receive(buf: &mut [u8]) -> Result<u64, SdError> {
+    for (index, chunk) in buf.chunks_exact_mut(4).enumerate() {
+        let retries = if index == 0 {
+            DATA_RETRIES
+        } else {
+            WORD_RETRIES
+        };
+        chunk.copy_from_slice(&pop(&RX, retries)?.to_be_bytes());
+    }
+    let high = pop(&RX, WORD_RETRIES)?;
+    let low = pop(&RX, WORD_RETRIES)?;
+    Ok((high as u64) << 32 | low as u64)
+}
+
+/// Sends a block and returns the CRC status the card answered with.
+fn send(data: &[u8; Block::LEN]) -> Result<u32, SdError> {
+    let crc = crc16_4bit(data);
+    reset(&TX);
+    // Running dry in the middle of the block would clock out stale nibbles.
+    cortex_m::interrupt::free(|_| -> Result<(), SdError> {
+        push(&TX, WRITE_NIBBLES - 1)?;
+        push(&TX, WRITE_START)?;
+        for chunk in data.chunks_exact(4) {
+            push(
+                &TX,
+                u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+            )?;
+        }
+        push(&TX, (crc >> 32) as u32)?;
+        push(&TX, crc as u32)?;
+        push(&TX, WRITE_END)
+    })?;
+    pop(&TX, DATA_RETRIES)
+}
+
+/// Count word for receiving `len` bytes and their CRC.
+fn nibbles(len: usize) -> u32 {
+    (2 * len + CRC_NIBBLES - 1) as u32
+}
+
+fn push(machine: &Machine, word: u32) -> Result<(), SdError> {
+    for _ in 0..WORD_RETRIES {
+        if read(machine.pio + FSTAT) & 1 << (FSTAT_TXFULL_SHIFT + machine.sm as u32) == 0 {
+            write(machine.pio + TXF0 + 4 * machine.sm, word);
+            return Ok(());
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+fn pop(machine: &Machine, retries: u32) -> Result<u32, SdError> {
+    for _ in 0..retries {
+        if read(machine.pio + FSTAT) & 1 << (FSTAT_RXEMPTY_SHIFT + machine.sm as u32) == 0 {
+            return Ok(read(machine.pio + RXF0 + 4 * machine.sm));
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+fn exec(machine: &Machine, instruction: u16) {
+    write(
+        machine.pio + sm_register(machine, SM_INSTR),
+        instruction as u32,
+    );
+}
+
+/// Offset of a state machine register, 0 is its clock divider.
+fn sm_register(machine: &Machine, register: usize) -> usize {
+    SM0_CLKDIV + machine.sm * SM_STRIDE + register
+}
+
+fn read(address: usize) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+fn write(address: usize, value: u32) {
+    unsafe { ptr::write_volatile(address as *mut u32, value) }
+}
@@ -0,0 +1,461 @@
+//! SD protocol shared by the SPI and SDIO drivers: card registers, command
+//! frames, responses and CRCs.
+//!
+//! Nothing in here touches the hardware, `tools/sdproto-test` runs it on the
+//! host. What only one driver needs is built for that driver alone, and for
+//! the host, which tests both.
+
+/// Cards have to be initialised at no more than 400 kHz.
+pub const INIT_HZ: u32 = 400_000;
+/// Clocks to step down through when transfers fail, down to `INIT_HZ`.
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub const FALLBACK_HZ: [u32; 5] = [12_500_000, 8_000_000, 4_000_000, 1_000_000, INIT_HZ];
+/// Cards whose lowered clock is remembered.
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub const REMEMBERED_CARDS: usize = 8;
+/// Length of `ClockMemory::to_bytes`, a CID and a little-endian clock for
+/// each card.
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub const CLOCK_MEMORY_LEN: usize = REMEMBERED_CARDS * CLOCK_RECORD_LEN;
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+const CLOCK_RECORD_LEN: usize = 16 + 4;
+
+pub const CMD0: u8 = 0;
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub const CMD2: u8 = 2;
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub const CMD3: u8 = 3;
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub const CMD7: u8 = 7;
+pub const CMD8: u8 = 8;
+pub const CMD9: u8 = 9;
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub const CMD10: u8 = 10;
+pub const CMD12: u8 = 12;
+pub const CMD13: u8 = 13;
+pub const CMD16: u8 = 16;
+pub const CMD17: u8 = 17;
+pub const CMD18: u8 = 18;
+pub const CMD24: u8 = 24;
+pub const CMD55: u8 = 55;
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub const CMD58: u8 = 58;
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub const CMD59: u8 = 59;
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub const ACMD6: u8 = 6;
+pub const ACMD13: u8 = 13;
+pub const ACMD41: u8 = 41;
+
+/// Argument of CMD8: 2.7-3.6 V and the check pattern the card echoes.
+pub const IF_COND: u32 = 0x1AA;
+
+/// Card status bits that report an error, everything but the state, the
+/// ready flag and the app command flag.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+const STATUS_ERRORS: u32 = 0xFFF9_0008;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdError {
+    NotInitialised,
+    /// The card did not answer, most likely there is none.
+    Timeout,
+    /// R1 of a command had an error bit set, in SD mode the byte of the card
+    /// status holding it.
+    Response(u8),
+    /// Error token instead of a data block.
+    #[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+    DataToken(u8),
+    WriteRejected(u8),
+    /// Not an SD card, or it does not support our voltage.
+    Unsupported,
+    /// The SPI peripheral or the chip select pin failed.
+    #[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+    Bus,
+    /// A response or data block arrived with a bad CRC.
+    Crc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardType {
+    /// Version 1.x standard capacity.
+    Sd1,
+    /// Version 2.0 standard capacity.
+    Sd2,
+    /// High or extended capacity, addressed in blocks.
+    Sdhc,
+}
+
+impl CardType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CardType::Sd1 => "SDSC v1",
+            CardType::Sd2 => "SDSC v2",
+            CardType::Sdhc => "SDHC/SDXC",
+        }
+    }
+}
+
+/// Card identification register.
+pub struct Cid(pub [u8; 16]);
+
+impl Cid {
+    pub fn manufacturer(&self) -> u8 {
+        self.0[0]
+    }
+
+    /// IDs are assigned by the SD Association and not published, these are
+    /// the commonly seen ones. Fakes often carry an unknown or zero ID.
+    pub fn manufacturer_name(&self) -> &'static str {
+        match self.0[0] {
+            0x01 => "Panasonic",
+            0x02 => "Toshiba",
+            0x03 => "SanDisk",
+            0x1B => "Samsung",
+            0x1D => "ADATA",
+            0x27 => "Phison",
+            0x28 => "Lexar",
+            0x31 => "Silicon Power",
+            0x41 => "Kingston",
+            0x74 => "Transcend",
+            0x76 => "Patriot",
+            0x82 => "Sony",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn oem(&self) -> &str {
+        ascii(&self.0[1..3])
+    }
+
+    pub fn product(&self) -> &str {
+        ascii(&self.0[3..8])
+    }
+
+    /// Product revision as major and minor.
+    pub fn revision(&self) -> (u8, u8) {
+        (self.0[8] >> 4, self.0[8] & 0x0F)
+    }
+
+    pub fn serial(&self) -> u32 {
+        u32::from_be_bytes([self.0[9], self.0[10], self.0[11], self.0[12]])
+    }
+
+    /// Manufacturing year and month.
+    pub fn date(&self) -> (u16, u8) {
+        let year = 2000 + ((self.0[13] as u16 & 0x0F) << 4 | self.0[14] as u16 >> 4);
+        (year, self.0[14] & 0x0F)
+    }
+}
+
+/// Card specific data register.
+pub struct Csd(pub [u8; 16]);
+
+impl Csd {
+    pub fn version(&self) -> u8 {
+        (self.0[0] >> 6) + 1
+    }
+
+    pub fn capacity(&self) -> u64 {
+        if self.version() == 1 {
+            let size = bits(&self.0, 73, 62) as u64;
+            let mult = bits(&self.0, 49, 47);
+            let block_len = bits(&self.0, 83, 80);
+            (size + 1) << (mult + 2 + block_len)
+        } else {
+            (bits(&self.0, 69, 48) as u64 + 1) * 512 * 1024
+        }
+    }
+
+    /// Card command classes, bit n set if class n is supported.
+    pub fn command_classes(&self) -> u16 {
+        bits(&self.0, 95, 84) as u16
+    }
+
+    /// Highest bus clock the card allows, from `TRAN_SPEED`.
+    pub fn max_clock(&self) -> u32 {
+        const UNITS: [u32; 4] = [10_000, 100_000, 1_000_000, 10_000_000];
+        const TENTHS: [u32; 16] = [
+            0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+        ];
+        let speed = self.0[3];
+        let unit = UNITS.get((speed & 0x07) as usize).copied().unwrap_or(0);
+        unit * TENTHS[(speed >> 3 & 0x0F) as usize]
+    }
+}
+
+/// Speed class from the SD status, `None` for class 0.
+pub fn speed_class(status: &[u8; 64]) -> Option<u8> {
+    match status[8] {
+        1 => Some(2),
+        2 => Some(4),
+        3 => Some(6),
+        4 => Some(10),
+        _ => None,
+    }
+}
+
+/// The next clock of `FALLBACK_HZ` below `hz`, `None` once there is none.
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub fn slower(hz: u32) -> Option<u32> {
+    FALLBACK_HZ.into_iter().find(|&step| step < hz)
+}
+
+/// Clocks that had to be lowered, by card identification, so a card that
+/// comes back starts at the clock that worked for it. The driver keeps it in
+/// RAM, `to_bytes` and `from_bytes` carry it across resets.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub struct ClockMemory {
+    cards: [Option<([u8; 16], u32)>; REMEMBERED_CARDS],
+    /// Replaced when a new card does not fit.
+    oldest: usize,
+}
+
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+impl ClockMemory {
+    pub const fn new() -> Self {
+        Self {
+            cards: [None; REMEMBERED_CARDS],
+            oldest: 0,
+        }
+    }
+
+    pub fn get(&self, cid: &Cid) -> Option<u32> {
+        self.cards
+            .iter()
+            .flatten()
+            .find(|(known, _)| *known == cid.0)
+            .map(|&(_, hz)| hz)
+    }
+
+    pub fn set(&mut self, cid: &Cid, hz: u32) {
+        let slot = match self
+            .cards
+            .iter()
+            .flatten()
+            .position(|(known, _)| *known == cid.0)
+        {
+            Some(slot) => slot,
+            None => match self.cards.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None => {
+                    let slot = self.oldest;
+                    self.oldest = (slot + 1) % REMEMBERED_CARDS;
+                    slot
+                }
+            },
+        };
+        self.cards[slot] = Some((cid.0, hz));
+    }
+
+    /// The cards stored first come first, so `from_bytes` replaces them
+    /// first again. A zero clock marks an unused record.
+    pub fn to_bytes(&self) -> [u8; CLOCK_MEMORY_LEN] {
+        let mut bytes = [0; CLOCK_MEMORY_LEN];
+        let oldest_first = self.cards[self.oldest..]
+            .iter()
+            .chain(&self.cards[..self.oldest])
+            .flatten();
+        for (record, (cid, hz)) in bytes.chunks_exact_mut(CLOCK_RECORD_LEN).zip(oldest_first) {
+            record[..16].copy_from_slice(cid);
+            record[16..].copy_from_slice(&hz.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads what `to_bytes` wrote. Records cut off at the end of `bytes`
+    /// and clocks well below `INIT_HZ`, which the driver never sets, are left
+    /// out.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut memory = Self::new();
+        let records = bytes.chunks_exact(CLOCK_RECORD_LEN).map(|record| {
+            let mut cid = [0; 16];
+            cid.copy_from_slice(&record[..16]);
+            let hz = u32::from_le_bytes([record[16], record[17], record[18], record[19]]);
+            (cid, hz)
+        });
+        for (slot, record) in memory
+            .cards
+            .iter_mut()
+            .zip(records.filter(|&(_, hz)| hz >= INIT_HZ / 2))
+        {
+            *slot = Some(record);
+        }
+        memory
+    }
+}
+
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+impl Default for ClockMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Command frame with start, transmission and end bits and CRC7.
+pub fn command_frame(cmd: u8, arg: u32) -> [u8; 6] {
+    let mut frame = [0x40 | cmd, 0, 0, 0, 0, 0];
+    frame[1..5].copy_from_slice(&arg.to_be_bytes());
+    frame[5] = crc7(&frame[..5]) << 1 | 1;
+    frame
+}
+
+/// The command frame as the SDIO state machine shifts it out, most
+/// significant bit first. 16 idle bits pad it to two words.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub fn command_words(cmd: u8, arg: u32) -> [u32; 2] {
+    let frame = command_frame(cmd, arg);
+    [
+        0xFFFF_0000 | (frame[0] as u32) << 8 | frame[1] as u32,
+        u32::from_be_bytes([frame[2], frame[3], frame[4], frame[5]]),
+    ]
+}
+
+/// Words the state machine pushes for a response of `bits` bits after the
+/// start bit: full words and then one with the remaining bits at the bottom.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub const fn response_words(bits: usize) -> usize {
+    bits / 32 + 1
+}
+
+/// Puts the response bits back into the frame they were sent as, starting
+/// with the start bit, so `frame` takes `(bits + 1) / 8` bytes.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub fn unpack_response(words: &[u32], bits: usize, frame: &mut [u8]) {
+    frame.fill(0);
+    let last = bits % 32;
+    for i in 0..bits {
+        let word = words[i / 32];
+        let shift = if i / 32 == bits / 32 {
+            last - 1 - i % 32
+        } else {
+            31 - i % 32
+        };
+        let bit = (word >> shift & 1) as u8;
+        frame[(i + 1) / 8] |= bit << (7 - (i + 1) % 8);
+    }
+}
+
+/// Card status from an R1 response to `cmd`, after checking its index and
+/// CRC.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub fn r1_status(frame: &[u8; 6], cmd: u8) -> Result<u32, SdError> {
+    if frame[0] & 0x3F != cmd || crc7(&frame[..5]) != frame[5] >> 1 {
+        return Err(SdError::Crc);
+    }
+    Ok(u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]))
+}
+
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub fn check_status(status: u32) -> Result<(), SdError> {
+    let errors = status & STATUS_ERRORS;
+    if errors == 0 {
+        return Ok(());
+    }
+    // Reported as the most significant byte with an error bit.
+    let byte = errors.to_be_bytes().into_iter().find(|&byte| byte != 0);
+    Err(SdError::Response(byte.unwrap_or(0)))
+}
+
+/// CRC16 of a data block sent on one line, as in SPI mode.
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        crc << 8 ^ CRC16[((crc >> 8) as u8 ^ byte) as usize]
+    })
+}
+
+/// x^16 + x^12 + x^5 + 1, a byte at a time.
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+const CRC16: [u16; 256] = crc16_table();
+
+#[cfg(any(not(feature = "sdio"), not(target_os = "none")))]
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = (byte as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// CRC16 of each of the four data lines, interleaved the way the card sends
+/// it after the data: bit `4 * i + n` is bit `i` of the CRC of DAT`n`. Data
+/// bytes go out high nibble first, bit `n` of a nibble on DAT`n`.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+pub fn crc16_4bit(data: &[u8]) -> u64 {
+    data.iter().fold(0, |crc, &byte| {
+        crc << 8 ^ CRC16_4BIT[((crc >> 56) as u8 ^ byte) as usize]
+    })
+}
+
+/// Both feedback nibbles of a byte only depend on the top byte of the CRC
+/// and the data byte, so a byte is one lookup.
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+const CRC16_4BIT: [u64; 256] = crc16_4bit_table();
+
+#[cfg(any(feature = "sdio", not(target_os = "none")))]
+const fn crc16_4bit_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = 0u64;
+        let mut shift = 4;
+        loop {
+            // x^16 + x^12 + x^5 + 1 on all four lines at once.
+            let feedback = crc >> 60 ^ (byte >> shift & 0x0F) as u64;
+            crc = crc << 4 ^ feedback << 48 ^ feedback << 20 ^ feedback;
+            if shift == 0 {
+                break;
+            }
+            shift -= 4;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        for bit in (0..8).rev() {
+            let feedback = (crc >> 6 ^ byte >> bit) & 1;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// Bits `high` down to `low` of a big-endian 128 bit register.
+fn bits(raw: &[u8; 16], high: usize, low: usize) -> u32 {
+    (low..=high).rev().fold(0, |value, bit| {
+        let byte = raw[15 - bit / 8];
+        value << 1 | (byte >> (bit % 8) & 1) as u32
+    })
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes)
+        .ok()
+        .filter(|text| {
+            text.bytes()
+                .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+        })
+        .unwrap_or("?")
+}
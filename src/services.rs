@@ -0,0 +1,289 @@
+use core::{mem, slice, str};
+
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use rp_loader_abi::{Rgb, ServiceTable, ABI_VERSION, ERR_INVALID, ERR_IO, ERR_NOT_FOUND, MAGIC};
+use rp_pico::hal::{gpio::PinId, spi::SpiDevice};
+
+use crate::{
+    app,
+    atm0130::{Atm0130, Color, MAX_TEXT_SCALE},
+    flashfs::{self, FlashFs},
+    fs::{Dir, FsError, Storage},
+    ui::SCREEN_SIZE,
+};
+
+#[used]
+#[link_section = ".services"]
+pub static SERVICES: ServiceTable = ServiceTable {
+    magic: MAGIC,
+    abi_version: ABI_VERSION,
+    size: mem::size_of::<ServiceTable>() as u32,
+    draw_rect,
+    draw_text,
+    read_file,
+    exit: app::exit,
+};
+
+pub trait Host {
+    fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, color: Color);
+    fn draw_text(
+        &mut self,
+        text: &str,
+        x: u8,
+        y: u8,
+        size_scalar: u32,
+        text_color: Color,
+        background_color: Color,
+    );
+    fn read_file(&mut self, name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32>;
+}
+
+static mut HOST: Option<*mut (dyn Host + 'static)> = None;
+
+/// Routes service calls to `host` until [`uninstall`] is called. The caller
+/// must keep `host` alive and untouched for as long as it is installed.
+pub unsafe fn install(host: &mut dyn Host) {
+    let host: &'static mut dyn Host = mem::transmute(host);
+    HOST = Some(host);
+}
+
+pub fn uninstall() {
+    unsafe { HOST = None };
+}
+
+fn with_host<R>(f: impl FnOnce(&mut dyn Host) -> R) -> Option<R> {
+    unsafe { HOST.map(|host| f(&mut *host)) }
+}
+
+pub fn color(rgb: Rgb) -> Color {
+    Color((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
+
+/// Clips a rectangle drawn by an app to the panel, `None` if none of it is
+/// left.
+pub fn clip_rect(x: u8, y: u8, width: u8, height: u8) -> Option<(u8, u8, u8, u8)> {
+    if x >= SCREEN_SIZE || y >= SCREEN_SIZE || width == 0 || height == 0 {
+        return None;
+    }
+    Some((
+        x,
+        y,
+        width.min(SCREEN_SIZE - x),
+        height.min(SCREEN_SIZE - y),
+    ))
+}
+
+/// Scale for text drawn by an app, `None` if it starts off the panel or
+/// would not show at all.
+pub fn text_scale(x: u8, y: u8, size_scalar: u32) -> Option<u32> {
+    if x >= SCREEN_SIZE || y >= SCREEN_SIZE || size_scalar == 0 {
+        return None;
+    }
+    Some(size_scalar.min(MAX_TEXT_SCALE))
+}
+
+unsafe extern "C" fn draw_rect(x: u8, y: u8, width: u8, height: u8, rgb: Rgb) {
+    with_host(|host| host.draw_rect(x, y, width, height, color(rgb)));
+}
+
+unsafe extern "C" fn draw_text(
+    text: *const u8,
+    len: usize,
+    x: u8,
+    y: u8,
+    size_scalar: u32,
+    text_color: Rgb,
+    background_color: Rgb,
+) {
+    if let Ok(text) = str::from_utf8(slice::from_raw_parts(text, len)) {
+        with_host(|host| {
+            host.draw_text(
+                text,
+                x,
+                y,
+                size_scalar,
+                color(text_color),
+                color(background_color),
+            )
+        });
+    }
+}
+
+unsafe extern "C" fn read_file(
+    name: *const u8,
+    name_len: usize,
+    offset: u32,
+    buf: *mut u8,
+    buf_len: usize,
+) -> i32 {
+    let name = match str::from_utf8(slice::from_raw_parts(name, name_len)) {
+        Ok(name) => name,
+        Err(_) => return ERR_INVALID,
+    };
+    let buf = slice::from_raw_parts_mut(buf, buf_len);
+    match with_host(|host| host.read_file(name, offset, buf)) {
+        Some(Ok(count)) => count as i32,
+        Some(Err(code)) => code,
+        None => ERR_INVALID,
+    }
+}
+
+/// What every host does for an app's `draw_rect`, apps are not trusted to
+/// stay on the panel. Drawing has no error in the app ABI, a broken display
+/// shows up as a loader error once the app has exited.
+fn draw_rect_on<SPI, SS, DC, RS>(
+    display: &mut Atm0130<SPI, SS, DC, RS>,
+    x: u8,
+    y: u8,
+    width: u8,
+    height: u8,
+    color: Color,
+) where
+    SPI: SpiDevice,
+    SS: PinId,
+    DC: PinId,
+    RS: PinId,
+{
+    if let Some((x, y, width, height)) = clip_rect(x, y, width, height) {
+        display.draw_rect(x, y, width, height, color).ok();
+    }
+}
+
+/// Like `draw_rect_on`, for `draw_text`.
+fn draw_text_on<SPI, SS, DC, RS>(
+    display: &mut Atm0130<SPI, SS, DC, RS>,
+    text: &str,
+    x: u8,
+    y: u8,
+    size_scalar: u32,
+    text_color: Color,
+    background_color: Color,
+) where
+    SPI: SpiDevice,
+    SS: PinId,
+    DC: PinId,
+    RS: PinId,
+{
+    if let Some(size_scalar) = text_scale(x, y, size_scalar) {
+        display
+            .draw_text(text, x, y, size_scalar, text_color, background_color)
+            .ok();
+    }
+}
+
+pub struct Session<'a, 'b, SPI, SS, DC, RS, D, T>
+where
+    SPI: SpiDevice,
+    SS: PinId,
+    DC: PinId,
+    RS: PinId,
+    D: BlockDevice,
+    T: TimeSource,
+{
+    pub display: &'a mut Atm0130<SPI, SS, DC, RS>,
+    pub storage: &'a mut Storage<'b, D, T>,
+    pub dir: &'a Dir,
+}
+
+impl<'a, 'b, SPI, SS, DC, RS, D, T> Host for Session<'a, 'b, SPI, SS, DC, RS, D, T>
+where
+    SPI: SpiDevice,
+    SS: PinId,
+    DC: PinId,
+    RS: PinId,
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, color: Color) {
+        draw_rect_on(self.display, x, y, width, height, color);
+    }
+
+    fn draw_text(
+        &mut self,
+        text: &str,
+        x: u8,
+        y: u8,
+        size_scalar: u32,
+        text_color: Color,
+        background_color: Color,
+    ) {
+        draw_text_on(
+            self.display,
+            text,
+            x,
+            y,
+            size_scalar,
+            text_color,
+            background_color,
+        );
+    }
+
+    fn read_file(&mut self, name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32> {
+        let mut file = self
+            .storage
+            .open(self.dir, name)
+            .map_err(|_| ERR_NOT_FOUND)?;
+        let result = file
+            .seek_from_start(offset)
+            .and_then(|()| self.storage.read(&mut file, buf))
+            .map_err(|err| match err {
+                FsError::Invalid => ERR_INVALID,
+                _ => ERR_IO,
+            });
+        self.storage.close(file);
+        result
+    }
+}
+
+/// Like `Session`, with files read from the flash volume while no card is
+/// in.
+pub struct FlashSession<'a, 'b, SPI, SS, DC, RS>
+where
+    SPI: SpiDevice,
+    SS: PinId,
+    DC: PinId,
+    RS: PinId,
+{
+    pub display: &'a mut Atm0130<SPI, SS, DC, RS>,
+    pub volume: &'a FlashFs<'b>,
+}
+
+impl<'a, 'b, SPI, SS, DC, RS> Host for FlashSession<'a, 'b, SPI, SS, DC, RS>
+where
+    SPI: SpiDevice,
+    SS: PinId,
+    DC: PinId,
+    RS: PinId,
+{
+    fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, color: Color) {
+        draw_rect_on(self.display, x, y, width, height, color);
+    }
+
+    fn draw_text(
+        &mut self,
+        text: &str,
+        x: u8,
+        y: u8,
+        size_scalar: u32,
+        text_color: Color,
+        background_color: Color,
+    ) {
+        draw_text_on(
+            self.display,
+            text,
+            x,
+            y,
+            size_scalar,
+            text_color,
+            background_color,
+        );
+    }
+
+    fn read_file(&mut self, name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32> {
+        flashfs::read_at(self.volume, name, offset, buf).map_err(|err| match err {
+            FsError::NotFound => ERR_NOT_FOUND,
+            FsError::Invalid => ERR_INVALID,
+            _ => ERR_IO,
+        })
+    }
+}
@@ -0,0 +1,302 @@
+use core::fmt::{self, Write};
+
+use cortex_m::delay::Delay;
+use heapless::{String, Vec};
+
+use crate::{
+    atm0130::{self, Color, ARTEMIS_COLOR, FONT_HEIGHT, FONT_WIDTH},
+    buttons::{Button, Buttons},
+    error::LoaderError,
+    Display,
+};
+
+pub const SCREEN_SIZE: u8 = 240;
+pub const ROW_HEIGHT: u8 = FONT_HEIGHT + 2;
+pub const COLUMNS: usize = ((SCREEN_SIZE - 2 * MARGIN) / (FONT_WIDTH + 1)) as usize;
+/// Rows available below the title.
+pub const ROWS: usize = ((SCREEN_SIZE - 2 * MARGIN) / ROW_HEIGHT) as usize - TITLE_ROWS;
+
+const MARGIN: u8 = 4;
+const TITLE_ROWS: usize = 2;
+/// What `edit_name` cycles through, a space ends the name.
+const NAME_CHARS: &[u8] = b" ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789._-";
+
+/// One line of text that fits the screen width.
+pub type Line = String<COLUMNS>;
+
+pub const BLACK: Color = Color(0, 0, 0);
+pub const WHITE: Color = Color(255, 255, 255);
+
+pub fn clear(display: &mut Display) -> Result<(), LoaderError> {
+    display.draw_rect(0, 0, SCREEN_SIZE, SCREEN_SIZE, BLACK)
+}
+
+pub fn message(display: &mut Display, text: &str) -> Result<(), LoaderError> {
+    clear(display)?;
+    display.draw_info(truncate(text))
+}
+
+/// Shows `err` with its code, the status byte the card sent and `hint`.
+pub fn error(display: &mut Display, err: &LoaderError, hint: &str) -> Result<(), LoaderError> {
+    let mut title = Line::new();
+    write!(title, "Error E{}", err.code()).ok();
+    clear(display)?;
+    draw_title(display, &title)?;
+    draw_line(display, 0, err.message(), WHITE, BLACK)?;
+    let mut row = 1;
+    if let Some(status) = err.detail() {
+        let mut line = Line::new();
+        write!(line, "Card status {:02X}", status).ok();
+        draw_line(display, row, &line, WHITE, BLACK)?;
+        row += 1;
+    }
+    draw_line(display, row + 1, hint, ARTEMIS_COLOR, BLACK)
+}
+
+pub fn draw_title(display: &mut Display, title: &str) -> Result<(), LoaderError> {
+    draw_row(display, 0, title, ARTEMIS_COLOR, BLACK)
+}
+
+/// Draws a full-width line below the title, `row` counts from zero.
+pub fn draw_line(
+    display: &mut Display,
+    row: usize,
+    text: &str,
+    text_color: Color,
+    bg: Color,
+) -> Result<(), LoaderError> {
+    draw_row(display, TITLE_ROWS + row, text, text_color, bg)
+}
+
+fn draw_row(
+    display: &mut Display,
+    row: usize,
+    text: &str,
+    text_color: Color,
+    bg: Color,
+) -> Result<(), LoaderError> {
+    let y = MARGIN + row as u8 * ROW_HEIGHT;
+    display.draw_rect(0, y, SCREEN_SIZE, ROW_HEIGHT, bg)?;
+
+    // The panel is mirrored horizontally, text starting at the visual left
+    // edge ends at the highest x.
+    let text = truncate(text);
+    if !text.is_empty() {
+        let width = atm0130::text_size(text, 1).0;
+        display.draw_text(text, SCREEN_SIZE - MARGIN - width, y + 1, 1, text_color, bg)?;
+    }
+    Ok(())
+}
+
+fn truncate(text: &str) -> &str {
+    text.char_indices()
+        .nth(COLUMNS)
+        .map_or(text, |(index, _)| &text[..index])
+}
+
+/// Lets the user pick one of `items`. Returns `None` if Back was pressed.
+pub fn select<S: AsRef<str>>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    title: &str,
+    items: &[S],
+) -> Result<Option<usize>, LoaderError> {
+    select_until(display, buttons, delay, title, items, || false)
+}
+
+/// Like `select`, but also returns `None` as soon as `stop` returns `true`.
+pub fn select_until<S: AsRef<str>, F: FnMut() -> bool>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    title: &str,
+    items: &[S],
+    mut stop: F,
+) -> Result<Option<usize>, LoaderError> {
+    let mut selected = 0;
+    let mut top = 0;
+
+    clear(display)?;
+    draw_title(display, title)?;
+    loop {
+        if selected < top {
+            top = selected;
+        } else if selected >= top + ROWS {
+            top = selected + 1 - ROWS;
+        }
+        for row in 0..ROWS {
+            let index = top + row;
+            let text = items.get(index).map_or("", |item| item.as_ref());
+            if index == selected && !items.is_empty() {
+                draw_line(display, row, text, BLACK, ARTEMIS_COLOR)?;
+            } else {
+                draw_line(display, row, text, WHITE, BLACK)?;
+            }
+        }
+
+        let button = match buttons.wait_until(delay, &mut stop) {
+            Some(button) => button,
+            None => return Ok(None),
+        };
+        match button {
+            Button::Up => selected = selected.saturating_sub(1),
+            Button::Down => {
+                if selected + 1 < items.len() {
+                    selected += 1;
+                }
+            }
+            Button::Select => {
+                if !items.is_empty() {
+                    return Ok(Some(selected));
+                }
+            }
+            Button::Back => return Ok(None),
+        }
+    }
+}
+
+/// Shows `lines` below `title` until Select or Back is pressed. Up and Down
+/// page through lines that do not fit.
+pub fn info<S: AsRef<str>>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    title: &str,
+    lines: &[S],
+) -> Result<(), LoaderError> {
+    let mut top = 0;
+
+    clear(display)?;
+    draw_title(display, title)?;
+    loop {
+        for row in 0..ROWS {
+            let text = lines.get(top + row).map_or("", |line| line.as_ref());
+            draw_line(display, row, text, WHITE, BLACK)?;
+        }
+
+        match buttons.wait(delay) {
+            Button::Up => top = top.saturating_sub(ROWS),
+            Button::Down => {
+                if top + ROWS < lines.len() {
+                    top += ROWS;
+                }
+            }
+            Button::Select | Button::Back => return Ok(()),
+        }
+    }
+}
+
+/// Shows `lines` below `title` and waits for Select, which confirms, or
+/// Back, which does not.
+pub fn confirm(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    title: &str,
+    lines: &[&str],
+) -> Result<bool, LoaderError> {
+    clear(display)?;
+    draw_title(display, title)?;
+    for (row, line) in lines.iter().enumerate() {
+        draw_line(display, row, line, WHITE, BLACK)?;
+    }
+    draw_line(
+        display,
+        lines.len() + 1,
+        "Select: yes   Back: no",
+        ARTEMIS_COLOR,
+        BLACK,
+    )?;
+    loop {
+        match buttons.wait(delay) {
+            Button::Select => return Ok(true),
+            Button::Back => return Ok(false),
+            Button::Up | Button::Down => {}
+        }
+    }
+}
+
+/// Lets the user spell a name of up to `N` characters, starting from
+/// `initial`. Up and Down change the character under the cursor and Select
+/// moves on, or ends the name on a blank. Back moves back, or returns `None`
+/// from the first character.
+pub fn edit_name<const N: usize>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    title: &str,
+    initial: &str,
+) -> Result<Option<String<N>>, LoaderError> {
+    let mut chars = [b' '; N];
+    for (c, byte) in chars.iter_mut().zip(initial.bytes()) {
+        let byte = byte.to_ascii_uppercase();
+        if NAME_CHARS.contains(&byte) {
+            *c = byte;
+        }
+    }
+    let mut cursor = 0;
+
+    clear(display)?;
+    draw_title(display, title)?;
+    draw_line(
+        display,
+        2,
+        "Up/Down: change  Select: next",
+        ARTEMIS_COLOR,
+        BLACK,
+    )?;
+    draw_line(display, 3, "Select on blank: done", ARTEMIS_COLOR, BLACK)?;
+    draw_line(display, 4, "Back: previous", ARTEMIS_COLOR, BLACK)?;
+    loop {
+        let end = chars
+            .iter()
+            .rposition(|&c| c != b' ')
+            .map_or(0, |last| last + 1)
+            .max(cursor + 1);
+        let mut line = Line::new();
+        for (i, &c) in chars[..end].iter().enumerate() {
+            if i == cursor {
+                write!(line, "[{}]", c as char).ok();
+            } else {
+                line.push(c as char).ok();
+            }
+        }
+        draw_line(display, 0, &line, WHITE, BLACK)?;
+
+        let index = NAME_CHARS
+            .iter()
+            .position(|&c| c == chars[cursor])
+            .unwrap_or(0);
+        match buttons.wait(delay) {
+            Button::Up => {
+                chars[cursor] = NAME_CHARS[(index + NAME_CHARS.len() - 1) % NAME_CHARS.len()]
+            }
+            Button::Down => chars[cursor] = NAME_CHARS[(index + 1) % NAME_CHARS.len()],
+            Button::Select if chars[cursor] == b' ' || cursor + 1 == N => {
+                if chars[cursor] != b' ' {
+                    cursor += 1;
+                }
+                if cursor > 0 {
+                    let mut name = String::new();
+                    for &c in &chars[..cursor] {
+                        name.push(c as char).ok();
+                    }
+                    return Ok(Some(name));
+                }
+            }
+            Button::Select => cursor += 1,
+            Button::Back => match cursor.checked_sub(1) {
+                Some(previous) => cursor = previous,
+                None => return Ok(None),
+            },
+        }
+    }
+}
+
+/// Formats a line and appends it, text past the screen width is dropped.
+pub fn push_line<const N: usize>(lines: &mut Vec<Line, N>, args: fmt::Arguments) {
+    let mut line = Line::new();
+    line.write_fmt(args).ok();
+    lines.push(line).ok();
+}
@@ -0,0 +1,114 @@
+//! Playback of raw frames from `.RAW` and `.VID` files.
+//!
+//! A file is a run of 240x240 frames, each stored as big-endian RGB565, rows
+//! top to bottom and pixels left to right. Frames are read from the card
+//! straight into the buffers the display DMA sends from, so the achieved
+//! frame rate also measures both SPI buses end to end.
+
+use cortex_m::delay::Delay;
+use embedded_sdmmc::{BlockDevice, TimeSource};
+use rp_pico::hal::Timer;
+
+use crate::{
+    buttons::Buttons,
+    error::LoaderError,
+    fs::{File, FsError, Storage},
+    info,
+    ui::{self, SCREEN_SIZE},
+    Display,
+};
+
+/// Used unless `fps` is set in the config.
+pub const FRAME_RATE: u8 = 15;
+pub const FRAME_BYTES: u32 = 2 * SCREEN_SIZE as u32 * SCREEN_SIZE as u32;
+
+pub fn is_video(extension: &str) -> bool {
+    matches!(extension, "RAW" | "VID")
+}
+
+/// Plays `file` once, any button stops it early. The results are shown
+/// afterwards.
+pub fn play<D, T>(
+    display: &mut Display,
+    buttons: &Buttons,
+    delay: &mut Delay,
+    timer: &Timer,
+    storage: &mut Storage<D, T>,
+    file: &mut File,
+    frame_rate: u8,
+) -> Result<(), LoaderError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    // A partial frame at the end is left out.
+    let frames = file.length() / FRAME_BYTES;
+    if frames == 0 {
+        ui::message(display, "Not a 240x240 RGB565 video.")?;
+        buttons.wait(delay);
+        return Ok(());
+    }
+    let period = 1_000_000 / frame_rate.max(1) as u32;
+
+    display.set_flip_columns(true)?;
+    let start = timer.get_counter_low();
+    let mut due = start;
+    let mut shown = 0;
+    let mut late = 0;
+    let mut failed: Option<FsError> = None;
+    while shown < frames && failed.is_none() {
+        display.stream_pixels(0, 0, SCREEN_SIZE, SCREEN_SIZE, |buf| {
+            if failed.is_none() {
+                if let Err(err) = read_full(storage, file, buf) {
+                    failed = Some(err);
+                }
+            } else {
+                buf.fill(0);
+            }
+        })?;
+        shown += 1;
+
+        due = due.wrapping_add(period);
+        let now = timer.get_counter_low();
+        let ahead = due.wrapping_sub(now) as i32;
+        if ahead > 0 {
+            delay.delay_us(ahead as u32);
+        } else {
+            // Catching up would only make the next frames late too.
+            late += 1;
+            due = now;
+        }
+        if buttons.pressed().is_some() {
+            buttons.wait(delay);
+            break;
+        }
+    }
+    let elapsed = timer.get_counter_low().wrapping_sub(start);
+    display.set_flip_columns(false)?;
+    if let Some(err) = failed {
+        return Err(err.into());
+    }
+
+    let lines = info::playback(shown, frames, late, frame_rate, elapsed);
+    ui::info(display, buttons, delay, "Playback", &lines)
+}
+
+fn read_full<D, T>(
+    storage: &mut Storage<D, T>,
+    file: &mut File,
+    buf: &mut [u8],
+) -> Result<(), FsError>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let mut filled = 0;
+    while filled < buf.len() && !file.eof() {
+        match storage.read(file, &mut buf[filled..])? {
+            0 => break,
+            count => filled += count,
+        }
+    }
+    buf[filled..].fill(0);
+    Ok(())
+}
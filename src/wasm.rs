@@ -0,0 +1,230 @@
+//! Sandboxed WebAssembly apps, stored on the card with a `.WSM` extension.
+//!
+//! A module exports `main: () -> i32` and may import the functions below from
+//! `env`. Its linear memory and the number of executed instructions are
+//! bounded by [`Limits`], and it can only reach the hardware through the
+//! [`Platform`] it is run with.
+//!
+//! Nothing in here touches the hardware, `tools/wasm-test` runs modules on
+//! the host against a recording `Platform`.
+
+use wasmi::{
+    core::TrapCode, Caller, Config, Engine, Extern, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+pub const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+pub const BUTTON_UP: u32 = 1 << 0;
+pub const BUTTON_DOWN: u32 = 1 << 1;
+pub const BUTTON_SELECT: u32 = 1 << 2;
+pub const BUTTON_BACK: u32 = 1 << 3;
+
+/// Returned by `read_file` when the module passed a bad pointer or name.
+const ERR_INVALID: i32 = -3;
+
+/// Longest name `read_file` takes, an 8.3 name with its dot. Longer names
+/// are refused, a shortened one could open a different file.
+const MAX_NAME_LEN: usize = 12;
+
+/// What a module can do. The loader implements this on top of the display,
+/// the buttons and the SD card.
+pub trait Platform {
+    fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, rgb: u32);
+    fn draw_text(&mut self, text: &str, x: u8, y: u8, size_scalar: u32, fg: u32, bg: u32);
+    /// Currently held buttons as a mask of `BUTTON_*`.
+    fn buttons(&mut self) -> u32;
+    /// Returns the number of bytes read, or a negative error code.
+    fn read_file(&mut self, name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32>;
+}
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Upper bound for the module's linear memory.
+    pub memory_bytes: usize,
+    /// Roughly the number of instructions the module may execute.
+    pub fuel: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            memory_bytes: WASM_PAGE_SIZE,
+            fuel: 50_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WasmError {
+    /// The bytes are not a valid module.
+    Invalid,
+    /// The module imports something that is not provided, or exceeds the limits.
+    Link,
+    NoMain,
+    OutOfFuel,
+    /// The module tried to grow its memory past `Limits::memory_bytes`.
+    OutOfMemory,
+    Trap,
+}
+
+impl WasmError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            WasmError::Invalid => "Invalid WASM module.",
+            WasmError::Link => "WASM module cannot be linked.",
+            WasmError::NoMain => "WASM module has no main.",
+            WasmError::OutOfFuel => "WASM app ran out of fuel.",
+            WasmError::OutOfMemory => "WASM app ran out of memory.",
+            WasmError::Trap => "WASM app trapped.",
+        }
+    }
+}
+
+struct State<'a, P: Platform> {
+    platform: &'a mut P,
+    limits: StoreLimits,
+}
+
+/// Runs the module in `wasm` to completion and returns the value of its
+/// `main` export.
+pub fn run<P: Platform>(wasm: &[u8], platform: &mut P, limits: Limits) -> Result<i32, WasmError> {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, wasm).map_err(|_| WasmError::Invalid)?;
+
+    let state = State {
+        platform,
+        limits: StoreLimitsBuilder::new()
+            .memory_size(limits.memory_bytes)
+            .instances(1)
+            .memories(1)
+            .tables(1)
+            .trap_on_grow_failure(true)
+            .build(),
+    };
+    let mut store = Store::new(&engine, state);
+    store.limiter(|state| &mut state.limits);
+    store.add_fuel(limits.fuel).map_err(|_| WasmError::Link)?;
+
+    let mut linker = Linker::new(&engine);
+    define_imports(&mut linker).map_err(|_| WasmError::Link)?;
+
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|pre| pre.start(&mut store))
+        .map_err(|_| WasmError::Link)?;
+    let main = instance
+        .get_typed_func::<(), i32>(&store, "main")
+        .map_err(|_| WasmError::NoMain)?;
+
+    main.call(&mut store, ())
+        .map_err(|err| match err.trap_code() {
+            Some(TrapCode::OutOfFuel) => WasmError::OutOfFuel,
+            Some(TrapCode::GrowthOperationLimited) => WasmError::OutOfMemory,
+            _ => WasmError::Trap,
+        })
+}
+
+fn define_imports<'a, P: Platform>(linker: &mut Linker<State<'a, P>>) -> Result<(), wasmi::Error> {
+    linker.func_wrap(
+        "env",
+        "draw_rect",
+        |mut caller: Caller<'_, State<'a, P>>,
+         x: i32,
+         y: i32,
+         width: i32,
+         height: i32,
+         rgb: i32| {
+            caller.data_mut().platform.draw_rect(
+                x as u8,
+                y as u8,
+                width as u8,
+                height as u8,
+                rgb as u32,
+            );
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "draw_text",
+        |mut caller: Caller<'_, State<'a, P>>,
+         ptr: i32,
+         len: i32,
+         x: i32,
+         y: i32,
+         size_scalar: i32,
+         fg: i32,
+         bg: i32| {
+            let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+                Some(memory) => memory,
+                None => return,
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            if let Some(text) = guest_str(data, ptr, len) {
+                state.platform.draw_text(
+                    text,
+                    x as u8,
+                    y as u8,
+                    size_scalar as u32,
+                    fg as u32,
+                    bg as u32,
+                );
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "buttons",
+        |mut caller: Caller<'_, State<'a, P>>| -> i32 {
+            caller.data_mut().platform.buttons() as i32
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "read_file",
+        |mut caller: Caller<'_, State<'a, P>>,
+         name_ptr: i32,
+         name_len: i32,
+         offset: i32,
+         buf_ptr: i32,
+         buf_len: i32|
+         -> i32 {
+            let memory = match caller.get_export("memory").and_then(Extern::into_memory) {
+                Some(memory) => memory,
+                None => return ERR_INVALID,
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+
+            // Copy the name out so the buffer can be borrowed mutably.
+            let mut name = [0u8; MAX_NAME_LEN];
+            let name = match guest_str(data, name_ptr, name_len) {
+                Some(text) if text.len() <= MAX_NAME_LEN => {
+                    name[..text.len()].copy_from_slice(text.as_bytes());
+                    core::str::from_utf8(&name[..text.len()]).unwrap()
+                }
+                _ => return ERR_INVALID,
+            };
+            let buf = match guest_slice(data, buf_ptr, buf_len) {
+                Some(buf) => buf,
+                None => return ERR_INVALID,
+            };
+            match state.platform.read_file(name, offset as u32, buf) {
+                Ok(count) => count as i32,
+                Err(code) => code,
+            }
+        },
+    )?;
+    Ok(())
+}
+
+fn guest_slice(data: &mut [u8], ptr: i32, len: i32) -> Option<&mut [u8]> {
+    let start = ptr as u32 as usize;
+    let end = start.checked_add(len as u32 as usize)?;
+    data.get_mut(start..end)
+}
+
+fn guest_str(data: &mut [u8], ptr: i32, len: i32) -> Option<&str> {
+    core::str::from_utf8(guest_slice(data, ptr, len)?).ok()
+}
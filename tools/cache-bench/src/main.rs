@@ -0,0 +1,162 @@
+//! Streams a 1 MiB file through the loader's block cache from a file-backed
+//! block device, the way `embedded_sdmmc::Controller` reads it from a FAT32
+//! volume, and compares the commands the card would see with and without
+//! the cache.
+//!
+//! The loader's `.cargo/config.toml` builds for the RP2040, so name the host
+//! target when running it:
+//!
+//! ```sh
+//! cargo run --release --target x86_64-unknown-linux-gnu
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::Instant,
+};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+#[path = "../../../src/cache.rs"]
+#[allow(dead_code)]
+mod cache;
+
+use cache::BlockCache;
+
+const IMAGE_BLOCKS: u32 = 4096;
+const FAT_START: u32 = 32;
+const DATA_START: u32 = 64;
+const BLOCKS_PER_CLUSTER: u32 = 8;
+const FILE_BLOCKS: u32 = 2048;
+
+/// SPI at 20 MHz: a command with its response and the wait for the data
+/// token, and the 514 byte transfer of one block with its CRC.
+const COMMAND_US: u64 = 120;
+const BLOCK_US: u64 = 206;
+
+struct FileDevice {
+    file: RefCell<File>,
+    commands: Cell<u64>,
+    blocks: Cell<u64>,
+}
+
+impl FileDevice {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: RefCell::new(File::options().read(true).write(true).open(path)?),
+            commands: Cell::new(0),
+            blocks: Cell::new(0),
+        })
+    }
+
+    fn card_time_us(&self) -> u64 {
+        self.commands.get() * COMMAND_US + self.blocks.get() * BLOCK_US
+    }
+}
+
+/// By reference, so the counters can be read while the cache owns it.
+impl BlockDevice for &FileDevice {
+    type Error = io::ErrorKind;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(start.0 as u64 * Block::LEN as u64))
+            .map_err(|err| err.kind())?;
+        for block in blocks.iter_mut() {
+            file.read_exact(&mut block.contents)
+                .map_err(|err| err.kind())?;
+        }
+        self.commands.set(self.commands.get() + 1);
+        self.blocks.set(self.blocks.get() + blocks.len() as u64);
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), Self::Error> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(start.0 as u64 * Block::LEN as u64))
+            .map_err(|err| err.kind())?;
+        for block in blocks {
+            file.write_all(&block.contents).map_err(|err| err.kind())?;
+        }
+        self.commands.set(self.commands.get() + 1);
+        self.blocks.set(self.blocks.get() + blocks.len() as u64);
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(IMAGE_BLOCKS))
+    }
+}
+
+/// Reads the file a block at a time and follows the cluster chain through the
+/// FAT after every cluster, returning a checksum of the data.
+fn stream<D: BlockDevice>(device: &D) -> Result<u64, D::Error> {
+    let mut block = [Block::new()];
+    let mut sum = 0u64;
+    let mut cluster = 2;
+    for index in 0..FILE_BLOCKS {
+        let lba = DATA_START + (cluster - 2) * BLOCKS_PER_CLUSTER + index % BLOCKS_PER_CLUSTER;
+        device.read(&mut block, BlockIdx(lba), "read")?;
+        sum = block[0].contents.iter().fold(sum, |sum, &byte| {
+            sum.wrapping_mul(31).wrapping_add(byte as u64)
+        });
+        if index % BLOCKS_PER_CLUSTER == BLOCKS_PER_CLUSTER - 1 {
+            device.read(
+                &mut block,
+                BlockIdx(FAT_START + cluster * 4 / 512),
+                "next_cluster",
+            )?;
+            cluster += 1;
+        }
+    }
+    Ok(sum)
+}
+
+fn main() -> io::Result<()> {
+    let path = std::env::temp_dir().join("cache-bench.img");
+    let image: Vec<u8> = (0..IMAGE_BLOCKS as usize * Block::LEN)
+        .map(|i| (i * 7 + i / 509) as u8)
+        .collect();
+    fs::write(&path, image)?;
+
+    let raw = FileDevice::open(&path)?;
+    let start = Instant::now();
+    let expected = stream(&&raw).map_err(io::Error::from)?;
+    let raw_elapsed = start.elapsed();
+
+    let device = FileDevice::open(&path)?;
+    let cached = BlockCache::new(&device);
+    let start = Instant::now();
+    let sum = stream(&cached).map_err(io::Error::from)?;
+    let cached_elapsed = start.elapsed();
+    assert_eq!(sum, expected, "cached data differs");
+
+    let stats = cached.stats();
+    println!("1 MiB in {} blocks of {} bytes", FILE_BLOCKS, Block::LEN);
+    println!(
+        "uncached: {:6} commands, {:5} ms on the card, {:?} on the host",
+        raw.commands.get(),
+        raw.card_time_us() / 1000,
+        raw_elapsed
+    );
+    println!(
+        "cached:   {:6} commands, {:5} ms on the card, {:?} on the host",
+        device.commands.get(),
+        device.card_time_us() / 1000,
+        cached_elapsed
+    );
+    println!(
+        "cache:    {} hits, {} misses, {} read-aheads",
+        stats.hits, stats.misses, stats.read_aheads
+    );
+
+    fs::remove_file(path)
+}
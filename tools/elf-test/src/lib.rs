@@ -0,0 +1,10 @@
+//! Host build of the loader's ELF relocator, so it can be checked against
+//! fixtures linked the way position-independent apps are. The loader's
+//! `.cargo/config.toml` builds for the RP2040, so name the host target:
+//!
+//! ```sh
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+
+#[path = "../../../src/elf.rs"]
+pub mod elf;
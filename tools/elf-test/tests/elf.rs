@@ -0,0 +1,118 @@
+use elf_test::elf::{Elf, ElfError};
+
+/// Only `R_ARM_RELATIVE`, see `fixtures/app.s`.
+const PIE: &[u8] = include_bytes!("fixtures/pie.elf");
+/// `R_ARM_ABS32` against the global symbols.
+const SHARED: &[u8] = include_bytes!("fixtures/shared.elf");
+
+const BASE: u32 = 0x2002_1000;
+
+const SHT_REL: u32 = 9;
+const SHT_DYNSYM: u32 = 11;
+
+/// Symbol values and `.bss` in the fixtures, from `llvm-readelf -s`.
+const ENTRY: u32 = 0x15;
+const MESSAGE: u32 = 0x18;
+const BSS_START: u32 = 0x30;
+const BSS_END: u32 = 0x40;
+
+fn word(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// File offset of the first section of type `kind`.
+fn section(file: &[u8], kind: u32) -> usize {
+    let shoff = word(file, 32) as usize;
+    let shnum = u16::from_le_bytes([file[48], file[49]]) as usize;
+    (0..shnum)
+        .map(|index| shoff + index * 40)
+        .find(|&header| word(file, header + 4) == kind)
+        .map(|header| word(file, header + 16) as usize)
+        .unwrap()
+}
+
+fn load(file: &[u8], base: u32) -> Result<Vec<u8>, ElfError> {
+    let mut src = file;
+    let elf = Elf::parse(&mut src)?;
+    let size = elf.image_size(&mut src)?;
+    // Whatever was in RAM before must not survive in `.bss`.
+    let mut image = vec![0xAA; size as usize];
+    elf.load(&mut src, &mut image, base)?;
+    Ok(image)
+}
+
+fn check_patched(image: &[u8], base: u32) {
+    // The app header: entry and `.bss` bounds.
+    assert_eq!(word(image, 0), 0x5041_5052);
+    assert_eq!(word(image, 8), base + ENTRY);
+    assert_eq!(word(image, 12), base + BSS_START);
+    assert_eq!(word(image, 16), base + BSS_END);
+    // `table` in `.data`, the addend of `message + 3` is kept.
+    assert_eq!(word(image, 0x20), base + MESSAGE);
+    assert_eq!(word(image, 0x24), base + MESSAGE + 3);
+    assert_eq!(word(image, 0x28), base + BSS_START);
+    assert_eq!(word(image, 0x2C), base + ENTRY);
+    assert_eq!(&image[MESSAGE as usize..MESSAGE as usize + 5], b"hello");
+    assert!(image[BSS_START as usize..BSS_END as usize]
+        .iter()
+        .all(|&byte| byte == 0));
+}
+
+#[test]
+fn relative_relocations_are_patched() {
+    check_patched(&load(PIE, BASE).unwrap(), BASE);
+    check_patched(&load(PIE, 0x2003_0000).unwrap(), 0x2003_0000);
+}
+
+#[test]
+fn abs32_relocations_resolve_symbols() {
+    check_patched(&load(SHARED, BASE).unwrap(), BASE);
+}
+
+#[test]
+fn image_size_covers_bss() {
+    let mut src = PIE;
+    let elf = Elf::parse(&mut src).unwrap();
+    assert!(elf.image_size(&mut src).unwrap() >= BSS_END);
+}
+
+#[test]
+fn unsupported_relocation_type_is_rejected() {
+    let mut file = PIE.to_vec();
+    // R_ARM_REL32 instead of R_ARM_RELATIVE in the first entry.
+    let rel = section(&file, SHT_REL);
+    file[rel + 4] = 3;
+    assert_eq!(load(&file, BASE), Err(ElfError::UnsupportedRelocation(3)));
+}
+
+#[test]
+fn relocation_outside_image_is_rejected() {
+    let size = load(PIE, BASE).unwrap().len() as u32;
+    for offset in [size - 3, size, u32::MAX - 2] {
+        let mut file = PIE.to_vec();
+        let rel = section(&file, SHT_REL);
+        file[rel..rel + 4].copy_from_slice(&offset.to_le_bytes());
+        assert_eq!(
+            load(&file, BASE),
+            Err(ElfError::OutOfBounds),
+            "offset {offset:#x}"
+        );
+    }
+}
+
+#[test]
+fn undefined_symbol_is_rejected() {
+    let mut file = SHARED.to_vec();
+    // Every symbol after the null one is referenced, make the first undefined.
+    let dynsym = section(&file, SHT_DYNSYM);
+    file[dynsym + 16 + 14..dynsym + 16 + 16].copy_from_slice(&0u16.to_le_bytes());
+    assert_eq!(load(&file, BASE), Err(ElfError::UndefinedSymbol));
+}
+
+#[test]
+fn non_arm_file_is_rejected() {
+    let mut file = PIE.to_vec();
+    file[18] = 0x3E;
+    assert_eq!(load(&file, BASE), Err(ElfError::Unsupported));
+    assert_eq!(load(&PIE[..20], BASE), Err(ElfError::OutOfBounds));
+}
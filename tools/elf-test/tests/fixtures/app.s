@@ -0,0 +1,46 @@
+@ Source of the ELF fixtures. Rebuild them from this directory with
+@
+@   llvm-mc -triple thumbv6m-none-eabi -filetype=obj app.s -o app.o
+@   rust-lld -flavor gnu -pie -z norelro --nmagic --no-dynamic-linker \
+@       -T ../../../../abi/app-pic.x app.o -o pie.elf
+@   rust-lld -flavor gnu -shared -z norelro --nmagic \
+@       -T ../../../../abi/app-pic.x app.o -o shared.elf
+@
+@ pie.elf only has R_ARM_RELATIVE relocations. In shared.elf the global
+@ symbols can be preempted, so the references to them become R_ARM_ABS32.
+
+    .syntax unified
+    .thumb
+
+    .section .app_header, "aw"
+    .word 0x50415052
+    .word 0x00010002
+    .word entry
+    .word __bss_start
+    .word __bss_end
+
+    .text
+    .thumb_func
+    .global entry
+entry:
+    movs r0, #42
+    bx lr
+
+    .section .rodata
+    .global message
+message:
+    .ascii "hello"
+
+    .data
+    .balign 4
+    .global table
+table:
+    .word message
+    .word message + 3
+    .word counter
+    .word entry
+
+    .bss
+    .balign 4
+counter:
+    .space 16
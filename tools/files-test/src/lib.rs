@@ -0,0 +1,33 @@
+//! Host build of the loader's file layer, so the changes the file manager
+//! makes, the cards the formatter writes and the volume check can be tried
+//! on FAT images, and the exFAT reader on hand-built exFAT ones.
+//! The loader's `.cargo/config.toml` builds for the RP2040, so name the host
+//! target:
+//!
+//! ```sh
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+
+#![allow(dead_code)]
+
+#[path = "../../../src/exfat.rs"]
+#[allow(clippy::result_unit_err)]
+pub mod exfat;
+#[path = "../../../src/fat.rs"]
+pub mod fat;
+#[path = "../../../src/format.rs"]
+pub mod format;
+#[path = "../../../src/fs.rs"]
+pub mod fs;
+#[path = "../../../src/fsck.rs"]
+pub mod fsck;
+#[path = "../../../src/lfn.rs"]
+pub mod lfn;
+#[path = "../../../src/partition.rs"]
+pub mod partition;
+
+/// Stands in for the screen layout, the file layer only needs the width.
+pub mod ui {
+    pub const COLUMNS: usize = 38;
+    pub type Line = heapless::String<COLUMNS>;
+}
@@ -0,0 +1,144 @@
+//! Shared by the test binaries: a card held in memory and FAT volumes made
+//! on it by an independent implementation.
+
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    io::{Cursor, Write},
+    rc::Rc,
+};
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, Controller, TimeSource, Timestamp};
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use files_test::{
+    fs::{self, Storage},
+    partition,
+};
+
+/// Leaves room for a partition table in front of the volume.
+pub const START: u32 = 2048;
+/// The smallest volumes that still count as FAT16 and FAT32 with 512-byte
+/// clusters.
+pub const FAT16_BLOCKS: u32 = 16 * 1024;
+pub const FAT32_BLOCKS: u32 = 68 * 1024;
+
+#[derive(Clone)]
+pub struct Card(pub Rc<RefCell<Vec<u8>>>);
+
+impl Card {
+    pub fn new(image: Vec<u8>) -> Self {
+        Card(Rc::new(RefCell::new(image)))
+    }
+}
+
+impl BlockDevice for Card {
+    type Error = ();
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, _reason: &str) -> Result<(), ()> {
+        let data = self.0.borrow();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let offset = (start.0 as usize + i) * Block::LEN;
+            let bytes = data.get(offset..offset + Block::LEN).ok_or(())?;
+            block.contents.copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), ()> {
+        let mut data = self.0.borrow_mut();
+        for (i, block) in blocks.iter().enumerate() {
+            let offset = (start.0 as usize + i) * Block::LEN;
+            let bytes = data.get_mut(offset..offset + Block::LEN).ok_or(())?;
+            bytes.copy_from_slice(&block.contents);
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, ()> {
+        Ok(BlockCount((self.0.borrow().len() / Block::LEN) as u32))
+    }
+}
+
+pub struct Clock;
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 54,
+            zero_indexed_month: 2,
+            zero_indexed_day: 14,
+            hours: 9,
+            minutes: 30,
+            seconds: 0,
+        }
+    }
+}
+
+pub fn contents(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// An MBR with one partition of `blocks` at `START`, of MBR type `kind`.
+pub fn partitioned(kind: u8, blocks: u32) -> Vec<u8> {
+    let mut image = vec![0; (START + blocks) as usize * Block::LEN];
+    let entry = &mut image[446..462];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&START.to_le_bytes());
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    image[510] = 0x55;
+    image[511] = 0xAA;
+    image
+}
+
+/// A card with one FAT partition of `blocks` at `START` holding `files`, with
+/// 512-byte clusters. The directories in their paths are made as needed.
+pub fn fixture(fat_type: FatType, blocks: u32, files: &[(&str, Vec<u8>)]) -> Card {
+    let kind = match fat_type {
+        FatType::Fat32 => 0x0C,
+        _ => 0x0E,
+    };
+    let mut image = partitioned(kind, blocks);
+
+    let volume = &mut image[START as usize * Block::LEN..];
+    let options = FormatVolumeOptions::new()
+        .fat_type(fat_type)
+        .bytes_per_cluster(512);
+    fatfs::format_volume(Cursor::new(&mut *volume), options).unwrap();
+    let fs = FileSystem::new(Cursor::new(volume), FsOptions::new()).unwrap();
+    let root = fs.root_dir();
+    for (path, data) in files {
+        for (end, _) in path.match_indices('/') {
+            root.create_dir(&path[..end]).unwrap();
+        }
+        root.create_file(path).unwrap().write_all(data).unwrap();
+    }
+    drop(root);
+    fs.unmount().unwrap();
+    Card::new(image)
+}
+
+/// Runs `f` on the first volume mounted by the loader, with its root
+/// directory.
+pub fn with_storage<R>(card: &Card, f: impl FnOnce(&mut Storage<Card, Clock>, &fs::Dir) -> R) -> R {
+    let mut controller = Controller::new(card.clone(), Clock);
+    let partitions = partition::scan(controller.device()).unwrap();
+    let mut volume = fs::mount(&mut controller, &partitions[0]).unwrap();
+    let mut storage = Storage {
+        controller: &mut controller,
+        volume: &mut volume,
+    };
+    let root = storage.open_root().unwrap();
+    let result = f(&mut storage, &root);
+    storage.close_dir(root);
+    result
+}
+
+/// The entry listed under `label` in `dir`.
+pub fn entry(storage: &mut Storage<Card, Clock>, dir: &fs::Dir, label: &str) -> fs::Entry {
+    storage
+        .list(dir, |_| true)
+        .into_iter()
+        .find(|entry| entry.label == label)
+        .unwrap()
+}
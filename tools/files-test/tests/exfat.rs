@@ -0,0 +1,355 @@
+mod common;
+
+use common::{contents, entry, with_storage, Card, Clock, START};
+use embedded_sdmmc::Block;
+use files_test::fs::{File, FsError, Storage};
+
+/// 1 KiB clusters, so a few KiB of data already spans several.
+const CLUSTER_SHIFT: u8 = 1;
+const CLUSTER_SIZE: usize = Block::LEN << CLUSTER_SHIFT;
+const CLUSTERS: u32 = 512;
+const FAT_OFFSET: u32 = 32;
+const HEAP_OFFSET: u32 = 64;
+const BLOCKS: u32 = HEAP_OFFSET + (CLUSTERS << CLUSTER_SHIFT);
+const ROOT_CLUSTERS: usize = 2;
+
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+const NAME_UNITS: usize = 15;
+const ALLOCATION_POSSIBLE: u8 = 0x01;
+const NO_FAT_CHAIN: u8 = 0x02;
+const ATTR_ARCHIVE: u16 = 0x20;
+/// 2024-03-15 09:30:00 as a FAT date and time.
+const MODIFIED: u32 = (44 << 25 | 3 << 21 | 15 << 16) | (9 << 11 | 30 << 5);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    /// Consecutive clusters, linked in the FAT.
+    Chained,
+    /// Every other cluster, back to front.
+    Fragmented,
+    /// Consecutive clusters the FAT knows nothing about.
+    NoFatChain,
+}
+
+/// An exFAT volume made by hand, as no exFAT writer is at hand on the host.
+/// It has what the loader reads plus the allocation bitmap and a label, the
+/// up-case table is left out.
+struct ExFat {
+    image: Vec<u8>,
+    next_cluster: u32,
+    allocated: Vec<u32>,
+    root: Vec<u32>,
+    entries: Vec<[u8; 32]>,
+}
+
+impl ExFat {
+    fn new() -> Self {
+        let mut volume = ExFat {
+            image: common::partitioned(0x07, BLOCKS),
+            next_cluster: 2,
+            allocated: Vec::new(),
+            root: Vec::new(),
+            entries: Vec::new(),
+        };
+        volume.set_fat(0, 0xFFFF_FFF8);
+        volume.set_fat(1, 0xFFFF_FFFF);
+        let bitmap = volume.allocate(1, Layout::Chained)[0];
+        // The root directory itself is fragmented, so listing follows the FAT.
+        volume.root = volume.allocate(ROOT_CLUSTERS, Layout::Fragmented);
+
+        let mut entry = [0; 32];
+        entry[0] = ENTRY_BITMAP;
+        entry[20..24].copy_from_slice(&bitmap.to_le_bytes());
+        entry[24..32].copy_from_slice(&(CLUSTERS as u64).div_ceil(8).to_le_bytes());
+        volume.entries.push(entry);
+
+        let mut label = [0; 32];
+        label[0] = ENTRY_LABEL;
+        label[1] = 4;
+        for (unit, c) in label[2..].chunks_exact_mut(2).zip("CARD".encode_utf16()) {
+            unit.copy_from_slice(&c.to_le_bytes());
+        }
+        volume.entries.push(label);
+        volume
+    }
+
+    fn block_offset(block: u32) -> usize {
+        (START + block) as usize * Block::LEN
+    }
+
+    fn cluster_offset(cluster: u32) -> usize {
+        Self::block_offset(HEAP_OFFSET + ((cluster - 2) << CLUSTER_SHIFT))
+    }
+
+    fn set_fat(&mut self, cluster: u32, next: u32) {
+        let offset = Self::block_offset(FAT_OFFSET) + cluster as usize * 4;
+        self.image[offset..offset + 4].copy_from_slice(&next.to_le_bytes());
+    }
+
+    fn fat(&self, cluster: u32) -> u32 {
+        let offset = Self::block_offset(FAT_OFFSET) + cluster as usize * 4;
+        u32::from_le_bytes(self.image[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Takes `count` free clusters, linked in the FAT unless `layout` says
+    /// otherwise.
+    fn allocate(&mut self, count: usize, layout: Layout) -> Vec<u32> {
+        let first = self.next_cluster;
+        let clusters: Vec<u32> = match layout {
+            Layout::Fragmented => (0..count as u32).rev().map(|i| first + 2 * i).collect(),
+            _ => (0..count as u32).map(|i| first + i).collect(),
+        };
+        self.next_cluster += match layout {
+            Layout::Fragmented => 2 * count as u32,
+            _ => count as u32,
+        };
+        assert!(self.next_cluster <= CLUSTERS + 2, "volume full");
+        self.allocated.extend(&clusters);
+        if layout != Layout::NoFatChain {
+            for pair in clusters.windows(2) {
+                self.set_fat(pair[0], pair[1]);
+            }
+            if let Some(&last) = clusters.last() {
+                self.set_fat(last, 0xFFFF_FFFF);
+            }
+        }
+        clusters
+    }
+
+    fn add_file(&mut self, name: &str, data: &[u8], layout: Layout) -> Vec<u32> {
+        let clusters = self.allocate(data.len().div_ceil(CLUSTER_SIZE), layout);
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks(CLUSTER_SIZE)) {
+            let offset = Self::cluster_offset(cluster);
+            self.image[offset..offset + chunk.len()].copy_from_slice(chunk);
+        }
+        let flags = if layout == Layout::NoFatChain {
+            NO_FAT_CHAIN
+        } else {
+            0
+        };
+        let first = clusters.first().copied().unwrap_or(0);
+        self.add_entry_set(name, first, data.len() as u64, flags);
+        clusters
+    }
+
+    /// A file claiming `length` bytes from a single cluster, for lengths too
+    /// large to hold in memory. Only its directory entries can be trusted.
+    fn add_oversized(&mut self, name: &str, length: u64) {
+        let first = self.allocate(1, Layout::NoFatChain)[0];
+        self.add_entry_set(name, first, length, NO_FAT_CHAIN);
+    }
+
+    fn add_entry_set(&mut self, name: &str, first_cluster: u32, length: u64, flags: u8) {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let name_entries = units.len().div_ceil(NAME_UNITS);
+
+        let mut file = [0; 32];
+        file[0] = ENTRY_FILE;
+        file[1] = 1 + name_entries as u8;
+        file[4..6].copy_from_slice(&ATTR_ARCHIVE.to_le_bytes());
+        for timestamp in [8, 12, 16] {
+            file[timestamp..timestamp + 4].copy_from_slice(&MODIFIED.to_le_bytes());
+        }
+
+        let mut stream = [0; 32];
+        stream[0] = ENTRY_STREAM;
+        stream[1] = ALLOCATION_POSSIBLE | flags;
+        stream[3] = units.len() as u8;
+        stream[4..6].copy_from_slice(&name_hash(&units).to_le_bytes());
+        stream[8..16].copy_from_slice(&length.to_le_bytes());
+        stream[20..24].copy_from_slice(&first_cluster.to_le_bytes());
+        stream[24..32].copy_from_slice(&length.to_le_bytes());
+
+        let mut set = vec![file, stream];
+        for part in units.chunks(NAME_UNITS) {
+            let mut entry = [0; 32];
+            entry[0] = ENTRY_NAME;
+            for (slot, unit) in entry[2..].chunks_exact_mut(2).zip(part) {
+                slot.copy_from_slice(&unit.to_le_bytes());
+            }
+            set.push(entry);
+        }
+        let checksum = set_checksum(&set);
+        set[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        self.entries.extend(set);
+    }
+
+    /// Writes the root directory and the bitmap, and hands over the card.
+    fn finish(mut self) -> Card {
+        let per_cluster = CLUSTER_SIZE / 32;
+        assert!(
+            self.entries.len() < ROOT_CLUSTERS * per_cluster,
+            "root full"
+        );
+        for (index, entry) in self.entries.iter().enumerate() {
+            let cluster = self.root[index / per_cluster];
+            let offset = Self::cluster_offset(cluster) + index % per_cluster * 32;
+            self.image[offset..offset + 32].copy_from_slice(entry);
+        }
+
+        let bitmap = Self::cluster_offset(2);
+        for &cluster in &self.allocated {
+            let bit = (cluster - 2) as usize;
+            self.image[bitmap + bit / 8] |= 1 << (bit % 8);
+        }
+
+        let boot = &mut self.image[Self::block_offset(0)..][..Block::LEN];
+        boot[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        boot[3..11].copy_from_slice(b"EXFAT   ");
+        boot[64..72].copy_from_slice(&(START as u64).to_le_bytes());
+        boot[72..80].copy_from_slice(&(BLOCKS as u64).to_le_bytes());
+        boot[80..84].copy_from_slice(&FAT_OFFSET.to_le_bytes());
+        let fat_length = ((CLUSTERS + 2) * 4).div_ceil(Block::LEN_U32);
+        boot[84..88].copy_from_slice(&fat_length.to_le_bytes());
+        boot[88..92].copy_from_slice(&HEAP_OFFSET.to_le_bytes());
+        boot[92..96].copy_from_slice(&CLUSTERS.to_le_bytes());
+        boot[96..100].copy_from_slice(&self.root[0].to_le_bytes());
+        boot[100..104].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        boot[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+        boot[108] = 9;
+        boot[109] = CLUSTER_SHIFT;
+        boot[110] = 1;
+        boot[111] = 0x80;
+        boot[112] = (self.allocated.len() * 100 / CLUSTERS as usize) as u8;
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+        Card::new(self.image)
+    }
+}
+
+/// Over the whole entry set, leaving out the checksum field itself.
+fn set_checksum(entries: &[[u8; 32]]) -> u16 {
+    let mut sum: u16 = 0;
+    for (index, &byte) in entries.iter().flatten().enumerate() {
+        if index != 2 && index != 3 {
+            sum = sum.rotate_right(1).wrapping_add(byte as u16);
+        }
+    }
+    sum
+}
+
+/// Over the up-cased name, ASCII is enough for the names used here.
+fn name_hash(units: &[u16]) -> u16 {
+    let mut hash: u16 = 0;
+    for unit in units {
+        let upper = match *unit {
+            unit @ 0x61..=0x7A => unit - 0x20,
+            unit => unit,
+        };
+        for byte in upper.to_le_bytes() {
+            hash = hash.rotate_right(1).wrapping_add(byte as u16);
+        }
+    }
+    hash
+}
+
+/// Reads the rest of `file` in pieces that straddle block and cluster
+/// boundaries.
+fn read_to_end(storage: &mut Storage<Card, Clock>, file: &mut File) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0; 700];
+    loop {
+        let count = storage.read(file, &mut buf).unwrap();
+        if count == 0 {
+            return data;
+        }
+        data.extend_from_slice(&buf[..count]);
+    }
+}
+
+#[test]
+fn fragmented_file_follows_the_fat() {
+    let data = contents(9000, 5);
+    let mut volume = ExFat::new();
+    let clusters = volume.add_file("FRAG.BIN", &data, Layout::Fragmented);
+    assert!(clusters.windows(2).all(|pair| pair[1] != pair[0] + 1));
+    let card = volume.finish();
+
+    with_storage(&card, |storage, root| {
+        let mut file = storage.open(root, "frag.bin").unwrap();
+        assert_eq!(file.length(), 9000);
+        assert_eq!(read_to_end(storage, &mut file), data);
+
+        // Going back walks the chain again from its start.
+        file.seek_from_start(2500).unwrap();
+        let mut buf = [0; 600];
+        assert_eq!(storage.read(&mut file, &mut buf).unwrap(), 600);
+        assert_eq!(buf[..], data[2500..3100]);
+        storage.close(file);
+    });
+}
+
+#[test]
+fn no_fat_chain_file_ignores_the_fat() {
+    let data = contents(5000, 6);
+    let mut volume = ExFat::new();
+    let clusters = volume.add_file("CONTIG.BIN", &data, Layout::NoFatChain);
+    assert!(clusters.iter().all(|&cluster| volume.fat(cluster) == 0));
+    let card = volume.finish();
+
+    with_storage(&card, |storage, root| {
+        let entry = entry(storage, root, "CONTIG.BIN");
+        assert_eq!(entry.extension, "BIN");
+        let mut file = storage.open_entry(root, &entry).unwrap();
+        assert_eq!(file.length(), 5000);
+        assert_eq!(read_to_end(storage, &mut file), data);
+        storage.close(file);
+    });
+}
+
+#[test]
+fn long_names_span_several_entries() {
+    let long = "A name well past fifteen units.txt";
+    let data = contents(1500, 7);
+    let mut volume = ExFat::new();
+    // Enough sets in front that the long one lands in the second root
+    // cluster.
+    for index in 0..10 {
+        volume.add_file(&format!("FILL{index}.BIN"), &[index], Layout::Chained);
+    }
+    volume.add_file(long, &data, Layout::Chained);
+    let card = volume.finish();
+
+    with_storage(&card, |storage, root| {
+        let listed = storage.list(root, |_| true);
+        assert_eq!(listed.len(), 11);
+        let entry = entry(storage, root, long);
+        assert_eq!(entry.extension, "TXT");
+        assert!(entry.short_name().is_none());
+        let mut file = storage.open_entry(root, &entry).unwrap();
+        assert_eq!(read_to_end(storage, &mut file), data);
+        storage.close(file);
+
+        let file = storage.open(root, &long.to_uppercase()).unwrap();
+        assert_eq!(file.length(), 1500);
+        storage.close(file);
+    });
+}
+
+#[test]
+fn files_of_4_gib_are_refused() {
+    let mut volume = ExFat::new();
+    volume.add_oversized("EDGE.BIN", u32::MAX as u64);
+    volume.add_oversized("HUGE.BIN", 1 << 32);
+    let card = volume.finish();
+
+    with_storage(&card, |storage, root| {
+        let file = storage.open(root, "EDGE.BIN").unwrap();
+        assert_eq!(file.length(), u32::MAX);
+        storage.close(file);
+
+        assert!(matches!(
+            storage.open(root, "HUGE.BIN"),
+            Err(FsError::TooLarge)
+        ));
+        let entry = entry(storage, root, "HUGE.BIN");
+        assert!(matches!(
+            storage.open_entry(root, &entry),
+            Err(FsError::TooLarge)
+        ));
+    });
+}
@@ -0,0 +1,223 @@
+mod common;
+
+use std::io::{Cursor, Read, Write};
+
+use common::{contents, entry, with_storage, Card, FAT16_BLOCKS, FAT32_BLOCKS, START};
+use embedded_sdmmc::Block;
+use fatfs::{FatType, FileSystem, FsOptions};
+use files_test::{fat, fs::FsError};
+
+const LONG_NAME: &str = "Holiday photo.bmp";
+const LONG_SHORT: &str = "HOLIDA~1.BMP";
+
+/// A card with one partition holding a few files, one of them under a long
+/// name and one in a subdirectory.
+fn fixture(fat_type: FatType, blocks: u32) -> Card {
+    let files = [
+        ("PHOTO1.BMP", contents(3000, 1)),
+        (LONG_NAME, contents(70_000, 2)),
+        ("IMAGES/SUNSET.QOI", contents(1500, 3)),
+    ];
+    common::fixture(fat_type, blocks, &files)
+}
+
+/// Reads the card back with an independent FAT implementation.
+fn check<R>(card: &Card, f: impl FnOnce(&fatfs::Dir<Cursor<&mut [u8]>>) -> R) -> R {
+    let mut image = card.0.borrow().clone();
+    let volume = &mut image[START as usize * Block::LEN..];
+    let fs = FileSystem::new(Cursor::new(volume), FsOptions::new()).unwrap();
+    let result = f(&fs.root_dir());
+    result
+}
+
+fn names(dir: &fatfs::Dir<Cursor<&mut [u8]>>) -> Vec<String> {
+    let mut names: Vec<String> = dir
+        .iter()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    names.sort();
+    names
+}
+
+fn read(dir: &fatfs::Dir<Cursor<&mut [u8]>>, path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    dir.open_file(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+/// Live long name entries anywhere on the card that start with `prefix`.
+fn long_name_entries(card: &Card, prefix: &str) -> usize {
+    let image = card.0.borrow();
+    let units: Vec<u8> = prefix.bytes().take(5).flat_map(|c| [c, 0]).collect();
+    image
+        .chunks_exact(fat::ENTRY_SIZE)
+        .filter(|entry| entry[11] == 0x0F && entry[0] != fat::DELETED)
+        .filter(|entry| entry[1..1 + units.len()] == units[..])
+        .count()
+}
+
+fn volumes() -> [Card; 2] {
+    [
+        fixture(FatType::Fat16, FAT16_BLOCKS),
+        fixture(FatType::Fat32, FAT32_BLOCKS),
+    ]
+}
+
+#[test]
+fn short_names() {
+    assert_eq!(fat::short_name("PHOTO1.BMP"), Some(*b"PHOTO1  BMP"));
+    assert_eq!(fat::short_name("backup"), Some(*b"BACKUP     "));
+    assert_eq!(fat::short_name("A-B_C.Q"), Some(*b"A-B_C   Q  "));
+    for name in [
+        "",
+        ".BMP",
+        "TOOLONGNAME.BMP",
+        "A.BMPX",
+        "A.B.C",
+        "A B.BMP",
+        "A+B",
+    ] {
+        assert_eq!(fat::short_name(name), None, "{name}");
+    }
+}
+
+#[test]
+fn delete_removes_file_and_long_name() {
+    for card in volumes() {
+        assert_eq!(long_name_entries(&card, LONG_NAME), 1);
+        with_storage(&card, |storage, root| {
+            let entry = entry(storage, root, LONG_NAME);
+            storage.delete(root, &entry).unwrap();
+            assert!(storage
+                .list(root, |_| true)
+                .iter()
+                .all(|e| e.label != LONG_NAME));
+        });
+        assert_eq!(long_name_entries(&card, LONG_NAME), 0);
+        check(&card, |root| {
+            assert_eq!(names(root), ["IMAGES", "PHOTO1.BMP"]);
+            assert_eq!(read(root, "PHOTO1.BMP"), contents(3000, 1));
+        });
+    }
+}
+
+#[test]
+fn delete_in_subdirectory() {
+    for card in volumes() {
+        with_storage(&card, |storage, root| {
+            let images = storage.open_dir_named(root, "IMAGES").unwrap();
+            let entry = entry(storage, &images, "SUNSET.QOI");
+            storage.delete(&images, &entry).unwrap();
+            storage.close_dir(images);
+        });
+        check(&card, |root| {
+            assert!(names(&root.open_dir("IMAGES").unwrap()).is_empty());
+        });
+    }
+}
+
+#[test]
+fn rename_replaces_long_name() {
+    for card in volumes() {
+        with_storage(&card, |storage, root| {
+            let entry = entry(storage, root, LONG_NAME);
+            assert_eq!(entry.short_name().unwrap(), LONG_SHORT);
+            storage.rename(root, &entry, "TRIP.BMP").unwrap();
+            let renamed = self::entry(storage, root, "TRIP.BMP");
+            assert_eq!(renamed.short_name().unwrap(), "TRIP.BMP");
+        });
+        assert_eq!(long_name_entries(&card, LONG_NAME), 0);
+        check(&card, |root| {
+            assert_eq!(names(root), ["IMAGES", "PHOTO1.BMP", "TRIP.BMP"]);
+            assert_eq!(read(root, "TRIP.BMP"), contents(70_000, 2));
+        });
+    }
+}
+
+#[test]
+fn rename_keeps_taken_names() {
+    for card in volumes() {
+        with_storage(&card, |storage, root| {
+            let entry = entry(storage, root, LONG_NAME);
+            assert!(matches!(
+                storage.rename(root, &entry, "PHOTO1.BMP"),
+                Err(FsError::Exists)
+            ));
+            assert!(matches!(
+                storage.rename(root, &entry, "NOT VALID"),
+                Err(FsError::Invalid)
+            ));
+        });
+        check(&card, |root| {
+            assert_eq!(names(root), ["Holiday photo.bmp", "IMAGES", "PHOTO1.BMP"]);
+            assert_eq!(read(root, "PHOTO1.BMP"), contents(3000, 1));
+        });
+    }
+}
+
+#[test]
+fn make_dir_creates_usable_directories() {
+    for card in volumes() {
+        with_storage(&card, |storage, root| {
+            storage.make_dir(root, "NEW").unwrap();
+            let new = storage.open_dir_named(root, "NEW").unwrap();
+            assert!(storage.list(&new, |_| true).is_empty());
+            storage.make_dir(&new, "SUB").unwrap();
+            storage.close_dir(new);
+            assert!(matches!(
+                storage.make_dir(root, "NEW"),
+                Err(FsError::Exists)
+            ));
+        });
+        check(&card, |root| {
+            assert_eq!(
+                names(root),
+                ["Holiday photo.bmp", "IMAGES", "NEW", "PHOTO1.BMP"]
+            );
+            let new = root.open_dir("NEW").unwrap();
+            assert_eq!(names(&new), ["SUB"]);
+            let sub = new.open_dir("SUB").unwrap();
+            assert!(names(&sub).is_empty());
+            // `..` leads back to the parent, and to the root from a top level
+            // directory.
+            assert_eq!(names(&sub.open_dir("..").unwrap()), ["SUB"]);
+            assert_eq!(names(&new.open_dir("..").unwrap()), names(root));
+
+            // Other implementations can fill them.
+            sub.create_file("NOTE.TXT")
+                .unwrap()
+                .write_all(&contents(5000, 4))
+                .unwrap();
+            assert_eq!(read(&sub, "NOTE.TXT"), contents(5000, 4));
+        });
+    }
+}
+
+#[test]
+fn copy_to_backup_directory() {
+    for card in volumes() {
+        with_storage(&card, |storage, root| {
+            storage.make_dir(root, "BACKUP").unwrap();
+            let backup = storage.open_dir_named(root, "BACKUP").unwrap();
+
+            let entry = entry(storage, root, LONG_NAME);
+            storage.copy(root, &entry, &backup).unwrap();
+            // A second copy replaces the first.
+            storage.copy(root, &entry, &backup).unwrap();
+
+            let images = storage.open_dir_named(root, "IMAGES").unwrap();
+            let entry = self::entry(storage, &images, "SUNSET.QOI");
+            storage.copy(&images, &entry, &backup).unwrap();
+            storage.close_dir(images);
+            storage.close_dir(backup);
+        });
+        check(&card, |root| {
+            let backup = root.open_dir("BACKUP").unwrap();
+            assert_eq!(names(&backup), [LONG_SHORT, "SUNSET.QOI"]);
+            assert_eq!(read(&backup, LONG_SHORT), contents(70_000, 2));
+            assert_eq!(read(&backup, "SUNSET.QOI"), contents(1500, 3));
+            assert_eq!(read(root, LONG_NAME), contents(70_000, 2));
+        });
+    }
+}
@@ -0,0 +1,256 @@
+mod common;
+
+use std::io::{Cursor, Read, Write};
+
+use common::{Card, Clock};
+use embedded_sdmmc::{Block, Controller};
+use fatfs::{FatType, FileSystem, FsOptions};
+use files_test::{
+    fat,
+    format::{self, FormatError, Geometry, VOLUME_START},
+    fs::{self, Storage},
+    partition::{self, FsKind},
+};
+
+const MIB: u32 = 1024 * 1024 / Block::LEN_U32;
+const SERIAL: u32 = 0x1234_5678;
+
+/// A used card: every byte set, and a GPT header where the formatter has to
+/// get rid of it.
+fn used_card(blocks: u32) -> Card {
+    let mut image = vec![0xA5; blocks as usize * Block::LEN];
+    image[Block::LEN..Block::LEN + 8].copy_from_slice(b"EFI PART");
+    Card::new(image)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn block(image: &[u8], index: u32) -> &[u8] {
+    &image[index as usize * Block::LEN..][..Block::LEN]
+}
+
+/// Checks the structures the way a FAT validator would: partition table,
+/// boot sector and its backup, FSInfo against the FATs, and both FATs the
+/// same. Returns the free clusters counted in the FAT.
+fn validate(card: &Card) -> u32 {
+    let image = card.0.borrow();
+    let blocks = (image.len() / Block::LEN) as u32;
+
+    let mbr = block(&image, 0);
+    assert_eq!(mbr[510..], [0x55, 0xAA]);
+    assert_eq!(mbr[446 + 4], 0x0C);
+    assert_eq!(u32_at(mbr, 446 + 8), VOLUME_START);
+    assert_eq!(u32_at(mbr, 446 + 12), blocks - VOLUME_START);
+    assert!(mbr[462..510].iter().all(|&byte| byte == 0));
+    assert!(block(&image, 1).iter().all(|&byte| byte == 0));
+
+    let boot = block(&image, VOLUME_START);
+    assert_eq!(boot, block(&image, VOLUME_START + 6));
+    assert_eq!(boot[510..], [0x55, 0xAA]);
+    assert_eq!(u16_at(boot, 11), 512);
+    let per_cluster = boot[13] as u32;
+    assert!(per_cluster.is_power_of_two());
+    let reserved = u16_at(boot, 14) as u32;
+    assert_eq!(boot[16], 2);
+    assert_eq!(u16_at(boot, 17), 0, "no fixed root directory");
+    assert_eq!(u16_at(boot, 19), 0);
+    assert_eq!(u16_at(boot, 22), 0);
+    assert_eq!(u32_at(boot, 28), VOLUME_START);
+    let total = u32_at(boot, 32);
+    assert_eq!(total, blocks - VOLUME_START);
+    let fat_blocks = u32_at(boot, 36);
+    assert_eq!(u32_at(boot, 44), 2);
+    assert_eq!(&boot[82..90], b"FAT32   ");
+
+    let data_start = reserved + 2 * fat_blocks;
+    assert_eq!(
+        (VOLUME_START + data_start) % per_cluster,
+        0,
+        "clusters aligned"
+    );
+    let clusters = (total - data_start) / per_cluster;
+    assert!(clusters >= format::MIN_CLUSTERS);
+    assert!(fat_blocks * 128 >= clusters + 2, "FAT covers every cluster");
+
+    let fat_start = (VOLUME_START + reserved) as usize * Block::LEN;
+    let fat_len = fat_blocks as usize * Block::LEN;
+    let (first, second) = image[fat_start..fat_start + 2 * fat_len].split_at(fat_len);
+    assert!(first == second, "FATs differ");
+    let entries: Vec<u32> = first
+        .chunks_exact(4)
+        .map(|entry| u32_at(entry, 0) & 0x0FFF_FFFF)
+        .collect();
+    assert_eq!(entries[0], 0x0FFF_FFF8);
+    assert_eq!(entries[1], 0x0FFF_FFFF);
+    assert!(entries[2] >= 0x0FFF_FFF8, "root is one cluster");
+    assert!(
+        entries[clusters as usize + 2..]
+            .iter()
+            .all(|&entry| entry == 0),
+        "entries past the last cluster are free"
+    );
+    let free = entries[2..clusters as usize + 2]
+        .iter()
+        .filter(|&&entry| entry == 0)
+        .count() as u32;
+
+    let info = block(&image, VOLUME_START + 1);
+    assert_eq!(info, block(&image, VOLUME_START + 7));
+    assert_eq!(u32_at(info, 0), 0x4161_5252);
+    assert_eq!(u32_at(info, 484), 0x6141_7272);
+    assert_eq!(u32_at(info, 508), 0xAA55_0000);
+    assert_eq!(u32_at(info, 488), free, "FSInfo free count");
+    free
+}
+
+fn with_fatfs<R>(card: &Card, f: impl FnOnce(&FileSystem<Cursor<&mut [u8]>>) -> R) -> R {
+    let mut image = card.0.borrow_mut();
+    let volume = &mut image[VOLUME_START as usize * Block::LEN..];
+    let fs = FileSystem::new(Cursor::new(volume), FsOptions::new()).unwrap();
+    let result = f(&fs);
+    fs.unmount().unwrap();
+    result
+}
+
+#[test]
+fn geometry_follows_card_size() {
+    for (size_mib, per_cluster) in [
+        (40, 1),
+        (256, 1),
+        (2 * 1024, 8),
+        (8 * 1024, 8),
+        (15 * 1024, 16),
+        (30 * 1024, 32),
+        (60 * 1024, 64),
+        (128 * 1024, 64),
+    ] {
+        let geometry = Geometry::new(size_mib * MIB).unwrap();
+        assert_eq!(geometry.blocks_per_cluster, per_cluster, "{size_mib} MiB");
+        assert_eq!(geometry.blocks, size_mib * MIB - VOLUME_START);
+        assert_eq!((VOLUME_START + geometry.data_start()) % per_cluster, 0);
+        assert!(geometry.fat_blocks * 128 >= geometry.clusters + 2);
+        assert!(geometry.clusters >= format::MIN_CLUSTERS);
+        let used = geometry.data_start() + geometry.clusters * per_cluster;
+        assert!(used <= geometry.blocks);
+        assert!(geometry.blocks - used < per_cluster);
+    }
+    assert_eq!(Geometry::new(32 * MIB), None);
+    assert_eq!(Geometry::new(VOLUME_START - 1), None);
+}
+
+#[test]
+fn volume_labels() {
+    assert_eq!(fat::volume_label("loader"), Some(*b"LOADER     "));
+    assert_eq!(fat::volume_label("PHOTOS_2024"), Some(*b"PHOTOS_2024"));
+    for label in ["", "TWELVE_CHARS", "A.B", "A+B"] {
+        assert_eq!(fat::volume_label(label), None, "{label}");
+    }
+}
+
+#[test]
+fn formatted_card_is_valid_fat32() {
+    let card = used_card(48 * MIB);
+    let mut reports = Vec::new();
+    let geometry = format::format(&card, b"PICO       ", SERIAL, |written| {
+        reports.push(written)
+    })
+    .unwrap();
+    assert!(reports.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(reports.last(), Some(&geometry.blocks_written()));
+
+    let free = validate(&card);
+    assert_eq!(free, geometry.clusters - 1);
+    with_fatfs(&card, |fs| {
+        assert_eq!(fs.fat_type(), FatType::Fat32);
+        assert_eq!(fs.volume_label(), "PICO");
+        assert_eq!(fs.volume_id(), SERIAL);
+        let stats = fs.stats().unwrap();
+        assert_eq!(stats.total_clusters(), geometry.clusters);
+        assert_eq!(stats.free_clusters(), free);
+        assert_eq!(fs.root_dir().iter().count(), 0);
+
+        let root = fs.root_dir();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        root.create_dir("APPS").unwrap();
+        root.create_file("APPS/HELLO.BIN")
+            .unwrap()
+            .write_all(&data)
+            .unwrap();
+        let mut back = Vec::new();
+        root.open_file("APPS/HELLO.BIN")
+            .unwrap()
+            .read_to_end(&mut back)
+            .unwrap();
+        assert_eq!(back, data);
+    });
+}
+
+#[test]
+fn formatted_card_mounts_in_loader() {
+    let card = used_card(48 * MIB);
+    let geometry = format::format(&card, b"LOADER     ", SERIAL, |_| {}).unwrap();
+
+    let mut controller = Controller::new(card.clone(), Clock);
+    let partitions = partition::scan(controller.device()).unwrap();
+    assert_eq!(partitions.len(), 1);
+    let volume = &partitions[0];
+    assert!(volume.fs == FsKind::Fat32);
+    assert!(volume.is_mountable());
+    assert_eq!(volume.label, "LOADER");
+    let usage = partition::usage(controller.device(), volume).unwrap();
+    assert_eq!(usage.clusters, geometry.clusters);
+    assert_eq!(usage.free_clusters, Some(geometry.clusters - 1));
+
+    let mut mounted = fs::mount(&mut controller, volume).unwrap();
+    let mut storage = Storage {
+        controller: &mut controller,
+        volume: &mut mounted,
+    };
+    let root = storage.open_root().unwrap();
+    assert!(storage.list(&root, |_| true).is_empty());
+    storage.make_dir(&root, "BACKUP").unwrap();
+    let mut file = storage.create(&root, "NOTE.TXT").unwrap();
+    storage.write(&mut file, b"formatted").unwrap();
+    storage.close(file);
+    storage.close_dir(root);
+
+    with_fatfs(&card, |fs| {
+        let mut text = String::new();
+        fs.root_dir()
+            .open_file("NOTE.TXT")
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "formatted");
+        assert_eq!(fs.root_dir().open_dir("BACKUP").unwrap().iter().count(), 2);
+    });
+}
+
+#[test]
+fn small_card_is_left_alone() {
+    let card = used_card(32 * MIB);
+    let before = card.0.borrow().clone();
+    assert_eq!(
+        format::format(&card, b"PICO       ", SERIAL, |_| {}),
+        Err(FormatError::TooSmall)
+    );
+    assert!(*card.0.borrow() == before);
+}
+
+#[test]
+fn card_just_over_volume_start_is_too_small() {
+    for blocks in VOLUME_START..VOLUME_START + 2 * 1024 {
+        assert_eq!(Geometry::new(blocks), None, "{blocks} blocks");
+    }
+    let card = Card::new(vec![0; (VOLUME_START + 16) as usize * Block::LEN]);
+    assert_eq!(
+        format::format(&card, b"PICO       ", SERIAL, |_| {}),
+        Err(FormatError::TooSmall)
+    );
+}
@@ -0,0 +1,194 @@
+mod common;
+
+use common::{with_storage, Card, FAT16_BLOCKS, FAT32_BLOCKS, START};
+use embedded_sdmmc::Block;
+use fatfs::FatType;
+use files_test::fsck::{self, Chain, Fat, Report};
+
+/// A card with one partition holding two apps of the same size, a small
+/// file and one in a subdirectory.
+fn fixture(fat_type: FatType, blocks: u32) -> Card {
+    let files = [
+        ("GAME.BIN", common::contents(5000, 0)),
+        ("TOOL.BIN", common::contents(5000, 0)),
+        ("NOTE.TXT", common::contents(100, 0)),
+        ("APPS/DEMO.ELF", common::contents(2000, 0)),
+    ];
+    common::fixture(fat_type, blocks, &files)
+}
+
+fn check(card: &Card, bitmap_bytes: usize) -> Report {
+    fsck::check(card, START, &mut vec![0; bitmap_bytes]).unwrap()
+}
+
+/// Byte offset of the root directory entry `name` in the image.
+fn root_entry(card: &Card, fat: &Fat, name: &[u8; 11]) -> usize {
+    let root = if fat.fat32 {
+        fat.data_start + (fat.root_cluster - 2) * fat.blocks_per_cluster
+    } else {
+        fat.root_start
+    };
+    let start = root as usize * Block::LEN;
+    let image = card.0.borrow();
+    (start..start + Block::LEN)
+        .step_by(32)
+        .find(|&offset| &image[offset..offset + 11] == name)
+        .unwrap()
+}
+
+fn first_cluster(card: &Card, entry: usize) -> u32 {
+    let image = card.0.borrow();
+    let high = u16::from_le_bytes([image[entry + 20], image[entry + 21]]) as u32;
+    let low = u16::from_le_bytes([image[entry + 26], image[entry + 27]]) as u32;
+    high << 16 | low
+}
+
+/// Sets the FAT entry of `cluster` in the FAT numbered `copy`.
+fn set_link(card: &Card, fat: &Fat, copy: u32, cluster: u32, next: u32) {
+    let width = if fat.fat32 { 4 } else { 2 };
+    let start = (fat.fat_start + copy * fat.fat_blocks) as usize * Block::LEN;
+    let offset = start + (cluster * width) as usize;
+    let bytes = next.to_le_bytes();
+    card.0.borrow_mut()[offset..offset + width as usize].copy_from_slice(&bytes[..width as usize]);
+}
+
+fn set_links(card: &Card, fat: &Fat, cluster: u32, next: u32) {
+    for copy in 0..fat.fats {
+        set_link(card, fat, copy, cluster, next);
+    }
+}
+
+fn app_chain(card: &Card, name: &str) -> Chain {
+    with_storage(card, |storage, root| {
+        let entry = common::entry(storage, root, name);
+        storage.check_chain(root, &entry).unwrap()
+    })
+}
+
+#[test]
+fn clean_volumes_pass() {
+    for (fat_type, blocks) in [
+        (FatType::Fat16, FAT16_BLOCKS),
+        (FatType::Fat32, FAT32_BLOCKS),
+    ] {
+        let card = fixture(fat_type, blocks);
+        let report = check(&card, fsck::BITMAP_BYTES);
+        assert!(report.is_clean(), "{fat_type:?}: {report:?}");
+        assert_eq!(report.files, 4);
+        assert_eq!(report.dirs, 1);
+        assert_eq!(report.skipped_dirs, 0);
+        assert!(report.damaged.is_empty());
+        assert_eq!(app_chain(&card, "GAME.BIN"), Chain::Intact);
+    }
+}
+
+#[test]
+fn differing_fat_copies_are_counted() {
+    let card = fixture(FatType::Fat32, FAT32_BLOCKS);
+    let fat = Fat::read(&card, START).unwrap();
+    // A link written to the first FAT only, as when the card was pulled.
+    set_link(&card, &fat, 0, fat.clusters, 0x0FFF_FFFF);
+    let report = check(&card, fsck::BITMAP_BYTES);
+    assert_eq!(report.fat_mismatches, 1);
+    assert_eq!(report.bad_chains, 0);
+    assert!(!report.is_clean());
+}
+
+#[test]
+fn cut_short_chain_is_found() {
+    for (fat_type, blocks) in [
+        (FatType::Fat16, FAT16_BLOCKS),
+        (FatType::Fat32, FAT32_BLOCKS),
+    ] {
+        let card = fixture(fat_type, blocks);
+        let fat = Fat::read(&card, START).unwrap();
+        let entry = root_entry(&card, &fat, b"GAME    BIN");
+        let first = first_cluster(&card, entry);
+        set_links(&card, &fat, first + 1, 0x0FFF_FFFF);
+
+        let report = check(&card, fsck::BITMAP_BYTES);
+        assert_eq!(report.bad_chains, 1, "{fat_type:?}");
+        assert_eq!(report.cross_linked, 0);
+        assert_eq!(report.damaged, ["GAME.BIN"]);
+        assert_eq!(app_chain(&card, "GAME.BIN"), Chain::Short);
+        assert_eq!(app_chain(&card, "TOOL.BIN"), Chain::Intact);
+    }
+}
+
+#[test]
+fn chain_into_free_cluster_is_broken() {
+    let card = fixture(FatType::Fat16, FAT16_BLOCKS);
+    let fat = Fat::read(&card, START).unwrap();
+    let entry = root_entry(&card, &fat, b"GAME    BIN");
+    let first = first_cluster(&card, entry);
+    set_links(&card, &fat, first + 2, fat.clusters);
+
+    let report = check(&card, fsck::BITMAP_BYTES);
+    assert_eq!(report.bad_chains, 1);
+    assert_eq!(app_chain(&card, "GAME.BIN"), Chain::Broken);
+}
+
+#[test]
+fn longer_chain_than_size() {
+    let card = fixture(FatType::Fat32, FAT32_BLOCKS);
+    let fat = Fat::read(&card, START).unwrap();
+    let entry = root_entry(&card, &fat, b"NOTE    TXT");
+    card.0.borrow_mut()[entry + 28..entry + 32].copy_from_slice(&0u32.to_le_bytes());
+
+    let report = check(&card, fsck::BITMAP_BYTES);
+    assert_eq!(report.bad_chains, 1);
+    assert_eq!(report.damaged, ["NOTE.TXT"]);
+    assert_eq!(app_chain(&card, "NOTE.TXT"), Chain::Long);
+}
+
+#[test]
+fn cross_links_are_found_in_every_window() {
+    let card = fixture(FatType::Fat32, FAT32_BLOCKS);
+    let fat = Fat::read(&card, START).unwrap();
+    let game = root_entry(&card, &fat, b"GAME    BIN");
+    let tool = root_entry(&card, &fat, b"TOOL    BIN");
+    {
+        let mut image = card.0.borrow_mut();
+        let (low, high) = (
+            image[game + 26..game + 28].to_vec(),
+            image[game + 20..game + 22].to_vec(),
+        );
+        image[tool + 26..tool + 28].copy_from_slice(&low);
+        image[tool + 20..tool + 22].copy_from_slice(&high);
+    }
+
+    let report = check(&card, fsck::BITMAP_BYTES);
+    // Both files are 5000 bytes, ten clusters of 512.
+    assert_eq!(report.cross_linked, 10);
+    assert_eq!(report.bad_chains, 0);
+    assert_eq!(report.damaged.len(), 1);
+    // A one-byte bitmap takes a walk for every eight clusters.
+    let windowed = check(&card, 1);
+    assert_eq!(windowed.cross_linked, report.cross_linked);
+    assert_eq!(windowed.files, report.files);
+    assert_eq!(windowed.damaged, report.damaged);
+}
+
+#[test]
+fn non_fat_volume_is_not_checked() {
+    let card = Card::new(vec![0; 64 * Block::LEN]);
+    assert_eq!(
+        fsck::check(&card, 0, &mut [0; 16]).unwrap_err(),
+        fsck::CheckError::Unsupported
+    );
+}
+
+#[test]
+fn overflowing_boot_sector_is_not_checked() {
+    let card = fixture(FatType::Fat32, FAT32_BLOCKS);
+    let boot = START as usize * Block::LEN;
+    for (offset, value) in [(32, u32::MAX), (36, u32::MAX / 2)] {
+        let card = Card::new(card.0.borrow().clone());
+        card.0.borrow_mut()[boot + offset..boot + offset + 4]
+            .copy_from_slice(&value.to_le_bytes());
+        assert_eq!(
+            Fat::read(&card, START).unwrap_err(),
+            fsck::CheckError::Unsupported
+        );
+    }
+}
@@ -0,0 +1,14 @@
+//! Host build of the loader's image decoders, so they can be checked against
+//! reference encoders. The loader's `.cargo/config.toml` builds for the
+//! RP2040, so name the host target:
+//!
+//! ```sh
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+
+#[path = "../../../src/inflate.rs"]
+pub mod inflate;
+#[path = "../../../src/png.rs"]
+pub mod png;
+#[path = "../../../src/qoi.rs"]
+pub mod qoi;
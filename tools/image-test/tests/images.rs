@@ -0,0 +1,361 @@
+use image_test::{inflate, png as decoder, qoi as qoi_decoder};
+
+const WORK: usize = decoder::WINDOW + 16 * 1024;
+
+/// Colour fields with flat areas, so every encoder op gets used.
+fn pattern(width: u32, height: u32, channels: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut noise = 0x1234_5678u32;
+    for y in 0..height {
+        for x in 0..width {
+            noise ^= noise << 13;
+            noise ^= noise >> 17;
+            noise ^= noise << 5;
+            let pixel = match (x / 8 + y / 8) % 4 {
+                0 => [200, 30, 90, 255],
+                1 => [x as u8, y as u8, (x + y) as u8, 255 - x as u8],
+                2 => noise.to_le_bytes(),
+                _ => [x as u8 / 2, x as u8 / 2 + 1, x as u8 / 2 + 2, 128],
+            };
+            data.extend_from_slice(&pixel[..channels]);
+        }
+    }
+    data
+}
+
+fn rgba(data: &[u8], channels: usize) -> Vec<[u8; 4]> {
+    data.chunks(channels)
+        .map(|pixel| match channels {
+            1 => [pixel[0], pixel[0], pixel[0], 255],
+            2 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+            3 => [pixel[0], pixel[1], pixel[2], 255],
+            _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        })
+        .collect()
+}
+
+fn decode_qoi(file: &[u8]) -> Result<Vec<[u8; 4]>, qoi_decoder::Error<()>> {
+    let mut bytes = file.iter().copied();
+    let mut image = qoi_decoder::Decoder::new(|| bytes.next().ok_or(()))?;
+    let count = image.width() * image.height();
+    (0..count).map(|_| image.next_pixel()).collect()
+}
+
+fn decode_png(file: &[u8], work: &mut [u8]) -> Result<Vec<[u8; 4]>, decoder::Error<()>> {
+    let mut bytes = file.iter().copied();
+    let mut image = decoder::Decoder::new(|| bytes.next().ok_or(()), work)?;
+    let mut pixels = Vec::new();
+    while image.next_row()? {
+        pixels.extend((0..image.width()).map(|x| image.pixel(x)));
+    }
+    assert_eq!(pixels.len() as u32, image.width() * image.height());
+    Ok(pixels)
+}
+
+struct Png<'a> {
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    data: &'a [u8],
+    filter: png::FilterType,
+    palette: Option<(&'a [u8], &'a [u8])>,
+    /// Splits the image data into IDAT chunks of this size.
+    chunk: Option<usize>,
+}
+
+impl<'a> Png<'a> {
+    fn new(width: u32, height: u32, color: png::ColorType, data: &'a [u8]) -> Self {
+        Self {
+            width,
+            height,
+            color,
+            depth: png::BitDepth::Eight,
+            data,
+            filter: png::FilterType::Paeth,
+            palette: None,
+            chunk: None,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut encoder = png::Encoder::new(&mut file, self.width, self.height);
+        encoder.set_color(self.color);
+        encoder.set_depth(self.depth);
+        encoder.set_filter(self.filter);
+        encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+        if let Some((palette, alpha)) = self.palette {
+            encoder.set_palette(palette.to_vec());
+            encoder.set_trns(alpha.to_vec());
+        }
+        let mut writer = encoder.write_header().unwrap();
+        match self.chunk {
+            Some(size) => {
+                use std::io::Write;
+                let mut stream = writer.stream_writer_with_size(size).unwrap();
+                stream.write_all(self.data).unwrap();
+                stream.finish().unwrap();
+            }
+            None => writer.write_image_data(self.data).unwrap(),
+        }
+        drop(writer);
+        file
+    }
+}
+
+/// Packs `depth`-bit samples into rows the way PNG stores them.
+fn pack(samples: &[u8], width: usize, depth: usize) -> Vec<u8> {
+    let stride = (width * depth).div_ceil(8);
+    let mut data = Vec::new();
+    for row in samples.chunks(width) {
+        let mut line = vec![0u8; stride];
+        for (x, &sample) in row.iter().enumerate() {
+            let bit = x * depth;
+            line[bit / 8] |= sample << (8 - depth - bit % 8);
+        }
+        data.extend(line);
+    }
+    data
+}
+
+#[test]
+fn qoi_matches_reference() {
+    for channels in [3, 4] {
+        let (width, height) = (67, 45);
+        let data = pattern(width, height, channels);
+        let colorspace = if channels == 3 {
+            qoi::ColorSpace::Srgb
+        } else {
+            qoi::ColorSpace::Linear
+        };
+        let file = qoi::Encoder::new(&data, width, height)
+            .unwrap()
+            .with_colorspace(colorspace)
+            .encode_to_vec()
+            .unwrap();
+        assert_eq!(decode_qoi(&file).unwrap(), rgba(&data, channels));
+    }
+}
+
+#[test]
+fn qoi_rejects_other_files() {
+    let data = pattern(4, 4, 3);
+    let file = Png::new(4, 4, png::ColorType::Rgb, &data).encode();
+    assert_eq!(decode_qoi(&file), Err(qoi_decoder::Error::Format));
+
+    let data = pattern(16, 16, 3);
+    let file = qoi::encode_to_vec(&data, 16, 16).unwrap();
+    assert_eq!(decode_qoi(&file[..40]), Err(qoi_decoder::Error::Read(())));
+}
+
+#[test]
+fn png_every_filter() {
+    let (width, height) = (53, 31);
+    let data = pattern(width, height, 3);
+    for filter in [
+        png::FilterType::NoFilter,
+        png::FilterType::Sub,
+        png::FilterType::Up,
+        png::FilterType::Avg,
+        png::FilterType::Paeth,
+    ] {
+        let mut image = Png::new(width, height, png::ColorType::Rgb, &data);
+        image.filter = filter;
+        let mut work = vec![0; WORK];
+        let pixels = decode_png(&image.encode(), &mut work).unwrap();
+        assert_eq!(pixels, rgba(&data, 3), "{:?}", filter);
+    }
+}
+
+#[test]
+fn png_color_types() {
+    let (width, height) = (40, 23);
+    for (color, channels) in [
+        (png::ColorType::Grayscale, 1),
+        (png::ColorType::GrayscaleAlpha, 2),
+        (png::ColorType::Rgb, 3),
+        (png::ColorType::Rgba, 4),
+    ] {
+        let data = pattern(width, height, channels);
+        let mut work = vec![0; WORK];
+        let pixels = decode_png(&Png::new(width, height, color, &data).encode(), &mut work);
+        assert_eq!(pixels.unwrap(), rgba(&data, channels), "{:?}", color);
+    }
+}
+
+#[test]
+fn png_sixteen_bit_keeps_high_byte() {
+    let (width, height) = (19, 17);
+    let high = pattern(width, height, 4);
+    let data: Vec<u8> = high.iter().flat_map(|&byte| [byte, !byte]).collect();
+    let mut image = Png::new(width, height, png::ColorType::Rgba, &data);
+    image.depth = png::BitDepth::Sixteen;
+    let mut work = vec![0; WORK];
+    assert_eq!(
+        decode_png(&image.encode(), &mut work).unwrap(),
+        rgba(&high, 4)
+    );
+}
+
+#[test]
+fn png_low_bit_depths() {
+    let (width, height) = (29, 11);
+    let palette: Vec<u8> = (0..=255u8).flat_map(|i| [i, 255 - i, i / 2]).collect();
+    let alpha: Vec<u8> = (0..16u8).map(|i| i * 16).collect();
+    for (depth, bits) in [
+        (png::BitDepth::One, 1),
+        (png::BitDepth::Two, 2),
+        (png::BitDepth::Four, 4),
+        (png::BitDepth::Eight, 8),
+    ] {
+        let max = ((1u32 << bits) - 1) as u8;
+        let samples: Vec<u8> = pattern(width, height, 1)
+            .iter()
+            .map(|&value| value & max)
+            .collect();
+        let data = pack(&samples, width as usize, bits);
+
+        let mut gray = Png::new(width, height, png::ColorType::Grayscale, &data);
+        gray.depth = depth;
+        let expected: Vec<[u8; 4]> = samples
+            .iter()
+            .map(|&sample| {
+                let value = (sample as u32 * 255 / max as u32) as u8;
+                [value, value, value, 255]
+            })
+            .collect();
+        let mut work = vec![0; WORK];
+        assert_eq!(decode_png(&gray.encode(), &mut work).unwrap(), expected);
+
+        let colors = 1usize << bits;
+        let mut indexed = Png::new(width, height, png::ColorType::Indexed, &data);
+        indexed.depth = depth;
+        indexed.palette = Some((&palette[..colors * 3], &alpha[..alpha.len().min(colors)]));
+        let expected: Vec<[u8; 4]> = samples
+            .iter()
+            .map(|&index| {
+                let i = index as usize;
+                let a = alpha.get(i).copied().unwrap_or(255);
+                [palette[i * 3], palette[i * 3 + 1], palette[i * 3 + 2], a]
+            })
+            .collect();
+        let mut work = vec![0; WORK];
+        assert_eq!(decode_png(&indexed.encode(), &mut work).unwrap(), expected);
+    }
+}
+
+#[test]
+fn png_split_image_data() {
+    let (width, height) = (64, 64);
+    let data = pattern(width, height, 4);
+    let mut image = Png::new(width, height, png::ColorType::Rgba, &data);
+    image.chunk = Some(100);
+    let file = image.encode();
+    assert!(file.windows(4).filter(|kind| kind == b"IDAT").count() > 1);
+    let mut work = vec![0; WORK];
+    assert_eq!(decode_png(&file, &mut work).unwrap(), rgba(&data, 4));
+}
+
+#[test]
+fn png_limits() {
+    let data = pattern(8, 8, 3);
+    let mut file = Png::new(8, 8, png::ColorType::Rgb, &data).encode();
+    let mut work = vec![0; WORK];
+
+    // Signature, IHDR length and type, then width, height, depth, colour
+    // type, compression and filter come before the interlace method.
+    file[8 + 8 + 12] = 1;
+    assert_eq!(
+        decode_png(&file, &mut work),
+        Err(decoder::Error::Unsupported)
+    );
+
+    let data = pattern(2000, 1, 4);
+    let file = Png::new(2000, 1, png::ColorType::Rgba, &data).encode();
+    let mut work = vec![0; decoder::WINDOW + 8000];
+    assert_eq!(
+        decode_png(&file, &mut work),
+        Err(decoder::Error::Unsupported)
+    );
+    let mut work = vec![0; decoder::WINDOW + 16000];
+    assert_eq!(decode_png(&file, &mut work).unwrap(), rgba(&data, 4));
+
+    assert_eq!(
+        decode_png(&file[..file.len() - 40], &mut work),
+        Err(decoder::Error::Read(()))
+    );
+}
+
+fn inflate_all(stream: &[u8], window: &mut [u8]) -> Result<Vec<u8>, inflate::Error<()>> {
+    let mut bytes = stream.iter().copied();
+    let mut input = || bytes.next().ok_or(());
+    let mut inflate = inflate::Inflate::new(window);
+    let mut data = Vec::new();
+    let mut buf = [0; 1000];
+    loop {
+        let len = inflate.read(&mut input, &mut buf)?;
+        data.extend_from_slice(&buf[..len]);
+        if len < buf.len() {
+            return Ok(data);
+        }
+    }
+}
+
+#[test]
+fn inflate_every_block_type() {
+    // Long enough for references across the whole window.
+    let mut data = pattern(300, 200, 4);
+    data.extend_from_within(..50_000);
+    // Stored, fixed Huffman for short input, dynamic Huffman.
+    for level in [0, 1, 6, 10] {
+        let stream = miniz_oxide::deflate::compress_to_vec_zlib(&data, level);
+        let mut window = vec![0; 32 * 1024];
+        assert_eq!(
+            inflate_all(&stream, &mut window).unwrap(),
+            data,
+            "{}",
+            level
+        );
+    }
+    let short = b"abcabcabcabd";
+    let stream = miniz_oxide::deflate::compress_to_vec_zlib(short, 1);
+    let mut window = vec![0; 32 * 1024];
+    assert_eq!(inflate_all(&stream, &mut window).unwrap(), short);
+}
+
+#[test]
+fn inflate_checks_stream() {
+    let data = pattern(64, 64, 4);
+    let mut stream = miniz_oxide::deflate::compress_to_vec_zlib(&data, 6);
+    let mut window = vec![0; 32 * 1024];
+
+    let last = stream.len() - 1;
+    stream[last] ^= 1;
+    assert_eq!(
+        inflate_all(&stream, &mut window),
+        Err(inflate::Error::Format)
+    );
+
+    stream[0] = 0x79;
+    assert_eq!(
+        inflate_all(&stream, &mut window),
+        Err(inflate::Error::Format)
+    );
+}
+
+#[test]
+fn inflate_bounded_window() {
+    // Noise, so the only matches are a whole block back.
+    let block = pattern(32, 16, 4);
+    let data = [&block[..], &block[..]].concat();
+    let stream = miniz_oxide::deflate::compress_to_vec_zlib(&data, 6);
+
+    let mut window = vec![0; 1024];
+    assert_eq!(
+        inflate_all(&stream, &mut window),
+        Err(inflate::Error::TooFar)
+    );
+    let mut window = vec![0; 4096];
+    assert_eq!(inflate_all(&stream, &mut window).unwrap(), data);
+}
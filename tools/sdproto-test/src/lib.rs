@@ -0,0 +1,10 @@
+//! Host build of the loader's SD protocol module, so its framing and CRCs can
+//! be tested without a card. The loader's `.cargo/config.toml` builds for the
+//! RP2040, so name the host target:
+//!
+//! ```sh
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+
+#[path = "../../../src/sdproto.rs"]
+pub mod sdproto;
@@ -0,0 +1,204 @@
+use sdproto_test::sdproto::*;
+
+/// One line at a time, bit by bit, as the SD specification defines it.
+fn crc16_line(bits: impl Iterator<Item = bool>) -> u16 {
+    bits.fold(0, |crc, bit| {
+        let feedback = (crc >> 15 == 1) ^ bit;
+        crc << 1 ^ if feedback { 0x1021 } else { 0 }
+    })
+}
+
+fn interleave(crcs: [u16; 4]) -> u64 {
+    (0..64).fold(0, |crc, bit| {
+        crc | ((crcs[bit % 4] >> (bit / 4) & 1) as u64) << bit
+    })
+}
+
+/// Shifts the bits after the start bit into words the way the command state
+/// machine does.
+fn pack_response(frame: &[u8], bits: usize) -> Vec<u32> {
+    let mut words = vec![0u32; response_words(bits)];
+    for i in 0..bits {
+        let bit = frame[(i + 1) / 8] >> (7 - (i + 1) % 8) & 1;
+        let word = &mut words[i / 32];
+        *word = *word << 1 | bit as u32;
+    }
+    words
+}
+
+#[test]
+fn command_frames() {
+    assert_eq!(command_frame(CMD0, 0), [0x40, 0, 0, 0, 0, 0x95]);
+    assert_eq!(command_frame(CMD8, IF_COND), [0x48, 0, 0, 0x01, 0xAA, 0x87]);
+    assert_eq!(command_frame(CMD55, 0), [0x77, 0, 0, 0, 0, 0x65]);
+    assert_eq!(
+        command_frame(ACMD41, 0x4000_0000),
+        [0x69, 0x40, 0, 0, 0, 0x77]
+    );
+}
+
+#[test]
+fn command_words_are_padded_frames() {
+    assert_eq!(command_words(CMD0, 0), [0xFFFF_4000, 0x0000_0095]);
+    assert_eq!(command_words(CMD8, IF_COND), [0xFFFF_4800, 0x0001_AA87]);
+}
+
+#[test]
+fn short_response_round_trip() {
+    let mut frame = command_frame(CMD17, 0x0000_0900);
+    frame[0] &= 0x3F;
+    frame[5] = crc7(&frame[..5]) << 1 | 1;
+    let words = pack_response(&frame, 47);
+    assert_eq!(words.len(), 2);
+
+    let mut unpacked = [0; 6];
+    unpack_response(&words, 47, &mut unpacked);
+    assert_eq!(unpacked, frame);
+    assert_eq!(r1_status(&unpacked, CMD17), Ok(0x0000_0900));
+    assert_eq!(r1_status(&unpacked, CMD18), Err(SdError::Crc));
+
+    unpacked[3] ^= 0x10;
+    assert_eq!(r1_status(&unpacked, CMD17), Err(SdError::Crc));
+}
+
+#[test]
+fn long_response_round_trip() {
+    let mut frame = [0u8; 17];
+    frame[0] = 0x3F;
+    for (i, byte) in frame[1..16].iter_mut().enumerate() {
+        *byte = (i as u8).wrapping_mul(37) ^ 0xA5;
+    }
+    frame[16] = crc7(&frame[1..16]) << 1 | 1;
+    let words = pack_response(&frame, 135);
+    assert_eq!(words.len(), 5);
+
+    let mut unpacked = [0; 17];
+    unpack_response(&words, 135, &mut unpacked);
+    assert_eq!(unpacked, frame);
+}
+
+#[test]
+fn card_status_errors() {
+    // Transfer state, ready for data.
+    assert_eq!(check_status(0x0000_0900), Ok(()));
+    // App command flag alone is no error.
+    assert_eq!(check_status(0x0000_0920), Ok(()));
+    assert_eq!(check_status(0x4000_0900), Err(SdError::Response(0x40)));
+    // Illegal command.
+    assert_eq!(check_status(0x0040_0900), Err(SdError::Response(0x40)));
+}
+
+#[test]
+fn crc16_known_answer() {
+    assert_eq!(crc16(&[0xFF; 512]), 0x7FA1);
+    assert_eq!(crc16(&[0; 512]), 0);
+}
+
+#[test]
+fn crc16_matches_bitwise() {
+    let data: Vec<u8> = (0..512u32).map(|i| (i * 7 + i / 13) as u8).collect();
+    let bits = data
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1));
+    assert_eq!(crc16(&data), crc16_line(bits));
+}
+
+#[test]
+fn crc16_4bit_matches_each_line() {
+    let data: Vec<u8> = (0..512u32).map(|i| (i * 7 + i / 13) as u8).collect();
+    let lines = [0, 1, 2, 3].map(|line| {
+        crc16_line(
+            data.iter()
+                .flat_map(|&byte| [byte >> 4, byte & 0x0F])
+                .map(|nibble| nibble >> line & 1 == 1),
+        )
+    });
+    assert_eq!(crc16_4bit(&data), interleave(lines));
+}
+
+#[test]
+fn crc16_4bit_known_answer() {
+    // 512 bytes of 0xFF on one line give 0x7FA1. Spread over four lines that
+    // takes 2048 bytes, and every line sees the same bits.
+    assert_eq!(crc16_4bit(&[0xFF; 2048]), interleave([0x7FA1; 4]));
+    assert_eq!(crc16_4bit(&[0; 512]), 0);
+}
+
+#[test]
+fn csd_v2() {
+    let csd = Csd([
+        0x40, 0x0E, 0x00, 0x32, 0x5B, 0x59, 0x00, 0x00, 0xED, 0xC8, 0x7F, 0x80, 0x0A, 0x40, 0x40,
+        0xDF,
+    ]);
+    assert_eq!(csd.version(), 2);
+    assert_eq!(csd.capacity(), 60873 * 512 * 1024);
+    assert_eq!(csd.command_classes(), 0x5B5);
+    assert_eq!(csd.max_clock(), 25_000_000);
+}
+
+#[test]
+fn fallback_steps_down_to_init_clock() {
+    // What a 125 MHz peripheral clock makes of 25 MHz and 20 MHz.
+    assert_eq!(slower(25_000_000), Some(12_500_000));
+    assert_eq!(slower(17_857_142), Some(12_500_000));
+    assert_eq!(slower(12_500_000), Some(8_000_000));
+    assert_eq!(slower(1_000_000), Some(INIT_HZ));
+    assert_eq!(slower(INIT_HZ), None);
+    assert_eq!(slower(390_625), None);
+}
+
+#[test]
+fn clock_memory_by_cid() {
+    let cid = |n: u8| Cid([n; 16]);
+    let mut memory = ClockMemory::new();
+    assert_eq!(memory.get(&cid(1)), None);
+    memory.set(&cid(1), 12_500_000);
+    memory.set(&cid(2), 4_000_000);
+    memory.set(&cid(1), 8_000_000);
+    assert_eq!(memory.get(&cid(1)), Some(8_000_000));
+    assert_eq!(memory.get(&cid(2)), Some(4_000_000));
+
+    // Once full, the card stored first makes room.
+    for n in 3..=REMEMBERED_CARDS as u8 + 1 {
+        memory.set(&cid(n), 1_000_000);
+    }
+    assert_eq!(memory.get(&cid(1)), None);
+    assert_eq!(memory.get(&cid(2)), Some(4_000_000));
+    assert_eq!(
+        memory.get(&cid(REMEMBERED_CARDS as u8 + 1)),
+        Some(1_000_000)
+    );
+}
+
+#[test]
+fn clock_memory_survives_bytes() {
+    let cid = |n: u8| Cid([n; 16]);
+    let mut memory = ClockMemory::new();
+    for n in 1..=REMEMBERED_CARDS as u8 + 2 {
+        memory.set(&cid(n), 1_000_000 * n as u32);
+    }
+    let mut loaded = ClockMemory::from_bytes(&memory.to_bytes());
+    assert!(loaded.to_bytes() == memory.to_bytes());
+    assert_eq!(loaded.get(&cid(4)), Some(4_000_000));
+
+    // Replaced in the same order as before the round trip.
+    loaded.set(&cid(100), 390_625);
+    memory.set(&cid(100), 390_625);
+    assert_eq!(loaded.get(&cid(3)), None);
+    assert!(loaded.to_bytes() == memory.to_bytes());
+}
+
+#[test]
+fn clock_memory_skips_damaged_records() {
+    let mut memory = ClockMemory::new();
+    memory.set(&Cid([1; 16]), 8_000_000);
+    memory.set(&Cid([2; 16]), 4_000_000);
+    let mut bytes = memory.to_bytes();
+    // The first clock reads as 8 Hz, the second record is cut short.
+    bytes[16..20].copy_from_slice(&8u32.to_le_bytes());
+    let loaded = ClockMemory::from_bytes(&bytes[..30]);
+    assert_eq!(loaded.get(&Cid([1; 16])), None);
+    assert_eq!(loaded.get(&Cid([2; 16])), None);
+    assert!(ClockMemory::from_bytes(&[]) == ClockMemory::new());
+    assert_eq!(CLOCK_MEMORY_LEN, 20 * REMEMBERED_CARDS);
+}
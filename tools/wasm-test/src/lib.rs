@@ -0,0 +1,10 @@
+//! Host build of the loader's WebAssembly runtime, run against a recording
+//! `Platform` instead of the display, buttons and card. The loader's
+//! `.cargo/config.toml` builds for the RP2040, so name the host target:
+//!
+//! ```sh
+//! cargo test --target x86_64-unknown-linux-gnu
+//! ```
+
+#[path = "../../../src/wasm.rs"]
+pub mod wasm;
@@ -0,0 +1,206 @@
+use wasm_test::wasm::{self, Limits, Platform, WasmError, BUTTON_SELECT, BUTTON_UP};
+
+#[derive(Debug, PartialEq)]
+enum Call {
+    Rect(u8, u8, u8, u8, u32),
+    Text(String, u8, u8, u32, u32, u32),
+    Buttons,
+    Read(String, u32, usize),
+}
+
+/// Records every call and serves reads from one file.
+struct FakePlatform {
+    calls: Vec<Call>,
+    held: u32,
+    file: (&'static str, &'static [u8]),
+}
+
+impl FakePlatform {
+    fn new() -> Self {
+        Self {
+            calls: Vec::new(),
+            held: BUTTON_UP | BUTTON_SELECT,
+            file: ("DATA.TXT", b"abcdefgh"),
+        }
+    }
+}
+
+impl Platform for FakePlatform {
+    fn draw_rect(&mut self, x: u8, y: u8, width: u8, height: u8, rgb: u32) {
+        self.calls.push(Call::Rect(x, y, width, height, rgb));
+    }
+
+    fn draw_text(&mut self, text: &str, x: u8, y: u8, size_scalar: u32, fg: u32, bg: u32) {
+        self.calls
+            .push(Call::Text(text.into(), x, y, size_scalar, fg, bg));
+    }
+
+    fn buttons(&mut self) -> u32 {
+        self.calls.push(Call::Buttons);
+        self.held
+    }
+
+    fn read_file(&mut self, name: &str, offset: u32, buf: &mut [u8]) -> Result<usize, i32> {
+        self.calls.push(Call::Read(name.into(), offset, buf.len()));
+        let (file, data) = self.file;
+        if name != file {
+            return Err(-1);
+        }
+        let data = data.get(offset as usize..).unwrap_or_default();
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+}
+
+const IMPORTS: &str = r#"
+    (import "env" "draw_rect" (func $draw_rect (param i32 i32 i32 i32 i32)))
+    (import "env" "draw_text" (func $draw_text (param i32 i32 i32 i32 i32 i32 i32)))
+    (import "env" "buttons" (func $buttons (result i32)))
+    (import "env" "read_file" (func $read_file (param i32 i32 i32 i32 i32) (result i32)))
+"#;
+
+/// A module with the imports above, one page of memory and `body`.
+fn module(body: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"(module {IMPORTS} (memory (export "memory") 1) {body})"#
+    ))
+    .unwrap()
+}
+
+fn run(wasm: &[u8], platform: &mut FakePlatform) -> Result<i32, WasmError> {
+    wasm::run(wasm, platform, Limits::default())
+}
+
+#[test]
+fn every_import_reaches_the_platform() {
+    let wasm = module(
+        r#"
+        (data (i32.const 0) "Hello")
+        (data (i32.const 16) "DATA.TXT")
+        (func (export "main") (result i32)
+            (local $read i32)
+            (call $draw_rect (i32.const 1) (i32.const 2) (i32.const 30) (i32.const 40)
+                (i32.const 0xFF0000))
+            (call $draw_text (i32.const 0) (i32.const 5) (i32.const 10) (i32.const 20)
+                (i32.const 2) (i32.const 0xFFFFFF) (i32.const 0))
+            (local.set $read (call $read_file (i32.const 16) (i32.const 8) (i32.const 2)
+                (i32.const 64) (i32.const 4)))
+            ;; Show what was read, straight from linear memory.
+            (call $draw_text (i32.const 64) (local.get $read) (i32.const 0) (i32.const 0)
+                (i32.const 1) (i32.const 1) (i32.const 2))
+            (i32.add (call $buttons) (i32.mul (local.get $read) (i32.const 100))))
+        "#,
+    );
+    let mut platform = FakePlatform::new();
+    assert_eq!(run(&wasm, &mut platform), Ok(405));
+    assert_eq!(
+        platform.calls,
+        [
+            Call::Rect(1, 2, 30, 40, 0xFF0000),
+            Call::Text("Hello".into(), 10, 20, 2, 0xFFFFFF, 0),
+            Call::Read("DATA.TXT".into(), 2, 4),
+            Call::Text("cdef".into(), 0, 0, 1, 1, 2),
+            Call::Buttons,
+        ]
+    );
+}
+
+#[test]
+fn platform_errors_reach_the_module() {
+    let wasm = module(
+        r#"
+        (data (i32.const 16) "NOPE.TXT")
+        (func (export "main") (result i32)
+            (call $read_file (i32.const 16) (i32.const 8) (i32.const 0) (i32.const 64)
+                (i32.const 4)))
+        "#,
+    );
+    assert_eq!(run(&wasm, &mut FakePlatform::new()), Ok(-1));
+}
+
+#[test]
+fn long_names_are_rejected_not_truncated() {
+    let wasm = module(
+        r#"
+        (data (i32.const 16) "DATA.TXT.LONG")
+        (func (export "main") (result i32)
+            (call $read_file (i32.const 16) (i32.const 13) (i32.const 0) (i32.const 64)
+                (i32.const 4)))
+        "#,
+    );
+    let mut platform = FakePlatform::new();
+    assert_eq!(run(&wasm, &mut platform), Ok(-3));
+    assert!(platform.calls.is_empty());
+}
+
+#[test]
+fn pointers_outside_memory_are_rejected() {
+    let wasm = module(
+        r#"
+        (data (i32.const 16) "DATA.TXT")
+        (func (export "main") (result i32)
+            (call $draw_text (i32.const 65534) (i32.const 4) (i32.const 0) (i32.const 0)
+                (i32.const 1) (i32.const 0) (i32.const 0))
+            (call $read_file (i32.const 16) (i32.const 8) (i32.const 0) (i32.const -4)
+                (i32.const 4)))
+        "#,
+    );
+    let mut platform = FakePlatform::new();
+    assert_eq!(run(&wasm, &mut platform), Ok(-3));
+    assert!(platform.calls.is_empty());
+}
+
+#[test]
+fn fuel_exhaustion_traps() {
+    let wasm =
+        module(r#"(func (export "main") (result i32) (loop $spin (br $spin)) (i32.const 0))"#);
+    let limits = Limits {
+        fuel: 10_000,
+        ..Limits::default()
+    };
+    assert_eq!(
+        wasm::run(&wasm, &mut FakePlatform::new(), limits),
+        Err(WasmError::OutOfFuel)
+    );
+}
+
+#[test]
+fn growing_past_the_memory_limit_traps() {
+    let wasm = module(r#"(func (export "main") (result i32) (memory.grow (i32.const 1)))"#);
+    assert_eq!(
+        run(&wasm, &mut FakePlatform::new()),
+        Err(WasmError::OutOfMemory)
+    );
+    let limits = Limits {
+        memory_bytes: 2 * wasm::WASM_PAGE_SIZE,
+        ..Limits::default()
+    };
+    assert_eq!(wasm::run(&wasm, &mut FakePlatform::new(), limits), Ok(1));
+}
+
+#[test]
+fn initial_memory_past_the_limit_is_refused() {
+    let wasm =
+        wat::parse_str(r#"(module (memory 2) (func (export "main") (result i32) (i32.const 0)))"#)
+            .unwrap();
+    assert_eq!(run(&wasm, &mut FakePlatform::new()), Err(WasmError::Link));
+}
+
+#[test]
+fn runaway_recursion_traps() {
+    let wasm = module(r#"(func $main (export "main") (result i32) (call $main))"#);
+    assert_eq!(run(&wasm, &mut FakePlatform::new()), Err(WasmError::Trap));
+}
+
+#[test]
+fn bad_modules_are_reported() {
+    let mut platform = FakePlatform::new();
+    assert_eq!(run(b"\0asm junk", &mut platform), Err(WasmError::Invalid));
+    let unknown = wat::parse_str(
+        r#"(module (import "env" "beep" (func)) (func (export "main") (result i32) (i32.const 0)))"#,
+    )
+    .unwrap();
+    assert_eq!(run(&unknown, &mut platform), Err(WasmError::Link));
+    assert_eq!(run(&module(""), &mut platform), Err(WasmError::NoMain));
+}